@@ -0,0 +1,75 @@
+// Attr cache :: Remembers attributes looked up in the source for a moment.
+//
+// Entries the cache doesn't cover, because they changed since it was built or only show up in
+// the live listing, are looked up in the source on every getattr, which adds up quickly over a
+// network. Their attributes, including that they don't exist, are kept for a few seconds instead,
+// and forgotten as soon as the mount modifies them or the watch on the source sees them change.
+//
+
+use crate::libc_extras::libc;
+use crate::utils::path_to_rel;
+use fuse_mt::FileAttr;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How many entries are kept at most, beyond that expired ones are dropped.
+const MAX_ENTRIES: usize = 16 * 1024;
+
+/// When attributes were looked up, and what that returned.
+type Lookup = (Instant, Result<FileAttr, libc::c_int>);
+
+pub struct AttrCache {
+    ttl: Duration,
+    /// By path relative to the source.
+    entries: Mutex<HashMap<PathBuf, Lookup>>,
+}
+
+impl AttrCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            entries: Mutex::default(),
+        }
+    }
+
+    /// The attributes of `path`, looked up with `lookup` unless that was done recently.
+    pub fn get(
+        &self,
+        path: &Path,
+        lookup: impl FnOnce() -> Result<FileAttr, libc::c_int>,
+    ) -> Result<FileAttr, libc::c_int> {
+        let path = path_to_rel(path);
+        if let Some((looked_up, result)) = self.entries.lock().unwrap().get(path) {
+            if looked_up.elapsed() < self.ttl {
+                return *result;
+            }
+        }
+        let result = lookup();
+        // other errors, like those of a lost connection, are worth asking again
+        if let Ok(_) | Err(libc::ENOENT) = result {
+            let mut entries = self.entries.lock().unwrap();
+            if entries.len() >= MAX_ENTRIES {
+                let ttl = self.ttl;
+                entries.retain(|_, (looked_up, _)| looked_up.elapsed() < ttl);
+                if entries.len() >= MAX_ENTRIES {
+                    entries.clear();
+                }
+            }
+            entries.insert(path.to_path_buf(), (Instant::now(), result));
+        }
+        result
+    }
+
+    /// Forgets the attributes of `path`, and with `recursive` of everything below it.
+    pub fn forget(&self, path: &Path, recursive: bool) {
+        let path = path_to_rel(path);
+        let mut entries = self.entries.lock().unwrap();
+        if recursive {
+            entries.retain(|other, _| !other.starts_with(path));
+        } else {
+            entries.remove(path);
+        }
+    }
+}
@@ -0,0 +1,292 @@
+// Audio :: Reads the length of audio files from their headers.
+//
+// Songs are usually MP3, Ogg Vorbis or Opus, FLAC, WAV or M4A. Each of them states its length
+// somewhere near the start, or for Ogg in the last page, so only a few KiB are read instead of
+// decoding the file. MP3s without a Xing or VBRI header are assumed to have a constant bitrate.
+//
+
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::path::Path;
+
+/// How much is read from the start or end of a file to find its header in.
+const PROBE: usize = 64 * 1024;
+
+/// The length of the audio file at `path` in seconds, or `None` if its format isn't known or
+/// its headers don't say.
+pub fn duration(path: &Path) -> io::Result<Option<f64>> {
+    let extension = match path.extension().and_then(|x| x.to_str()) {
+        Some(extension) => extension.to_lowercase(),
+        None => return Ok(None),
+    };
+    let mut file = File::open(path)?;
+    match extension.as_str() {
+        "mp3" => mp3(&mut file),
+        "ogg" | "opus" => ogg(&mut file),
+        "flac" => flac(&mut file),
+        "wav" => wav(&mut file),
+        "m4a" | "mp4" => mp4(&mut file),
+        _ => Ok(None),
+    }
+}
+
+/// Reads up to [`PROBE`] bytes at `offset`.
+fn read_at(file: &mut File, offset: u64) -> io::Result<Vec<u8>> {
+    file.seek(SeekFrom::Start(offset))?;
+    let mut data = Vec::with_capacity(PROBE);
+    file.take(PROBE as u64).read_to_end(&mut data)?;
+    Ok(data)
+}
+
+fn u16_le(data: &[u8]) -> u16 {
+    u16::from_le_bytes([data[0], data[1]])
+}
+
+fn u32_le(data: &[u8]) -> u32 {
+    u32::from_le_bytes([data[0], data[1], data[2], data[3]])
+}
+
+fn u32_be(data: &[u8]) -> u32 {
+    u32::from_be_bytes([data[0], data[1], data[2], data[3]])
+}
+
+fn u64_be(data: &[u8]) -> u64 {
+    (u32_be(data) as u64) << 32 | u32_be(&data[4..]) as u64
+}
+
+/// Bitrates in kbit/s by MPEG version 1 or later, layer and index.
+const MP3_BITRATES: [[[u16; 15]; 3]; 2] = [
+    [
+        [
+            0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
+        ],
+        [
+            0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+        ],
+        [
+            0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+        ],
+    ],
+    [
+        [
+            0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256,
+        ],
+        [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+        [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+    ],
+];
+
+/// The header of an MPEG audio frame.
+struct Frame {
+    mpeg1: bool,
+    mono: bool,
+    /// In bit/s.
+    bitrate: u32,
+    sample_rate: u32,
+    samples: u32,
+}
+
+impl Frame {
+    fn parse(header: &[u8]) -> Option<Self> {
+        if header[0] != 0xFF || header[1] & 0xE0 != 0xE0 {
+            return None;
+        }
+        // 0: MPEG 2.5, 2: MPEG 2, 3: MPEG 1
+        let version = (header[1] >> 3) & 3;
+        // 1: layer III, 2: layer II, 3: layer I
+        let layer = (header[1] >> 1) & 3;
+        let bitrate = (header[2] >> 4) as usize;
+        let sample_rate = ((header[2] >> 2) & 3) as usize;
+        if version == 1 || layer == 0 || bitrate == 0 || bitrate == 15 || sample_rate == 3 {
+            return None;
+        }
+        let mpeg1 = version == 3;
+        let layer = 3 - layer as usize;
+        let sample_rate = [44100, 48000, 32000][sample_rate] >> (3 - version.max(1));
+        let samples = match layer {
+            0 => 384,
+            2 if !mpeg1 => 576,
+            _ => 1152,
+        };
+        Some(Self {
+            mpeg1,
+            mono: header[3] >> 6 == 3,
+            bitrate: MP3_BITRATES[!mpeg1 as usize][layer][bitrate] as u32 * 1000,
+            sample_rate,
+            samples,
+        })
+    }
+
+    fn seconds(&self, frames: u32) -> f64 {
+        frames as f64 * self.samples as f64 / self.sample_rate as f64
+    }
+}
+
+fn mp3(file: &mut File) -> io::Result<Option<f64>> {
+    let head = read_at(file, 0)?;
+    // skip an ID3v2 tag, which may hold a whole cover
+    let mut start = 0;
+    if head.len() >= 10 && head.starts_with(b"ID3") {
+        let size = head[6..10]
+            .iter()
+            .fold(0u64, |size, &byte| size << 7 | (byte & 0x7F) as u64);
+        let footer = if head[5] & 0x10 != 0 { 10 } else { 0 };
+        start = 10 + size + footer;
+    }
+    let data = read_at(file, start)?;
+
+    let offset =
+        match (0..data.len().saturating_sub(4)).find(|&i| Frame::parse(&data[i..]).is_some()) {
+            Some(offset) => offset,
+            None => return Ok(None),
+        };
+    let frame = Frame::parse(&data[offset..]).unwrap();
+    let side_info = match (frame.mpeg1, frame.mono) {
+        (true, false) => 32,
+        (true, true) | (false, false) => 17,
+        (false, true) => 9,
+    };
+    let xing = offset + 4 + side_info;
+    if let Some(tag) = data.get(xing..xing + 12) {
+        if (&tag[..4] == b"Xing" || &tag[..4] == b"Info") && u32_be(&tag[4..]) & 1 != 0 {
+            return Ok(Some(frame.seconds(u32_be(&tag[8..]))));
+        }
+    }
+    let vbri = offset + 4 + 32;
+    if let Some(tag) = data.get(vbri..vbri + 18) {
+        if &tag[..4] == b"VBRI" {
+            return Ok(Some(frame.seconds(u32_be(&tag[14..]))));
+        }
+    }
+
+    let length = file.metadata()?.len();
+    let audio = length.saturating_sub(start + offset as u64);
+    Ok(Some(audio as f64 * 8.0 / frame.bitrate as f64))
+}
+
+fn ogg(file: &mut File) -> io::Result<Option<f64>> {
+    let head = read_at(file, 0)?;
+    if head.len() < 28 || !head.starts_with(b"OggS") {
+        return Ok(None);
+    }
+    let packet = 27 + head[26] as usize;
+    let packet = match head.get(packet..packet + 19) {
+        Some(packet) => packet,
+        None => return Ok(None),
+    };
+    // Opus always counts samples at 48 kHz, after skipping some at the start.
+    let (sample_rate, skip) = if packet.starts_with(b"\x01vorbis") {
+        (u32_le(&packet[12..]), 0)
+    } else if packet.starts_with(b"OpusHead") {
+        (48000, u16_le(&packet[10..]))
+    } else {
+        return Ok(None);
+    };
+    if sample_rate == 0 {
+        return Ok(None);
+    }
+
+    let length = file.metadata()?.len();
+    let tail = read_at(file, length.saturating_sub(PROBE as u64))?;
+    let page = match tail.windows(4).rposition(|window| window == b"OggS") {
+        Some(page) if page + 14 <= tail.len() => page,
+        _ => return Ok(None),
+    };
+    let granule = u32_le(&tail[page + 6..]) as u64 | (u32_le(&tail[page + 10..]) as u64) << 32;
+    Ok(Some(
+        granule.saturating_sub(skip as u64) as f64 / sample_rate as f64,
+    ))
+}
+
+fn flac(file: &mut File) -> io::Result<Option<f64>> {
+    let head = read_at(file, 0)?;
+    // STREAMINFO is always the first metadata block.
+    if head.len() < 42 || !head.starts_with(b"fLaC") || head[4] & 0x7F != 0 {
+        return Ok(None);
+    }
+    let info = &head[8..42];
+    let sample_rate = (info[10] as u32) << 12 | (info[11] as u32) << 4 | (info[12] as u32) >> 4;
+    let samples = ((info[13] & 0x0F) as u64) << 32 | u32_be(&info[14..]) as u64;
+    if sample_rate == 0 || samples == 0 {
+        return Ok(None);
+    }
+    Ok(Some(samples as f64 / sample_rate as f64))
+}
+
+fn wav(file: &mut File) -> io::Result<Option<f64>> {
+    let head = read_at(file, 0)?;
+    if head.len() < 12 || !head.starts_with(b"RIFF") || &head[8..12] != b"WAVE" {
+        return Ok(None);
+    }
+    let mut byte_rate = None;
+    let mut offset = 12;
+    while let Some(chunk) = head.get(offset..offset + 8) {
+        let size = u32_le(&chunk[4..]) as usize;
+        match &chunk[..4] {
+            b"fmt " => byte_rate = head.get(offset + 16..offset + 20).map(u32_le),
+            b"data" => {
+                return Ok(match byte_rate {
+                    Some(byte_rate) if byte_rate > 0 => Some(size as f64 / byte_rate as f64),
+                    _ => None,
+                })
+            }
+            _ => {}
+        }
+        // chunks are padded to an even size
+        offset += 8 + size + size % 2;
+    }
+    Ok(None)
+}
+
+/// Finds the box `name` among those between `start` and `end`, returning where its content
+/// starts and ends.
+fn find_box(
+    file: &mut File,
+    mut start: u64,
+    end: u64,
+    name: &[u8],
+) -> io::Result<Option<(u64, u64)>> {
+    while start + 8 <= end {
+        file.seek(SeekFrom::Start(start))?;
+        let mut header = [0; 16];
+        file.read_exact(&mut header[..8])?;
+        let (size, content) = match u32_be(&header) {
+            0 => (end - start, start + 8),
+            1 => {
+                file.read_exact(&mut header[8..])?;
+                (u64_be(&header[8..]), start + 16)
+            }
+            size => (size as u64, start + 8),
+        };
+        if size < content - start {
+            return Ok(None);
+        }
+        if &header[4..8] == name {
+            return Ok(Some((content, (start + size).min(end))));
+        }
+        start += size;
+    }
+    Ok(None)
+}
+
+fn mp4(file: &mut File) -> io::Result<Option<f64>> {
+    let length = file.metadata()?.len();
+    let (start, end) = match find_box(file, 0, length, b"moov")? {
+        Some(moov) => moov,
+        None => return Ok(None),
+    };
+    let (start, _) = match find_box(file, start, end, b"mvhd")? {
+        Some(mvhd) => mvhd,
+        None => return Ok(None),
+    };
+    let header = read_at(file, start)?;
+    let (timescale, duration) = match header.first() {
+        Some(0) if header.len() >= 20 => (u32_be(&header[12..]), u32_be(&header[16..]) as u64),
+        Some(1) if header.len() >= 32 => (u32_be(&header[20..]), u64_be(&header[24..])),
+        _ => return Ok(None),
+    };
+    if timescale == 0 {
+        return Ok(None);
+    }
+    Ok(Some(duration as f64 / timescale as f64))
+}
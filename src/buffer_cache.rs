@@ -0,0 +1,96 @@
+// Buffer cache :: Keeps recently served cache files decompressed in memory.
+//
+// Buffers are shared between all handles of a file. Once the configured limit is exceeded the
+// least recently used ones are dropped, and read from the zip again on the next open. Handles
+// that are still open keep their buffer alive regardless.
+
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+
+/// 64 MiB
+pub const DEFAULT_LIMIT: u64 = 64 * 1024 * 1024;
+
+struct Buffer {
+    data: Arc<[u8]>,
+    last_used: u64,
+}
+
+struct Buffers {
+    buffers: HashMap<PathBuf, Buffer>,
+    size: u64,
+    clock: u64,
+}
+
+pub struct BufferCache {
+    limit: u64,
+    inner: Mutex<Buffers>,
+}
+
+impl BufferCache {
+    pub fn new(limit: u64) -> Self {
+        Self {
+            limit,
+            inner: Mutex::new(Buffers {
+                buffers: HashMap::new(),
+                size: 0,
+                clock: 0,
+            }),
+        }
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit
+    }
+
+    pub fn clear(&self) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.buffers.clear();
+        inner.size = 0;
+    }
+
+    /// The number of buffers kept in memory and their size in bytes.
+    pub fn usage(&self) -> (usize, u64) {
+        let inner = self.inner.lock().unwrap();
+        (inner.buffers.len(), inner.size)
+    }
+
+    pub fn get(&self, path: &Path) -> Option<Arc<[u8]>> {
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let clock = inner.clock;
+        let buffer = inner.buffers.get_mut(path)?;
+        buffer.last_used = clock;
+        Some(buffer.data.clone())
+    }
+
+    pub fn insert(&self, path: &Path, data: Arc<[u8]>) {
+        let len = data.len() as u64;
+        if len > self.limit {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        inner.clock += 1;
+        let buffer = Buffer {
+            data,
+            last_used: inner.clock,
+        };
+        if let Some(previous) = inner.buffers.insert(path.to_path_buf(), buffer) {
+            inner.size -= previous.data.len() as u64;
+        }
+        inner.size += len;
+
+        while inner.size > self.limit {
+            let oldest = inner
+                .buffers
+                .iter()
+                .min_by_key(|(_, buffer)| buffer.last_used)
+                .map(|(path, _)| path.clone())
+                .expect("buffers exceed the limit, so there is at least one");
+            let evicted = inner.buffers.remove(&oldest).unwrap();
+            inner.size -= evicted.data.len() as u64;
+            debug!("buffer cache: evicted {:?}", oldest);
+        }
+    }
+}
@@ -1,54 +1,98 @@
+#[cfg(feature = "mount")]
+use crate::buffer_cache::{self, BufferCache};
+#[cfg(feature = "cover")]
+use crate::coverdb::{CoverDB, CoverImage, LoadedCover, SeenCovers, ThumbnailOptions};
+use crate::libc_extras::libc;
+#[cfg(feature = "mount")]
+use crate::metrics::METRICS;
 #[cfg(feature = "cover")]
-use crate::coverdb::CoverDB;
-use crate::stat::stat_to_fuse_serializable;
-use crate::types::SerializableFileAttr;
+use crate::placeholder;
+#[cfg(feature = "mount")]
+use crate::signing::PublicKey;
+use crate::signing::SigningKey;
+use crate::stat::{size_to_blocks, stat_to_fuse_serializable};
+#[cfg(feature = "sqlite")]
+use crate::tree_db::TreeDb;
+use crate::types::{SerializableFileAttr, SerializableFileType};
 use crate::utils::*;
+#[cfg(feature = "mount")]
+use crate::zip_stream::ZipStream;
 use anyhow::{anyhow, Context, Result};
+use glob::Pattern;
+#[cfg(feature = "cover")]
+use image::{DynamicImage, GenericImageView, ImageFormat, ImageOutputFormat};
 use indicatif::{ProgressBar, ProgressStyle};
+use memmap2::Mmap;
+use rayon::prelude::*;
 use serde::{Deserialize, Serialize};
-use std::cmp::Ordering;
-use std::ffi::OsString;
-use std::fs::File;
-use std::io::copy;
-use std::path::Path;
-use walkdir::WalkDir;
+use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ops::{Deref, DerefMut, Range};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+#[cfg(feature = "cover")]
+use std::process::Command;
+use std::sync::{Arc, OnceLock};
+#[cfg(feature = "mount")]
+use std::sync::{Mutex, RwLock, RwLockReadGuard};
 #[cfg(feature = "mount")]
-use zip::ZipArchive;
+use std::time::Instant;
+use zip::read::{ZipArchive, ZipFile};
+use zip::write::FileOptions;
+use zip::CompressionMethod;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub enum Entry {
     Dict {
         name: OsString,
-        contents: Vec<Entry>,
+        contents: Contents,
         stat: SerializableFileAttr,
     },
     File {
         name: OsString,
         stat: SerializableFileAttr,
     },
+    /// A symlink, from format version 8 on; older caches store them as files.
+    Link {
+        name: OsString,
+        stat: SerializableFileAttr,
+        target: OsString,
+    },
 }
 
 impl Entry {
-    fn new(path: &Path) -> Self {
+    #[cfg(any(feature = "cover", feature = "mount"))]
+    fn try_new(path: &Path) -> io::Result<Self> {
+        let stat = crate::libc_wrappers::lstat(OsString::from(path))
+            .map_err(io::Error::from_raw_os_error)?;
+        if stat.st_mode & libc::S_IFMT == libc::S_IFLNK {
+            let target = fs::read_link(path)?.into_os_string();
+            return Ok(Self::link(path, stat, target));
+        }
+        Ok(Self::from_stat(path, stat, path.is_dir()))
+    }
+
+    /// Creates the entry for `path` from its stats read already, as a directory if `is_dir`.
+    fn from_stat(path: &Path, stat: libc::stat64, is_dir: bool) -> Self {
         // path needs to have a filename, otherwise we got a root, which is useless.
         // This function is private and the api would be annoying otherwise,
         // so we just require this.
         let name = path
             .file_name()
-            .expect("Entry::new got a root")
+            .expect("Entry::from_stat got a root")
             .to_os_string();
-        if path.is_dir() {
+        if is_dir {
             Entry::Dict {
                 name,
-                contents: Vec::new(),
-                stat: stat_to_fuse_serializable(
-                    crate::libc_wrappers::lstat(OsString::from(path)).unwrap(),
-                ),
+                contents: Contents::default(),
+                stat: stat_to_fuse_serializable(stat),
             }
         } else {
-            let mut stat = stat_to_fuse_serializable(
-                crate::libc_wrappers::lstat(OsString::from(path)).unwrap(),
-            );
+            let mut stat = stat_to_fuse_serializable(stat);
             if path.extension().map_or(false, |x| x == "txt") {
                 // remove write permission as files will be read from cache and readonly.
                 stat.perm = stat.perm & 0o5555;
@@ -57,21 +101,195 @@ impl Entry {
         }
     }
 
-    fn add_entry(&mut self, path: &Path) -> Result<()> {
+    /// Creates the entry for the symlink `path` pointing at `target`.
+    fn link(path: &Path, stat: libc::stat64, target: OsString) -> Self {
+        Entry::Link {
+            name: path
+                .file_name()
+                .expect("Entry::link got a root")
+                .to_os_string(),
+            stat: stat_to_fuse_serializable(stat),
+            target,
+        }
+    }
+
+    /// Creates the entry for `path` including everything below it.
+    #[cfg(feature = "mount")]
+    fn scan(path: &Path) -> io::Result<Self> {
+        let mut entry = Self::try_new(path)?;
+        if let Entry::Dict {
+            name: _,
+            contents,
+            stat: _,
+        } = &mut entry
+        {
+            let mut children = fs::read_dir(path)?
+                .map(|child| child.map(|child| child.path()))
+                .collect::<io::Result<Vec<_>>>()?;
+            // all share the same parent, so this sorts by name
+            children.sort();
+            for child in children {
+                match Self::scan(&child) {
+                    Ok(child) => contents.push(child),
+                    // removed in the meantime
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+                    Err(e) => return Err(e),
+                }
+            }
+        }
+        Ok(entry)
+    }
+
+    pub fn name(&self) -> &OsStr {
+        match self {
+            Entry::File { name, stat: _ } => name,
+            Entry::Dict {
+                name,
+                contents: _,
+                stat: _,
+            } => name,
+            Entry::Link { name, .. } => name,
+        }
+    }
+
+    /// Adds a child, keeping the contents sorted by name.
+    fn insert(&mut self, entry: Entry) -> Result<()> {
         match self {
-            Entry::File { name: _, stat: _ } => Err(anyhow!("Can't add entry to a file")),
+            Entry::File { .. } | Entry::Link { .. } => Err(anyhow!("Can't add entry to a file")),
             Entry::Dict {
                 name: _,
                 contents,
                 stat: _,
             } => {
-                contents.push(Entry::new(path));
+                let index = contents
+                    .binary_search_by(|other| other.name().cmp(entry.name()))
+                    .unwrap_or_else(|index| index);
+                contents.insert(index, entry);
                 Ok(())
             }
         }
     }
 
+    /// Removes the child called `name`.
+    #[cfg(feature = "mount")]
+    fn remove(&mut self, name: &OsStr) -> Option<Entry> {
+        match self {
+            Entry::File { .. } | Entry::Link { .. } => None,
+            Entry::Dict {
+                name: _,
+                contents,
+                stat: _,
+            } => {
+                let index = contents
+                    .binary_search_by(|other| other.name().cmp(name))
+                    .ok()?;
+                Some(contents.remove(index))
+            }
+        }
+    }
+
+    #[cfg(feature = "mount")]
+    fn set_stat(&mut self, new: SerializableFileAttr) {
+        match self {
+            Entry::File { name: _, stat } => *stat = new,
+            Entry::Dict {
+                name: _,
+                contents: _,
+                stat,
+            } => *stat = new,
+            Entry::Link { stat, .. } => *stat = new,
+        }
+    }
+
+    #[cfg(feature = "mount")]
+    fn set_name(&mut self, new: OsString) {
+        match self {
+            Entry::File { name, stat: _ } => *name = new,
+            Entry::Dict {
+                name,
+                contents: _,
+                stat: _,
+            } => *name = new,
+            Entry::Link { name, .. } => *name = new,
+        }
+    }
+
+    /// The number of 512 byte blocks and of entries used by this entry and everything below it.
+    #[cfg(feature = "mount")]
+    pub fn usage(&self) -> (u64, u64) {
+        match self {
+            Entry::File { name: _, stat } | Entry::Link { stat, .. } => (stat.blocks, 1),
+            Entry::Dict {
+                name: _,
+                contents,
+                stat,
+            } => contents
+                .iter()
+                .map(Entry::usage)
+                .fold((stat.blocks, 1), |(blocks, files), (b, f)| {
+                    (blocks + b, files + f)
+                }),
+        }
+    }
+
+    /// The number of entries below this one.
+    fn descendants(&self) -> u64 {
+        match self {
+            Entry::File { .. } | Entry::Link { .. } => 0,
+            Entry::Dict { contents, .. } => contents.iter().map(|e| 1 + e.descendants()).sum(),
+        }
+    }
+
+    /// Fills a directory left empty by sharding with the contents of `shard`, its full entry.
+    fn fill_from(&mut self, shard: Entry) {
+        if let (
+            Entry::Dict { contents, .. },
+            Entry::Dict {
+                contents: stored, ..
+            },
+        ) = (self, shard)
+        {
+            *contents = stored;
+        }
+    }
+
+    /// The number of directories in this entry, including itself.
     #[cfg(feature = "mount")]
+    pub fn dirs(&self) -> u64 {
+        match self {
+            Entry::File { .. } | Entry::Link { .. } => 0,
+            Entry::Dict { contents, .. } => 1 + contents.iter().map(Entry::dirs).sum::<u64>(),
+        }
+    }
+
+    /// Updates the size of a file whose cached content differs from the source.
+    fn set_size(&mut self, size: u64) {
+        if let Entry::File { name: _, stat } = self {
+            stat.size = size;
+            stat.blocks = size_to_blocks(size);
+        }
+    }
+
+    pub fn stat(&self) -> &SerializableFileAttr {
+        match self {
+            Entry::File { name: _, stat } => stat,
+            Entry::Dict {
+                name: _,
+                contents: _,
+                stat,
+            } => stat,
+            Entry::Link { stat, .. } => stat,
+        }
+    }
+
+    /// The target of a symlink.
+    pub fn target(&self) -> Option<&OsStr> {
+        match self {
+            Entry::Link { target, .. } => Some(target),
+            _ => None,
+        }
+    }
+
     pub fn find(&self, path: &Path) -> Result<&Entry> {
         let path = path_to_rel(path);
         if path == Path::new("") {
@@ -87,30 +305,20 @@ impl Entry {
             .skip(1)
         {
             match item {
-                Entry::File { name: _, stat: _ } => return Err(anyhow!("Can't search in a file")),
+                Entry::File { .. } | Entry::Link { .. } => {
+                    return Err(anyhow!("Can't search in a file"))
+                }
                 Entry::Dict {
                     name: _,
                     contents,
                     stat: _,
                 } => {
-                    // We're assuming that all Entries are sorted, therefore we can execute a binary search.
-                    item = match contents.binary_search_by(|other: &Entry| -> Ordering {
-                        let a = ancestor
-                            .file_name()
-                            .expect("Entry::find requires relative path");
-                        let b = match other {
-                            Entry::File { name, stat: _ } => name,
-                            Entry::Dict {
-                                name,
-                                contents: _,
-                                stat: _,
-                            } => name,
-                        };
-                        // TODO: solve File not Found error when it obviously exists
-                        (**b).cmp(a)
-                    }) {
-                        Ok(i) => &contents[i],
-                        Err(_) => return Err(anyhow!("File not found")),
+                    let name = ancestor
+                        .file_name()
+                        .expect("Entry::find requires relative path");
+                    item = match contents.position(name) {
+                        Some(i) => &contents[i],
+                        None => return Err(anyhow!("File not found")),
                     };
                 }
             }
@@ -118,6 +326,22 @@ impl Entry {
         Ok(item)
     }
 
+    /// Appends the paths of all entries below this one, prefixed with `prefix`.
+    fn collect_paths(&self, prefix: &Path, paths: &mut Vec<PathBuf>) {
+        if let Entry::Dict {
+            name: _,
+            contents,
+            stat: _,
+        } = self
+        {
+            for entry in contents {
+                let path = prefix.join(entry.name());
+                entry.collect_paths(&path, paths);
+                paths.push(path);
+            }
+        }
+    }
+
     fn find_mut(&mut self, path: &Path) -> Result<&mut Entry> {
         let path = path_to_rel(path);
         if path == Path::new("") {
@@ -133,30 +357,20 @@ impl Entry {
             .skip(1)
         {
             match item {
-                Entry::File { name: _, stat: _ } => return Err(anyhow!("Can't search in a file")),
+                Entry::File { .. } | Entry::Link { .. } => {
+                    return Err(anyhow!("Can't search in a file"))
+                }
                 Entry::Dict {
                     name: _,
                     contents,
                     stat: _,
                 } => {
-                    // We're assuming that all Entries are sorted, therefore we can execute a binary search.
-                    item = match contents.binary_search_by(|other: &Entry| -> Ordering {
-                        let a = ancestor
-                            .file_name()
-                            .expect("Entry::find_mut requires relative path");
-                        let b = match other {
-                            Entry::File { name, stat: _ } => name,
-                            Entry::Dict {
-                                name,
-                                contents: _,
-                                stat: _,
-                            } => name,
-                        };
-                        // TODO: solve File not Found error when it obviously exists
-                        (**b).cmp(a)
-                    }) {
-                        Ok(i) => &mut contents[i],
-                        Err(_) => return Err(anyhow!("File not found")),
+                    let name = ancestor
+                        .file_name()
+                        .expect("Entry::find_mut requires relative path");
+                    item = match contents.position(name) {
+                        Some(i) => &mut contents[i],
+                        None => return Err(anyhow!("File not found")),
                     };
                 }
             }
@@ -165,150 +379,4299 @@ impl Entry {
     }
 }
 
-fn add_txt_to_cache(
-    p: &Path,
-    mut zip: &mut zip::ZipWriter<File>,
-    options: &zip::write::FileOptions,
-) -> Result<()> {
-    zip.start_file_from_path(p, *options)
-        .context("Failed to start zip file")?;
-    let mut file = File::open(p)?;
-    copy(&mut file, &mut zip).context("Failed to copy into cache")?;
-    Ok(())
+/// The entries of a directory, sorted by name.
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct Contents {
+    entries: Vec<Entry>,
+    /// The entries whose names aren't in NFC by their NFC form, built on the first lookup that
+    /// needs it and dropped whenever the entries change.
+    #[serde(skip)]
+    normalized: OnceLock<HashMap<String, usize>>,
 }
 
-#[cfg(feature = "cover")]
-fn add_to_coverdb(p: &Path, cover_db: &mut CoverDB) -> Result<()> {
-    // ultrastar-txt's errors are not Sync, which anyhow needs
-    let txt = ultrastar_txt::parse_txt_song(p)
-        .map_err(|err| anyhow!("Unable to parse song file: {}", err))?;
-    if let Some(cover_path) = txt.header.cover_path {
-        cover_db
-            .add(&cover_path)
-            .with_context(|| format!("Failed to load cover '{}' into db", cover_path.display()))?;
+impl Contents {
+    /// The index of the entry called `name`.
+    ///
+    /// Names that only differ in their Unicode normalization match as well, as macOS decomposes
+    /// umlauts and the like where Linux keeps them composed. An exact match is preferred.
+    fn position(&self, name: &OsStr) -> Option<usize> {
+        let search = |name: &OsStr| self.binary_search_by(|other| other.name().cmp(name));
+        if let Ok(i) = search(name) {
+            return Some(i);
+        }
+        let name = name.to_str().filter(|name| !name.is_ascii())?;
+        let name = nfc(name);
+        if let Ok(i) = search(OsStr::new(&*name)) {
+            return Some(i);
+        }
+        let normalized = self.normalized.get_or_init(|| {
+            self.entries
+                .iter()
+                .enumerate()
+                .filter_map(|(i, entry)| {
+                    let name = entry.name().to_str()?;
+                    match nfc(name) {
+                        Cow::Owned(name) => Some((name, i)),
+                        Cow::Borrowed(_) => None,
+                    }
+                })
+                .collect()
+        });
+        normalized.get(&*name).copied()
     }
-    Ok(())
 }
 
-#[allow(unused_variables)]
-pub fn build<P1: AsRef<Path>, P2: AsRef<Path>>(
-    src_path: P1,
-    output_path: P2,
-    generate_coverdb: bool,
-) -> Result<()> {
-    let src_path = src_path.as_ref();
-    let output_path = output_path.as_ref();
-    assert!(src_path.is_dir());
-    let working_dir = std::env::current_dir();
+impl From<Vec<Entry>> for Contents {
+    fn from(entries: Vec<Entry>) -> Self {
+        Self {
+            entries,
+            normalized: OnceLock::new(),
+        }
+    }
+}
 
-    let zip_file = File::create(output_path).context("Unable to create cache.zip")?;
-    let mut zip = zip::ZipWriter::new(zip_file);
-    let options = zip::write::FileOptions::default();
+impl Deref for Contents {
+    type Target = Vec<Entry>;
 
-    // Create root
-    let mut root = Entry::Dict {
-        name: OsString::from("."),
-        contents: Vec::new(),
-        stat: stat_to_fuse_serializable(
-            crate::libc_wrappers::lstat(OsString::from(src_path))
-                .map_err(|errno| std::io::Error::from_raw_os_error(errno))
-                .with_context(|| format!("Unable to read stats of '{}'", src_path.display()))?,
-        ),
-    };
+    fn deref(&self) -> &Vec<Entry> {
+        &self.entries
+    }
+}
 
-    // Create Cache DB
-    #[cfg(feature = "cover")]
-    let mut cover_db = CoverDB::new(src_path).context("Unable to initialize cover.db")?;
+impl DerefMut for Contents {
+    fn deref_mut(&mut self) -> &mut Vec<Entry> {
+        self.normalized.take();
+        &mut self.entries
+    }
+}
 
-    let pb = ProgressBar::new_spinner();
-    pb.set_style(
-        ProgressStyle::default_spinner().template("{spinner:.green} [{elapsed_precise}] {msg}"),
-    );
-    let mut counter = 1;
+impl IntoIterator for Contents {
+    type Item = Entry;
+    type IntoIter = std::vec::IntoIter<Entry>;
 
-    std::env::set_current_dir(src_path)
-        .with_context(|| format!("Unable to change current_dir to '{}'", src_path.display()))?;
-    let entries = WalkDir::new(".")
-        .sort_by(|a, b| a.file_name().cmp(b.file_name()))
-        .min_depth(1);
+    fn into_iter(self) -> Self::IntoIter {
+        self.entries.into_iter()
+    }
+}
 
-    for entry in entries {
-        pb.set_message(&format!("Processed entries: {}", counter));
-        counter += 1;
+impl<'a> IntoIterator for &'a Contents {
+    type Item = &'a Entry;
+    type IntoIter = std::slice::Iter<'a, Entry>;
 
-        let e = match entry {
-            Ok(e) => e,
-            Err(err) => {
-                warn!("Unable to process: '{}'", err);
-                continue;
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a mut Contents {
+    type Item = &'a mut Entry;
+    type IntoIter = std::slice::IterMut<'a, Entry>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter_mut()
+    }
+}
+
+/// Serialization of the directory structure stored in a cache.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum MetadataFormat {
+    /// Human readable, and the only format known to older versions.
+    #[default]
+    Json,
+    /// Compact and considerably faster to load at mount.
+    Bincode,
+    /// An SQLite database, which a mount reads directory by directory, see [`TreeDb`].
+    Sqlite,
+}
+
+/// Version of the cache layout written by this build.
+///
+/// Bump it with every change to what is stored, e.g. to [`SerializableFileAttr`], and teach
+/// [`load_from_zip`] to migrate caches of the previous version.
+///
+/// - 0: the directory structure in files.json, nothing else.
+/// - 1: [`FORMAT_TAG`] naming the format of the directory structure.
+/// - 2: [`META`] describing the cache.
+/// - 3: [`LINKS`] between files with the same content.
+/// - 4: optionally sharded directory structure, see [`Meta::sharded`].
+/// - 5: shards at every level instead of just for the top-level directories.
+/// - 6: the content of files optionally in a [`PACK`], see [`Container::Pack`].
+/// - 7: the directory structure optionally in [`MetadataFormat::Sqlite`].
+/// - 8: symlinks as [`Entry::Link`] with their target.
+const FORMAT_VERSION: u32 = 8;
+
+/// Archive member describing how the rest of the cache is stored, from version 2 on.
+const META: &str = "meta.json";
+
+/// Archive member mapping names of files to the member holding their content, when it is
+/// identical to that of a file stored before, from version 3 on.
+const LINKS: &str = "links.json";
+
+/// Archive member mapping names of files to the SHA-256 of their content, which the zip CRC
+/// alone is too weak to catch corruption with. Caches built before don't have it.
+const CHECKSUMS: &str = "checksums.json";
+
+/// Archive member holding the content of all files back to back, uncompressed, in caches built
+/// with [`Container::Pack`].
+const PACK: &str = "files.pack";
+
+/// Archive member mapping names of files to the offset and size of their content in the
+/// [`PACK`].
+const PACK_INDEX: &str = "pack.json";
+
+/// Archive member holding the signature of the [`CacheArchive::manifest`] as hex, in signed
+/// caches.
+const SIGNATURE: &str = "signature";
+
+/// Directory of the archive members holding files whose name isn't UTF-8, see [`member_name`].
+const RAW_NAMES: &str = ".ultrastar-fs/raw";
+
+/// Archive member listing every cached song with its metadata as [`SongInfo`], for tools that
+/// want to browse the collection. Caches built without the cover feature don't have it.
+#[cfg(feature = "cover")]
+const INDEX: &str = "index.json";
+
+/// Archive member listing song files a mount hides, such as duplicates of other songs. Caches
+/// without it hide nothing.
+#[cfg(any(feature = "cover", feature = "mount"))]
+const HIDDEN: &str = "hidden.json";
+
+/// Archive member listing the paths of files that are hard links to the same file in the source,
+/// in groups. A mount keeps their attributes in step, but fuse_mt numbers inodes by path, so
+/// each of them still gets an inode of its own. Their content is stored only once like that of
+/// any files with the same content, see [`LINKS`]. Caches without it have none.
+const HARD_LINKS: &str = "hardlinks.json";
+
+/// Archive member mapping the paths of entries to their extended attributes, or to `null` if
+/// they couldn't be recorded. Entries it leaves out have none, but only caches built with
+/// [`BuildOptions::xattrs`] have it at all; mounts of others read them from the source.
+const XATTRS: &str = "xattrs.json";
+
+/// The extended attributes of an entry by their name, see [`XATTRS`].
+pub type Xattrs = BTreeMap<String, Vec<u8>>;
+
+/// Directory of the archive members holding the preview clip of an audio file under its path.
+const CLIPS: &str = ".ultrastar-fs/clips";
+
+/// The archive member holding the preview clip of the audio file `p`.
+fn clip_member(p: &Path) -> PathBuf {
+    Path::new(CLIPS).join(path_to_rel(p))
+}
+
+/// Archive member naming the format of the directory structure in version 1.
+const FORMAT_TAG: &str = "files.format";
+
+/// Contents of [`META`].
+#[derive(Debug, Serialize, Deserialize)]
+struct Meta {
+    version: u32,
+    metadata_format: MetadataFormat,
+    /// Version of ultrastar-fs that built the cache.
+    #[serde(default)]
+    created_by: Option<String>,
+    /// Whether the contents of directories are stored in a member of their own, see
+    /// [`MetadataFormat::shard_name`], and left empty in the directory structure or the shard
+    /// of their parent. Before version 5 only top-level directories were, each with everything
+    /// below it.
+    #[serde(default)]
+    sharded: bool,
+}
+
+impl Meta {
+    fn read(zip: &mut CacheArchive) -> Result<Self> {
+        if let Some(file) = zip.by_name(META) {
+            return serde_json::from_reader(file)
+                .with_context(|| format!("'{}' is malformed", META));
+        }
+
+        let metadata_format = match zip.by_name(FORMAT_TAG) {
+            None => {
+                return Ok(Meta {
+                    version: 0,
+                    metadata_format: MetadataFormat::Json,
+                    created_by: None,
+                    sharded: false,
+                })
+            }
+            Some(mut tag) => {
+                let mut name = String::new();
+                tag.read_to_string(&mut name)
+                    .with_context(|| format!("Unable to read '{}'", FORMAT_TAG))?;
+                MetadataFormat::from_name(name.trim())
+                    .with_context(|| format!("Unknown cache metadata format '{}'", name.trim()))?
             }
         };
-        let p = e.path();
+        Ok(Meta {
+            version: 1,
+            metadata_format,
+            created_by: None,
+            sharded: false,
+        })
+    }
+}
 
-        // For a file to be added, the parent has to have been added first so unwrapping should be safe.
-        let parent = match p.parent() {
-            None => &mut root,
-            Some(x) => root.find_mut(x)?,
-        };
-        parent.add_entry(p)?;
+impl MetadataFormat {
+    #[cfg(feature = "sqlite")]
+    pub const NAMES: &'static [&'static str] = &["json", "bincode", "sqlite"];
+    #[cfg(not(feature = "sqlite"))]
+    pub const NAMES: &'static [&'static str] = &["json", "bincode"];
 
-        if p.extension().map_or(false, |x| x == "txt") {
-            // Add to cache if it is a .txt-file
-            if let Err(err) = add_txt_to_cache(p, &mut zip, &options) {
-                pb.println(format!("[WARN] Unable to cache '{}': {}", p.display(), err));
-                continue;
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "json" => Some(MetadataFormat::Json),
+            "bincode" => Some(MetadataFormat::Bincode),
+            #[cfg(feature = "sqlite")]
+            "sqlite" => Some(MetadataFormat::Sqlite),
+            _ => None,
+        }
+    }
+
+    /// The archive member holding the directory structure.
+    fn file_name(self) -> &'static str {
+        match self {
+            MetadataFormat::Json => "files.json",
+            MetadataFormat::Bincode => "files.bin",
+            MetadataFormat::Sqlite => "files.db",
+        }
+    }
+
+    /// Whether directories can be stored in shards of their own. A database is read by
+    /// directory anyway.
+    fn shardable(self) -> bool {
+        self != MetadataFormat::Sqlite
+    }
+
+    /// The archive member holding the directory at `path` of a sharded cache.
+    fn shard_name(self, path: &Path) -> String {
+        let hash = to_hex(&Sha256::digest(path.as_os_str().as_bytes()));
+        match self {
+            MetadataFormat::Json => format!("shards/{}.json", hash),
+            MetadataFormat::Bincode | MetadataFormat::Sqlite => format!("shards/{}.bin", hash),
+        }
+    }
+
+    /// The archive member holding the `index`th top-level directory of a version 4 cache.
+    fn top_level_shard_name(self, index: usize) -> String {
+        match self {
+            MetadataFormat::Json => format!("shards/{}.json", index),
+            MetadataFormat::Bincode | MetadataFormat::Sqlite => format!("shards/{}.bin", index),
+        }
+    }
+
+    fn write<W: Write>(self, out: W, entry: &Entry) -> Result<()> {
+        match self {
+            MetadataFormat::Json => {
+                serde_json::to_writer_pretty(out, entry).map_err(anyhow::Error::from)
             }
+            MetadataFormat::Bincode => {
+                bincode::serialize_into(out, entry).map_err(anyhow::Error::from)
+            }
+            #[cfg(feature = "sqlite")]
+            MetadataFormat::Sqlite => crate::tree_db::write(entry, out),
+            #[cfg(not(feature = "sqlite"))]
+            MetadataFormat::Sqlite => Err(no_sqlite()),
+        }
+    }
 
-            // Generate cover db entry, if this is a .txt-file
-            #[cfg(feature = "cover")]
-            if generate_coverdb {
-                if let Err(err) = add_to_coverdb(p, &mut cover_db) {
-                    pb.println(format!(
-                        "[WARN] Unable to add to cover database '{}': {}",
-                        p.display(),
-                        err
-                    ));
-                    continue;
-                }
+    fn read(self, data: &[u8]) -> Result<Entry> {
+        match self {
+            MetadataFormat::Json => serde_json::from_slice(data).map_err(anyhow::Error::from),
+            MetadataFormat::Bincode => bincode::deserialize(data).map_err(anyhow::Error::from),
+            MetadataFormat::Sqlite => Err(anyhow!("A database is only read by directory")),
+        }
+    }
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn no_sqlite() -> anyhow::Error {
+    anyhow!("Storing the directory structure in SQLite needs a build with the sqlite feature")
+}
+
+/// How the files in a cache are compressed, written as `METHOD[#LEVEL]`, e.g. `deflate#9`.
+///
+/// Every file is compressed on its own, so the zip's central directory doubles as the index to
+/// seek to it. Readers detect the method of each file, so nothing has to be configured at mount.
+#[derive(Clone, Copy, Debug)]
+pub struct Compression {
+    method: CompressionMethod,
+    level: Option<i32>,
+}
+
+impl Compression {
+    pub const METHODS: &'static [&'static str] = &["stored", "deflate", "bzip2", "zstd"];
+
+    pub fn from_spec(spec: &str) -> Result<Self, String> {
+        let (method, level) = match spec.find('#') {
+            Some(i) => (&spec[..i], Some(&spec[i + 1..])),
+            None => (spec, None),
+        };
+        let (method, levels) = match method {
+            "stored" => (CompressionMethod::Stored, None),
+            "deflate" => (CompressionMethod::Deflated, Some(0..=9)),
+            "bzip2" => (CompressionMethod::Bzip2, Some(1..=9)),
+            // decompresses several times faster than deflate, which shows on weak hardware
+            "zstd" => (CompressionMethod::Zstd, Some(1..=22)),
+            _ => {
+                return Err(format!(
+                    "unknown method '{}', use one of {}",
+                    method,
+                    Self::METHODS.join(", ")
+                ))
             }
+        };
+        let level = match (level, levels) {
+            (None, _) => None,
+            (Some(_), None) => return Err(String::from("stored takes no level")),
+            (Some(level), Some(levels)) => match level.parse() {
+                Ok(level) if levels.contains(&level) => Some(level),
+                _ => {
+                    return Err(format!(
+                        "level has to be between {} and {}",
+                        levels.start(),
+                        levels.end()
+                    ))
+                }
+            },
+        };
+        Ok(Self { method, level })
+    }
+
+    fn options(self) -> FileOptions {
+        FileOptions::default()
+            .compression_method(self.method)
+            .compression_level(self.level)
+    }
+}
+
+impl Default for Compression {
+    fn default() -> Self {
+        Self {
+            method: CompressionMethod::Deflated,
+            level: None,
         }
     }
+}
 
-    pb.finish();
+/// How the content of files is laid out in a cache.
+#[derive(Clone, Copy, Debug, PartialEq, Default)]
+pub enum Container {
+    /// Every file in a zip member of its own, compressed as [`Compression`] says.
+    #[default]
+    Zip,
+    /// All files uncompressed and back to back in one [`PACK`], so reading one is a single copy
+    /// out of the mapped cache. Meant for caches on fast local storage, where the space saved by
+    /// compression matters less than the time spent decompressing.
+    Pack,
+}
 
-    // Store directory structure
-    zip.start_file("files.json", options)
-        .context("Failed to create 'files.json' in cache.zip")?;
-    serde_json::to_writer_pretty(&mut zip, &root)
-        .context("Failed to write 'files.json' in cache.zip")?;
+impl Container {
+    pub const NAMES: &'static [&'static str] = &["zip", "pack"];
 
-    // Store coverdb
-    #[cfg(feature = "cover")]
-    {
-        zip.start_file("cover.db", options)
-            .context("Failed to add cover.db to cache.zip")?;
-        cover_db
-            .write(&mut zip)
-            .context("Failed to write cover.db to cache.zip")?;
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "zip" => Some(Container::Zip),
+            "pack" => Some(Container::Pack),
+            _ => None,
+        }
     }
+}
 
-    zip.finish().context("Failed to finish up cache.zip")?;
+/// Glob patterns selecting which entries of the song directory go into a cache.
+///
+/// Patterns are matched against paths relative to the song directory, where `*` also matches
+/// `/`. An excluded directory is skipped along with everything below it.
+#[derive(Debug, Default)]
+pub struct Filter {
+    /// Only files matching one of these are added, unless it is empty.
+    pub include: Vec<Pattern>,
+    pub exclude: Vec<Pattern>,
+}
 
-    // Restore original working directory (if any)
-    if let Ok(working_dir) = working_dir {
-        // ignore failure
-        let _ = std::env::set_current_dir(working_dir);
+impl Filter {
+    fn excludes(&self, p: &Path) -> bool {
+        let p = path_to_rel(p);
+        self.exclude.iter().any(|pattern| pattern.matches_path(p))
     }
 
-    Ok(())
+    fn includes_file(&self, p: &Path) -> bool {
+        let p = path_to_rel(p);
+        self.include.is_empty() || self.include.iter().any(|pattern| pattern.matches_path(p))
+    }
 }
 
-#[cfg(feature = "mount")]
-pub fn load_from_zip(zip: &mut ZipArchive<File>) -> Result<Entry> {
-    serde_json::from_reader(
-        zip.by_name("files.json")
-            .context("Cache contains no files.json / is malformed")?,
-    )
-    .context("files.json is no valid json")
-    .into()
+/// Settings for building a cache.
+#[derive(Debug, Default)]
+pub struct BuildOptions {
+    /// How to store the directory structure.
+    pub metadata_format: MetadataFormat,
+    /// How to compress song files and the directory structure, generated images are stored as
+    /// they are.
+    pub compression: Compression,
+    /// How to lay out the content of files.
+    pub container: Container,
+    pub filter: Filter,
+    /// Count the entries before building, to show how far along the build is.
+    pub prescan: bool,
+    /// Store each top-level directory separately, so a mount only loads those it needs.
+    pub shard: bool,
+    /// Store song files in other encodings as UTF-8.
+    pub normalize_encoding: bool,
+    /// Point media tags of song files at the files next to them that differ in case or extension.
+    pub repair_references: bool,
+    /// Record the extended attributes of all entries, so a mount serves them from the cache.
+    pub xattrs: bool,
+    /// Sign the finished cache with this key.
+    pub signing_key: Option<SigningKey>,
+    /// Generate a cover.db to be imported at mount.
+    #[cfg(feature = "cover")]
+    pub coverdb: bool,
+    /// Generate placeholder covers for songs that don't have one.
+    #[cfg(feature = "cover")]
+    pub placeholder_covers: bool,
+    /// Store a frame of the video of each song, using ffmpeg.
+    #[cfg(feature = "cover")]
+    pub video_previews: bool,
+    /// How to generate the thumbnails in the cover.db.
+    #[cfg(feature = "cover")]
+    pub thumbnails: ThumbnailOptions,
+    /// Store the cover of each song, so a mount serves it without going to the source.
+    #[cfg(feature = "cover")]
+    pub embed_covers: bool,
+    /// Scale embedded covers down to at most this width and height.
+    #[cfg(feature = "cover")]
+    pub embedded_cover_size: Option<u32>,
+    /// Also write the song index to this file, as CSV if it ends in `.csv` and JSON otherwise.
+    #[cfg(feature = "cover")]
+    pub export_index: Option<PathBuf>,
+    /// List the songs that are in the collection more than once in this file.
+    #[cfg(feature = "cover")]
+    pub report_duplicates: Option<PathBuf>,
+    /// Hide the songs that are in the collection more than once, keeping one folder of each.
+    #[cfg(feature = "cover")]
+    pub hide_duplicates: bool,
+    /// Read the length of each song from the header of its audio file.
+    #[cfg(feature = "cover")]
+    pub durations: bool,
+    /// Add `#PREVIEWSTART` and `#END` to song files that have neither, from the length of their
+    /// audio.
+    #[cfg(feature = "cover")]
+    pub preview_tags: bool,
+    /// Store a short clip of the audio of each song, using ffmpeg.
+    #[cfg(feature = "cover")]
+    pub preview_clips: bool,
+}
+
+/// The metadata of a song in the [`INDEX`].
+#[cfg(feature = "cover")]
+#[derive(Debug, Serialize, Deserialize)]
+struct SongInfo {
+    artist: String,
+    title: String,
+    language: Option<String>,
+    year: Option<u32>,
+    /// The song file, relative to the song directory.
+    path: String,
+    has_video: bool,
+    /// Length of the song in seconds, if known.
+    duration: Option<f64>,
+    /// The canonical path of the audio file, if it exists.
+    #[serde(skip)]
+    audio: Option<PathBuf>,
+}
+
+/// Reads the metadata of the song file `p` in the song directory `root`, with the length of its
+/// audio if `duration`.
+#[cfg(feature = "cover")]
+fn song_info(root: &Path, p: &Path, duration: bool) -> Result<SongInfo> {
+    let path = root.join(path_to_rel(p));
+    // ultrastar-txt's errors are not Sync, which anyhow needs
+    let song = ultrastar_txt::parse_txt_song(&path)
+        .map_err(|err| anyhow!("Unable to parse song file: {}", err))?;
+    let audio = path
+        .parent()
+        .unwrap_or_else(|| Path::new(""))
+        .join(&song.header.audio_path)
+        .canonicalize()
+        .ok();
+    let duration = match &audio {
+        Some(audio) if duration => crate::audio::duration(audio).ok().flatten(),
+        _ => None,
+    };
+    Ok(SongInfo {
+        audio,
+        artist: song.header.artist,
+        title: song.header.title,
+        language: song.header.language,
+        year: song.header.year,
+        path: path_to_rel(p).to_string_lossy().into_owned(),
+        has_video: song.header.video_path.is_some(),
+        duration,
+    })
+}
+
+/// Songs that are in the collection more than once, in different folders.
+#[cfg(feature = "cover")]
+struct Duplicates {
+    /// What they have in common.
+    reason: String,
+    /// Their song files, in order.
+    paths: Vec<String>,
+}
+
+#[cfg(feature = "cover")]
+impl Duplicates {
+    /// The song files to hide so only those in the folder of the first one are left.
+    fn redundant(&self) -> impl Iterator<Item = &String> {
+        let kept = Path::new(&self.paths[0]).parent();
+        self.paths
+            .iter()
+            .filter(move |path| Path::new(path).parent() != kept)
+    }
+}
+
+/// Finds the songs with the same artist and title or the same audio among `songs`.
+///
+/// Only audio files with the same size as another one are read to compare them, those that can't
+/// be are passed to `warn`.
+#[cfg(feature = "cover")]
+fn find_duplicates<F: Fn(String)>(songs: &BTreeMap<String, SongInfo>, warn: F) -> Vec<Duplicates> {
+    // Variants of a song, like duets, share a folder.
+    fn in_different_folders(songs: &[&SongInfo]) -> Option<Vec<String>> {
+        let folders: HashSet<_> = songs
+            .iter()
+            .map(|song| Path::new(&song.path).parent())
+            .collect();
+        if folders.len() < 2 {
+            return None;
+        }
+        let mut paths: Vec<String> = songs.iter().map(|song| song.path.clone()).collect();
+        paths.sort();
+        Some(paths)
+    }
+
+    let mut duplicates = Vec::new();
+    let mut by_name: BTreeMap<(String, String), Vec<&SongInfo>> = BTreeMap::new();
+    for song in songs.values() {
+        let key = (
+            song.artist.trim().to_lowercase(),
+            song.title.trim().to_lowercase(),
+        );
+        by_name.entry(key).or_default().push(song);
+    }
+    for songs in by_name.values() {
+        if let Some(paths) = in_different_folders(songs) {
+            duplicates.push(Duplicates {
+                reason: format!(
+                    "same artist and title: {} - {}",
+                    songs[0].artist, songs[0].title
+                ),
+                paths,
+            });
+        }
+    }
+
+    let mut by_size: HashMap<u64, Vec<&SongInfo>> = HashMap::new();
+    for song in songs.values() {
+        if let Some(metadata) = song
+            .audio
+            .as_ref()
+            .and_then(|audio| fs::metadata(audio).ok())
+        {
+            by_size.entry(metadata.len()).or_default().push(song);
+        }
+    }
+    let candidates: Vec<&SongInfo> = by_size
+        .into_iter()
+        .filter(|(_, songs)| songs.len() > 1)
+        .flat_map(|(_, songs)| songs)
+        .collect();
+    let digests: Vec<io::Result<[u8; 32]>> = candidates
+        .par_iter()
+        .map(|song| {
+            let mut digest = Sha256::new();
+            io::copy(&mut File::open(song.audio.as_ref().unwrap())?, &mut digest)?;
+            Ok(digest.finalize().into())
+        })
+        .collect();
+    let mut by_digest: BTreeMap<[u8; 32], Vec<&SongInfo>> = BTreeMap::new();
+    for (song, digest) in candidates.into_iter().zip(digests) {
+        match digest {
+            Ok(digest) => by_digest.entry(digest).or_default().push(song),
+            Err(err) => warn(format!(
+                "Unable to read the audio of '{}': {}",
+                song.path, err
+            )),
+        }
+    }
+    for songs in by_digest.values() {
+        if let Some(paths) = in_different_folders(songs) {
+            duplicates.push(Duplicates {
+                reason: String::from("same audio"),
+                paths,
+            });
+        }
+    }
+    duplicates
+}
+
+/// Writes `duplicates` as a list for people to go through.
+#[cfg(feature = "cover")]
+fn write_duplicates<W: Write>(mut w: W, duplicates: &[Duplicates]) -> io::Result<()> {
+    for duplicate in duplicates {
+        writeln!(w, "{}", duplicate.reason)?;
+        for path in &duplicate.paths {
+            writeln!(w, "    {}", path)?;
+        }
+    }
+    Ok(())
+}
+
+/// Writes `songs` as CSV with a header line.
+#[cfg(feature = "cover")]
+fn write_index_csv<'a, W: Write>(
+    mut w: W,
+    songs: impl Iterator<Item = &'a SongInfo>,
+) -> io::Result<()> {
+    fn field(value: &str) -> String {
+        if value.contains(&[',', '"', '\n', '\r'][..]) {
+            format!("\"{}\"", value.replace('"', "\"\""))
+        } else {
+            value.to_string()
+        }
+    }
+    writeln!(w, "artist,title,language,year,path,has_video,duration")?;
+    for song in songs {
+        writeln!(
+            w,
+            "{},{},{},{},{},{},{}",
+            field(&song.artist),
+            field(&song.title),
+            field(song.language.as_deref().unwrap_or("")),
+            song.year.map(|year| year.to_string()).unwrap_or_default(),
+            field(&song.path),
+            song.has_video,
+            song.duration
+                .map(|duration| duration.to_string())
+                .unwrap_or_default(),
+        )?;
+    }
+    Ok(())
+}
+
+/// A cover decoded ahead of adding it to the cover.db.
+#[cfg(feature = "cover")]
+struct Cover {
+    path: PathBuf,
+    image: LoadedCover,
+}
+
+/// Decodes the cover referenced by the song file `p` in the song directory `root`, if there is
+/// one.
+#[cfg(feature = "cover")]
+fn load_cover(
+    root: &Path,
+    p: &Path,
+    options: &ThumbnailOptions,
+    seen: &SeenCovers,
+) -> Result<Option<Cover>> {
+    match cover_path(root, p)? {
+        Some(path) => load_cover_at(path, options, seen).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// The canonical path of the cover referenced by the song file `p` in the song directory `root`,
+/// if there is one.
+#[cfg(feature = "cover")]
+fn cover_path(root: &Path, p: &Path) -> Result<Option<PathBuf>> {
+    let p = root.join(path_to_rel(p));
+    // ultrastar-txt's errors are not Sync, which anyhow needs
+    let txt = ultrastar_txt::parse_txt_song(&p)
+        .map_err(|err| anyhow!("Unable to parse song file: {}", err))?;
+    match txt.header.cover_path {
+        // USDX looks #COVER up in the folder of the song
+        Some(cover) => {
+            let path = p.parent().unwrap_or_else(|| Path::new("")).join(cover);
+            let path = path
+                .canonicalize()
+                .with_context(|| format!("Cover '{}' not found", path.display()))?;
+            Ok(Some(path))
+        }
+        None => Ok(None),
+    }
+}
+
+#[cfg(feature = "cover")]
+fn load_cover_at(path: PathBuf, options: &ThumbnailOptions, seen: &SeenCovers) -> Result<Cover> {
+    let image = seen
+        .load(&path, options)
+        .with_context(|| format!("Failed to load cover '{}' into db", path.display()))?;
+    Ok(Cover { path, image })
+}
+
+/// A cover to be stored in the cache.
+#[cfg(feature = "cover")]
+struct EmbeddedCover {
+    /// Relative to the song directory.
+    path: PathBuf,
+    data: Vec<u8>,
+}
+
+/// Reads the cover referenced by the song file `p` to store it in the cache, scaled down to fit
+/// `size`, if there is one.
+#[cfg(feature = "cover")]
+fn embed_cover(root: &Path, p: &Path, size: Option<u32>) -> Result<Option<EmbeddedCover>> {
+    match cover_path(root, p)? {
+        Some(path) => read_embedded(root, &path, size).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// The path of the file at the canonical `path` within the canonical song directory `root`.
+#[cfg(feature = "cover")]
+fn relative_path(root: &Path, path: &Path) -> Result<PathBuf> {
+    let relative = path
+        .strip_prefix(root)
+        .with_context(|| format!("'{}' is outside of the song directory", path.display()))?;
+    Ok(relative.to_path_buf())
+}
+
+#[cfg(feature = "cover")]
+fn read_embedded(root: &Path, path: &Path, size: Option<u32>) -> Result<EmbeddedCover> {
+    let relative = relative_path(root, path)?;
+    let data =
+        fs::read(path).with_context(|| format!("Unable to read cover '{}'", path.display()))?;
+    let data = match size {
+        Some(size) => downscale(path, data, size)?,
+        None => data,
+    };
+    Ok(EmbeddedCover {
+        path: relative,
+        data,
+    })
+}
+
+/// Scales the image `data` of the file at `path` down to fit `size`, keeping its format as songs
+/// reference it by its name.
+#[cfg(feature = "cover")]
+fn downscale(path: &Path, data: Vec<u8>, size: u32) -> Result<Vec<u8>> {
+    let format = ImageFormat::from_path(path).context("Unknown image format")?;
+    let image =
+        image::load_from_memory_with_format(&data, format).context("Unable to decode cover")?;
+    if image.width() <= size && image.height() <= size {
+        return Ok(data);
+    }
+    let mut scaled = Vec::new();
+    image
+        .thumbnail(size, size)
+        .write_to(&mut scaled, format)
+        .context("Unable to encode cover")?;
+    Ok(scaled)
+}
+
+const PLACEHOLDER_SUFFIX: &str = " [CO placeholder].png";
+const PREVIEW_SUFFIX: &str = " [BG preview].jpg";
+
+/// Video previews are scaled down to at most this width.
+#[cfg(feature = "cover")]
+const PREVIEW_WIDTH: u32 = 640;
+
+/// Length of the preview clips of audio files.
+#[cfg(feature = "cover")]
+const CLIP_SECONDS: u32 = 30;
+
+/// The name of a file generated for the song file `p`, which is stored next to it.
+#[cfg(feature = "cover")]
+fn generated_name(p: &Path, suffix: &str) -> Option<OsString> {
+    let mut name = p.file_stem()?.to_os_string();
+    name.push(suffix);
+    Some(name)
+}
+
+/// Whether `p` was generated while building the cache and only exists there.
+pub fn is_generated(p: &Path) -> bool {
+    p.file_name()
+        .and_then(|name| name.to_str())
+        .is_some_and(|name| name.ends_with(PLACEHOLDER_SUFFIX) || name.ends_with(PREVIEW_SUFFIX))
+}
+
+/// A tile with artist and title for a song that has no cover.
+#[cfg(feature = "cover")]
+struct Placeholder {
+    name: String,
+    image: CoverImage,
+    png: Vec<u8>,
+}
+
+/// Renders a placeholder cover for the song file `p` in the song directory `root`, unless it
+/// references a cover already.
+#[cfg(feature = "cover")]
+fn render_placeholder(
+    root: &Path,
+    p: &Path,
+    options: &ThumbnailOptions,
+) -> Result<Option<Placeholder>> {
+    let p = root.join(path_to_rel(p));
+    // ultrastar-txt's errors are not Sync, which anyhow needs
+    let song = ultrastar_txt::parse_txt_song(&p)
+        .map_err(|err| anyhow!("Unable to parse song file: {}", err))?;
+    if song.header.cover_path.is_some() {
+        return Ok(None);
+    }
+
+    let name = generated_name(&p, PLACEHOLDER_SUFFIX).context("Song file has no name")?;
+    let cover_path = p.with_file_name(&name);
+    if cover_path.exists() {
+        return Err(anyhow!("'{}' already exists", cover_path.display()));
+    }
+    let name = name
+        .into_string()
+        .map_err(|_| anyhow!("Song file name is no valid UTF-8"))?;
+
+    let image =
+        DynamicImage::ImageRgb8(placeholder::render(&song.header.artist, &song.header.title));
+    let mut png = Vec::new();
+    image
+        .write_to(&mut png, ImageOutputFormat::Png)
+        .context("Unable to encode placeholder cover")?;
+    Ok(Some(Placeholder {
+        name,
+        image: CoverImage::new(&image, options),
+        png,
+    }))
+}
+
+/// A frame of the video of a song, for when the video itself is too slow to get to.
+#[cfg(feature = "cover")]
+struct Preview {
+    name: String,
+    jpeg: Vec<u8>,
+    /// Whether the song has no background yet, so the preview becomes its background.
+    background: bool,
+}
+
+/// Extracts the first keyframe of the video referenced by the song file `p` in the song
+/// directory `root` with ffmpeg.
+#[cfg(feature = "cover")]
+fn render_preview(root: &Path, p: &Path) -> Result<Option<Preview>> {
+    let p = root.join(path_to_rel(p));
+    // ultrastar-txt's errors are not Sync, which anyhow needs
+    let song = ultrastar_txt::parse_txt_song(&p)
+        .map_err(|err| anyhow!("Unable to parse song file: {}", err))?;
+    // relative to the folder of the song, like the cover
+    let video = match song.header.video_path {
+        Some(video) => p.parent().unwrap_or_else(|| Path::new("")).join(video),
+        None => return Ok(None),
+    };
+
+    let name = generated_name(&p, PREVIEW_SUFFIX).context("Song file has no name")?;
+    let preview_path = p.with_file_name(&name);
+    if preview_path.exists() {
+        return Err(anyhow!("'{}' already exists", preview_path.display()));
+    }
+    let name = name
+        .into_string()
+        .map_err(|_| anyhow!("Song file name is no valid UTF-8"))?;
+
+    let output = Command::new("ffmpeg")
+        .args(["-nostdin", "-v", "error", "-skip_frame", "nokey", "-i"])
+        .arg(&video)
+        .args(["-frames:v", "1", "-vf"])
+        .arg(format!("scale='min({},iw)':-2", PREVIEW_WIDTH))
+        .args(["-f", "image2pipe", "-c:v", "mjpeg", "pipe:1"])
+        .output()
+        .context("Unable to run ffmpeg")?;
+    if !output.status.success() || output.stdout.is_empty() {
+        return Err(anyhow!(
+            "ffmpeg failed on '{}': {}",
+            video.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(Some(Preview {
+        name,
+        jpeg: output.stdout,
+        background: song.header.background_path.is_none(),
+    }))
+}
+
+/// Converts a song file in Windows-1252 to UTF-8, declaring it in its `#ENCODING` tag. Returns
+/// `None` if it is UTF-8 already or declares an encoding it can't be converted from.
+fn to_utf8(txt: &[u8]) -> Option<Vec<u8>> {
+    const BOM: &[u8] = b"\xEF\xBB\xBF";
+    if txt.is_ascii() || txt.starts_with(BOM) {
+        return None;
+    }
+    let line_ending = if txt.windows(2).any(|w| w == b"\r\n") {
+        "\r\n"
+    } else {
+        "\n"
+    };
+    // The header is made up of the tag lines at the start.
+    let is_encoding =
+        |line: &[u8]| line.len() >= 10 && line[..10].eq_ignore_ascii_case(b"#ENCODING:");
+    let declared = txt
+        .split(|&byte| byte == b'\n')
+        .take_while(|line| line.starts_with(b"#"))
+        .find(|line| is_encoding(line))
+        .map(|line| String::from_utf8_lossy(&line[10..]).trim().to_uppercase());
+    let text = match std::str::from_utf8(txt) {
+        Ok(_) if matches!(declared.as_deref(), Some("UTF8") | Some("UTF-8")) => return None,
+        Ok(text) => text.to_string(),
+        Err(_) => match declared.as_deref() {
+            None | Some("AUTO") | Some("CP1252") | Some("LOCALE") => decode_cp1252(txt),
+            Some(_) => return None,
+        },
+    };
+
+    let mut in_header = true;
+    let lines: Vec<&str> = text
+        .split('\n')
+        .filter(|line| {
+            in_header &= line.starts_with('#');
+            !(in_header && is_encoding(line.as_bytes()))
+        })
+        .collect();
+    Some(format!("#ENCODING:UTF8{}{}", line_ending, lines.join("\n")).into_bytes())
+}
+
+/// Tags of song files referencing a file next to them, with the extensions that file may have.
+const MEDIA_TAGS: &[(&str, &[&str])] = &[
+    ("MP3", AUDIO_EXTENSIONS),
+    ("AUDIO", AUDIO_EXTENSIONS),
+    ("COVER", IMAGE_EXTENSIONS),
+    ("BACKGROUND", IMAGE_EXTENSIONS),
+    ("VIDEO", VIDEO_EXTENSIONS),
+];
+
+/// What a media tag of a song file found.
+enum Reference {
+    Found,
+    /// Only a file differing in case or extension, which is called this.
+    Renamed(OsString),
+    Missing,
+}
+
+/// Finds the file `value` references among the `names` in the folder of the song, looking for
+/// one with another extension out of `extensions` if there is none differing in case only.
+fn resolve_reference(value: &[u8], names: &[OsString], extensions: &[&str]) -> Reference {
+    // references into other folders aren't checked
+    if value.contains(&b'/') || value.contains(&b'\\') {
+        return Reference::Found;
+    }
+    let value = OsStr::from_bytes(value);
+    if names.iter().any(|name| name == value) {
+        return Reference::Found;
+    }
+    let lowercase = |name: &OsStr| name.to_string_lossy().to_lowercase();
+    let wanted = lowercase(value);
+    if let Some(name) = names.iter().find(|name| lowercase(name) == wanted) {
+        return Reference::Renamed(name.clone());
+    }
+    let stem = Path::new(value).file_stem().map(lowercase);
+    let mut candidates = names.iter().filter(|name| {
+        let name = Path::new(name);
+        has_extension(name, extensions) && name.file_stem().map(lowercase) == stem
+    });
+    match (candidates.next(), candidates.next()) {
+        (Some(name), None) => Reference::Renamed(name.clone()),
+        _ => Reference::Missing,
+    }
+}
+
+/// Points the media tags of the song file `p` in the song directory `root` with the content
+/// `txt` at the files next to it that only match ignoring case or with another extension.
+/// Returns the references it couldn't find at all.
+fn repair_references(root: &Path, p: &Path, txt: &mut Vec<u8>) -> io::Result<Vec<String>> {
+    let dir = match path_to_rel(p).parent() {
+        Some(dir) => root.join(dir),
+        None => root.to_path_buf(),
+    };
+    let names = fs::read_dir(dir)?
+        .map(|entry| entry.map(|entry| entry.file_name()))
+        .collect::<io::Result<Vec<_>>>()?;
+
+    let mut missing = Vec::new();
+    let mut changed = false;
+    let mut in_header = true;
+    let mut lines = Vec::new();
+    for line in txt.split(|&byte| byte == b'\n') {
+        const BOM: &[u8] = b"\xEF\xBB\xBF";
+        let start = if lines.is_empty() && line.starts_with(BOM) {
+            BOM.len()
+        } else {
+            0
+        };
+        in_header &= line[start..].starts_with(b"#");
+        let colon = line.iter().position(|&byte| byte == b':');
+        let (tag, colon) = match colon {
+            Some(colon) if in_header => (&line[start + 1..colon], colon),
+            _ => {
+                lines.push(line.to_vec());
+                continue;
+            }
+        };
+        let extensions = MEDIA_TAGS
+            .iter()
+            .find(|(name, _)| tag.eq_ignore_ascii_case(name.as_bytes()))
+            .map(|(_, extensions)| *extensions);
+        let cr = line.ends_with(b"\r");
+        let value = &line[colon + 1..];
+        let from = value
+            .iter()
+            .position(|byte| !byte.is_ascii_whitespace())
+            .unwrap_or(value.len());
+        let to = value
+            .iter()
+            .rposition(|byte| !byte.is_ascii_whitespace())
+            .map_or(from, |end| end + 1);
+        let value = &value[from..to];
+        match extensions.map(|extensions| resolve_reference(value, &names, extensions)) {
+            Some(Reference::Renamed(name)) => {
+                let mut repaired = line[..=colon].to_vec();
+                repaired.extend_from_slice(name.as_bytes());
+                if cr {
+                    repaired.push(b'\r');
+                }
+                lines.push(repaired);
+                changed = true;
+                continue;
+            }
+            Some(Reference::Missing) if !value.is_empty() => {
+                missing.push(String::from_utf8_lossy(value).into_owned())
+            }
+            _ => {}
+        }
+        lines.push(line.to_vec());
+    }
+    if changed {
+        *txt = lines.join(&b'\n');
+    }
+    Ok(missing)
+}
+
+/// Whether the header of a song file has the tag `tag`.
+#[cfg(feature = "cover")]
+fn has_tag(txt: &[u8], tag: &str) -> bool {
+    const BOM: &[u8] = b"\xEF\xBB\xBF";
+    let txt = if txt.starts_with(BOM) {
+        &txt[BOM.len()..]
+    } else {
+        txt
+    };
+    let prefix = format!("#{}:", tag);
+    txt.split(|&byte| byte == b'\n')
+        .take_while(|line| line.starts_with(b"#"))
+        .any(|line| {
+            line.len() >= prefix.len()
+                && line[..prefix.len()].eq_ignore_ascii_case(prefix.as_bytes())
+        })
+}
+
+/// Adds the preview and end of a song of `duration` seconds to its song file, unless it states
+/// them already. The preview starts a quarter in, which is usually past the intro.
+#[cfg(feature = "cover")]
+fn add_preview_tags(txt: &mut Vec<u8>, duration: f64) {
+    if !has_tag(txt, "PREVIEWSTART") {
+        inject_tag(txt, "PREVIEWSTART", &format!("{:.1}", duration / 4.0));
+    }
+    // in milliseconds, unlike the other times
+    if !has_tag(txt, "END") {
+        inject_tag(txt, "END", &format!("{}", (duration * 1000.0) as u64));
+    }
+}
+
+/// The first seconds of the audio of a song, to play while the audio itself is opened.
+#[cfg(feature = "cover")]
+struct Clip {
+    /// The audio file, relative to the song directory.
+    audio: PathBuf,
+    data: Vec<u8>,
+}
+
+/// The canonical path of the audio file of the song file `p` in the song directory `root`.
+#[cfg(feature = "cover")]
+fn audio_path(root: &Path, p: &Path) -> Result<PathBuf> {
+    let p = root.join(path_to_rel(p));
+    // ultrastar-txt's errors are not Sync, which anyhow needs
+    let song = ultrastar_txt::parse_txt_song(&p)
+        .map_err(|err| anyhow!("Unable to parse song file: {}", err))?;
+    let path = p
+        .parent()
+        .unwrap_or_else(|| Path::new(""))
+        .join(&song.header.audio_path);
+    path.canonicalize()
+        .with_context(|| format!("Audio '{}' not found", path.display()))
+}
+
+/// Encodes the first [`CLIP_SECONDS`] of the audio of the song file `p` in the song directory
+/// `root` with ffmpeg.
+#[cfg(feature = "cover")]
+fn render_clip(root: &Path, p: &Path) -> Result<Clip> {
+    let audio = audio_path(root, p)?;
+    let output = Command::new("ffmpeg")
+        .args(["-nostdin", "-v", "error", "-i"])
+        .arg(&audio)
+        .args(["-t", &CLIP_SECONDS.to_string(), "-vn", "-ac", "1"])
+        .args(["-c:a", "libopus", "-b:a", "48k", "-f", "ogg", "pipe:1"])
+        .output()
+        .context("Unable to run ffmpeg")?;
+    if !output.status.success() || output.stdout.is_empty() {
+        return Err(anyhow!(
+            "ffmpeg failed on '{}': {}",
+            audio.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        ));
+    }
+    Ok(Clip {
+        audio: relative_path(root, &audio)?,
+        data: output.stdout,
+    })
+}
+
+/// Prepends a `#TAG:value` line to a song file, keeping a byte order mark in front.
+#[cfg(any(feature = "cover", feature = "mount"))]
+fn inject_tag(txt: &mut Vec<u8>, tag: &str, value: &str) {
+    const BOM: &[u8] = b"\xEF\xBB\xBF";
+    let line_ending: &[u8] = if txt.windows(2).any(|w| w == b"\r\n") {
+        b"\r\n"
+    } else {
+        b"\n"
+    };
+    let offset = if txt.starts_with(BOM) { BOM.len() } else { 0 };
+    let rest = txt.split_off(offset);
+    txt.extend_from_slice(format!("#{}:{}", tag, value).as_bytes());
+    txt.extend_from_slice(line_ending);
+    txt.extend_from_slice(&rest);
+}
+
+/// How a mount changes the song files it serves from the cache.
+#[cfg(feature = "mount")]
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct Rewrite {
+    /// Drops the `#VIDEO` and `#VIDEOGAP` tags, so USDX plays no videos but doesn't miss files.
+    pub strip_video: bool,
+    /// Gives songs without a `#BACKGROUND` their `#COVER` as one, to show in place of the video.
+    pub cover_background: bool,
+}
+
+#[cfg(feature = "mount")]
+impl Rewrite {
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// The song file `txt` with this applied to its header, or `None` if that changes nothing.
+    fn apply(&self, txt: &[u8]) -> Option<Vec<u8>> {
+        const BOM: &[u8] = b"\xEF\xBB\xBF";
+        let offset = if txt.starts_with(BOM) { BOM.len() } else { 0 };
+        let tag_is = |line: &[u8], tag: &str| {
+            let prefix = format!("#{}:", tag);
+            line.len() >= prefix.len()
+                && line[..prefix.len()].eq_ignore_ascii_case(prefix.as_bytes())
+        };
+
+        let mut changed = false;
+        let mut in_header = true;
+        let mut cover = None;
+        let mut has_background = false;
+        let mut lines = Vec::new();
+        for line in txt[offset..].split(|&byte| byte == b'\n') {
+            in_header &= line.starts_with(b"#");
+            if in_header {
+                if self.strip_video && (tag_is(line, "VIDEO") || tag_is(line, "VIDEOGAP")) {
+                    changed = true;
+                    continue;
+                }
+                if tag_is(line, "COVER") {
+                    let value = String::from_utf8_lossy(&line["#COVER:".len()..]);
+                    cover = Some(value.trim().to_string()).filter(|value| !value.is_empty());
+                }
+                has_background |= tag_is(line, "BACKGROUND");
+            }
+            lines.push(line);
+        }
+
+        let mut txt = txt[..offset].to_vec();
+        txt.extend_from_slice(&lines.join(&b'\n'));
+        match cover {
+            Some(cover) if self.cover_background && !has_background => {
+                inject_tag(&mut txt, "BACKGROUND", &cover)
+            }
+            _ if !changed => return None,
+            _ => {}
+        }
+        Some(txt)
+    }
+}
+
+/// A song file read ahead of adding it to the cache, along with everything derived from it.
+struct PreparedSong {
+    data: Vec<u8>,
+    #[cfg(feature = "cover")]
+    placeholder: Option<Placeholder>,
+    #[cfg(feature = "cover")]
+    preview: Option<Preview>,
+    #[cfg(feature = "cover")]
+    cover: Option<Cover>,
+    #[cfg(feature = "cover")]
+    embedded_cover: Option<EmbeddedCover>,
+    #[cfg(feature = "cover")]
+    info: Option<SongInfo>,
+    #[cfg(feature = "cover")]
+    clip: Option<Clip>,
+    /// Problems that don't keep the song file from being cached.
+    warnings: Vec<String>,
+}
+
+/// What the songs prepared in parallel share.
+#[derive(Default)]
+struct Shared {
+    #[cfg(feature = "cover")]
+    covers: SeenCovers,
+}
+
+/// Reads the song file `p` in the song directory `root` and does all the work for it that
+/// doesn't touch the cache yet.
+#[cfg_attr(not(feature = "cover"), allow(unused_variables))]
+fn prepare_song(
+    root: &Path,
+    p: &Path,
+    options: &BuildOptions,
+    shared: &Shared,
+) -> io::Result<PreparedSong> {
+    let mut song = PreparedSong {
+        data: fs::read(root.join(path_to_rel(p)))?,
+        #[cfg(feature = "cover")]
+        placeholder: None,
+        #[cfg(feature = "cover")]
+        preview: None,
+        #[cfg(feature = "cover")]
+        cover: None,
+        #[cfg(feature = "cover")]
+        embedded_cover: None,
+        #[cfg(feature = "cover")]
+        info: None,
+        #[cfg(feature = "cover")]
+        clip: None,
+        warnings: Vec::new(),
+    };
+    if options.normalize_encoding {
+        if let Some(data) = to_utf8(&song.data) {
+            song.data = data;
+        }
+    }
+    if options.repair_references {
+        match repair_references(root, p, &mut song.data) {
+            Ok(missing) => {
+                for name in missing {
+                    song.warnings.push(format!(
+                        "'{}' references '{}', which doesn't exist",
+                        p.display(),
+                        name
+                    ));
+                }
+            }
+            Err(err) => song.warnings.push(format!(
+                "Unable to check the files referenced by '{}': {}",
+                p.display(),
+                err
+            )),
+        }
+    }
+
+    #[cfg(feature = "cover")]
+    {
+        match song_info(root, p, options.durations || options.preview_tags) {
+            Ok(info) => {
+                if let Some(duration) = info.duration.filter(|_| options.preview_tags) {
+                    add_preview_tags(&mut song.data, duration);
+                }
+                song.info = Some(info);
+            }
+            Err(err) => song
+                .warnings
+                .push(format!("Unable to index '{}': {}", p.display(), err)),
+        }
+        if options.placeholder_covers {
+            match render_placeholder(root, p, &options.thumbnails) {
+                Ok(placeholder) => song.placeholder = placeholder,
+                Err(err) => song.warnings.push(format!(
+                    "Unable to generate placeholder cover for '{}': {}",
+                    p.display(),
+                    err
+                )),
+            }
+        }
+        if options.video_previews {
+            match render_preview(root, p) {
+                Ok(preview) => song.preview = preview,
+                Err(err) => song.warnings.push(format!(
+                    "Unable to generate video preview for '{}': {}",
+                    p.display(),
+                    err
+                )),
+            }
+        }
+        if options.coverdb {
+            match load_cover(root, p, &options.thumbnails, &shared.covers) {
+                Ok(cover) => song.cover = cover,
+                Err(err) => song.warnings.push(format!(
+                    "Unable to add to cover database '{}': {}",
+                    p.display(),
+                    err
+                )),
+            }
+        }
+        if options.preview_clips {
+            match render_clip(root, p) {
+                Ok(clip) => song.clip = Some(clip),
+                Err(err) => song.warnings.push(format!(
+                    "Unable to generate preview clip for '{}': {}",
+                    p.display(),
+                    err
+                )),
+            }
+        }
+        if options.embed_covers {
+            match embed_cover(root, p, options.embedded_cover_size) {
+                Ok(cover) => song.embedded_cover = cover,
+                Err(err) => song.warnings.push(format!(
+                    "Unable to embed cover for '{}': {}",
+                    p.display(),
+                    err
+                )),
+            }
+        }
+    }
+
+    Ok(song)
+}
+
+/// The root of the directory structure of a cache of `src_path`, without any contents yet.
+fn root_entry(src_path: &Path) -> Result<Entry> {
+    Ok(Entry::Dict {
+        name: OsString::from("."),
+        contents: Contents::default(),
+        stat: stat_to_fuse_serializable(
+            crate::libc_wrappers::lstat(OsString::from(src_path))
+                .map_err(std::io::Error::from_raw_os_error)
+                .with_context(|| format!("Unable to read stats of '{}'", src_path.display()))?,
+        ),
+    })
+}
+
+/// A cache under construction.
+///
+/// All paths passed to it are relative to the song directory.
+struct Builder<'a> {
+    options: &'a BuildOptions,
+    /// The canonical path of the song directory.
+    src: PathBuf,
+    root: Entry,
+    zip: zip::ZipWriter<File>,
+    zip_options: FileOptions,
+    #[cfg(feature = "cover")]
+    cover_db: CoverDB,
+    /// Sizes of the covers stored so far by their path.
+    #[cfg(feature = "cover")]
+    embedded: HashMap<PathBuf, u64>,
+    /// See [`INDEX`], by the path of the song.
+    #[cfg(feature = "cover")]
+    index: BTreeMap<String, SongInfo>,
+    /// Audio files whose preview clip was stored so far.
+    #[cfg(feature = "cover")]
+    clips: HashSet<PathBuf>,
+    /// See [`HIDDEN`].
+    #[cfg(feature = "cover")]
+    hidden: std::collections::BTreeSet<String>,
+    shared: Shared,
+    pb: ProgressBar,
+    counter: u64,
+    /// How many entries are expected, if known.
+    total: Option<u64>,
+    /// Members stored so far by the digest of their content.
+    blobs: HashMap<[u8; 32], String>,
+    /// Names of files whose content is stored in another member, see [`LINKS`].
+    links: BTreeMap<String, String>,
+    /// See [`CHECKSUMS`].
+    checksums: BTreeMap<String, String>,
+    /// See [`XATTRS`], if they are recorded.
+    xattrs: Option<BTreeMap<String, Option<Xattrs>>>,
+    /// Paths of the files with more than one link, in groups of those of the same file, see
+    /// [`HARD_LINKS`].
+    hard_links: Vec<Vec<PathBuf>>,
+    /// The groups in `hard_links` by the device and inode of their file.
+    inodes: HashMap<(u64, u64), usize>,
+    /// The [`PACK_INDEX`] of the files written so far, if they go into a [`PACK`], which is the
+    /// member open until [`Builder::finish`].
+    pack: Option<BTreeMap<String, (u64, u64)>>,
+    /// Bytes written to the [`PACK`] so far.
+    pack_size: u64,
+    /// Leaves the files taken over from a previous cache out, so the cache can only be mounted
+    /// layered over that one.
+    delta: bool,
+}
+
+impl<'a> Builder<'a> {
+    fn new(src_path: &Path, output_path: &Path, options: &'a BuildOptions) -> Result<Self> {
+        // readable as well, to sign it once finished
+        let zip_file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(output_path)
+            .context("Unable to create cache.zip")?;
+
+        let src = src_path
+            .canonicalize()
+            .with_context(|| format!("Unable to resolve '{}'", src_path.display()))?;
+        let root = root_entry(src_path)?;
+
+        let pb = ProgressBar::new_spinner();
+        pb.set_style(
+            ProgressStyle::default_spinner().template("{spinner:.green} [{elapsed_precise}] {msg}"),
+        );
+
+        let mut zip = zip::ZipWriter::new(zip_file);
+        let pack = match options.container {
+            Container::Zip => None,
+            Container::Pack => {
+                let stored = FileOptions::default()
+                    .compression_method(CompressionMethod::Stored)
+                    .large_file(true);
+                zip.start_file(PACK, stored)
+                    .with_context(|| format!("Failed to create '{}' in cache.zip", PACK))?;
+                Some(BTreeMap::new())
+            }
+        };
+
+        let mut builder = Self {
+            options,
+            src,
+            root,
+            zip,
+            zip_options: options.compression.options(),
+            // Create Cache DB
+            #[cfg(feature = "cover")]
+            cover_db: CoverDB::new(src_path).context("Unable to initialize cover.db")?,
+            #[cfg(feature = "cover")]
+            embedded: HashMap::new(),
+            #[cfg(feature = "cover")]
+            index: BTreeMap::new(),
+            #[cfg(feature = "cover")]
+            clips: HashSet::new(),
+            #[cfg(feature = "cover")]
+            hidden: std::collections::BTreeSet::new(),
+            shared: Shared::default(),
+            pb,
+            counter: 1,
+            total: None,
+            blobs: HashMap::new(),
+            links: BTreeMap::new(),
+            checksums: BTreeMap::new(),
+            xattrs: None,
+            hard_links: Vec::new(),
+            inodes: HashMap::new(),
+            pack,
+            pack_size: 0,
+            delta: false,
+        };
+        if options.xattrs {
+            builder.xattrs = Some(BTreeMap::new());
+            let xattrs = source_xattrs(&builder.src);
+            builder.add_xattrs(Path::new(""), xattrs);
+        }
+        Ok(builder)
+    }
+
+    /// Switches from the spinner to a progress bar with an ETA, once the number of entries to
+    /// process is known.
+    fn expect_entries(&mut self, total: u64) {
+        self.pb.set_style(ProgressStyle::default_bar().template(
+            "[{elapsed_precise}] {bar:40.green} {pos}/{len} entries ({percent}%), ETA {eta}",
+        ));
+        self.pb.set_length(total);
+        self.total = Some(total);
+    }
+
+    /// Adds the entries `walked`, which are in walk order, caching every song file that isn't
+    /// taken over by `reuse`.
+    ///
+    /// Song files are processed and covers decoded in parallel, while the results are added one
+    /// after another in the original order.
+    fn add_chunk<F>(&mut self, walked: &[Walked], mut reuse: F) -> Result<()>
+    where
+        F: FnMut(&mut Self, &Path) -> bool,
+    {
+        if self.xattrs.is_some() {
+            let src = &self.src;
+            let xattrs: Vec<_> = walked
+                .par_iter()
+                .map(|walked| source_xattrs(&src.join(&walked.path)))
+                .collect();
+            for (walked, xattrs) in walked.iter().zip(xattrs) {
+                self.add_xattrs(&walked.path, xattrs);
+            }
+        }
+
+        let mut songs = Vec::new();
+        #[cfg(feature = "cover")]
+        let mut reused = Vec::new();
+        for walked in walked {
+            let p = &walked.path;
+            self.pb
+                .set_message(&format!("Processed entries: {}", self.counter));
+            self.pb.set_position(self.counter);
+            // the count may be off, e.g. if it came from a previous cache
+            if self.total.is_some_and(|total| self.counter > total) {
+                self.pb.set_length(self.counter);
+                self.total = Some(self.counter);
+            }
+            self.counter += 1;
+
+            let entry = walked.entry();
+            // Parents come before their contents, so they have been added already.
+            match p.parent() {
+                None => self.root.insert(entry)?,
+                Some(x) => self.root.find_mut(x)?.insert(entry)?,
+            }
+            if !walked.is_dir() && walked.stat.st_nlink > 1 {
+                let inode = (walked.stat.st_dev, walked.stat.st_ino);
+                let hard_links = &mut self.hard_links;
+                let group = *self.inodes.entry(inode).or_insert_with(|| {
+                    hard_links.push(Vec::new());
+                    hard_links.len() - 1
+                });
+                self.hard_links[group].push(path_to_rel(p).to_path_buf());
+            }
+
+            // symlinked song files are read through their target
+            if !is_song(p) || walked.target.is_some() {
+                continue;
+            }
+            if reuse(self, p) {
+                #[cfg(feature = "cover")]
+                reused.push(p.as_path());
+            } else {
+                songs.push(p.as_path());
+            }
+        }
+
+        let options = self.options;
+        let src = &self.src;
+        let shared = &self.shared;
+        let prepared: Vec<_> = songs
+            .par_iter()
+            .map(|p| prepare_song(src, p, options, shared))
+            .collect();
+        for (p, song) in songs.into_iter().zip(prepared) {
+            match song {
+                Ok(song) => self.add_song(p, song),
+                Err(err) => self.warn(format!("Unable to cache '{}': {}", p.display(), err)),
+            }
+        }
+
+        #[cfg(feature = "cover")]
+        {
+            let duration = options.durations || options.preview_tags;
+            let src = &self.src;
+            let infos: Vec<_> = reused
+                .par_iter()
+                .map(|p| song_info(src, p, duration))
+                .collect();
+            for (p, info) in reused.iter().zip(infos) {
+                match info {
+                    Ok(info) => {
+                        self.index.insert(info.path.clone(), info);
+                    }
+                    Err(err) => self.warn(format!("Unable to index '{}': {}", p.display(), err)),
+                }
+            }
+        }
+
+        // Songs taken over from elsewhere still need their covers in the cover.db, which only
+        // have to be loaded again if the previous one doesn't have them up to date.
+        #[cfg(feature = "cover")]
+        if options.coverdb {
+            let src = &self.src;
+            let paths: Vec<_> = reused.par_iter().map(|p| cover_path(src, p)).collect();
+            let mut stale = Vec::new();
+            for (p, path) in reused.into_iter().zip(paths) {
+                match path {
+                    Ok(Some(path)) if !self.cover_db.keep(&path) => stale.push((p, path)),
+                    Ok(_) => {}
+                    Err(err) => self.warn(format!(
+                        "Unable to add to cover database '{}': {}",
+                        p.display(),
+                        err
+                    )),
+                }
+            }
+            let shared = &self.shared;
+            let covers: Vec<_> = stale
+                .par_iter()
+                .map(|(_, path)| load_cover_at(path.clone(), &options.thumbnails, &shared.covers))
+                .collect();
+            for ((p, _), cover) in stale.into_iter().zip(covers) {
+                match cover {
+                    Ok(cover) => self.add_cover(p, cover),
+                    Err(err) => self.warn(format!(
+                        "Unable to add to cover database '{}': {}",
+                        p.display(),
+                        err
+                    )),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Records the extended attributes `xattrs` of the entry `p`, as read by [`source_xattrs`].
+    fn add_xattrs(&mut self, p: &Path, xattrs: io::Result<Option<Xattrs>>) {
+        let xattrs = match xattrs {
+            Ok(Some(xattrs)) if xattrs.is_empty() => return,
+            Ok(xattrs) => xattrs,
+            Err(err) => {
+                self.warn(format!(
+                    "Unable to read extended attributes of '{}': {}",
+                    p.display(),
+                    err
+                ));
+                None
+            }
+        };
+        // Mounts read those of entries whose path isn't UTF-8 from the source.
+        if let (Some(recorded), Some(path)) = (&mut self.xattrs, path_to_rel(p).to_str()) {
+            recorded.insert(path.to_string(), xattrs);
+        }
+    }
+
+    /// Caches the song file `p`, which has to have been added already.
+    fn add_song(&mut self, p: &Path, mut song: PreparedSong) {
+        for warning in song.warnings.drain(..) {
+            self.warn(warning);
+        }
+
+        #[cfg(feature = "cover")]
+        if let Some(placeholder) = song.placeholder.take() {
+            match self.add_placeholder(p, &placeholder) {
+                Ok(()) => inject_tag(&mut song.data, "COVER", &placeholder.name),
+                Err(err) => self.warn(format!(
+                    "Unable to generate placeholder cover for '{}': {}",
+                    p.display(),
+                    err
+                )),
+            }
+        }
+        #[cfg(feature = "cover")]
+        if let Some(preview) = song.preview.take() {
+            match self.add_generated(p, &preview.name, &preview.jpeg) {
+                Ok(()) if preview.background => {
+                    inject_tag(&mut song.data, "BACKGROUND", &preview.name)
+                }
+                Ok(()) => {}
+                Err(err) => self.warn(format!(
+                    "Unable to store video preview for '{}': {}",
+                    p.display(),
+                    err
+                )),
+            }
+        }
+
+        // Add to cache if it is a .txt-file
+        if let Err(err) = self.store(p, &song.data) {
+            self.warn(format!("Unable to cache '{}': {}", p.display(), err));
+            return;
+        }
+        if let Ok(entry) = self.root.find_mut(p) {
+            entry.set_size(song.data.len() as u64);
+        }
+        #[cfg(feature = "cover")]
+        if let Some(info) = song.info.take() {
+            self.index.insert(info.path.clone(), info);
+        }
+
+        #[cfg(feature = "cover")]
+        if let Some(clip) = song.clip.take() {
+            if let Err(err) = self.add_clip(clip) {
+                self.warn(format!(
+                    "Unable to store preview clip for '{}': {}",
+                    p.display(),
+                    err
+                ));
+            }
+        }
+        #[cfg(feature = "cover")]
+        if let Some(cover) = song.embedded_cover.take() {
+            if let Err(err) = self.add_embedded(cover) {
+                self.warn(format!(
+                    "Unable to embed cover for '{}': {}",
+                    p.display(),
+                    err
+                ));
+            }
+        }
+
+        // Generate cover db entry, if this is a .txt-file
+        #[cfg(feature = "cover")]
+        if let Some(cover) = song.cover {
+            self.add_cover(p, cover);
+        }
+    }
+
+    /// Stores `data` as the content of the file `p`, unless a file with the same content was
+    /// stored before, which `p` is linked to then.
+    fn store(&mut self, p: &Path, data: &[u8]) -> Result<()> {
+        let name = member_name(p);
+        let digest: [u8; 32] = Sha256::digest(data).into();
+        self.checksums.insert(name.clone(), to_hex(&digest));
+        if let Some(blob) = self.blobs.get(&digest) {
+            self.links.insert(name, blob.clone());
+            return Ok(());
+        }
+        self.write_blob(p, &name, data)?;
+        self.blobs.insert(digest, name);
+        Ok(())
+    }
+
+    /// Writes `data` as the content of the file `p`, stored as `name`.
+    fn write_blob(&mut self, p: &Path, name: &str, data: &[u8]) -> Result<()> {
+        if let Some(pack) = &mut self.pack {
+            self.zip
+                .write_all(data)
+                .context("Failed to write into cache")?;
+            pack.insert(name.to_string(), (self.pack_size, data.len() as u64));
+            self.pack_size += data.len() as u64;
+            return Ok(());
+        }
+        // They hardly get any smaller, but take time to decompress
+        let options = if has_extension(p, IMAGE_EXTENSIONS) || has_extension(p, AUDIO_EXTENSIONS) {
+            FileOptions::default().compression_method(CompressionMethod::Stored)
+        } else {
+            self.zip_options
+        };
+        self.zip
+            .start_file(name, options)
+            .context("Failed to start zip file")?;
+        self.zip
+            .write_all(data)
+            .context("Failed to write into cache")
+    }
+
+    /// Takes over the file `p` from the previous cache `old`, returning its size.
+    ///
+    /// It is copied without decompressing it again unless it can be linked to a file with the
+    /// same content, or it moves between a zip member and a [`PACK`].
+    fn copy_member(&mut self, p: &Path, old: &mut CacheArchive) -> Result<u64> {
+        let name = member_name(p);
+        if self.delta {
+            return old.size(&name).context("File is not cached");
+        }
+        let data = old
+            .read_member(&name)
+            .context("File is not cached")?
+            .context("Failed to read from previous cache")?;
+        let digest: [u8; 32] = Sha256::digest(&data).into();
+        self.checksums.insert(name.clone(), to_hex(&digest));
+        if let Some(blob) = self.blobs.get(&digest) {
+            self.links.insert(name, blob.clone());
+            return Ok(data.len() as u64);
+        }
+        match old.by_name(&name) {
+            Some(file) if self.pack.is_none() => self
+                .zip
+                .raw_copy_file_rename(file, &name)
+                .context("Failed to copy from previous cache")?,
+            _ => self.write_blob(p, &name, &data)?,
+        }
+        self.blobs.insert(digest, name);
+        Ok(data.len() as u64)
+    }
+
+    /// Stores a file called `name` with `data` next to the song file `p`, taking over its
+    /// attributes.
+    #[cfg(feature = "cover")]
+    fn add_generated(&mut self, p: &Path, name: &str, data: &[u8]) -> Result<()> {
+        self.store(&p.with_file_name(name), data)?;
+
+        let mut entry = Entry::File {
+            name: OsString::from(name),
+            stat: match self.root.find_mut(p)? {
+                Entry::File { name: _, stat } => *stat,
+                Entry::Dict { .. } => return Err(anyhow!("Song file is a directory")),
+                Entry::Link { .. } => return Err(anyhow!("Song file is a symlink")),
+            },
+        };
+        entry.set_size(data.len() as u64);
+        match p.parent() {
+            None => self.root.insert(entry)?,
+            Some(x) => self.root.find_mut(x)?.insert(entry)?,
+        }
+        Ok(())
+    }
+
+    /// Stores a placeholder cover next to the song file `p` and adds it to the cover.db.
+    #[cfg(feature = "cover")]
+    fn add_placeholder(&mut self, p: &Path, placeholder: &Placeholder) -> Result<()> {
+        let cover_path = p.with_file_name(&placeholder.name);
+        self.add_generated(p, &placeholder.name, &placeholder.png)
+            .context("Failed to store placeholder cover")?;
+
+        if self.options.coverdb {
+            self.cover_db
+                .add_image(path_to_rel(&cover_path), &placeholder.image)
+                .context("Failed to add placeholder cover into db")?;
+        }
+        Ok(())
+    }
+
+    /// Stores a cover read by [`embed_cover`], unless another song stored it already.
+    #[cfg(feature = "cover")]
+    fn add_embedded(&mut self, cover: EmbeddedCover) -> Result<()> {
+        if self.embedded.contains_key(&cover.path) {
+            return Ok(());
+        }
+        self.store(&cover.path, &cover.data)?;
+        self.embedded.insert(cover.path, cover.data.len() as u64);
+        Ok(())
+    }
+
+    /// Takes over the cover of the song file `p` from the previous cache `old`, or stores it
+    /// again if it changed since or wasn't stored there.
+    #[cfg(feature = "cover")]
+    fn copy_embedded(&mut self, p: &Path, old: &mut CacheArchive, old_root: &Entry) -> Result<()> {
+        let path = match cover_path(&self.src, p)? {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let relative = relative_path(&self.src, &path)?;
+        if self.embedded.contains_key(&relative) {
+            return Ok(());
+        }
+        let unchanged = match (old_root.find(&relative), Entry::try_new(&path)) {
+            (Ok(old), Ok(new)) => {
+                old.stat().mtime == new.stat().mtime && old.stat().ctime == new.stat().ctime
+            }
+            _ => false,
+        };
+        if unchanged && old.contains(&member_name(&relative)) {
+            let size = self.copy_member(&relative, old)?;
+            self.embedded.insert(relative, size);
+            return Ok(());
+        }
+        let cover = read_embedded(&self.src, &path, self.options.embedded_cover_size)?;
+        self.add_embedded(cover)
+    }
+
+    /// Takes over the preview clip of the song file `p` from the previous cache `old`, or
+    /// generates it again if the audio changed since or has none there.
+    #[cfg(feature = "cover")]
+    fn copy_clip(&mut self, p: &Path, old: &mut CacheArchive, old_root: &Entry) -> Result<()> {
+        let path = audio_path(&self.src, p)?;
+        let audio = relative_path(&self.src, &path)?;
+        if self.clips.contains(&audio) {
+            return Ok(());
+        }
+        let member = clip_member(&audio);
+        let unchanged = match (old_root.find(&audio), Entry::try_new(&path)) {
+            (Ok(old), Ok(new)) => {
+                old.stat().mtime == new.stat().mtime && old.stat().ctime == new.stat().ctime
+            }
+            _ => false,
+        };
+        if unchanged && old.contains(&member_name(&member)) {
+            self.copy_member(&member, old)?;
+            self.clips.insert(audio);
+            return Ok(());
+        }
+        let clip = render_clip(&self.src, p)?;
+        self.add_clip(clip)
+    }
+
+    /// Stores a clip generated by [`render_clip`], unless another song with the same audio
+    /// stored it already.
+    #[cfg(feature = "cover")]
+    fn add_clip(&mut self, clip: Clip) -> Result<()> {
+        if self.clips.contains(&clip.audio) {
+            return Ok(());
+        }
+        self.store(&clip_member(&clip.audio), &clip.data)?;
+        self.clips.insert(clip.audio);
+        Ok(())
+    }
+
+    #[cfg(feature = "cover")]
+    fn add_cover(&mut self, p: &Path, cover: Cover) {
+        if let Err(err) = self
+            .cover_db
+            .add(&cover.path, &cover.image)
+            .with_context(|| format!("Failed to load cover '{}' into db", cover.path.display()))
+        {
+            self.warn(format!(
+                "Unable to add to cover database '{}': {}",
+                p.display(),
+                err
+            ));
+        }
+    }
+
+    /// Takes over the cached song file `p` from a previous cache, instead of reading it again.
+    #[cfg_attr(not(feature = "cover"), allow(unused_variables))]
+    fn copy_song(&mut self, p: &Path, old: &mut CacheArchive, old_root: &Entry) -> Result<()> {
+        let size = self.copy_member(p, old)?;
+        self.root.find_mut(p)?.set_size(size);
+
+        // Generated files don't exist in the source, so they have to be carried over, too.
+        #[cfg(feature = "cover")]
+        for suffix in &[PLACEHOLDER_SUFFIX, PREVIEW_SUFFIX] {
+            let generated = generated_name(p, suffix).map(|name| p.with_file_name(name));
+            if let Some(generated) = generated {
+                if let Ok(entry) = old_root.find(&generated) {
+                    self.copy_member(&generated, old)
+                        .context("Failed to copy generated file from previous cache")?;
+                    if *suffix == PLACEHOLDER_SUFFIX && self.options.coverdb {
+                        self.cover_db.keep_image(path_to_rel(&generated));
+                    }
+                    let entry = Entry::File {
+                        name: entry.name().to_os_string(),
+                        stat: *entry.stat(),
+                    };
+                    match p.parent() {
+                        None => self.root.insert(entry)?,
+                        Some(x) => self.root.find_mut(x)?.insert(entry)?,
+                    }
+                }
+            }
+        }
+        #[cfg(feature = "cover")]
+        if self.options.preview_clips {
+            if let Err(err) = self.copy_clip(p, old, old_root) {
+                self.warn(format!(
+                    "Unable to generate preview clip for '{}': {}",
+                    p.display(),
+                    err
+                ));
+            }
+        }
+        #[cfg(feature = "cover")]
+        if self.options.embed_covers {
+            if let Err(err) = self.copy_embedded(p, old, old_root) {
+                self.warn(format!(
+                    "Unable to embed cover for '{}': {}",
+                    p.display(),
+                    err
+                ));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Reports the songs that are in the collection more than once and stores the [`HIDDEN`]
+    /// ones, as far as the options say so.
+    #[cfg(feature = "cover")]
+    fn handle_duplicates(&mut self) -> Result<()> {
+        let duplicates = find_duplicates(&self.index, |message| self.warn(message));
+        println!(
+            "Found {} groups of songs that are in the collection more than once",
+            duplicates.len()
+        );
+        if let Some(path) = &self.options.report_duplicates {
+            let result = File::create(path).and_then(|file| {
+                let mut file = io::BufWriter::new(file);
+                write_duplicates(&mut file, &duplicates)?;
+                file.flush()
+            });
+            if let Err(err) = result {
+                self.warn(format!(
+                    "Unable to write the duplicates to '{}': {}",
+                    path.display(),
+                    err
+                ));
+            }
+        }
+        if self.options.hide_duplicates {
+            let hidden: Vec<String> = duplicates
+                .iter()
+                .flat_map(Duplicates::redundant)
+                .cloned()
+                .collect();
+            self.hidden.extend(hidden);
+        }
+        Ok(())
+    }
+
+    #[cfg(feature = "cover")]
+    fn export_index(&self, path: &Path) -> Result<()> {
+        let mut file = io::BufWriter::new(File::create(path)?);
+        if path.extension().is_some_and(|x| x == "csv") {
+            write_index_csv(&mut file, self.index.values())?;
+        } else {
+            serde_json::to_writer_pretty(&mut file, &self.index.values().collect::<Vec<_>>())?;
+        }
+        file.flush()?;
+        Ok(())
+    }
+
+    fn warn(&self, message: String) {
+        self.pb.println(format!("[WARN] {}", message));
+    }
+
+    /// Adds everything in the cache at `path` that it doesn't hold yet, taking the attributes
+    /// of the song directory from it if it is the `first`.
+    fn merge_cache(&mut self, path: &Path, first: bool) -> Result<()> {
+        let mut archive = CacheArchive::open(path)?;
+        let root = load_from_zip(&mut archive)
+            .with_context(|| format!("Unable to load '{}'", path.display()))?;
+        let (contents, stat) = match root {
+            Entry::Dict {
+                name: _,
+                contents,
+                stat,
+            } => (contents, stat),
+            Entry::File { .. } | Entry::Link { .. } => {
+                return Err(anyhow!("'{}' holds no directory", path.display()))
+            }
+        };
+        if first {
+            self.root = Entry::Dict {
+                name: OsString::from("."),
+                contents: Contents::default(),
+                stat,
+            };
+        }
+
+        // Only the attributes of the entries this cache adds are taken over.
+        let xattrs = read_xattrs(&mut archive)?;
+        match (&mut self.xattrs, xattrs) {
+            (recorded, Some(xattrs)) if first => *recorded = Some(xattrs),
+            (Some(recorded), Some(xattrs)) => {
+                for (path, xattrs) in xattrs {
+                    if self.root.find(Path::new(&path)).is_err() {
+                        recorded.insert(path, xattrs);
+                    }
+                }
+            }
+            (recorded @ Some(_), None) => {
+                *recorded = None;
+                self.warn(format!(
+                    "'{}' records no extended attributes, leaving them out of the merged cache",
+                    path.display()
+                ));
+            }
+            (None, _) => {}
+        }
+
+        let mut files = Vec::new();
+        let mut skipped = 0;
+        for entry in contents {
+            skipped += merge_entry(&mut self.root, entry, Path::new(""), &mut files)?;
+        }
+        if skipped > 0 {
+            self.warn(format!(
+                "Left out {} entries of '{}' that an earlier cache has already",
+                skipped,
+                path.display()
+            ));
+        }
+        let added: HashSet<&Path> = files.iter().map(PathBuf::as_path).collect();
+        for group in read_hard_links(&mut archive)? {
+            // paths left out are files of an earlier cache, which have nothing to do with these
+            let group: Vec<_> = group
+                .into_iter()
+                .filter(|p| added.contains(p.as_path()))
+                .collect();
+            if group.len() > 1 {
+                self.hard_links.push(group);
+            }
+        }
+        for p in &files {
+            if archive.contains(&member_name(p)) {
+                self.copy_member(p, &mut archive)?;
+            }
+            let clip = clip_member(p);
+            if archive.contains(&member_name(&clip)) {
+                self.copy_member(&clip, &mut archive)?;
+            }
+        }
+
+        #[cfg(feature = "cover")]
+        {
+            if let Some(cover_db) = archive.by_name("cover.db") {
+                self.cover_db.merge(cover_db).with_context(|| {
+                    format!("Unable to merge the cover.db of '{}'", path.display())
+                })?;
+            }
+            if let Some(index) = archive.by_name(INDEX) {
+                let songs: Vec<SongInfo> = serde_json::from_reader(index)
+                    .with_context(|| format!("'{}' is malformed", INDEX))?;
+                for song in songs {
+                    self.index.entry(song.path.clone()).or_insert(song);
+                }
+            }
+            if let Some(hidden) = archive.by_name(HIDDEN) {
+                let hidden: Vec<String> = serde_json::from_reader(hidden)
+                    .with_context(|| format!("'{}' is malformed", HIDDEN))?;
+                self.hidden.extend(hidden);
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(mut self) -> Result<()> {
+        self.pb.finish();
+
+        // Embedded covers are served like song files, which may come before them in the walk.
+        #[cfg(feature = "cover")]
+        for (p, size) in &self.embedded {
+            if let Ok(entry) = self.root.find_mut(p) {
+                entry.set_size(*size);
+                if let Entry::File { name: _, stat } = entry {
+                    stat.perm &= 0o5555;
+                }
+            }
+        }
+
+        // closes the pack, which is the member written until now
+        if let Some(pack) = self.pack.take() {
+            self.zip
+                .start_file(PACK_INDEX, self.zip_options)
+                .with_context(|| format!("Failed to create '{}' in cache.zip", PACK_INDEX))?;
+            serde_json::to_writer(&mut self.zip, &pack)
+                .with_context(|| format!("Failed to write '{}' in cache.zip", PACK_INDEX))?;
+        }
+        if !self.links.is_empty() {
+            self.zip
+                .start_file(LINKS, self.zip_options)
+                .with_context(|| format!("Failed to create '{}' in cache.zip", LINKS))?;
+            serde_json::to_writer(&mut self.zip, &self.links)
+                .with_context(|| format!("Failed to write '{}' in cache.zip", LINKS))?;
+            println!(
+                "Stored {} files with the same content as another one only once",
+                self.links.len()
+            );
+        }
+        self.zip
+            .start_file(CHECKSUMS, self.zip_options)
+            .with_context(|| format!("Failed to create '{}' in cache.zip", CHECKSUMS))?;
+        serde_json::to_writer(&mut self.zip, &self.checksums)
+            .with_context(|| format!("Failed to write '{}' in cache.zip", CHECKSUMS))?;
+        let hard_links: Vec<Vec<&str>> = self
+            .hard_links
+            .iter()
+            // the mount finds files by the UTF-8 paths it is asked for
+            .map(|group| group.iter().filter_map(|p| p.to_str()).collect::<Vec<_>>())
+            .filter(|group| group.len() > 1)
+            .collect();
+        if !hard_links.is_empty() {
+            self.zip
+                .start_file(HARD_LINKS, self.zip_options)
+                .with_context(|| format!("Failed to create '{}' in cache.zip", HARD_LINKS))?;
+            serde_json::to_writer(&mut self.zip, &hard_links)
+                .with_context(|| format!("Failed to write '{}' in cache.zip", HARD_LINKS))?;
+        }
+        if let Some(xattrs) = &self.xattrs {
+            self.zip
+                .start_file(XATTRS, self.zip_options)
+                .with_context(|| format!("Failed to create '{}' in cache.zip", XATTRS))?;
+            serde_json::to_writer(&mut self.zip, xattrs)
+                .with_context(|| format!("Failed to write '{}' in cache.zip", XATTRS))?;
+        }
+
+        let format = self.options.metadata_format;
+        let meta = Meta {
+            version: FORMAT_VERSION,
+            metadata_format: format,
+            created_by: Some(format!("ultrastar-fs {}", env!("CARGO_PKG_VERSION"))),
+            sharded: self.options.shard && format.shardable(),
+        };
+        self.zip
+            .start_file(META, self.zip_options)
+            .with_context(|| format!("Failed to create '{}' in cache.zip", META))?;
+        serde_json::to_writer_pretty(&mut self.zip, &meta)
+            .with_context(|| format!("Failed to write '{}' in cache.zip", META))?;
+
+        // Store directory structure
+        if meta.sharded {
+            write_shards(
+                &mut self.zip,
+                self.zip_options,
+                format,
+                &mut self.root,
+                Path::new(""),
+            )?;
+        }
+
+        self.zip
+            .start_file(format.file_name(), self.zip_options)
+            .with_context(|| format!("Failed to create '{}' in cache.zip", format.file_name()))?;
+        format
+            .write(&mut self.zip, &self.root)
+            .with_context(|| format!("Failed to write '{}' in cache.zip", format.file_name()))?;
+
+        #[cfg(feature = "cover")]
+        if self.options.report_duplicates.is_some() || self.options.hide_duplicates {
+            self.handle_duplicates()?;
+        }
+        #[cfg(feature = "cover")]
+        if !self.hidden.is_empty() {
+            self.zip
+                .start_file(HIDDEN, self.zip_options)
+                .with_context(|| format!("Failed to create '{}' in cache.zip", HIDDEN))?;
+            serde_json::to_writer(&mut self.zip, &self.hidden)
+                .with_context(|| format!("Failed to write '{}' in cache.zip", HIDDEN))?;
+        }
+        #[cfg(feature = "cover")]
+        {
+            self.zip
+                .start_file(INDEX, self.zip_options)
+                .with_context(|| format!("Failed to create '{}' in cache.zip", INDEX))?;
+            serde_json::to_writer(&mut self.zip, &self.index.values().collect::<Vec<_>>())
+                .with_context(|| format!("Failed to write '{}' in cache.zip", INDEX))?;
+            if let Some(path) = &self.options.export_index {
+                if let Err(err) = self.export_index(path) {
+                    self.warn(format!(
+                        "Unable to export the song index to '{}': {}",
+                        path.display(),
+                        err
+                    ));
+                }
+            }
+        }
+
+        // Store coverdb
+        #[cfg(feature = "cover")]
+        {
+            self.zip
+                .start_file("cover.db", self.zip_options)
+                .context("Failed to add cover.db to cache.zip")?;
+            self.cover_db
+                .write(&mut self.zip)
+                .context("Failed to write cover.db to cache.zip")?;
+        }
+
+        let file = self.zip.finish().context("Failed to finish up cache.zip")?;
+        if let Some(key) = &self.options.signing_key {
+            sign(file, key).context("Unable to sign the cache")?;
+        }
+        Ok(())
+    }
+}
+
+/// Stores the contents of every directory below `dir`, at `path`, in a shard of its own and
+/// leaves them empty in `dir`.
+fn write_shards<W: Write + Seek>(
+    zip: &mut zip::ZipWriter<W>,
+    options: FileOptions,
+    format: MetadataFormat,
+    dir: &mut Entry,
+    path: &Path,
+) -> Result<()> {
+    let contents = match dir {
+        Entry::Dict { contents, .. } => contents,
+        Entry::File { .. } | Entry::Link { .. } => return Ok(()),
+    };
+    for child in contents.iter_mut() {
+        let path = path.join(child.name());
+        // deepest first, so each shard only holds one level
+        write_shards(zip, options, format, child, &path)?;
+        match child {
+            Entry::Dict { contents, .. } if !contents.is_empty() => {}
+            _ => continue,
+        }
+        let name = format.shard_name(&path);
+        zip.start_file(name.as_str(), options)
+            .with_context(|| format!("Failed to create '{}' in cache.zip", name))?;
+        format
+            .write(&mut *zip, child)
+            .with_context(|| format!("Failed to write '{}' in cache.zip", name))?;
+        if let Entry::Dict { contents, .. } = child {
+            contents.clear();
+        }
+    }
+    Ok(())
+}
+
+/// Appends the [`SIGNATURE`] made with `key` to the finished cache in `file`.
+fn sign(file: File, key: &SigningKey) -> Result<()> {
+    let reader = file
+        .try_clone()
+        .and_then(SharedFile::new)
+        .context("Unable to reopen the cache")?;
+    let signature = key.sign(&CacheArchive::new(reader)?.manifest()?);
+
+    let mut zip = zip::ZipWriter::new_append(file).context("Unable to append to the cache")?;
+    zip.start_file(
+        SIGNATURE,
+        FileOptions::default().compression_method(CompressionMethod::Stored),
+    )
+    .with_context(|| format!("Failed to create '{}' in cache.zip", SIGNATURE))?;
+    zip.write_all(signature.as_bytes())
+        .with_context(|| format!("Failed to write '{}' in cache.zip", SIGNATURE))?;
+    zip.finish().context("Failed to finish up cache.zip")?;
+    println!("Signed the cache");
+    Ok(())
+}
+
+/// Number of entries `walk` passes to its callback at once.
+const CHUNK_SIZE: usize = 256;
+
+/// An entry of the song directory found by [`walk`].
+struct Walked {
+    /// Relative to the song directory.
+    path: PathBuf,
+    stat: libc::stat64,
+    /// The target of a symlink, if it could be read.
+    target: Option<OsString>,
+}
+
+impl Walked {
+    fn is_dir(&self) -> bool {
+        self.stat.st_mode & libc::S_IFMT == libc::S_IFDIR
+    }
+
+    fn entry(&self) -> Entry {
+        match &self.target {
+            Some(target) => Entry::link(&self.path, self.stat, target.clone()),
+            None => Entry::from_stat(&self.path, self.stat, self.is_dir()),
+        }
+    }
+}
+
+/// Walks the song directory `src_path`, passing the entries that pass `filter` to `f` in chunks
+/// of up to [`CHUNK_SIZE`], in order.
+///
+/// Directories are opened and their entries read relative to the parent, so the paths to them
+/// aren't resolved over and over again, and the working directory stays as it is.
+fn walk<F: FnMut(&[Walked]) -> Result<()>>(
+    src_path: &Path,
+    filter: &Filter,
+    mut f: F,
+) -> Result<()> {
+    let fd = crate::libc_wrappers::open(
+        OsString::from(src_path),
+        libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+    )
+    .map_err(io::Error::from_raw_os_error)
+    .with_context(|| format!("Unable to open '{}'", src_path.display()))?;
+
+    let mut chunk = Vec::with_capacity(CHUNK_SIZE);
+    walk_dir(fd, Path::new("."), filter, &mut chunk, &mut f)?;
+    if !chunk.is_empty() {
+        f(&chunk)?;
+    }
+    Ok(())
+}
+
+/// Walks the directory open as `fd` at `path` like [`walk`], collecting its entries in `chunk`.
+/// Closes `fd` once done.
+fn walk_dir<F: FnMut(&[Walked]) -> Result<()>>(
+    fd: u64,
+    path: &Path,
+    filter: &Filter,
+    chunk: &mut Vec<Walked>,
+    f: &mut F,
+) -> Result<()> {
+    let result = walk_entries(fd, path, filter, chunk, f);
+    // ignore failure
+    let _ = crate::libc_wrappers::close(fd);
+    result
+}
+
+/// Walks the entries of the directory open as `fd` at `path`, see [`walk_dir`].
+fn walk_entries<F: FnMut(&[Walked]) -> Result<()>>(
+    fd: u64,
+    path: &Path,
+    filter: &Filter,
+    chunk: &mut Vec<Walked>,
+    f: &mut F,
+) -> Result<()> {
+    let mut names: Vec<_> = match crate::libc_wrappers::read_dir(fd) {
+        Ok(entries) => entries.into_iter().map(|entry| entry.name).collect(),
+        Err(errno) => {
+            warn!(
+                "Unable to process '{}': {}",
+                path.display(),
+                io::Error::from_raw_os_error(errno)
+            );
+            return Ok(());
+        }
+    };
+    names.sort();
+
+    for name in names {
+        let child = path.join(&name);
+        if filter.excludes(&child) {
+            continue;
+        }
+        let walked = match crate::libc_wrappers::fstatat(fd, name.clone()) {
+            Ok(stat) if stat.st_mode & libc::S_IFMT == libc::S_IFLNK => {
+                let target = match crate::libc_wrappers::readlinkat(fd, name.clone()) {
+                    Ok(target) => Some(target),
+                    Err(errno) => {
+                        warn!(
+                            "Unable to read the target of '{}': {}",
+                            child.display(),
+                            io::Error::from_raw_os_error(errno)
+                        );
+                        None
+                    }
+                };
+                Walked {
+                    path: child,
+                    stat,
+                    target,
+                }
+            }
+            Ok(stat) => Walked {
+                path: child,
+                stat,
+                target: None,
+            },
+            Err(errno) => {
+                warn!(
+                    "Unable to process '{}': {}",
+                    child.display(),
+                    io::Error::from_raw_os_error(errno)
+                );
+                continue;
+            }
+        };
+        let is_dir = walked.is_dir();
+        if !is_dir && !filter.includes_file(&walked.path) {
+            continue;
+        }
+        let child = walked.path.clone();
+        chunk.push(walked);
+        if chunk.len() == CHUNK_SIZE {
+            f(chunk)?;
+            chunk.clear();
+        }
+        if is_dir {
+            let flags = libc::O_RDONLY | libc::O_DIRECTORY | libc::O_NOFOLLOW | libc::O_CLOEXEC;
+            match crate::libc_wrappers::openat(fd, name, flags) {
+                Ok(child_fd) => walk_dir(child_fd, &child, filter, chunk, f)?,
+                Err(errno) => warn!(
+                    "Unable to process '{}': {}",
+                    child.display(),
+                    io::Error::from_raw_os_error(errno)
+                ),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Counts the entries `walk` passes on, without reading anything but the directories.
+fn count_entries(src_path: &Path, filter: &Filter) -> Result<u64> {
+    let mut total = 0;
+    walk(src_path, filter, |walked| {
+        total += walked.len() as u64;
+        Ok(())
+    })?;
+    Ok(total)
+}
+
+/// Reads the extended attributes of the entry at `path`, without following symlinks. `None`
+/// if they can't be recorded, as a name isn't UTF-8.
+fn source_xattrs(path: &Path) -> io::Result<Option<Xattrs>> {
+    use crate::libc_wrappers::{lgetxattr, llistxattr};
+
+    let names = match xattr_value(|buf| llistxattr(path.as_os_str().to_owned(), buf)) {
+        Ok(names) => names,
+        // the file system has none
+        Err(libc::ENOTSUP) => return Ok(Some(Xattrs::new())),
+        Err(errno) => return Err(io::Error::from_raw_os_error(errno)),
+    };
+    let mut xattrs = Xattrs::new();
+    for name in names.split(|&b| b == 0).filter(|name| !name.is_empty()) {
+        let name = match std::str::from_utf8(name) {
+            Ok(name) => name,
+            Err(_) => return Ok(None),
+        };
+        match xattr_value(|buf| lgetxattr(path.as_os_str().to_owned(), OsString::from(name), buf)) {
+            Ok(value) => {
+                xattrs.insert(name.to_string(), value);
+            }
+            // removed since it was listed
+            Err(libc::ENODATA) => {}
+            Err(errno) => return Err(io::Error::from_raw_os_error(errno)),
+        }
+    }
+    Ok(Some(xattrs))
+}
+
+/// Calls `f` with a buffer as large as it reports needing when called with an empty one, as the
+/// xattr calls do.
+fn xattr_value<F>(mut f: F) -> Result<Vec<u8>, libc::c_int>
+where
+    F: FnMut(&mut [u8]) -> Result<usize, libc::c_int>,
+{
+    loop {
+        let mut buf = vec![0; f(&mut [])?];
+        match f(&mut buf) {
+            Ok(size) => {
+                buf.truncate(size);
+                return Ok(buf);
+            }
+            // grew in between
+            Err(libc::ERANGE) => continue,
+            Err(errno) => return Err(errno),
+        }
+    }
+}
+
+fn is_song(p: &Path) -> bool {
+    p.extension().is_some_and(|x| x == "txt")
+}
+
+pub fn build<P1: AsRef<Path>, P2: AsRef<Path>>(
+    src_path: P1,
+    output_path: P2,
+    options: &BuildOptions,
+) -> Result<()> {
+    let src_path = src_path.as_ref();
+    let output_path = output_path.as_ref();
+    assert!(src_path.is_dir());
+
+    // Mounts map the cache into memory, so it is replaced rather than overwritten.
+    let tmp_path = tmp_path(output_path);
+    let mut builder = Builder::new(src_path, &tmp_path, options)?;
+    let result = (|| {
+        if options.prescan {
+            builder.expect_entries(count_entries(src_path, &options.filter)?);
+        }
+        walk(src_path, &options.filter, |walked| {
+            builder.add_chunk(walked, |_, _| false)
+        })?;
+        builder.finish()
+    })();
+
+    if let Err(err) = result {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(err);
+    }
+    fs::rename(&tmp_path, output_path)
+        .with_context(|| format!("Unable to replace '{}'", output_path.display()))
+}
+
+/// Where a new cache for `path` is written before it replaces `path`.
+fn tmp_path(path: &Path) -> PathBuf {
+    let mut tmp_path = path.as_os_str().to_os_string();
+    tmp_path.push(".tmp");
+    PathBuf::from(tmp_path)
+}
+
+/// Counts written bytes without keeping them.
+struct ByteCounter(u64);
+
+impl Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Walks `src_path` like [`build`] and prints what it would cache, without writing anything.
+///
+/// The estimated size is before compression and doesn't include generated covers and previews.
+pub fn dry_run<P: AsRef<Path>>(src_path: P, options: &BuildOptions) -> Result<()> {
+    let src_path = src_path.as_ref();
+    assert!(src_path.is_dir());
+
+    let mut root = root_entry(src_path)?;
+    let (mut dirs, mut songs, mut audio, mut images, mut other) = (0u64, 0u64, 0u64, 0u64, 0u64);
+    let mut song_bytes = 0;
+    walk(src_path, &options.filter, |walked| {
+        for walked in walked {
+            let p = &walked.path;
+            let entry = walked.entry();
+            match &entry {
+                Entry::Dict { .. } => dirs += 1,
+                Entry::File { name: _, stat } if is_song(p) => {
+                    songs += 1;
+                    song_bytes += stat.size;
+                }
+                Entry::File { .. } if has_extension(p, AUDIO_EXTENSIONS) => audio += 1,
+                Entry::File { .. } if has_extension(p, IMAGE_EXTENSIONS) => images += 1,
+                Entry::File { .. } | Entry::Link { .. } => other += 1,
+            }
+            match p.parent() {
+                None => root.insert(entry)?,
+                Some(x) => root.find_mut(x)?.insert(entry)?,
+            }
+        }
+        Ok(())
+    })?;
+
+    let mut metadata = ByteCounter(0);
+    options
+        .metadata_format
+        .write(&mut metadata, &root)
+        .context("Unable to serialize the directory structure")?;
+    let size = song_bytes + metadata.0;
+
+    println!("Directories:  {}", dirs);
+    println!(
+        "Song files:   {} ({}, cached)",
+        songs,
+        format_size(song_bytes)
+    );
+    println!("Audio files:  {}", audio);
+    println!("Images:       {}", images);
+    println!("Other files:  {}", other);
+    println!("Metadata:     {}", format_size(metadata.0));
+    // Every song has at most one cover, which is stored as a thumbnail of at most this size.
+    #[cfg(feature = "cover")]
+    let size = if options.coverdb {
+        let pixels = u64::from(options.thumbnails.size).pow(2);
+        let thumbnails = songs.min(images) * pixels * options.thumbnails.format.bytes_per_pixel();
+        println!("Cover DB:     up to {}", format_size(thumbnails));
+        size + thumbnails
+    } else {
+        size
+    };
+    println!(
+        "Cache size:   about {} before compression",
+        format_size(size)
+    );
+    Ok(())
+}
+
+fn format_size(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
+
+/// Brings the cache at `cache_path` up to date with `src_path`.
+///
+/// Song files that weren't modified since the cache was built are copied over from it, everything
+/// else is processed like in a fresh build. The size isn't compared, since cached song files may
+/// differ in size from the source; modifying a file changes its mtime and ctime anyway.
+///
+/// With a `delta` path the cache is left as it is and a delta is written there instead, which
+/// only holds the song files that changed and is mounted layered over the cache.
+pub fn update<P1: AsRef<Path>, P2: AsRef<Path>>(
+    src_path: P1,
+    cache_path: P2,
+    delta: Option<&Path>,
+    options: &BuildOptions,
+) -> Result<()> {
+    let src_path = src_path.as_ref();
+    let cache_path = cache_path.as_ref();
+    assert!(src_path.is_dir());
+
+    let mut old = CacheArchive::open(cache_path)?;
+    let old_root = load_from_zip(&mut old).context("Unable to load cache")?;
+
+    let output_path = delta.unwrap_or(cache_path);
+    let tmp_path = tmp_path(output_path);
+    let mut builder = Builder::new(src_path, &tmp_path, options)?;
+    builder.delta = delta.is_some();
+    // Covers of unchanged songs are taken over from the previous cover.db.
+    #[cfg(feature = "cover")]
+    if options.coverdb {
+        if let Some(previous) = old.by_name("cover.db") {
+            builder.cover_db = CoverDB::from_previous(src_path, previous)
+                .context("Unable to load the cover.db of the cache")?;
+        }
+    }
+    builder.expect_entries(if options.prescan {
+        count_entries(src_path, &options.filter)?
+    } else {
+        old_root.descendants()
+    });
+    let mut copied = 0;
+    let result = walk(src_path, &options.filter, |walked| {
+        builder.add_chunk(walked, |builder, p| {
+            let unchanged = match (old_root.find(p), builder.root.find(p)) {
+                (Ok(old), Ok(new)) => {
+                    old.stat().mtime == new.stat().mtime && old.stat().ctime == new.stat().ctime
+                }
+                _ => false,
+            };
+            if !unchanged {
+                return false;
+            }
+            match builder.copy_song(p, &mut old, &old_root) {
+                Ok(()) => {
+                    copied += 1;
+                    true
+                }
+                Err(err) => {
+                    builder.warn(format!("Unable to reuse '{}': {:#}", p.display(), err));
+                    false
+                }
+            }
+        })
+    })
+    .and_then(|_| builder.finish());
+
+    if let Err(err) = result {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(err);
+    }
+    fs::rename(&tmp_path, output_path)
+        .with_context(|| format!("Unable to replace '{}'", output_path.display()))?;
+    if delta.is_some() {
+        println!("Left {} unchanged song files to the cache", copied);
+    } else {
+        println!("Reused {} unchanged song files", copied);
+    }
+    Ok(())
+}
+
+/// Merges the caches at `inputs`, e.g. built per drive, into one at `output`.
+///
+/// Their directory structures are united, with earlier caches taking precedence where they
+/// hold the same entry. Files with the same content are stored only once, and the cover.db,
+/// index and hidden songs of all caches are combined.
+pub fn merge<P: AsRef<Path>>(inputs: &[P], output: &Path, options: &BuildOptions) -> Result<()> {
+    let tmp_path = tmp_path(output);
+    let mut builder = Builder::new(Path::new("."), &tmp_path, options)?;
+    let result = inputs
+        .iter()
+        .enumerate()
+        .try_for_each(|(i, input)| builder.merge_cache(input.as_ref(), i == 0))
+        .and_then(|_| builder.finish());
+
+    if let Err(err) = result {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(err);
+    }
+    fs::rename(&tmp_path, output)
+        .with_context(|| format!("Unable to replace '{}'", output.display()))?;
+    println!("Merged {} caches", inputs.len());
+    Ok(())
+}
+
+/// Adds `entry`, below the directory `path`, to `dir`, descending into directories both have.
+/// Collects the paths of the files it adds in `added`, and returns how many entries it left out
+/// as `dir` has them already.
+fn merge_entry(
+    dir: &mut Entry,
+    entry: Entry,
+    path: &Path,
+    added: &mut Vec<PathBuf>,
+) -> Result<u64> {
+    let path = path.join(entry.name());
+    let contents = match dir {
+        Entry::Dict { contents, .. } => contents,
+        Entry::File { .. } | Entry::Link { .. } => {
+            return Err(anyhow!("Can't add entry to a file"))
+        }
+    };
+    let index = match contents.binary_search_by(|other| other.name().cmp(entry.name())) {
+        Ok(index) => index,
+        Err(index) => {
+            collect_files(&entry, &path, added);
+            contents.insert(index, entry);
+            return Ok(0);
+        }
+    };
+    let existing = &mut contents[index];
+    match entry {
+        Entry::Dict {
+            contents: below, ..
+        } if matches!(existing, Entry::Dict { .. }) => {
+            let mut skipped = 0;
+            for child in below {
+                skipped += merge_entry(existing, child, &path, added)?;
+            }
+            Ok(skipped)
+        }
+        entry => Ok(1 + entry.descendants()),
+    }
+}
+
+/// Collects the paths of `entry`, at `path`, and all files below it, if they are files.
+fn collect_files(entry: &Entry, path: &Path, files: &mut Vec<PathBuf>) {
+    match entry {
+        Entry::File { .. } => files.push(path.to_path_buf()),
+        Entry::Dict { contents, .. } => {
+            for child in contents {
+                collect_files(child, &path.join(child.name()), files);
+            }
+        }
+        // hold no content
+        Entry::Link { .. } => {}
+    }
+}
+
+/// Compares the cache at `cache_path` with `src_path`, printing every difference, and returns
+/// their number.
+///
+/// Song files and embedded covers are compared by their modification time only, as the cache
+/// serves them read-only and possibly modified. Entries not passing `filter` are ignored in the
+/// song directory.
+pub fn verify<P1: AsRef<Path>, P2: AsRef<Path>>(
+    src_path: P1,
+    cache_path: P2,
+    filter: &Filter,
+) -> Result<usize> {
+    let src_path = src_path.as_ref();
+    let mut archive = CacheArchive::open(cache_path)?;
+    let root = load_from_zip(&mut archive).context("Unable to load cache")?;
+
+    let mut seen = HashSet::new();
+    let mut differences = 0;
+    walk(src_path, filter, |walked| {
+        for walked in walked {
+            let p = path_to_rel(&walked.path);
+            let source = stat_to_fuse_serializable(walked.stat);
+            match root.find(p) {
+                Ok(entry) => {
+                    seen.insert(p.to_path_buf());
+                    // Embedded covers are served like song files, symlinks as they are.
+                    let served = entry.target().is_none()
+                        && (is_song(p) || archive.contains(&member_name(p)));
+                    let mut changes = stat_differences(entry.stat(), &source, served);
+                    if let Some(target) = entry.target() {
+                        if walked.target.as_deref() != Some(target) {
+                            changes.push("target");
+                        }
+                    }
+                    if !changes.is_empty() {
+                        println!("changed: {} ({})", p.display(), changes.join(", "));
+                        differences += 1;
+                    }
+                }
+                Err(_) => {
+                    println!("missing: {}", p.display());
+                    differences += 1;
+                }
+            }
+        }
+        Ok(())
+    })?;
+
+    let mut cached = Vec::new();
+    root.collect_paths(Path::new(""), &mut cached);
+    for p in cached {
+        // Placeholder covers and video previews only exist in the cache.
+        if !seen.contains(&p) && !is_generated(&p) {
+            println!("extra: {}", p.display());
+            differences += 1;
+        }
+    }
+    Ok(differences)
+}
+
+/// How the cached stats of a file differ from those in the source, where `served` files are served
+/// from the cache.
+fn stat_differences(
+    cached: &SerializableFileAttr,
+    source: &SerializableFileAttr,
+    served: bool,
+) -> Vec<&'static str> {
+    if cached.kind != source.kind {
+        return vec!["type"];
+    }
+
+    let mut differences = Vec::new();
+    let perm = if served {
+        source.perm & 0o5555
+    } else {
+        source.perm
+    };
+    if cached.perm != perm {
+        differences.push("permissions");
+    }
+    if source.kind != SerializableFileType::Directory {
+        if !served && cached.size != source.size {
+            differences.push("size");
+        }
+        if cached.mtime != source.mtime {
+            differences.push("mtime");
+        }
+    }
+    differences
+}
+
+/// A cache archive with an index of its member names.
+///
+/// Archives produced by other zip tools may store names in CP437 or as UTF-8 without setting the
+/// corresponding flag, so lookups go through names normalized at load time instead of relying on
+/// the zip crate's exact match.
+///
+/// Clones share the underlying file and index, but read independently of each other.
+#[derive(Clone)]
+pub struct CacheArchive {
+    zip: ZipArchive<SharedFile>,
+    file: SharedFile,
+    names: Arc<HashMap<String, Member>>,
+}
+
+/// Where the content of a cached file is.
+#[derive(Clone)]
+enum Member {
+    /// In the zip member at this index.
+    Zip(usize),
+    /// At this range of the cache file, in its [`PACK`].
+    Packed(Range<usize>),
+}
+
+impl CacheArchive {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let file = File::open(path)
+            .and_then(SharedFile::new)
+            .with_context(|| format!("Failed to open cache zip at '{}'", path.display()))?;
+        Self::new(file)
+    }
+
+    pub fn new(file: SharedFile) -> Result<Self> {
+        let mut zip = ZipArchive::new(file.clone()).context("Failed to parse cache file as zip")?;
+        let mut names = HashMap::with_capacity(zip.len());
+        for i in 0..zip.len() {
+            let file = zip
+                .by_index(i)
+                .with_context(|| format!("Unable to read entry {} of the cache", i))?;
+            let name = decoded_name(&file);
+            if let Some(Member::Zip(previous)) = names.insert(name, Member::Zip(i)) {
+                warn!(
+                    "Cache entries {} and {} share the name '{}', using the latter",
+                    previous,
+                    i,
+                    file.name()
+                );
+            }
+        }
+        if let Some(Member::Zip(index)) = names.get(PACK_INDEX).cloned() {
+            let pack = match names.get(PACK) {
+                Some(Member::Zip(pack)) => zip
+                    .by_index(*pack)
+                    .with_context(|| format!("Unable to read '{}'", PACK))?,
+                _ => return Err(anyhow!("Cache contains no {} / is malformed", PACK)),
+            };
+            if pack.compression() != CompressionMethod::Stored {
+                return Err(anyhow!("'{}' is compressed", PACK));
+            }
+            let (start, size) = (pack.data_start(), pack.size());
+            drop(pack);
+            let file = zip
+                .by_index(index)
+                .with_context(|| format!("Unable to read '{}'", PACK_INDEX))?;
+            let packed: BTreeMap<String, (u64, u64)> = serde_json::from_reader(file)
+                .with_context(|| format!("'{}' is malformed", PACK_INDEX))?;
+            for (name, (offset, len)) in packed {
+                match offset.checked_add(len) {
+                    Some(end) if end <= size => {
+                        let range = (start + offset) as usize..(start + end) as usize;
+                        names.insert(name, Member::Packed(range));
+                    }
+                    _ => warn!("'{}' extends past the end of '{}'", name, PACK),
+                }
+            }
+        }
+        if let Some(Member::Zip(index)) = names.get(LINKS).cloned() {
+            let file = zip
+                .by_index(index)
+                .with_context(|| format!("Unable to read '{}'", LINKS))?;
+            let links: BTreeMap<String, String> = serde_json::from_reader(file)
+                .with_context(|| format!("'{}' is malformed", LINKS))?;
+            for (link, blob) in links {
+                match names.get(&blob).cloned() {
+                    Some(member) => {
+                        names.insert(link, member);
+                    }
+                    None => warn!("'{}' links to '{}', which is not cached", link, blob),
+                }
+            }
+        }
+        Ok(Self {
+            zip,
+            file,
+            names: Arc::new(names),
+        })
+    }
+
+    /// The SHA-256 of the names and contents of all members but the [`SIGNATURE`] and files,
+    /// in the order they are stored.
+    ///
+    /// This covers the content of files as well, through their [`CHECKSUMS`].
+    fn manifest(&mut self) -> Result<[u8; 32]> {
+        let checksums = read_checksums(self)?;
+        let mut manifest = Sha256::new();
+        for i in 0..self.zip.len() {
+            let mut file = self
+                .zip
+                .by_index(i)
+                .with_context(|| format!("Unable to read entry {} of the cache", i))?;
+            let name = decoded_name(&file);
+            // the pack only holds files
+            if name == SIGNATURE || name == PACK || checksums.contains_key(&name) {
+                continue;
+            }
+            let mut content = Sha256::new();
+            io::copy(&mut file, &mut content)
+                .with_context(|| format!("Unable to read '{}'", name))?;
+            manifest.update(name.as_bytes());
+            manifest.update([0]);
+            manifest.update(content.finalize());
+        }
+        Ok(manifest.finalize().into())
+    }
+
+    /// Checks that the cache was signed with the secret key of `key` and not modified since.
+    #[cfg(feature = "mount")]
+    pub fn verify_signature(&mut self, key: &PublicKey) -> Result<()> {
+        let mut signature = String::new();
+        self.by_name(SIGNATURE)
+            .context("The cache is not signed")?
+            .read_to_string(&mut signature)
+            .with_context(|| format!("Unable to read '{}'", SIGNATURE))?;
+        key.verify(&self.manifest()?, signature.trim())
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.names.contains_key(&normalize_member_name(name))
+    }
+
+    /// The zip member called `name`, or `None` if there is none, like for files in a [`PACK`].
+    pub fn by_name(&mut self, name: &str) -> Option<ZipFile<'_>> {
+        match self.names.get(&normalize_member_name(name))? {
+            Member::Zip(index) => self.zip.by_index(*index).ok(),
+            Member::Packed(_) => None,
+        }
+    }
+
+    /// The size of the file stored as `name`.
+    fn size(&mut self, name: &str) -> Option<u64> {
+        match self.names.get(&normalize_member_name(name))? {
+            Member::Zip(index) => self.zip.by_index(*index).ok().map(|file| file.size()),
+            Member::Packed(range) => Some(range.len() as u64),
+        }
+    }
+
+    /// The content of the file stored as `name`, wherever it is.
+    fn read_member(&mut self, name: &str) -> Option<io::Result<Vec<u8>>> {
+        match self.names.get(&normalize_member_name(name))? {
+            Member::Zip(index) => {
+                let mut file = self.zip.by_index(*index).ok()?;
+                let mut buf = Vec::new();
+                Some(file.read_to_end(&mut buf).map(|_| buf))
+            }
+            Member::Packed(range) => Some(Ok(self.file.contents()[range.clone()].to_vec())),
+        }
+    }
+
+    /// Returns the content a mount serves for the file at `path`, or `None` if it isn't cached.
+    pub fn read_file(&mut self, path: &Path) -> Option<io::Result<Vec<u8>>> {
+        self.read_member(&member_name(path))
+    }
+
+    /// Like `read_file`, but decompresses the content only as far as it is read.
+    ///
+    /// Packed files and stored members are served from the mapped cache file directly, members
+    /// the stream can't decode are read fully instead.
+    #[cfg(feature = "mount")]
+    pub fn stream(&mut self, path: &Path) -> Option<io::Result<Content>> {
+        let shared = self.file.clone();
+        let name = member_name(path);
+        if let Member::Packed(range) = self.names.get(&name)? {
+            return Some(Ok(Content::Mapped {
+                file: shared,
+                range: range.clone(),
+            }));
+        }
+        let mut file = self.by_name(&name)?;
+        if file.compression() == CompressionMethod::Stored {
+            let start = file.data_start() as usize;
+            let end = start.saturating_add(file.size() as usize);
+            if end > shared.contents().len() {
+                return Some(Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "member extends past the end of the cache",
+                )));
+            }
+            return Some(Ok(Content::Mapped {
+                file: shared,
+                range: start..end,
+            }));
+        }
+        if let Some(stream) = ZipStream::new(shared, &file) {
+            return Some(stream.map(Content::Stream));
+        }
+        let mut buf = Vec::new();
+        Some(
+            file.read_to_end(&mut buf)
+                .map(|_| Content::Whole(buf.into())),
+        )
+    }
+}
+
+/// The cache file mapped into memory, with its own read position.
+///
+/// Clones share the mapping, but read independently of each other. Reading is copying from
+/// memory, and `contents` hands out the content without copying at all.
+#[derive(Clone)]
+pub struct SharedFile {
+    map: Arc<Mmap>,
+    position: u64,
+}
+
+impl SharedFile {
+    pub fn new(file: File) -> io::Result<Self> {
+        // Safety: caches are only ever replaced by renaming a new file over them, see `build`
+        // and `update`, so the mapped file isn't modified.
+        let map = unsafe { Mmap::map(&file)? };
+        Ok(Self {
+            map: Arc::new(map),
+            position: 0,
+        })
+    }
+
+    /// The whole content of the file.
+    pub fn contents(&self) -> &[u8] {
+        &self.map
+    }
+}
+
+impl Read for SharedFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let start = self.position.min(self.map.len() as u64) as usize;
+        let n = buf.len().min(self.map.len() - start);
+        buf[..n].copy_from_slice(&self.map[start..start + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for SharedFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let (base, offset) = match pos {
+            SeekFrom::Start(offset) => {
+                self.position = offset;
+                return Ok(offset);
+            }
+            SeekFrom::End(offset) => (self.map.len() as u64, offset),
+            SeekFrom::Current(offset) => (self.position, offset),
+        };
+        match (base as i64).checked_add(offset) {
+            Some(position) if position >= 0 => {
+                self.position = position as u64;
+                Ok(self.position)
+            }
+            _ => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
+    }
+}
+
+/// The name of the archive member holding the cached content of `p`.
+///
+/// zip can only represent UTF-8 names, so others are stored under a hash of their bytes below
+/// [`RAW_NAMES`]. Their actual name is kept in the directory structure.
+fn member_name(p: &Path) -> String {
+    let p = path_to_rel(p);
+    match p.to_str() {
+        Some(name) => normalize_member_name(name),
+        None => {
+            let digest = Sha256::digest(p.as_os_str().as_bytes());
+            format!("{}/{}", RAW_NAMES, to_hex(&digest))
+        }
+    }
+}
+
+/// The normalized name of an archive member.
+fn decoded_name(file: &ZipFile<'_>) -> String {
+    match std::str::from_utf8(file.name_raw()) {
+        Ok(name) => normalize_member_name(name),
+        // Not UTF-8, so it's what the zip crate decoded as CP437
+        Err(_) => normalize_member_name(file.name()),
+    }
+}
+
+/// Reads the [`CHECKSUMS`] of the files in `zip`, empty if it has none.
+fn read_checksums(zip: &mut CacheArchive) -> Result<HashMap<String, String>> {
+    match zip.by_name(CHECKSUMS) {
+        Some(file) => {
+            serde_json::from_reader(file).with_context(|| format!("'{}' is malformed", CHECKSUMS))
+        }
+        None => Ok(HashMap::new()),
+    }
+}
+
+/// Reads the groups of [`HARD_LINKS`] of `zip`, empty if it has none.
+fn read_hard_links(zip: &mut CacheArchive) -> Result<Vec<Vec<PathBuf>>> {
+    match zip.by_name(HARD_LINKS) {
+        Some(file) => {
+            serde_json::from_reader(file).with_context(|| format!("'{}' is malformed", HARD_LINKS))
+        }
+        None => Ok(Vec::new()),
+    }
+}
+
+/// Reads the [`XATTRS`] of `zip`, `None` if it doesn't record them.
+fn read_xattrs(zip: &mut CacheArchive) -> Result<Option<BTreeMap<String, Option<Xattrs>>>> {
+    match zip.by_name(XATTRS) {
+        Some(file) => serde_json::from_reader(file)
+            .map(Some)
+            .with_context(|| format!("'{}' is malformed", XATTRS)),
+        None => Ok(None),
+    }
+}
+
+/// Reads the [`HIDDEN`] song files of `zip`.
+#[cfg(feature = "mount")]
+fn read_hidden(zip: &mut CacheArchive) -> Result<HashSet<PathBuf>> {
+    match zip.by_name(HIDDEN) {
+        Some(file) => {
+            serde_json::from_reader(file).with_context(|| format!("'{}' is malformed", HIDDEN))
+        }
+        None => Ok(HashSet::new()),
+    }
+}
+
+fn normalize_member_name(name: &str) -> String {
+    nfc(name)
+        .replace('\\', "/")
+        .trim_start_matches("./")
+        .trim_start_matches('/')
+        .to_string()
+}
+
+/// The directory structure of a cache, with the directories of a sharded cache below the top
+/// level still empty.
+struct Metadata {
+    root: Entry,
+    tree: Tree,
+    /// The paths of the empty directories and the shards holding their contents.
+    shards: Vec<(PathBuf, String)>,
+}
+
+/// Where the contents of directories left empty in [`Metadata`] are read from.
+enum Tree {
+    /// Archive members in this format, named by [`MetadataFormat::shard_name`].
+    Members(MetadataFormat),
+    /// A database, whose shards are the rows of the directories.
+    #[cfg(feature = "sqlite")]
+    Database(TreeDb),
+}
+
+/// Loads the directory structure of a cache, including all shards.
+pub fn load_from_zip(zip: &mut CacheArchive) -> Result<Entry> {
+    let mut metadata = load_metadata(zip)?;
+    while let Some((path, shard)) = metadata.shards.pop() {
+        let (dir, children) = read_shard(zip, &metadata.tree, &path, &shard)?;
+        metadata.shards.extend(children);
+        metadata.root.find_mut(&path)?.fill_from(dir);
+    }
+    Ok(metadata.root)
+}
+
+/// The directories directly below `dir`, at `path`, whose contents are held by a shard of their
+/// own, along with that shard.
+fn child_shards(
+    zip: &CacheArchive,
+    format: MetadataFormat,
+    dir: &Entry,
+    path: &Path,
+) -> Vec<(PathBuf, String)> {
+    let contents = match dir {
+        Entry::Dict { contents, .. } => contents,
+        Entry::File { .. } | Entry::Link { .. } => return Vec::new(),
+    };
+    contents
+        .iter()
+        .filter(|entry| matches!(entry, Entry::Dict { .. }))
+        .map(|entry| path.join(entry.name()))
+        .filter_map(|path| {
+            let shard = format.shard_name(&path);
+            if zip.contains(&shard) {
+                Some((path, shard))
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+/// Reads the directory at `path` stored in `shard`, along with the shards of the directories
+/// below it, see [`child_shards`].
+fn read_shard(
+    zip: &mut CacheArchive,
+    tree: &Tree,
+    path: &Path,
+    shard: &str,
+) -> Result<(Entry, Vec<(PathBuf, String)>)> {
+    let format = match tree {
+        Tree::Members(format) => *format,
+        #[cfg(feature = "sqlite")]
+        Tree::Database(db) => {
+            let id = shard
+                .parse()
+                .with_context(|| format!("'{}' is no row of a directory", shard))?;
+            let (dir, dirs) = db.dir(id)?;
+            return Ok((dir, db_shards(path, dirs)));
+        }
+    };
+    let mut data = Vec::new();
+    zip.by_name(shard)
+        .with_context(|| format!("Cache contains no {} / is malformed", shard))?
+        .read_to_end(&mut data)
+        .with_context(|| format!("Unable to read '{}'", shard))?;
+    let dir = format
+        .read(&data)
+        .with_context(|| format!("'{}' is malformed, try rebuilding the cache", shard))?;
+    let children = child_shards(zip, format, &dir, path);
+    Ok((dir, children))
+}
+
+/// The directories `dirs` below `path`, with the rows holding their contents as their shards.
+#[cfg(feature = "sqlite")]
+fn db_shards(path: &Path, dirs: Vec<(OsString, i64)>) -> Vec<(PathBuf, String)> {
+    dirs.into_iter()
+        .map(|(name, id)| (path.join(name), id.to_string()))
+        .collect()
+}
+
+/// Loads the root directory of a cache storing its structure in a database.
+#[cfg(feature = "sqlite")]
+fn load_database(zip: &mut CacheArchive) -> Result<Metadata> {
+    let name = MetadataFormat::Sqlite.file_name();
+    let db = TreeDb::extract(
+        zip.by_name(name)
+            .with_context(|| format!("Cache contains no {} / is malformed", name))?,
+    )?;
+    let (root, dirs) = db.root()?;
+    Ok(Metadata {
+        root,
+        tree: Tree::Database(db),
+        shards: db_shards(Path::new(""), dirs),
+    })
+}
+
+#[cfg(not(feature = "sqlite"))]
+fn load_database(_zip: &mut CacheArchive) -> Result<Metadata> {
+    Err(no_sqlite())
+}
+
+/// Loads the directory structure of a cache without its shards, migrating it from older
+/// versions of the format.
+fn load_metadata(zip: &mut CacheArchive) -> Result<Metadata> {
+    let meta = Meta::read(zip)?;
+    if meta.version > FORMAT_VERSION {
+        return Err(anyhow!(
+            "Cache has format version {}{}, but only versions up to {} are supported. \
+             Update ultrastar-fs or rebuild the cache.",
+            meta.version,
+            meta.created_by
+                .map(|by| format!(" (created by {})", by))
+                .unwrap_or_default(),
+            FORMAT_VERSION
+        ));
+    }
+
+    let format = meta.metadata_format;
+    if format == MetadataFormat::Sqlite {
+        return load_database(zip);
+    }
+    let mut data = Vec::new();
+    zip.by_name(format.file_name())
+        .with_context(|| format!("Cache contains no {} / is malformed", format.file_name()))?
+        .read_to_end(&mut data)
+        .with_context(|| format!("Unable to read '{}'", format.file_name()))?;
+    // All versions so far store the same entries, they only differ in how their format is
+    // recorded and in version 3 added links between members, which CacheArchive resolves.
+    // Version 8 added Entry::Link, older caches store symlinks as files without their target,
+    // which mounts read from the source. Migrations of older entries go here once that changes.
+    let root = format.read(&data).with_context(|| {
+        format!(
+            "'{}' of format version {} is malformed, try rebuilding the cache",
+            format.file_name(),
+            meta.version
+        )
+    })?;
+
+    let shards = match (meta.sharded, &root) {
+        (false, _) => Vec::new(),
+        (true, _) if meta.version >= 5 => child_shards(zip, format, &root, Path::new("")),
+        (true, Entry::Dict { contents, .. }) => contents
+            .iter()
+            .filter(|entry| matches!(entry, Entry::Dict { .. }))
+            .enumerate()
+            .map(|(i, dir)| (PathBuf::from(dir.name()), format.top_level_shard_name(i)))
+            .collect(),
+        (true, Entry::File { .. } | Entry::Link { .. }) => Vec::new(),
+    };
+    Ok(Metadata {
+        root,
+        tree: Tree::Members(format),
+        shards,
+    })
+}
+
+/// Writes the content of `path` to `out` as the cache stores it. A mount may serve it differently,
+/// rewritten by `--no-video` or `--cover-background`, or from `--override` or a layered cache.
+pub fn cat<P1: AsRef<Path>, P2: AsRef<Path>, W: Write>(
+    cache_path: P1,
+    path: P2,
+    mut out: W,
+) -> Result<()> {
+    let path = path.as_ref();
+    let mut zip = CacheArchive::open(cache_path)?;
+    let data = zip
+        .read_file(path)
+        .with_context(|| format!("'{}' is not contained in the cache", path.display()))?
+        .with_context(|| format!("Failed to read '{}' from the cache", path.display()))?;
+    out.write_all(&data).context("Failed to write output")?;
+    out.flush().context("Failed to write output")
+}
+
+/// `path` below `from`, moved to below `to`.
+#[cfg(feature = "mount")]
+fn rebase(path: &Path, from: &Path, to: &Path) -> PathBuf {
+    match path.strip_prefix(from) {
+        Ok(rest) if rest != Path::new("") => to.join(rest),
+        _ => to.to_path_buf(),
+    }
+}
+
+/// How the tree of a mount diverged from the cache it was built from, by relative paths.
+#[cfg(feature = "mount")]
+#[derive(Default)]
+struct Changes {
+    /// Paths that changed in the source since the cache was built, which are served from there.
+    stale: HashSet<PathBuf>,
+    /// Paths entries were renamed to through the mount, and where they are found in the archive.
+    moved: HashMap<PathBuf, PathBuf>,
+}
+
+#[cfg(feature = "mount")]
+impl Changes {
+    fn is_stale(&self, path: &Path) -> bool {
+        if self.stale.is_empty() {
+            return false;
+        }
+        for ancestor in path.ancestors() {
+            if self.stale.contains(ancestor) {
+                return true;
+            }
+            // moved here from a part of the cache that is still current
+            if self.moved.contains_key(ancestor) {
+                return false;
+            }
+        }
+        false
+    }
+
+    fn archive_path(&self, path: &Path) -> PathBuf {
+        for ancestor in path.ancestors() {
+            if let Some(origin) = self.moved.get(ancestor) {
+                return rebase(path, ancestor, origin);
+            }
+        }
+        path.to_path_buf()
+    }
+
+    /// Serves `path` and everything below it from the source from now on.
+    fn mark_stale(&mut self, path: &Path) {
+        self.moved.retain(|moved, _| !moved.starts_with(path));
+        self.stale.insert(path.to_path_buf());
+    }
+
+    fn rename(&mut self, from: &Path, to: &Path) {
+        let stale = self.is_stale(from);
+        let origin = self.archive_path(from);
+        // Whatever was replaced at `to` is gone, everything below `from` moves along.
+        self.stale = self
+            .stale
+            .drain()
+            .filter(|p| !p.starts_with(to))
+            .map(|p| {
+                if p.starts_with(from) {
+                    rebase(&p, from, to)
+                } else {
+                    p
+                }
+            })
+            .collect();
+        self.moved = self
+            .moved
+            .drain()
+            .filter(|(p, _)| !p.starts_with(to))
+            .map(|(p, origin)| {
+                if p.starts_with(from) {
+                    (rebase(&p, from, to), origin)
+                } else {
+                    (p, origin)
+                }
+            })
+            .collect();
+        if stale {
+            self.stale.insert(to.to_path_buf());
+        } else if origin != to {
+            self.moved.insert(to.to_path_buf(), origin);
+        }
+    }
+}
+
+/// Everything a mount serves from one cache file.
+#[cfg(feature = "mount")]
+pub struct Cache {
+    /// The cache file this was loaded from.
+    path: PathBuf,
+    entries: RwLock<Entry>,
+    tree: Tree,
+    /// Directories whose contents weren't loaded yet and the members holding them, by the
+    /// `shard_key` of their path, see [`Metadata::shards`].
+    shards: Mutex<HashMap<String, (PathBuf, String)>>,
+    archives: ArchivePool,
+    /// Decompressed files by their name in the archive.
+    buffers: BufferCache,
+    changes: Mutex<Changes>,
+    /// What to do about corrupted files and the [`CHECKSUMS`] to find them with, if they are
+    /// checked at all.
+    verify: Option<(VerifyReads, HashMap<String, String>)>,
+    /// The key the cache had to be signed with.
+    public_key: Option<PublicKey>,
+    /// See [`HIDDEN`].
+    hidden: HashSet<PathBuf>,
+    /// See [`XATTRS`], by the path in the archive, if the cache records them.
+    xattrs: Option<Mutex<HashMap<PathBuf, Option<Xattrs>>>>,
+    /// The groups of [`HARD_LINKS`] by each of their paths, as they are at the mount.
+    hard_links: Mutex<HashMap<PathBuf, Arc<[PathBuf]>>>,
+    rewrite: Rewrite,
+    /// The size of song files changed by `rewrite`, or `None` for those it leaves alone.
+    rewritten: Mutex<HashMap<PathBuf, Option<u64>>>,
+    /// The cache this one is layered over, serving the files this one doesn't hold.
+    base: Option<Arc<Cache>>,
+}
+
+/// What a mount does when a file read from the cache doesn't match its checksum.
+#[cfg(feature = "mount")]
+#[derive(Debug, Clone, Copy)]
+pub enum VerifyReads {
+    /// Serves it anyway, but logs the corruption.
+    Log,
+    /// Fails the read with an I/O error.
+    Fail,
+}
+
+#[cfg(feature = "mount")]
+impl VerifyReads {
+    pub const NAMES: [&'static str; 2] = ["log", "fail"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "log" => Some(VerifyReads::Log),
+            "fail" => Some(VerifyReads::Fail),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "mount")]
+impl Cache {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        let mut archive = CacheArchive::open(path)?;
+        let metadata = load_metadata(&mut archive).context("Unable to load cache")?;
+        let hidden = read_hidden(&mut archive)?;
+        let xattrs = read_xattrs(&mut archive)?.map(|xattrs| {
+            Mutex::new(
+                xattrs
+                    .into_iter()
+                    .map(|(path, xattrs)| (PathBuf::from(path), xattrs))
+                    .collect(),
+            )
+        });
+        let mut hard_links = HashMap::new();
+        for group in read_hard_links(&mut archive)? {
+            let group: Arc<[PathBuf]> = group.into();
+            for p in group.iter() {
+                hard_links.insert(p.clone(), group.clone());
+            }
+        }
+        Ok(Self {
+            path: path.to_path_buf(),
+            entries: RwLock::new(metadata.root),
+            tree: metadata.tree,
+            shards: Mutex::new(
+                metadata
+                    .shards
+                    .into_iter()
+                    .map(|(path, shard)| (shard_key(&path), (path, shard)))
+                    .collect(),
+            ),
+            archives: ArchivePool::new(archive),
+            buffers: BufferCache::new(buffer_cache::DEFAULT_LIMIT),
+            changes: Mutex::new(Changes::default()),
+            verify: None,
+            public_key: None,
+            hidden,
+            xattrs,
+            hard_links: Mutex::new(hard_links),
+            rewrite: Rewrite::default(),
+            rewritten: Mutex::default(),
+            base: None,
+        })
+    }
+
+    /// Only accepts the cache if it was signed with the secret key of `key`.
+    ///
+    /// The signature only covers the content of files through their checksums, so reads are
+    /// verified from then on, failing for corrupted files.
+    pub fn with_public_key(mut self, key: PublicKey) -> Result<Self> {
+        self.archive().verify_signature(&key)?;
+        let checksums = match self.verify.take() {
+            Some((_, checksums)) => checksums,
+            None => read_checksums(&mut self.archive())?,
+        };
+        self.verify = Some((VerifyReads::Fail, checksums));
+        self.public_key = Some(key);
+        Ok(self)
+    }
+
+    pub fn public_key(&self) -> Option<PublicKey> {
+        self.public_key
+    }
+
+    /// Checks files against their checksum whenever they are read from the cache file.
+    pub fn with_verify_reads(mut self, verify: VerifyReads) -> Result<Self> {
+        let checksums = read_checksums(&mut self.archive())?;
+        if checksums.is_empty() {
+            warn!("The cache holds no checksums to verify reads with, rebuild it to add them");
+        }
+        self.verify = Some((verify, checksums));
+        Ok(self)
+    }
+
+    pub fn verify_reads(&self) -> Option<VerifyReads> {
+        self.verify.as_ref().map(|(verify, _)| *verify)
+    }
+
+    /// Serves song files with their headers changed according to `rewrite`.
+    pub fn with_rewrite(mut self, rewrite: Rewrite) -> Self {
+        self.rewrite = rewrite;
+        self
+    }
+
+    pub fn rewrite(&self) -> Rewrite {
+        self.rewrite
+    }
+
+    /// Whether the rewrite applies to the file at `path`, a song file.
+    fn rewrites(&self, path: &Path) -> bool {
+        !self.rewrite.is_empty() && path.extension().is_some_and(|x| x == "txt")
+    }
+
+    /// The content served for the song file at `path` if the rewrite changes it. It is kept
+    /// with the decompressed files, so it counts towards the buffer limit.
+    pub fn rewritten(&self, path: &Path) -> Option<Arc<[u8]>> {
+        if !self.rewrites(path) {
+            return None;
+        }
+        let path = path_to_rel(path);
+        if let Some(None) = self.rewritten.lock().unwrap().get(path) {
+            return None;
+        }
+        // Names in the archive are relative, so this never is one of them.
+        let key = Path::new("/").join(path);
+        if let Some(data) = self.buffers.get(&key) {
+            return Some(data);
+        }
+        let rewritten: Option<Arc<[u8]>> = match self.read_file(path)? {
+            Ok(data) => self.rewrite.apply(&data).map(Arc::from),
+            // left to the read to report
+            Err(_) => return None,
+        };
+        self.rewritten.lock().unwrap().insert(
+            path.to_path_buf(),
+            rewritten.as_ref().map(|data| data.len() as u64),
+        );
+        if let Some(data) = &rewritten {
+            self.buffers.insert(&key, data.clone());
+        }
+        rewritten
+    }
+
+    /// The size of [`Cache::rewritten`] for `path`, which its attributes have to report. Only
+    /// rewrites the file the first time.
+    pub fn rewritten_size(&self, path: &Path) -> Option<u64> {
+        if !self.rewrites(path) {
+            return None;
+        }
+        if let Some(size) = self.rewritten.lock().unwrap().get(path_to_rel(path)) {
+            return *size;
+        }
+        self.rewritten(path).map(|data| data.len() as u64)
+    }
+
+    pub fn with_base(mut self, base: Arc<Cache>) -> Self {
+        self.base = Some(base);
+        self
+    }
+
+    pub fn base(&self) -> Option<Arc<Cache>> {
+        self.base.clone()
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Whether the cache, or one it is layered over, hides the song file at `path`.
+    pub fn is_hidden(&self, path: &Path) -> bool {
+        self.hidden.contains(path_to_rel(path))
+            || self.base.as_ref().is_some_and(|base| base.is_hidden(path))
+    }
+
+    /// The directory structure, with the entries on the way to `path` loaded.
+    pub fn entries(&self, path: &Path) -> RwLockReadGuard<'_, Entry> {
+        if !self.shards.lock().unwrap().is_empty() {
+            let mut prefix = PathBuf::new();
+            for component in path_to_rel(path).components() {
+                prefix.push(component);
+                self.load_shard(&shard_key(&prefix));
+            }
+        }
+        self.entries.read().unwrap()
+    }
+
+    /// The whole directory structure, loading everything that wasn't yet.
+    pub fn all_entries(&self) -> RwLockReadGuard<'_, Entry> {
+        self.load_below(Path::new(""));
+        self.entries.read().unwrap()
+    }
+
+    /// Loads everything at and below `path` that wasn't yet.
+    fn load_below(&self, path: &Path) {
+        let path = path_to_rel(path);
+        drop(self.entries(path));
+        loop {
+            // Loading a directory makes the shards of its subdirectories known.
+            let keys: Vec<String> = self
+                .shards
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, (dir, _))| dir.starts_with(path))
+                .map(|(key, _)| key.clone())
+                .collect();
+            if keys.is_empty() {
+                return;
+            }
+            for key in keys {
+                self.load_shard(&key);
+            }
+        }
+    }
+
+    /// Loads the contents of the directory with the `shard_key` `key`, if they weren't yet.
+    fn load_shard(&self, key: &str) {
+        // Held while loading, so concurrent lookups wait for it instead of loading it again.
+        let mut shards = self.shards.lock().unwrap();
+        // Not retried if it fails, the directory just stays empty.
+        let (path, shard) = match shards.remove(key) {
+            Some(shard) => shard,
+            None => return,
+        };
+        let mut archive = self.archive();
+        let loaded =
+            read_shard(&mut archive, &self.tree, &path, &shard).and_then(|(dir, children)| {
+                self.entries
+                    .write()
+                    .unwrap()
+                    .find_mut(&path)?
+                    .fill_from(dir);
+                Ok(children)
+            });
+        match loaded {
+            Ok(children) => {
+                for (path, shard) in children {
+                    shards.insert(shard_key(&path), (path, shard));
+                }
+            }
+            Err(e) => error!("Unable to load the cache of {:?}: {:#}", path, e),
+        }
+    }
+
+    /// Brings the entry at `path` up to date with `real`, the file in the source it mirrors.
+    /// Directories are only rescanned with `recursive`, otherwise just their attributes are
+    /// updated. The other hard links to a file are brought up to date along with it.
+    pub fn refresh(&self, path: &Path, real: &Path, recursive: bool) -> Result<()> {
+        // Taken first, as the link is dropped if it doesn't exist anymore.
+        let others = self.other_links(path);
+        self.refresh_entry(path, real, recursive)?;
+        if !others.is_empty() {
+            let source = real
+                .ancestors()
+                .nth(path_to_rel(path).components().count())
+                .unwrap_or(real);
+            for other in others {
+                self.refresh_entry(&other, &source.join(&other), false)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Brings the entry at `path` up to date like [`Cache::refresh`], but not its other links.
+    fn refresh_entry(&self, path: &Path, real: &Path, recursive: bool) -> Result<()> {
+        let path = path_to_rel(path);
+        self.rewritten.lock().unwrap().clear();
+        // Shards below would otherwise be filled into what replaces it.
+        self.load_below(path);
+        let mut root = self.entries.write().unwrap();
+        let fresh = match Entry::try_new(real) {
+            Ok(entry) => Some(entry),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+            Err(e) => {
+                return Err(e).with_context(|| format!("Unable to read '{}'", real.display()))
+            }
+        };
+
+        let name = match path.file_name() {
+            Some(name) => name,
+            None => {
+                if let Some(fresh) = fresh {
+                    root.set_stat(*fresh.stat());
+                }
+                return Ok(());
+            }
+        };
+        // Changes below unknown directories are picked up once the directory itself is.
+        let parent = match root.find_mut(path.parent().unwrap_or_else(|| Path::new(""))) {
+            Ok(parent) => parent,
+            Err(_) => return Ok(()),
+        };
+
+        match fresh {
+            Some(fresh @ Entry::Dict { .. }) if !recursive => {
+                match parent.find_mut(Path::new(name)) {
+                    Ok(existing @ Entry::Dict { .. }) => existing.set_stat(*fresh.stat()),
+                    _ => {
+                        parent.remove(name);
+                        parent.insert(Entry::scan(real)?)?;
+                        relink(&mut self.hard_links.lock().unwrap(), path, |_| None);
+                    }
+                }
+                return Ok(());
+            }
+            Some(Entry::Dict { .. }) => {
+                parent.remove(name);
+                parent.insert(Entry::scan(real)?)?;
+            }
+            Some(fresh) => {
+                parent.remove(name);
+                parent.insert(fresh)?;
+            }
+            None => {
+                parent.remove(name);
+            }
+        }
+        // Links that were removed, or replaced by files that aren't linked anymore, are dropped.
+        relink(&mut self.hard_links.lock().unwrap(), path, |p| {
+            match root.find(p) {
+                Ok(entry @ Entry::File { .. }) if entry.stat().nlink > 1 => Some(p.to_path_buf()),
+                _ => None,
+            }
+        });
+        debug!("refreshed {:?}", path);
+        self.changes.lock().unwrap().mark_stale(path);
+        Ok(())
+    }
+
+    /// Moves the entry at `from` to `to` after it was renamed in the source, so what was cached
+    /// is still served from its new location. Entries that aren't known are scanned from `real`,
+    /// the new location in the source, instead.
+    pub fn rename(&self, from: &Path, to: &Path, real: &Path) -> Result<()> {
+        let from = path_to_rel(from);
+        let to = path_to_rel(to);
+        let (from_name, to_name) = match (from.file_name(), to.file_name()) {
+            (Some(from_name), Some(to_name)) => (from_name, to_name),
+            _ => return Err(anyhow!("Can't rename the root")),
+        };
+        // Shards below are found by their path, so they have to be loaded before it changes.
+        self.load_below(from);
+        drop(self.entries(to));
+        let mut root = self.entries.write().unwrap();
+        let entry = root
+            .find_mut(from.parent().unwrap_or_else(|| Path::new("")))
+            .ok()
+            .and_then(|parent| parent.remove(from_name));
+        let mut entry = match entry {
+            Some(entry) => entry,
+            None => {
+                drop(root);
+                relink(&mut self.hard_links.lock().unwrap(), from, |_| None);
+                return self.refresh(to, real, true);
+            }
+        };
+
+        entry.set_name(to_name.to_os_string());
+        // Changes below unknown directories are picked up once the directory itself is.
+        if let Ok(parent) = root.find_mut(to.parent().unwrap_or_else(|| Path::new(""))) {
+            parent.remove(to_name);
+            parent.insert(entry)?;
+        }
+        let mut links = self.hard_links.lock().unwrap();
+        // Whatever was at `to` was replaced.
+        relink(&mut links, to, |_| None);
+        relink(&mut links, from, |p| {
+            p.strip_prefix(from).ok().map(|below| to.join(below))
+        });
+        drop(links);
+        self.changes.lock().unwrap().rename(from, to);
+        debug!("renamed {:?} to {:?}", from, to);
+        Ok(())
+    }
+
+    /// Changes the attributes of the entry at `path` in memory only, returning whether it exists.
+    /// Those of its other hard links change along with it.
+    pub fn update_stat<F: Fn(&mut SerializableFileAttr)>(&self, path: &Path, f: F) -> bool {
+        let mut root = self.entries.write().unwrap();
+        for other in self.other_links(path) {
+            if let Ok(entry) = root.find_mut(&other) {
+                let mut stat = *entry.stat();
+                f(&mut stat);
+                entry.set_stat(stat);
+            }
+        }
+        match root.find_mut(path) {
+            Ok(entry) => {
+                let mut stat = *entry.stat();
+                f(&mut stat);
+                entry.set_stat(stat);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// The paths of the other hard links to the file at `path`, see [`HARD_LINKS`].
+    fn other_links(&self, path: &Path) -> Vec<PathBuf> {
+        let path = path_to_rel(path);
+        match self.hard_links.lock().unwrap().get(path) {
+            Some(group) => group.iter().filter(|p| *p != path).cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// The extended attributes of the entry at `path` as the cache recorded them, `None` if they
+    /// have to be read from the source.
+    pub fn xattrs(&self, path: &Path) -> Option<Xattrs> {
+        let recorded = self.xattrs.as_ref()?;
+        let archive_path = self.member_path(path)?;
+        archive_path.to_str()?;
+        self.entries(path).find(path).ok()?;
+        let recorded = recorded.lock().unwrap();
+        match recorded.get(&archive_path) {
+            Some(xattrs) => xattrs.clone(),
+            None => Some(Xattrs::new()),
+        }
+    }
+
+    /// Reads the extended attributes of `path` from the source from now on, as they were
+    /// changed there.
+    pub fn forget_xattrs(&self, path: &Path) {
+        if let (Some(recorded), Some(path)) = (&self.xattrs, self.member_path(path)) {
+            recorded.lock().unwrap().insert(path, None);
+        }
+    }
+
+    /// The target of the symlink at `path` as the cache recorded it, `None` if it has to be read
+    /// from the source.
+    pub fn link_target(&self, path: &Path) -> Option<OsString> {
+        if self.is_stale(path) {
+            return None;
+        }
+        let entries = self.entries(path);
+        entries.find(path).ok()?.target().map(OsStr::to_os_string)
+    }
+
+    /// Whether `path` changed in the source since the cache was built, so it has to be served
+    /// from there.
+    pub fn is_stale(&self, path: &Path) -> bool {
+        self.changes.lock().unwrap().is_stale(path_to_rel(path))
+    }
+
+    /// Limits how many bytes of decompressed files are kept in memory.
+    pub fn with_buffer_limit(mut self, limit: u64) -> Self {
+        self.buffers = BufferCache::new(limit);
+        self
+    }
+
+    pub fn buffer_limit(&self) -> u64 {
+        self.buffers.limit()
+    }
+
+    /// Drops all decompressed files kept in memory, handles that are still open keep theirs.
+    pub fn drop_buffers(&self) {
+        self.buffers.clear();
+        if let Some(base) = &self.base {
+            base.drop_buffers();
+        }
+    }
+
+    /// The number of decompressed files kept in memory and their size in bytes, including
+    /// those of the caches this one is layered over.
+    pub fn buffer_usage(&self) -> (usize, u64) {
+        let (count, size) = self.buffers.usage();
+        match &self.base {
+            Some(base) => {
+                let (base_count, base_size) = base.buffer_usage();
+                (count + base_count, size + base_size)
+            }
+            None => (count, size),
+        }
+    }
+
+    /// Whether a mount serves the content of `path` from the cache.
+    pub fn is_cached(&self, path: &Path) -> bool {
+        let path = path_to_rel(path);
+        let changes = self.changes.lock().unwrap();
+        if changes.is_stale(path) {
+            return false;
+        }
+        let path = changes.archive_path(path);
+        self.archive().contains(&member_name(&path))
+            || self.base.as_ref().is_some_and(|base| base.is_cached(&path))
+    }
+
+    /// The archive member `path` is served from, or `None` if it changed in the source.
+    fn member_path(&self, path: &Path) -> Option<PathBuf> {
+        let path = path_to_rel(path);
+        let changes = self.changes.lock().unwrap();
+        if changes.is_stale(path) {
+            return None;
+        }
+        Some(changes.archive_path(path))
+    }
+
+    /// Returns the content a mount serves for the file at `path`, or `None` if it isn't cached.
+    pub fn read_file(&self, path: &Path) -> Option<io::Result<Arc<[u8]>>> {
+        let path = self.member_path(path)?;
+        if let Some(data) = self.buffers.get(&path) {
+            return Some(Ok(data));
+        }
+        let start = Instant::now();
+        let data: Arc<[u8]> = match self.archive().read_file(&path) {
+            Some(Ok(data)) => data.into(),
+            Some(Err(e)) => return Some(Err(e)),
+            None => return self.base.as_ref()?.read_file(&path),
+        };
+        METRICS.zip_decode.observe(start.elapsed());
+        if let Err(e) = self.check(&path, &data) {
+            return Some(Err(e));
+        }
+        self.buffers.insert(&path, data.clone());
+        Some(Ok(data))
+    }
+
+    /// Like `read_file`, but for a handle: unless the file is kept in memory already, its
+    /// content is decompressed only as far as it is read.
+    ///
+    /// Verified reads need the whole content for its checksum, so they are served by
+    /// `read_file`.
+    pub fn open_file(&self, path: &Path) -> Option<io::Result<Content>> {
+        if let Some(data) = self.rewritten(path) {
+            return Some(Ok(Content::Whole(data)));
+        }
+        if self.verify.is_some() {
+            return self.read_file(path).map(|data| data.map(Content::Whole));
+        }
+        let path = self.member_path(path)?;
+        if let Some(data) = self.buffers.get(&path) {
+            return Some(Ok(Content::Whole(data)));
+        }
+        let content = self.archive().stream(&path);
+        match content {
+            None => self.base.as_ref()?.open_file(&path),
+            content => content,
+        }
+    }
+
+    /// The preview clip stored for the audio file at `path`, if there is one.
+    pub fn open_clip(&self, path: &Path) -> Option<io::Result<Content>> {
+        let clip = self.archive().stream(&clip_member(path));
+        match clip {
+            None => self.base.as_ref()?.open_clip(path),
+            clip => clip,
+        }
+    }
+
+    /// Compares `data` read from the archive member `path` with its checksum, if reads are
+    /// verified.
+    fn check(&self, path: &Path, data: &[u8]) -> io::Result<()> {
+        let (verify, checksums) = match &self.verify {
+            Some(verify) => verify,
+            None => return Ok(()),
+        };
+        let expected = match checksums.get(&member_name(path)) {
+            Some(expected) => expected,
+            None => return Ok(()),
+        };
+        if to_hex(&Sha256::digest(data)) == *expected {
+            return Ok(());
+        }
+        match verify {
+            VerifyReads::Log => {
+                error!(
+                    "'{}' in the cache is corrupted, its checksum doesn't match",
+                    path.display()
+                );
+                Ok(())
+            }
+            VerifyReads::Fail => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "corrupted, its checksum doesn't match",
+            )),
+        }
+    }
+
+    /// Borrows a reader of the cache file, so concurrent operations don't block each other.
+    pub fn archive(&self) -> PooledArchive<'_> {
+        self.archives.get()
+    }
+}
+
+/// Replaces the paths at and below `path` in the groups of hard links `links` by what `f` maps
+/// them to, dropping those it maps to `None`. Groups left with a single path are dropped as well.
+#[cfg(feature = "mount")]
+fn relink<F>(links: &mut HashMap<PathBuf, Arc<[PathBuf]>>, path: &Path, f: F)
+where
+    F: Fn(&Path) -> Option<PathBuf>,
+{
+    let groups: Vec<Arc<[PathBuf]>> = links
+        .iter()
+        .filter(|(p, _)| p.starts_with(path))
+        .map(|(_, group)| group.clone())
+        .collect();
+    for group in groups {
+        // Groups with several paths below `path` are listed once for each.
+        if !matches!(links.get(&group[0]), Some(current) if Arc::ptr_eq(current, &group)) {
+            continue;
+        }
+        for p in group.iter() {
+            links.remove(p);
+        }
+        let group: Arc<[PathBuf]> = group
+            .iter()
+            .filter_map(|p| {
+                if p.starts_with(path) {
+                    f(p)
+                } else {
+                    Some(p.clone())
+                }
+            })
+            .collect();
+        if group.len() > 1 {
+            for p in group.iter() {
+                links.insert(p.clone(), group.clone());
+            }
+        }
+    }
+}
+
+/// What [`Cache::shards`] are looked up by: `path` normalized, as names are matched regardless
+/// of their normalization.
+#[cfg(feature = "mount")]
+fn shard_key(path: &Path) -> String {
+    nfc(&path.to_string_lossy()).into_owned()
+}
+
+/// The content of a file a handle serves from the cache.
+#[cfg(feature = "mount")]
+pub enum Content {
+    Whole(Arc<[u8]>),
+    /// An uncompressed member, at `range` in the cache file.
+    Mapped {
+        file: SharedFile,
+        range: Range<usize>,
+    },
+    Stream(ZipStream),
+}
+
+#[cfg(feature = "mount")]
+impl Content {
+    /// The up to `size` bytes at `offset`.
+    pub fn read_at(&mut self, offset: u64, size: usize) -> io::Result<&[u8]> {
+        match self {
+            Content::Whole(data) => Ok(slice(data, offset, size)),
+            Content::Mapped { file, range } => {
+                Ok(slice(&file.contents()[range.clone()], offset, size))
+            }
+            Content::Stream(stream) => stream.read_at(offset, size),
+        }
+    }
+}
+
+/// The up to `size` bytes of `data` at `offset`.
+#[cfg(feature = "mount")]
+fn slice(data: &[u8], offset: u64, size: usize) -> &[u8] {
+    let start = offset.min(data.len() as u64) as usize;
+    let end = start.saturating_add(size).min(data.len());
+    &data[start..end]
+}
+
+/// Readers of a cache file that are currently not in use.
+///
+/// A new reader is cloned from the first one whenever all are busy, so the pool grows to the
+/// highest number of concurrent reads seen.
+#[cfg(feature = "mount")]
+struct ArchivePool {
+    template: CacheArchive,
+    idle: Mutex<Vec<CacheArchive>>,
+}
+
+#[cfg(feature = "mount")]
+impl ArchivePool {
+    fn new(archive: CacheArchive) -> Self {
+        Self {
+            idle: Mutex::new(vec![archive.clone()]),
+            template: archive,
+        }
+    }
+
+    fn get(&self) -> PooledArchive<'_> {
+        let idle = self.idle.lock().unwrap().pop();
+        PooledArchive {
+            archive: Some(idle.unwrap_or_else(|| self.template.clone())),
+            pool: self,
+        }
+    }
+}
+
+/// A reader borrowed from an [`ArchivePool`], which is returned to it when dropped.
+#[cfg(feature = "mount")]
+pub struct PooledArchive<'a> {
+    archive: Option<CacheArchive>,
+    pool: &'a ArchivePool,
+}
+
+#[cfg(feature = "mount")]
+impl Deref for PooledArchive<'_> {
+    type Target = CacheArchive;
+
+    fn deref(&self) -> &CacheArchive {
+        self.archive.as_ref().unwrap()
+    }
+}
+
+#[cfg(feature = "mount")]
+impl DerefMut for PooledArchive<'_> {
+    fn deref_mut(&mut self) -> &mut CacheArchive {
+        self.archive.as_mut().unwrap()
+    }
+}
+
+#[cfg(feature = "mount")]
+impl Drop for PooledArchive<'_> {
+    fn drop(&mut self) {
+        if let Some(archive) = self.archive.take() {
+            self.pool.idle.lock().unwrap().push(archive);
+        }
+    }
 }
@@ -0,0 +1,240 @@
+// Control :: Commands for a running mount, received over a unix socket.
+//
+// Clients send a single line `<command> [argument]` per connection, and get back `ok` or
+// `error: <message>`, optionally preceded by further output lines.
+//
+// Every mount listens on a socket derived from its mount point unless one is given explicitly, so
+// `stats <target>` finds it without further configuration.
+
+use crate::cache::{Cache, Entry};
+use crate::invalidate::Invalidator;
+use crate::metrics::METRICS;
+use anyhow::{anyhow, Context, Result};
+use log::LevelFilter;
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::ffi::OsString;
+use std::fmt::Write as _;
+use std::hash::{Hash, Hasher};
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::thread;
+
+const MIB: f64 = 1024.0 * 1024.0;
+
+/// The names of the entries at the root of `cache`.
+fn root_names(cache: &Cache) -> Vec<OsString> {
+    match &*cache.entries(Path::new("")) {
+        Entry::Dict { contents, .. } => contents
+            .iter()
+            .map(|entry| entry.name().to_os_string())
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Shared state of a mounted filesystem, which the control commands operate on.
+#[derive(Clone)]
+pub struct Control {
+    cache: Arc<RwLock<Arc<Cache>>>,
+    kernel: Arc<Invalidator>,
+}
+
+impl Control {
+    pub fn new(cache: Arc<RwLock<Arc<Cache>>>, kernel: Arc<Invalidator>) -> Self {
+        Self { cache, kernel }
+    }
+
+    /// Replaces the active cache with the one at `path`. With layered caches only the topmost
+    /// is replaced, so a fresh delta can be swapped in over the same base.
+    ///
+    /// Operations already in progress finish on the old cache and open handles keep their
+    /// content. The kernel is told to look up everything at the root of either cache again.
+    pub fn swap_cache<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let (limit, verify, key, rewrite, base) = {
+            let cache = self.cache.read().unwrap();
+            (
+                cache.buffer_limit(),
+                cache.verify_reads(),
+                cache.public_key(),
+                cache.rewrite(),
+                cache.base(),
+            )
+        };
+        let mut cache = Cache::open(path)
+            .with_context(|| format!("'{}' is no valid cache", path.display()))?
+            .with_buffer_limit(limit)
+            .with_rewrite(rewrite);
+        if let Some(verify) = verify {
+            cache = cache.with_verify_reads(verify)?;
+        }
+        if let Some(key) = key {
+            cache = cache.with_public_key(key)?;
+        }
+        if let Some(base) = base {
+            cache = cache.with_base(base);
+        }
+        let cache = Arc::new(cache);
+        let old = std::mem::replace(&mut *self.cache.write().unwrap(), cache.clone());
+        info!("Swapped active cache for '{}'", path.display());
+
+        let mut names = root_names(&old);
+        names.extend(root_names(&cache));
+        names.sort();
+        names.dedup();
+        // The control file is written to on a thread serving requests, which the invalidation
+        // must not wait on.
+        let kernel = self.kernel.clone();
+        thread::spawn(move || {
+            for name in names {
+                kernel.entry(Path::new(&name));
+            }
+            kernel.inode(Path::new(""));
+        });
+        Ok(())
+    }
+
+    /// Live counters of the mount, one per line.
+    pub fn stats(&self) -> String {
+        let cache = self.cache.read().unwrap().clone();
+        let (buffers, buffered) = cache.buffer_usage();
+        let load = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+
+        let mut out = String::new();
+        let _ = writeln!(out, "cache hits:    {}", load(&METRICS.opens_cache));
+        let _ = writeln!(out, "cache misses:  {}", load(&METRICS.opens_source));
+        let _ = writeln!(out, "attr hits:     {}", load(&METRICS.attrs_cache));
+        let _ = writeln!(out, "attr misses:   {}", load(&METRICS.attrs_source));
+        let _ = writeln!(out, "open handles:  {}", load(&METRICS.open_handles));
+        let _ = write!(
+            out,
+            "buffered:      {:.1} of {:.1} MiB in {} files",
+            buffered as f64 / MIB,
+            cache.buffer_limit() as f64 / MIB,
+            buffers
+        );
+        out
+    }
+
+    /// Runs the command in `line`, returning its output.
+    pub fn execute(&self, line: &str) -> Result<String> {
+        let line = line.trim();
+        let (command, argument) = match line.find(' ') {
+            Some(i) => (&line[..i], line[i + 1..].trim()),
+            None => (line, ""),
+        };
+        match command {
+            "swap-cache" if !argument.is_empty() => {
+                self.swap_cache(argument).map(|()| String::new())
+            }
+            "swap-cache" => Err(anyhow!("usage: swap-cache <path>")),
+            "reload-cache" => {
+                let path = self.cache.read().unwrap().path().to_path_buf();
+                self.swap_cache(path).map(|()| String::new())
+            }
+            "drop-buffers" => {
+                self.cache.read().unwrap().drop_buffers();
+                Ok(String::new())
+            }
+            "log-level" => {
+                let level: LevelFilter = argument
+                    .parse()
+                    .map_err(|_| anyhow!("usage: log-level <off|error|warn|info|debug|trace>"))?;
+                log::set_max_level(level);
+                info!("Logging up to {}", level);
+                Ok(String::new())
+            }
+            "stats" => Ok(self.stats()),
+            _ => Err(anyhow!("unknown command '{}'", command)),
+        }
+    }
+
+    fn handle(&self, stream: UnixStream) -> Result<()> {
+        let mut line = String::new();
+        BufReader::new(&stream).read_line(&mut line)?;
+        debug!("control: {:?}", line.trim());
+
+        let mut stream = &stream;
+        match self.execute(&line) {
+            Ok(output) => {
+                if !output.is_empty() {
+                    writeln!(stream, "{}", output)?;
+                }
+                writeln!(stream, "ok")?
+            }
+            Err(e) => {
+                warn!("control: {}: {:#}", line.trim(), e);
+                writeln!(stream, "error: {:#}", e)?
+            }
+        }
+        Ok(())
+    }
+
+    /// Listens for commands on a unix socket at `path` in a background thread.
+    pub fn serve<P: AsRef<Path>>(self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        if path.exists() {
+            // left over from a previous mount
+            std::fs::remove_file(path)
+                .with_context(|| format!("Unable to remove stale socket '{}'", path.display()))?;
+        }
+        let listener = UnixListener::bind(path)
+            .with_context(|| format!("Unable to create control socket '{}'", path.display()))?;
+
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                match stream {
+                    Ok(stream) => {
+                        if let Err(e) = self.handle(stream) {
+                            warn!("control: {:#}", e);
+                        }
+                    }
+                    Err(e) => error!("control: unable to accept connection: {}", e),
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+/// The control socket of the mount at `target`, if none was given explicitly.
+///
+/// It lives in `$XDG_RUNTIME_DIR`, or the temporary directory, under a hash of the mount point, as
+/// the mount point itself may be too long for a socket path.
+pub fn default_socket<P: AsRef<Path>>(target: P) -> Result<PathBuf> {
+    let target = target.as_ref();
+    let target = target
+        .canonicalize()
+        .with_context(|| format!("Unable to resolve '{}'", target.display()))?;
+    let mut hasher = DefaultHasher::new();
+    target.hash(&mut hasher);
+    let dir = env::var_os("XDG_RUNTIME_DIR")
+        .map(PathBuf::from)
+        .unwrap_or_else(env::temp_dir);
+    Ok(dir.join(format!("ultrastar-fs-{:016x}.sock", hasher.finish())))
+}
+
+/// Sends a command to the control socket at `path` and prints the response.
+pub fn send<P: AsRef<Path>>(path: P, command: &str) -> Result<()> {
+    let path = path.as_ref();
+    let mut stream = UnixStream::connect(path)
+        .with_context(|| format!("Unable to connect to control socket '{}'", path.display()))?;
+    writeln!(stream, "{}", command)?;
+
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    let mut lines: Vec<&str> = response.lines().collect();
+    let status = lines.pop().unwrap_or("error: no response");
+    for line in lines {
+        println!("{}", line);
+    }
+    if status == "ok" {
+        Ok(())
+    } else {
+        Err(anyhow!("{}", status.trim_start_matches("error: ")))
+    }
+}
@@ -1,13 +1,17 @@
-use anyhow::{Context, Result};
+use anyhow::{anyhow, Context, Result};
 use diesel::connection::SimpleConnection;
 use diesel::prelude::*;
-use image::GenericImageView;
-#[cfg(feature = "mount")]
+use image::imageops::FilterType;
+use image::{DynamicImage, GenericImageView, ImageFormat};
 use indicatif::{ProgressBar, ProgressIterator};
+use sha2::{Digest, Sha256};
 
 use std::{
-    io::{Seek, Write},
+    collections::{HashMap, HashSet},
+    fs::{self, File},
+    io::{self, Read, Seek, Write},
     path::{Path, PathBuf},
+    sync::Mutex,
     time::SystemTime,
 };
 
@@ -30,97 +34,451 @@ table! {
         Format -> Integer,
         Width -> Integer,
         Height -> Integer,
-        Data -> Integer,
+        Data -> Nullable<Binary>,
     }
 }
 
 allow_tables_to_appear_in_same_query!(Cover, CoverThumbnail,);
 
-// Default Thumbnail format in USDX
-const TEXTURE_FORMAT: i32 = 1; //`ipfRGB` in USDX
-
 // https://github.com/UltraStar-Deluxe/USDX/blob/master/src/base/UCovers.pas#L456
 // https://github.com/UltraStar-Deluxe/USDX/blob/4849669cae06421369430c56c7e302f43fc47713/src/base/UImage.pas#L50
 
+/// Pixel layout of a thumbnail, `TImagePixelFmt` in USDX.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ThumbnailFormat {
+    Rgba,
+    Rgb,
+    Bgra,
+    Bgr,
+}
+
+impl ThumbnailFormat {
+    pub const NAMES: &'static [&'static str] = &["rgba", "rgb", "bgra", "bgr"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "rgba" => Some(ThumbnailFormat::Rgba),
+            "rgb" => Some(ThumbnailFormat::Rgb),
+            "bgra" => Some(ThumbnailFormat::Bgra),
+            "bgr" => Some(ThumbnailFormat::Bgr),
+            _ => None,
+        }
+    }
+
+    fn from_id(id: i32) -> Option<Self> {
+        match id {
+            0 => Some(ThumbnailFormat::Rgba),
+            1 => Some(ThumbnailFormat::Rgb),
+            2 => Some(ThumbnailFormat::Bgra),
+            3 => Some(ThumbnailFormat::Bgr),
+            _ => None,
+        }
+    }
+
+    /// The value USDX stores in `CoverThumbnail.Format`.
+    fn id(self) -> i32 {
+        match self {
+            ThumbnailFormat::Rgba => 0,
+            ThumbnailFormat::Rgb => 1,
+            ThumbnailFormat::Bgra => 2,
+            ThumbnailFormat::Bgr => 3,
+        }
+    }
+
+    pub fn bytes_per_pixel(self) -> u64 {
+        match self {
+            ThumbnailFormat::Rgba | ThumbnailFormat::Bgra => 4,
+            ThumbnailFormat::Rgb | ThumbnailFormat::Bgr => 3,
+        }
+    }
+
+    /// The pixels of `image` in this layout, row by row from the top.
+    fn encode(self, image: &DynamicImage) -> Vec<u8> {
+        match self {
+            ThumbnailFormat::Rgba => image.to_rgba8().into_raw(),
+            ThumbnailFormat::Rgb => image.to_rgb8().into_raw(),
+            ThumbnailFormat::Bgra => image.to_bgra8().into_raw(),
+            ThumbnailFormat::Bgr => image.to_bgr8().into_raw(),
+        }
+    }
+}
+
+impl Default for ThumbnailFormat {
+    /// Default Thumbnail format in USDX
+    fn default() -> Self {
+        ThumbnailFormat::Rgb
+    }
+}
+
+/// How thumbnails are generated, which has to match the configuration of USDX.
+#[derive(Debug)]
+pub struct ThumbnailOptions {
+    /// Thumbnails are at most this wide and high.
+    pub size: u32,
+    pub format: ThumbnailFormat,
+}
+
+impl Default for ThumbnailOptions {
+    fn default() -> Self {
+        ThumbnailOptions {
+            // USDX's default texture size
+            size: 256,
+            format: ThumbnailFormat::default(),
+        }
+    }
+}
+
+/// A cover with the thumbnail USDX would generate for it.
+pub struct CoverImage {
+    width: u32,
+    height: u32,
+    thumbnail_width: u32,
+    thumbnail_height: u32,
+    format: ThumbnailFormat,
+    data: Vec<u8>,
+}
+
+impl CoverImage {
+    pub fn new(image: &DynamicImage, options: &ThumbnailOptions) -> Self {
+        let (width, height) = image.dimensions();
+        let size = options.size;
+        let thumbnail = if width > size || height > size {
+            image.resize(size, size, FilterType::Triangle)
+        } else {
+            image.clone()
+        };
+        CoverImage {
+            width,
+            height,
+            thumbnail_width: thumbnail.width(),
+            thumbnail_height: thumbnail.height(),
+            format: options.format,
+            data: options.format.encode(&thumbnail),
+        }
+    }
+}
+
+/// A cover loaded by [`SeenCovers::load`].
+pub enum LoadedCover {
+    Decoded {
+        hash: [u8; 32],
+        image: CoverImage,
+    },
+    /// A copy of a cover loaded before, which isn't decoded again.
+    Copy {
+        hash: [u8; 32],
+    },
+}
+
+/// The content hashes of the covers loaded during a build, so each of the copies of an image
+/// songs tend to share is only decoded once.
+#[derive(Default)]
+pub struct SeenCovers(Mutex<HashSet<[u8; 32]>>);
+
+impl SeenCovers {
+    /// Decodes the cover at `cover` and generates its thumbnail, unless a file with the same
+    /// content was loaded before. Can happen on any thread.
+    pub fn load<P: AsRef<Path>>(
+        &self,
+        cover: P,
+        options: &ThumbnailOptions,
+    ) -> Result<LoadedCover> {
+        let cover = cover.as_ref();
+        let data = fs::read(cover)
+            .with_context(|| format!("Unable to load image file '{}'", cover.display()))?;
+        let hash: [u8; 32] = Sha256::digest(&data).into();
+        if !self.0.lock().unwrap().insert(hash) {
+            return Ok(LoadedCover::Copy { hash });
+        }
+        // like image::open, which goes by the extension first
+        let image = match ImageFormat::from_path(cover) {
+            Ok(format) => image::load_from_memory_with_format(&data, format),
+            Err(_) => image::load_from_memory(&data),
+        }
+        .with_context(|| format!("Unable to load image file '{}'", cover.display()))?;
+        Ok(LoadedCover::Decoded {
+            hash,
+            image: CoverImage::new(&image, options),
+        })
+    }
+}
+
 pub struct CoverDB {
     dbfile: tempfile::NamedTempFile,
     conn: diesel::sqlite::SqliteConnection,
     relative_to: PathBuf,
+    /// The row of each decoded cover, by its content hash.
+    ids: HashMap<[u8; 32], i32>,
+    /// Copies of covers that weren't added yet, by the hash of the cover.
+    pending: HashMap<[u8; 32], Vec<PathBuf>>,
+    /// Rows of the previous cover.db that weren't kept or replaced yet, their ID and date by
+    /// their name.
+    previous: HashMap<String, (i32, i32)>,
+    /// Rows that are up to date, taken over from the previous cover.db or added since, their ID
+    /// by their name.
+    current: HashMap<String, i32>,
 }
 
 impl CoverDB {
     pub fn new<P: AsRef<Path>>(relative: P) -> Result<CoverDB> {
         let temp =
             tempfile::NamedTempFile::new().context("Unable to open temporary cover.db file")?;
+        let db = Self::open(temp, relative.as_ref())?;
+        db.conn
+            .batch_execute(include_str!("init.sql"))
+            .context("Failed to initialize database")?;
+        Ok(db)
+    }
+
+    /// Continues the cover.db `previous` of an earlier build. Its rows are kept with
+    /// [`CoverDB::keep`] or replaced by adding their cover again, all others are dropped when
+    /// writing it.
+    pub fn from_previous<P: AsRef<Path>, R: Read>(relative: P, mut previous: R) -> Result<CoverDB> {
+        let mut temp =
+            tempfile::NamedTempFile::new().context("Unable to open temporary cover.db file")?;
+        io::copy(&mut previous, &mut temp).context("Unable to copy the previous cover.db")?;
+        temp.flush()?;
+        let mut db = Self::open(temp, relative.as_ref())?;
+        db.previous = Cover::table
+            .select((Cover::Filename, Cover::ID, Cover::CreationDate))
+            .load::<(String, i32, i32)>(&db.conn)
+            .context("Failed to load table Cover from the previous cover.db")?
+            .into_iter()
+            .map(|(name, id, date)| (name, (id, date)))
+            .collect();
+        Ok(db)
+    }
+
+    fn open(temp: tempfile::NamedTempFile, relative: &Path) -> Result<CoverDB> {
         let conn = diesel::sqlite::SqliteConnection::establish(
             temp.path()
                 .to_str()
                 .expect("NamedFile path is no valid UTF-8"),
         )?;
-        conn.batch_execute(include_str!("init.sql"))
-            .context("Failed to initialize database")?;
         Ok(CoverDB {
             dbfile: temp,
             conn,
-            relative_to: PathBuf::from(relative.as_ref()),
+            // covers are canonicalized
+            relative_to: relative
+                .canonicalize()
+                .with_context(|| format!("Unable to resolve '{}'", relative.display()))?,
+            ids: HashMap::new(),
+            pending: HashMap::new(),
+            previous: HashMap::new(),
+            current: HashMap::new(),
         })
     }
 
-    pub fn add<P: AsRef<Path>>(&mut self, cover: P) -> Result<()> {
+    /// Takes over the row of the cover at `cover` from the previous cover.db, unless the file
+    /// changed since its thumbnail was generated. Returns whether it did.
+    pub fn keep<P: AsRef<Path>>(&mut self, cover: P) -> bool {
         let cover = cover.as_ref();
+        let name = match cover.strip_prefix(&self.relative_to).map(db_name) {
+            Ok(Ok(name)) => name,
+            _ => return false,
+        };
+        if self.current.contains_key(&name) {
+            return true;
+        }
+        let (id, date) = match self.previous.get(&name) {
+            Some(&row) => row,
+            None => return false,
+        };
+        let modified = fs::metadata(cover)
+            .and_then(|m| m.modified())
+            .ok()
+            .and_then(|modified| modified.duration_since(SystemTime::UNIX_EPOCH).ok());
+        match modified {
+            Some(modified) if modified.as_secs() <= date as u64 => {
+                self.previous.remove(&name);
+                self.current.insert(name, id);
+                true
+            }
+            _ => false,
+        }
+    }
 
-        self.conn.transaction(|| {
-            let image = image::open(cover)
-                .with_context(|| format!("Unable to load image file '{}'", cover.display()))?;
-            let mut file_name = cover
-                .strip_prefix(&self.relative_to)
-                .with_context(|| format!("Cover '{}' is not relative to src_dir", cover.display()))?
-                .to_str()
-                .with_context(|| {
-                    format!("Unable to store filename '{}' in database", cover.display())
-                })?
-                .to_string();
-            // Add null byte at the end since usdx is weird.
-            file_name.push(char::from(0));
-            diesel::insert_into(Cover::table)
-                .values((
-                    Cover::Filename.eq(&file_name),
-                    Cover::CreationDate.eq(SystemTime::now()
-                        .duration_since(SystemTime::UNIX_EPOCH)
-                        .expect("SystemTime before unix epoch")
-                        .as_secs() as i32),
-                    Cover::Width.eq(image.width() as i32),
-                    Cover::Height.eq(image.height() as i32),
-                ))
-                .execute(&self.conn)
-                .with_context(|| {
-                    format!("Unable to add cover to database '{}'", cover.display())
-                })?;
+    /// Takes over the row of the generated cover `file_name` from the previous cover.db, as
+    /// [`CoverDB::add_image`] added it. Returns whether there was one.
+    pub fn keep_image<P: AsRef<Path>>(&mut self, file_name: P) -> bool {
+        let name = match db_name(file_name.as_ref()) {
+            Ok(name) => name,
+            Err(_) => return false,
+        };
+        if let Some((id, _)) = self.previous.remove(&name) {
+            self.current.insert(name.clone(), id);
+        }
+        self.current.contains_key(&name)
+    }
 
-            let id: i32 = Cover::table
-                .select(Cover::ID)
-                .order(Cover::ID.desc())
+    /// Makes way for adding a row for `file_name` by dropping the one of the previous cover.db.
+    /// Returns the row instead if it is up to date already, which stays as it is.
+    fn replace(&mut self, file_name: &Path) -> Result<Option<i32>> {
+        let name = db_name(file_name)?;
+        if let Some(&id) = self.current.get(&name) {
+            return Ok(Some(id));
+        }
+        if let Some((id, _)) = self.previous.remove(&name) {
+            delete_cover(&self.conn, id)
+                .with_context(|| format!("Unable to replace cover '{}'", file_name.display()))?;
+        }
+        Ok(None)
+    }
+
+    /// Decodes the cover at `cover` and generates its thumbnail, which doesn't need the database
+    /// and can happen on any thread.
+    pub fn load<P: AsRef<Path>>(cover: P, options: &ThumbnailOptions) -> Result<CoverImage> {
+        let cover = cover.as_ref();
+        let image = image::open(cover)
+            .with_context(|| format!("Unable to load image file '{}'", cover.display()))?;
+        Ok(CoverImage::new(&image, options))
+    }
+
+    /// Registers a cover loaded by [`SeenCovers::load`] from `cover`.
+    ///
+    /// USDX looks thumbnails up by the row of their cover, so copies still get a row each, but
+    /// one copied from the first cover with the same content rather than generated again.
+    pub fn add<P: AsRef<Path>>(&mut self, cover: P, loaded: &LoadedCover) -> Result<()> {
+        let cover = cover.as_ref();
+        let file_name = cover
+            .strip_prefix(&self.relative_to)
+            .with_context(|| format!("Cover '{}' is not relative to src_dir", cover.display()))?;
+        match loaded {
+            LoadedCover::Decoded { hash, image } => {
+                let id = match self.replace(file_name)? {
+                    Some(current) => current,
+                    None => {
+                        let id = self.insert_image(file_name, image)?;
+                        self.current.insert(db_name(file_name)?, id);
+                        id
+                    }
+                };
+                self.ids.insert(*hash, id);
+                for copy in self.pending.remove(hash).unwrap_or_default() {
+                    self.add_copy(&copy, id)?;
+                }
+                Ok(())
+            }
+            LoadedCover::Copy { hash } => match self.ids.get(hash) {
+                Some(&id) => self.add_copy(file_name, id),
+                // loaded first by a song that is added later
+                None => {
+                    self.pending
+                        .entry(*hash)
+                        .or_default()
+                        .push(file_name.to_path_buf());
+                    Ok(())
+                }
+            },
+        }
+    }
+
+    /// Registers an already decoded cover under `file_name`, relative to the song directory.
+    pub fn add_image<P: AsRef<Path>>(&mut self, file_name: P, image: &CoverImage) -> Result<()> {
+        let file_name = file_name.as_ref();
+        if self.replace(file_name)?.is_none() {
+            let id = self.insert_image(file_name, image)?;
+            self.current.insert(db_name(file_name)?, id);
+        }
+        Ok(())
+    }
+
+    /// Adds a copy of the row `of` as `file_name`.
+    fn add_copy(&mut self, file_name: &Path, of: i32) -> Result<()> {
+        if self.replace(file_name)?.is_none() {
+            let id = self.insert_copy(file_name, of)?;
+            self.current.insert(db_name(file_name)?, id);
+        }
+        Ok(())
+    }
+
+    /// Adds a row for the cover `cover` and returns its ID.
+    fn insert_cover(&self, cover: &Path, width: i32, height: i32) -> Result<i32> {
+        let file_name = db_name(cover)?;
+        diesel::insert_into(Cover::table)
+            .values((
+                Cover::Filename.eq(&file_name),
+                Cover::CreationDate.eq(now()),
+                Cover::Width.eq(width),
+                Cover::Height.eq(height),
+            ))
+            .execute(&self.conn)
+            .with_context(|| format!("Unable to add cover to database '{}'", cover.display()))?;
+
+        Cover::table
+            .select(Cover::ID)
+            .order(Cover::ID.desc())
+            .first(&self.conn)
+            .context("Unable to get ID of cover")
+    }
+
+    /// Adds the cover `cover` with the dimensions and the thumbnail of the row `of` and returns
+    /// its ID.
+    fn insert_copy(&self, cover: &Path, of: i32) -> Result<i32> {
+        self.conn.transaction(|| {
+            let (width, height): (i32, i32) = Cover::table
+                .find(of)
+                .select((Cover::Width, Cover::Height))
                 .first(&self.conn)
-                .context("Unable to get ID of cover")?;
+                .context("Unable to find the copied cover")?;
+            let id = self.insert_cover(cover, width, height)?;
+            diesel::sql_query(
+                "INSERT INTO CoverThumbnail (ID, Format, Width, Height, Data) \
+                 SELECT ?, Format, Width, Height, Data FROM CoverThumbnail WHERE ID = ?",
+            )
+            .bind::<diesel::sql_types::Integer, _>(id)
+            .bind::<diesel::sql_types::Integer, _>(of)
+            .execute(&self.conn)
+            .with_context(|| format!("Unable to add cover to database '{}'", cover.display()))?;
+            Ok(id)
+        })
+    }
+
+    fn insert_image(&self, cover: &Path, image: &CoverImage) -> Result<i32> {
+        self.conn.transaction(|| {
+            let id = self.insert_cover(cover, image.width as i32, image.height as i32)?;
             // the database fields needs to be uncompressed/non-overlapping
             diesel::insert_into(CoverThumbnail::table)
                 .values((
                     CoverThumbnail::ID.eq(id),
-                    CoverThumbnail::Format.eq(TEXTURE_FORMAT),
-                    CoverThumbnail::Width.eq(image.width() as i32),
-                    CoverThumbnail::Height.eq(image.height() as i32),
-                    CoverThumbnail::Data.eq(0),
+                    CoverThumbnail::Format.eq(image.format.id()),
+                    CoverThumbnail::Width.eq(image.thumbnail_width as i32),
+                    CoverThumbnail::Height.eq(image.thumbnail_height as i32),
+                    CoverThumbnail::Data.eq(Some(image.data.as_slice())),
                 ))
                 .execute(&self.conn)
                 .with_context(|| {
                     format!("Unable to add cover to database '{}'", cover.display())
                 })?;
 
-            Ok(())
+            Ok(id)
         })
     }
 
+    /// Adds the covers of the cover.db `other` that this one doesn't have yet, e.g. of another
+    /// cache merged into this one.
+    pub fn merge<R: Read>(&mut self, mut other: R) -> Result<()> {
+        let mut temp =
+            tempfile::NamedTempFile::new().context("Unable to open temporary cover.db file")?;
+        io::copy(&mut other, &mut temp).context("Unable to copy the cover.db to merge")?;
+        temp.flush()?;
+        // both are relative to the song directory
+        import(temp.path(), self.dbfile.path(), "")
+    }
+
     pub fn write<W: Write>(mut self, mut target: W) -> Result<()> {
+        // covers no song references anymore
+        for (_, (id, _)) in self.previous.drain() {
+            delete_cover(&self.conn, id).context("Unable to drop stale cover")?;
+        }
+        let skipped: usize = self.pending.values().map(Vec::len).sum();
+        if skipped > 0 {
+            // the cover they copy failed to load, which was reported for its song
+            warn!(
+                "Left out {} copies of covers that couldn't be loaded",
+                skipped
+            );
+        }
         std::mem::drop(self.conn);
         self.dbfile.flush()?;
         self.dbfile.seek(std::io::SeekFrom::Start(0))?;
@@ -131,7 +489,204 @@ impl CoverDB {
     }
 }
 
-#[cfg(feature = "mount")]
+/// How `cover` is stored in `Cover.Filename`.
+fn db_name(cover: &Path) -> Result<String> {
+    let mut file_name = cover
+        .to_str()
+        .with_context(|| format!("Unable to store filename '{}' in database", cover.display()))?
+        .to_string();
+    // Add null byte at the end since usdx is weird.
+    file_name.push(char::from(0));
+    Ok(file_name)
+}
+
+/// Removes the row `id` of `Cover` along with its thumbnail.
+fn delete_cover(conn: &diesel::sqlite::SqliteConnection, id: i32) -> QueryResult<()> {
+    conn.transaction(|| {
+        diesel::delete(CoverThumbnail::table.find(id)).execute(conn)?;
+        diesel::delete(Cover::table.find(id)).execute(conn)?;
+        Ok(())
+    })
+}
+
+/// The time to store in `Cover.Date`.
+fn now() -> i32 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .expect("SystemTime before unix epoch")
+        .as_secs() as i32
+}
+
+/// Why a row of `Cover` doesn't match the collection.
+enum Problem {
+    /// The cover is gone or can't be decoded anymore, so the row can only be removed.
+    Missing(String),
+    /// The row or its thumbnail is out of date, so it can be generated again.
+    Stale(String),
+}
+
+/// Checks each cover in the cover.db at `db`, or in the cover.db of the cache at `db`, against
+/// the files it was generated from, printing every inconsistency. Relative names are looked up
+/// below `root`.
+///
+/// With `fix`, rows of covers that are gone are removed and stale ones generated again with these
+/// options. Returns how many inconsistencies were found.
+pub fn verify(db: &Path, root: Option<&Path>, fix: Option<&ThumbnailOptions>) -> Result<usize> {
+    let extracted;
+    let (db, in_cache) = if is_sqlite(db)? {
+        (db, false)
+    } else {
+        if fix.is_some() {
+            return Err(anyhow!(
+                "Unable to fix the cover.db of a cache, update the cache instead"
+            ));
+        }
+        extracted = extract(db)?;
+        (extracted.path(), true)
+    };
+    let conn = diesel::sqlite::SqliteConnection::establish(
+        db.to_str().context("cover.db path is no valid UTF-8")?,
+    )?;
+    let covers = Cover::table
+        .load::<(i32, String, i32, i32, i32)>(&conn)
+        .context("Failed to load table Cover")?;
+
+    let mut problems = 0;
+    for (id, file_name, date, width, height) in covers {
+        // USDX terminates the names with a null byte
+        let name = file_name.trim_end_matches(char::from(0));
+        let cover = Path::new(name);
+        // Placeholder covers only exist in the cache.
+        if in_cache && crate::cache::is_generated(cover) {
+            continue;
+        }
+        let cover = match root {
+            _ if cover.is_absolute() => cover.to_path_buf(),
+            Some(root) => root.join(cover),
+            None => {
+                return Err(anyhow!(
+                    "Cover '{}' is relative, the song directory is needed to check it",
+                    name
+                ))
+            }
+        };
+        let problem = match check(&conn, id, &cover, date, (width, height))? {
+            Some(problem) => problem,
+            None => continue,
+        };
+        problems += 1;
+        match (&problem, fix) {
+            (Problem::Missing(reason), None) => println!("missing: {} ({})", name, reason),
+            (Problem::Stale(reason), None) => println!("stale: {} ({})", name, reason),
+            (Problem::Missing(reason), Some(_)) => {
+                println!("removed: {} ({})", name, reason);
+                delete_cover(&conn, id)
+                    .with_context(|| format!("Unable to remove cover '{}'", name))?;
+            }
+            (Problem::Stale(reason), Some(options)) => {
+                println!("regenerated: {} ({})", name, reason);
+                let image = CoverDB::load(&cover, options)?;
+                regenerate(&conn, id, &image)
+                    .with_context(|| format!("Unable to update cover '{}'", name))?;
+            }
+        }
+    }
+    Ok(problems)
+}
+
+/// Whether the file at `path` is an SQLite database rather than a cache.
+fn is_sqlite(path: &Path) -> Result<bool> {
+    let mut header = Vec::new();
+    File::open(path)
+        .and_then(|file| file.take(16).read_to_end(&mut header))
+        .with_context(|| format!("Unable to read '{}'", path.display()))?;
+    Ok(header == b"SQLite format 3\0")
+}
+
+/// Copies the cover.db out of the cache at `cache`.
+fn extract(cache: &Path) -> Result<tempfile::NamedTempFile> {
+    let mut archive = crate::cache::CacheArchive::open(cache)?;
+    let mut coverdb = archive
+        .by_name("cover.db")
+        .with_context(|| format!("The cache '{}' has no cover.db", cache.display()))?;
+    let mut file =
+        tempfile::NamedTempFile::new().context("Unable to open temporary cover.db file")?;
+    io::copy(&mut coverdb, &mut file).context("Failed to extract cache coverdb")?;
+    file.flush()?;
+    Ok(file)
+}
+
+/// Finds what is wrong with the row `id` of `Cover` for the file at `cover`, if anything.
+fn check(
+    conn: &diesel::sqlite::SqliteConnection,
+    id: i32,
+    cover: &Path,
+    date: i32,
+    dimensions: (i32, i32),
+) -> Result<Option<Problem>> {
+    let modified = match fs::metadata(cover).and_then(|m| m.modified()) {
+        Ok(modified) => modified,
+        Err(e) => return Ok(Some(Problem::Missing(e.to_string()))),
+    };
+    let (width, height) = match image::image_dimensions(cover) {
+        Ok(found) => found,
+        Err(e) => return Ok(Some(Problem::Missing(e.to_string()))),
+    };
+    if (width as i32, height as i32) != dimensions {
+        return Ok(Some(Problem::Stale(format!(
+            "is {}x{}, the cover.db has {}x{}",
+            width, height, dimensions.0, dimensions.1
+        ))));
+    }
+    let modified = modified
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs());
+    if modified > date as u64 {
+        return Ok(Some(Problem::Stale(String::from(
+            "modified after its thumbnail was generated",
+        ))));
+    }
+
+    let thumbnail = CoverThumbnail::table
+        .find(id)
+        .first::<(i32, i32, i32, i32, Option<Vec<u8>>)>(conn)
+        .optional()
+        .with_context(|| format!("Unable to load the thumbnail of '{}'", cover.display()))?;
+    let (_, format, width, height, data) = match thumbnail {
+        Some(thumbnail) => thumbnail,
+        None => return Ok(Some(Problem::Stale(String::from("no thumbnail")))),
+    };
+    let expected = ThumbnailFormat::from_id(format)
+        .map(|format| width as u64 * height as u64 * format.bytes_per_pixel());
+    match (expected, data) {
+        (Some(expected), Some(data)) if data.len() as u64 == expected => Ok(None),
+        _ => Ok(Some(Problem::Stale(String::from("broken thumbnail")))),
+    }
+}
+
+/// Replaces the dimensions and the thumbnail of the row `id` with those of `image`.
+fn regenerate(conn: &diesel::sqlite::SqliteConnection, id: i32, image: &CoverImage) -> Result<()> {
+    conn.transaction(|| {
+        diesel::update(Cover::table.find(id))
+            .set((
+                Cover::CreationDate.eq(now()),
+                Cover::Width.eq(image.width as i32),
+                Cover::Height.eq(image.height as i32),
+            ))
+            .execute(conn)?;
+        diesel::replace_into(CoverThumbnail::table)
+            .values((
+                CoverThumbnail::ID.eq(id),
+                CoverThumbnail::Format.eq(image.format.id()),
+                CoverThumbnail::Width.eq(image.thumbnail_width as i32),
+                CoverThumbnail::Height.eq(image.thumbnail_height as i32),
+                CoverThumbnail::Data.eq(Some(image.data.as_slice())),
+            ))
+            .execute(conn)?;
+        Ok(())
+    })
+}
+
 pub fn import<P1: AsRef<Path>, P2: AsRef<Path>, P3: AsRef<Path>>(
     cache: P1,
     dest: P2,
@@ -195,7 +750,7 @@ pub fn import<P1: AsRef<Path>, P2: AsRef<Path>, P3: AsRef<Path>>(
                     .with_context(|| format!("Unable to get new ID of cover {}", old_id))?;
                 let cover_thumbnail = CoverThumbnail::table
                     .find(old_id)
-                    .first::<(i32, i32, i32, i32, i32)>(&src)
+                    .first::<(i32, i32, i32, i32, Option<Vec<u8>>)>(&src)
                     .with_context(|| format!("Unable to find CoverThumbnail for {}", old_id))?;
 
                 diesel::insert_into(CoverThumbnail::table)
@@ -204,7 +759,7 @@ pub fn import<P1: AsRef<Path>, P2: AsRef<Path>, P3: AsRef<Path>>(
                         CoverThumbnail::Format.eq(cover_thumbnail.1),
                         CoverThumbnail::Width.eq(cover_thumbnail.2),
                         CoverThumbnail::Height.eq(cover_thumbnail.3),
-                        CoverThumbnail::Data.eq(cover_thumbnail.4),
+                        CoverThumbnail::Data.eq(&cover_thumbnail.4),
                     ))
                     .execute(&dest)
                     .with_context(|| format!("Unable to add thumbnail to database '{}'", old_id))?;
@@ -0,0 +1,205 @@
+// Daemon :: Detaches a mount from the terminal it was started from.
+//
+// The process forks before any threads are started. The parent only returns once the filesystem
+// shows up at the mount point, or fails if the child exits before that, so scripts can rely on
+// the mount being usable as soon as ultrastar-fs returns.
+//
+// Whether in the background or not, SIGTERM and SIGINT unmount the filesystem, so the mount
+// returns and cleans up after itself.
+//
+// A log file is rotated by moving it aside and pointing the output at a new file in its place.
+//
+
+use crate::libc_extras::libc;
+use anyhow::{anyhow, Context, Result};
+use std::fs::{self, File, OpenOptions};
+use std::io;
+use std::mem;
+use std::os::unix::fs::MetadataExt;
+use std::os::unix::io::AsRawFd;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long the parent waits for the mount to show up.
+const MOUNT_TIMEOUT: Duration = Duration::from_secs(30);
+pub const POLL_INTERVAL: Duration = Duration::from_millis(50);
+/// Rotated logs are kept as FILE.1 (the latest) up to FILE.LOG_KEEP.
+const LOG_KEEP: u32 = 3;
+/// How often the size of the log file is checked.
+const ROTATE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// A file holding the pid of the mount, which is removed again once it is dropped.
+pub struct PidFile {
+    path: PathBuf,
+}
+
+impl PidFile {
+    pub fn create<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+        fs::write(&path, format!("{}\n", std::process::id()))
+            .with_context(|| format!("Unable to write pidfile '{}'", path.display()))?;
+        Ok(Self { path })
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        // ignore failure
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Continues in a background process once `target` is mounted there, exiting in the foreground.
+///
+/// Output of the background process goes to `log`, or nowhere.
+pub fn daemonize(target: &Path, log: Option<&Path>) -> Result<()> {
+    let log = match log {
+        Some(log) => open_log(log)?,
+        None => OpenOptions::new()
+            .write(true)
+            .open("/dev/null")
+            .context("Unable to open /dev/null")?,
+    };
+    let null = File::open("/dev/null").context("Unable to open /dev/null")?;
+    let unmounted = device(target)?;
+
+    match unsafe { libc::fork() } {
+        -1 => Err(io::Error::last_os_error()).context("Unable to fork"),
+        0 => {
+            if -1 == unsafe { libc::setsid() } {
+                return Err(io::Error::last_os_error()).context("Unable to start a new session");
+            }
+            if -1 == unsafe { libc::dup2(null.as_raw_fd(), libc::STDIN_FILENO) } {
+                return Err(io::Error::last_os_error()).context("Unable to redirect input");
+            }
+            redirect_output(&log)
+        }
+        child => {
+            wait_for_mount(child, target, unmounted)?;
+            println!("Mounted in the background (pid {})", child);
+            std::process::exit(0);
+        }
+    }
+}
+
+fn open_log(path: &Path) -> Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Unable to open log file '{}'", path.display()))
+}
+
+/// Points stdout and stderr at `log`.
+fn redirect_output(log: &File) -> Result<()> {
+    for fd in &[libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        if -1 == unsafe { libc::dup2(log.as_raw_fd(), *fd) } {
+            return Err(io::Error::last_os_error()).context("Unable to redirect output");
+        }
+    }
+    Ok(())
+}
+
+/// Rotates the log file at `path`, which the output has been redirected to, whenever it grows
+/// beyond `max_size` bytes.
+pub fn rotate_log(path: &Path, max_size: u64) {
+    let path = path.to_path_buf();
+    thread::spawn(move || loop {
+        thread::sleep(ROTATE_INTERVAL);
+        match fs::metadata(&path) {
+            Ok(metadata) if metadata.len() > max_size => {
+                if let Err(e) = rotate(&path) {
+                    error!("Unable to rotate log: {:#}", e);
+                }
+            }
+            _ => {}
+        }
+    });
+}
+
+fn rotate(path: &Path) -> Result<()> {
+    let numbered = |n: u32| {
+        let mut name = path.as_os_str().to_os_string();
+        name.push(format!(".{}", n));
+        PathBuf::from(name)
+    };
+    for n in (1..LOG_KEEP).rev() {
+        // some may not exist yet
+        let _ = fs::rename(numbered(n), numbered(n + 1));
+    }
+    fs::rename(path, numbered(1))
+        .with_context(|| format!("Unable to move '{}' aside", path.display()))?;
+    redirect_output(&open_log(path)?)
+}
+
+/// Waits until a filesystem other than the one on device `unmounted` shows up at `target`.
+fn wait_for_mount(child: libc::pid_t, target: &Path, unmounted: u64) -> Result<()> {
+    let start = Instant::now();
+    loop {
+        let mut status = 0;
+        if unsafe { libc::waitpid(child, &mut status, libc::WNOHANG) } == child {
+            let reason = if libc::WIFEXITED(status) {
+                format!("exit code {}", libc::WEXITSTATUS(status))
+            } else {
+                format!("signal {}", libc::WTERMSIG(status))
+            };
+            return Err(anyhow!(
+                "Mount exited before it was ready ({}), see its log for details",
+                reason
+            ));
+        }
+        if is_mounted(target, unmounted) {
+            return Ok(());
+        }
+        if start.elapsed() > MOUNT_TIMEOUT {
+            unsafe { libc::kill(child, libc::SIGTERM) };
+            return Err(anyhow!("Timed out waiting for the mount"));
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// The device of the filesystem `target` is on.
+pub fn device(target: &Path) -> Result<u64> {
+    Ok(fs::metadata(target)
+        .with_context(|| format!("Unable to read '{}'", target.display()))?
+        .dev())
+}
+
+/// Whether a filesystem other than the one on device `unmounted` shows up at `target`.
+pub fn is_mounted(target: &Path, unmounted: u64) -> bool {
+    fs::metadata(target).is_ok_and(|metadata| metadata.dev() != unmounted)
+}
+
+/// Unmounts `target` on SIGTERM or SIGINT.
+///
+/// Has to be called before any other threads are started, so they block the signals as well.
+pub fn unmount_on_signal(target: &Path) -> Result<()> {
+    let mut signals: libc::sigset_t = unsafe { mem::zeroed() };
+    unsafe {
+        libc::sigemptyset(&mut signals);
+        libc::sigaddset(&mut signals, libc::SIGTERM);
+        libc::sigaddset(&mut signals, libc::SIGINT);
+    }
+    let result = unsafe { libc::pthread_sigmask(libc::SIG_BLOCK, &signals, ptr::null_mut()) };
+    if result != 0 {
+        return Err(io::Error::from_raw_os_error(result)).context("Unable to block signals");
+    }
+
+    let target = target.to_path_buf();
+    thread::spawn(move || loop {
+        let mut signal = 0;
+        if unsafe { libc::sigwait(&signals, &mut signal) } != 0 {
+            continue;
+        }
+        info!("Received signal {}, unmounting", signal);
+        #[cfg(target_os = "linux")]
+        crate::systemd::notify("STOPPING=1");
+        if let Err(e) = crate::umount::umount(&target) {
+            error!("Unable to unmount: {:#}", e);
+        }
+    });
+    Ok(())
+}
@@ -0,0 +1,61 @@
+// Deadline :: Gives up on calls of the source that hang, instead of blocking the mount with them.
+//
+// A hung NFS server blocks every call on it until it answers again, which may take forever, and
+// the FUSE thread making the call with it. Calls with a deadline run on a thread of their own
+// instead; if the deadline passes first they fail with EIO, and the thread is left to finish
+// whenever the server answers. Only so many of those threads run at once, beyond that calls fail
+// right away until some of them returned.
+//
+
+use crate::libc_extras::libc;
+use std::fmt;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::channel;
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+/// Calls running at once, most of which hang if there are that many.
+const MAX_RUNNING: usize = 32;
+
+pub struct Deadline {
+    timeout: Duration,
+    running: Arc<AtomicUsize>,
+}
+
+impl Deadline {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            running: Arc::default(),
+        }
+    }
+
+    /// Runs `call` on another thread, failing with EIO if it doesn't return within the
+    /// deadline. `what` names the call in the log.
+    pub fn run<T: Send + 'static>(
+        &self,
+        what: impl fmt::Display,
+        call: impl FnOnce() -> Result<T, libc::c_int> + Send + 'static,
+    ) -> Result<T, libc::c_int> {
+        if self.running.fetch_add(1, Ordering::AcqRel) >= MAX_RUNNING {
+            self.running.fetch_sub(1, Ordering::AcqRel);
+            warn!("{}: too many calls of the source hang", what);
+            return Err(libc::EIO);
+        }
+        let (tx, rx) = channel();
+        let running = self.running.clone();
+        thread::spawn(move || {
+            // nobody waits for the result anymore if the deadline passed
+            let _ = tx.send(call());
+            running.fetch_sub(1, Ordering::AcqRel);
+        });
+        match rx.recv_timeout(self.timeout) {
+            Ok(result) => result,
+            Err(_) => {
+                warn!("{}: no answer within {:?}", what, self.timeout);
+                Err(libc::EIO)
+            }
+        }
+    }
+}
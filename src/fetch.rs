@@ -0,0 +1,114 @@
+// Fetch :: Downloads a cache served over HTTP(S) before it is mounted.
+//
+// The download is kept in a local directory along with its ETag and Last-Modified date, so later
+// mounts only transfer it again once it changed on the server. If the server can't be reached,
+// the copy of the last successful download is used.
+//
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::env;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// What the server told about the downloaded cache, to ask whether it changed since.
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct Validators {
+    etag: Option<String>,
+    last_modified: Option<String>,
+}
+
+/// Whether `cache` is to be fetched rather than a local path.
+pub fn is_url(cache: &str) -> bool {
+    cache.starts_with("http://") || cache.starts_with("https://")
+}
+
+/// Where downloads are kept unless another directory is given.
+fn default_dir() -> Result<PathBuf> {
+    let base = match env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => env::var_os("HOME")
+            .map(|home| PathBuf::from(home).join(".cache"))
+            .context("Neither XDG_CACHE_HOME nor HOME is set, use --cache-dir")?,
+    };
+    Ok(base.join("ultrastar-fs"))
+}
+
+/// Downloads the cache at `url` into `dir`, unless the copy there is up to date, and returns
+/// the path of the local copy.
+pub fn fetch(url: &str, dir: Option<&Path>) -> Result<PathBuf> {
+    let dir = match dir {
+        Some(dir) => dir.to_path_buf(),
+        None => default_dir()?,
+    };
+    fs::create_dir_all(&dir).with_context(|| format!("Unable to create '{}'", dir.display()))?;
+
+    let mut hasher = DefaultHasher::new();
+    url.hash(&mut hasher);
+    let stem = format!("{:016x}", hasher.finish());
+    let path = dir.join(format!("{}.zip", stem));
+    let validators_path = dir.join(format!("{}.json", stem));
+
+    let validators: Validators = if path.exists() {
+        fs::read(&validators_path)
+            .ok()
+            .and_then(|data| serde_json::from_slice(&data).ok())
+            .unwrap_or_default()
+    } else {
+        Validators::default()
+    };
+    let mut request = ureq::get(url);
+    if let Some(etag) = &validators.etag {
+        request = request.set("If-None-Match", etag);
+    }
+    if let Some(date) = &validators.last_modified {
+        request = request.set("If-Modified-Since", date);
+    }
+
+    let response = match request.call() {
+        Ok(response) => response,
+        Err(e) if path.exists() => {
+            warn!(
+                "Unable to fetch '{}', using the previous download: {}",
+                url, e
+            );
+            return Ok(path);
+        }
+        Err(e) => return Err(anyhow!("Unable to fetch '{}': {}", url, e)),
+    };
+    if response.status() == 304 {
+        info!("Cache at '{}' is unchanged", url);
+        return Ok(path);
+    }
+
+    let validators = Validators {
+        etag: response.header("ETag").map(String::from),
+        last_modified: response.header("Last-Modified").map(String::from),
+    };
+    let tmp_path = dir.join(format!("{}.zip.tmp", stem));
+    let result = File::create(&tmp_path)
+        .and_then(|mut file| io::copy(&mut response.into_reader(), &mut file))
+        .with_context(|| format!("Unable to download '{}'", url))
+        .and_then(|_| {
+            fs::rename(&tmp_path, &path)
+                .with_context(|| format!("Unable to replace '{}'", path.display()))
+        });
+    if let Err(e) = result {
+        // ignore failure
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+    let validators = serde_json::to_vec(&validators).expect("validators serialize");
+    if let Err(e) = fs::write(&validators_path, validators) {
+        warn!(
+            "Unable to write '{}', the cache will be downloaded again: {}",
+            validators_path.display(),
+            e
+        );
+    }
+    println!("Downloaded cache from '{}'", url);
+    Ok(path)
+}
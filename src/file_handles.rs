@@ -1,55 +1,109 @@
+use crate::cache::Content;
+use crate::metrics::METRICS;
+use crate::retry::Retry;
 use anyhow::{anyhow, Context, Result};
-use std::collections::HashMap;
-use std::ffi::OsString;
-use std::io::{Cursor, Error as IoError};
+use parking_lot::{ArcMutexGuard, Mutex, RawMutex, RwLock};
+use std::io::Error as IoError;
 use std::path::PathBuf;
 use std::thread::spawn;
+use std::time::Instant;
 use std::sync::{
-    atomic::{AtomicU64, Ordering},
+    atomic::{AtomicUsize, Ordering},
     mpsc::{channel, Receiver},
+    Arc,
 };
 
-static FH_COUNTER: AtomicU64 = AtomicU64::new(0);
+/// The shard the next handle is registered in.
+static NEXT_SHARD: AtomicUsize = AtomicUsize::new(0);
 
+/// Number of slabs the handles are spread over, so registering and releasing them rarely
+/// contends either.
+const SHARDS: usize = 16;
+
+/// A descriptor, locked for as long as it is used.
+pub type DescriptorGuard = ArcMutexGuard<RawMutex, Descriptor>;
+
+/// Handles of one shard, by their index: the handle divided by [`SHARDS`].
+#[derive(Default)]
+struct Slab {
+    slots: Vec<Option<Arc<Mutex<Descriptor>>>>,
+    /// Indices of released slots, reused before the slab grows.
+    free: Vec<usize>,
+}
+
+/// The open handles, each behind a lock of its own so operations on different handles don't
+/// wait for each other.
+///
+/// Handles are slots of a slab, the shard being the remainder by [`SHARDS`], so released ones
+/// are reused and registering never has to search for a free number.
 pub struct FileHandles {
-    open: HashMap<u64, Descriptor>,
+    shards: Vec<RwLock<Slab>>,
 }
 
 impl FileHandles {
     pub fn new() -> Self {
         Self {
-            open: HashMap::new(),
+            shards: (0..SHARDS).map(|_| RwLock::default()).collect(),
         }
     }
 
-    fn find_first_available(&self) -> u64 {
-        let mut key: u64 = FH_COUNTER.fetch_add(1, Ordering::SeqCst);
-
-        while self.open.contains_key(&key) {
-            key = FH_COUNTER.fetch_add(1, Ordering::SeqCst);
-        }
-        key
+    /// The shard of `handle` and its index there.
+    fn locate(&self, handle: u64) -> (&RwLock<Slab>, usize) {
+        let shard = &self.shards[(handle % SHARDS as u64) as usize];
+        (shard, (handle / SHARDS as u64) as usize)
     }
 
-    pub fn register_handle(&mut self, descriptor: Descriptor) -> u64 {
-        let key = self.find_first_available();
-        self.open.insert(key, descriptor);
-        key
+    pub fn register_handle(&self, descriptor: Descriptor) -> u64 {
+        let shard = NEXT_SHARD.fetch_add(1, Ordering::Relaxed) % SHARDS;
+        let mut slab = self.shards[shard].write();
+        let descriptor = Some(Arc::new(Mutex::new(descriptor)));
+        let index = match slab.free.pop() {
+            Some(index) => {
+                slab.slots[index] = descriptor;
+                index
+            }
+            None => {
+                slab.slots.push(descriptor);
+                slab.slots.len() - 1
+            }
+        };
+        METRICS.open_handles.fetch_add(1, Ordering::Relaxed);
+        (index * SHARDS + shard) as u64
     }
 
-    pub fn free_handle(&mut self, handle: u64) -> Result<Descriptor> {
-        match self.open.remove(&handle) {
+    pub fn free_handle(&self, handle: u64) -> Result<Descriptor> {
+        let (shard, index) = self.locate(handle);
+        let descriptor = {
+            let mut slab = shard.write();
+            let descriptor = slab.slots.get_mut(index).and_then(Option::take);
+            if descriptor.is_some() {
+                slab.free.push(index);
+            }
+            descriptor
+        };
+        match descriptor {
             None => Err(anyhow!("Handle not found")),
-            Some(d) => Ok(d),
+            Some(d) => {
+                METRICS.open_handles.fetch_sub(1, Ordering::Relaxed);
+                // Still in use if the kernel releases it while an operation on it is running.
+                Ok(Arc::try_unwrap(d).map(Mutex::into_inner).unwrap_or_else(|d| {
+                    std::mem::replace(&mut *d.lock(), Descriptor::Error(libc::EBADF))
+                }))
+            }
         }
     }
 
-    pub fn find(&mut self, handle: u64) -> Result<&mut Descriptor> {
-        match self.open.get_mut(&handle) {
+    pub fn find(&self, handle: u64) -> Result<DescriptorGuard> {
+        let (shard, index) = self.locate(handle);
+        let descriptor = shard.read().slots.get(index).cloned().flatten();
+        match descriptor {
             None => Err(anyhow!("Handle not found")),
-            Some(d) => match d.resolve() {
-                Ok(d) => Ok(d),
-                Err(err) => Err(err).context("Handle failed to open"),
+            Some(d) => {
+                let mut d = d.lock_arc();
+                match d.resolve() {
+                    Ok(_) => Ok(d),
+                    Err(err) => Err(err).context("Handle failed to open"),
+                }
             }
         }
     }
@@ -62,10 +116,8 @@ pub enum Descriptor {
     Lazy(Receiver<Result<u64, i32>>),
     // Placeholder, so we can still release them properly later
     Error(i32),
-    File {
-        path: OsString,
-        cursor: Cursor<Vec<u8>>,
-    },
+    /// The content of a file served from the cache.
+    File { content: Content },
 }
 
 impl Descriptor {
@@ -73,14 +125,19 @@ impl Descriptor {
         Self::Path(path.into())
     }
 
-    pub fn lazy<I: Into<PathBuf>>(path: I, flags: u32) -> Self {
+    pub fn lazy<I: Into<PathBuf>>(path: I, flags: u32, retry: Retry) -> Self {
         let (tx, rx) = channel();
         let owned = path.into();
         spawn(move || {
             use crate::libc_wrappers;
 
             let path = owned.clone();
-            tx.send(match libc_wrappers::open(owned.into_os_string(), flags as libc::c_int) {
+            let start = Instant::now();
+            let result = retry.run(format_args!("open({:?})", path.display()), || {
+                libc_wrappers::open(owned.clone().into_os_string(), flags as libc::c_int)
+            });
+            METRICS.lazy_open.observe(start.elapsed());
+            tx.send(match result {
                 Ok(fh) => Ok(
                     fh,
                 ),
@@ -0,0 +1,88 @@
+// Id map :: Translates user and group ids between the source and the mount.
+//
+// Entries owned by a mapped id in the source or the cache are reported with the id it maps to,
+// and ownership changes are translated back before they are applied. Mappings are written as
+// `uid:uid` and `@gid:@gid`, separated by commas or, in a map file, by lines.
+//
+
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+#[derive(Debug, Default)]
+pub struct IdMap {
+    /// From the source to the mount.
+    uids: HashMap<u32, u32>,
+    gids: HashMap<u32, u32>,
+    /// From the mount to the source.
+    uids_back: HashMap<u32, u32>,
+    gids_back: HashMap<u32, u32>,
+}
+
+impl IdMap {
+    /// Adds the comma separated mappings of `spec`.
+    pub fn parse(&mut self, spec: &str) -> Result<()> {
+        for mapping in spec.split(',').map(str::trim).filter(|m| !m.is_empty()) {
+            self.add(mapping)
+                .with_context(|| format!("Invalid id mapping '{}'", mapping))?;
+        }
+        Ok(())
+    }
+
+    /// Adds the mappings of the file at `path`, one per line. Lines starting with `#` are
+    /// ignored.
+    pub fn read<P: AsRef<Path>>(&mut self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Unable to read id map '{}'", path.display()))?;
+        for (i, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            self.parse(line)
+                .with_context(|| format!("In line {} of '{}'", i + 1, path.display()))?;
+        }
+        Ok(())
+    }
+
+    fn add(&mut self, mapping: &str) -> Result<()> {
+        let (from, to) = match mapping.find(':') {
+            Some(i) => (&mapping[..i], &mapping[i + 1..]),
+            None => return Err(anyhow!("Expected FROM:TO")),
+        };
+        let (from, to, forward, back) = match (from.strip_prefix('@'), to.strip_prefix('@')) {
+            (Some(from), Some(to)) => (from, to, &mut self.gids, &mut self.gids_back),
+            (None, None) => (from, to, &mut self.uids, &mut self.uids_back),
+            _ => return Err(anyhow!("Can't map between a user and a group")),
+        };
+        let from: u32 = from.parse().context("Ids have to be numeric")?;
+        let to: u32 = to.parse().context("Ids have to be numeric")?;
+        if forward.insert(from, to).is_some() {
+            return Err(anyhow!("{} is mapped twice", from));
+        }
+        if back.insert(to, from).is_some() {
+            return Err(anyhow!("More than one id is mapped to {}", to));
+        }
+        Ok(())
+    }
+
+    /// The uid the mount reports for `uid` in the source.
+    pub fn mount_uid(&self, uid: u32) -> u32 {
+        *self.uids.get(&uid).unwrap_or(&uid)
+    }
+
+    pub fn mount_gid(&self, gid: u32) -> u32 {
+        *self.gids.get(&gid).unwrap_or(&gid)
+    }
+
+    /// The uid in the source that the mount reports as `uid`.
+    pub fn source_uid(&self, uid: u32) -> u32 {
+        *self.uids_back.get(&uid).unwrap_or(&uid)
+    }
+
+    pub fn source_gid(&self, gid: u32) -> u32 {
+        *self.gids_back.get(&gid).unwrap_or(&gid)
+    }
+}
@@ -0,0 +1,73 @@
+// Invalidate :: Tells the kernel to forget what it kept of entries that changed underneath it.
+//
+// The kernel holds on to entries and attributes for their TTL, and to the pages of files for as
+// long as they are open. Once the watch sees a change or the cache is swapped, those are
+// invalidated through the notifier of the session, so the next access asks the mount again.
+//
+// Notifications address inodes, whose numbers fuse_mt hands out without telling the filesystem.
+// They are looked up through the mount itself instead, which the kernel mostly answers from what
+// it cached. This must not happen on a thread serving requests, it would wait on itself.
+//
+
+use crate::utils::path_to_rel;
+use fuser::Notifier;
+use std::fs;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+#[derive(Default)]
+pub struct Invalidator {
+    /// The mount point and the notifier of its session, once mounted.
+    session: Mutex<Option<(PathBuf, Notifier)>>,
+}
+
+impl Invalidator {
+    /// Starts sending notifications through `notifier` of the session mounted at `mount_point`.
+    pub fn connect<P: Into<PathBuf>>(&self, mount_point: P, notifier: Notifier) {
+        *self.session.lock().unwrap() = Some((mount_point.into(), notifier));
+    }
+
+    /// Makes the kernel look up `path` again, dropping everything below it if it is a directory,
+    /// and forget the attributes and content it has of the entry.
+    pub fn entry(&self, path: &Path) {
+        let (mount_point, notifier) = match &*self.session.lock().unwrap() {
+            Some(session) => session.clone(),
+            None => return,
+        };
+        let path = path_to_rel(path);
+        let (parent, name) = match (path.parent(), path.file_name()) {
+            (Some(parent), Some(name)) => (parent, name),
+            // the root never goes away
+            _ => return self.inode(path),
+        };
+        if let Ok(metadata) = fs::symlink_metadata(mount_point.join(path)) {
+            if let Err(e) = notifier.inval_inode(metadata.ino(), 0, 0) {
+                debug!("invalidate: inode of {:?}: {}", path, e);
+            }
+        }
+        let parent = match fs::symlink_metadata(mount_point.join(parent)) {
+            Ok(metadata) => metadata.ino(),
+            // not looked up by the kernel either
+            Err(_) => return,
+        };
+        if let Err(e) = notifier.inval_entry(parent, name) {
+            debug!("invalidate: entry {:?}: {}", path, e);
+        }
+    }
+
+    /// Makes the kernel forget the attributes and content it has of `path`, e.g. a directory that
+    /// got new entries.
+    pub fn inode(&self, path: &Path) {
+        let (mount_point, notifier) = match &*self.session.lock().unwrap() {
+            Some(session) => session.clone(),
+            None => return,
+        };
+        let path = path_to_rel(path);
+        if let Ok(metadata) = fs::symlink_metadata(mount_point.join(path)) {
+            if let Err(e) = notifier.inval_inode(metadata.ino(), 0, 0) {
+                debug!("invalidate: inode of {:?}: {}", path, e);
+            }
+        }
+    }
+}
@@ -0,0 +1,164 @@
+// Lazy sync :: Coalesces fsync calls on passthrough handles and runs them in the background.
+//
+// Copying songs into a mount syncs each file as it is written, and on a network source every
+// sync waits for a round trip. Instead each sync is answered right away with a duplicate of the
+// descriptor queued, and a worker syncs it once `DELAY` passed since the first request for the
+// file, however many more came in meanwhile through any handle. Failures can only be logged by
+// then.
+//
+
+use crate::libc_extras::libc;
+use crate::libc_wrappers;
+use std::collections::HashMap;
+use std::io;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How long a sync may be put off.
+const DELAY: Duration = Duration::from_secs(1);
+
+enum Request {
+    Sync {
+        file: File,
+        fd: libc::c_int,
+        datasync: bool,
+    },
+    /// Runs all pending syncs and reports back once they are done.
+    SyncAll(Sender<()>),
+}
+
+/// The device and inode of a file.
+type File = (u64, u64);
+
+struct Pending {
+    fd: libc::c_int,
+    /// Only if all requests were for the data alone.
+    datasync: bool,
+    deadline: Instant,
+}
+
+#[derive(Default)]
+pub struct LazySync {
+    /// The worker is started on the first request, so it runs in the sandbox of the thread
+    /// handling it.
+    sender: Mutex<Option<Sender<Request>>>,
+}
+
+impl LazySync {
+    /// Queues a sync of the file open as `fd`.
+    pub fn sync(&self, fd: u64, datasync: bool) -> Result<(), libc::c_int> {
+        let stat = libc_wrappers::fstat(fd)?;
+        let file = (stat.st_dev, stat.st_ino);
+        // the handle may be released before the sync runs
+        let fd = unsafe { libc::dup(fd as libc::c_int) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error().raw_os_error().unwrap());
+        }
+        self.send(Request::Sync { file, fd, datasync });
+        Ok(())
+    }
+
+    /// Runs all pending syncs and waits for them to finish.
+    pub fn sync_all(&self) {
+        if self.sender.lock().unwrap().is_none() {
+            return;
+        }
+        let (sender, receiver) = mpsc::channel();
+        self.send(Request::SyncAll(sender));
+        let _ = receiver.recv();
+    }
+
+    fn send(&self, request: Request) {
+        let mut sender = self.sender.lock().unwrap();
+        let sender = sender.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel();
+            thread::spawn(move || run(receiver));
+            sender
+        });
+        // the worker only stops once the sender is gone
+        let _ = sender.send(request);
+    }
+}
+
+fn run(receiver: Receiver<Request>) {
+    let mut pending: HashMap<File, Pending> = HashMap::new();
+    loop {
+        let request = match pending.values().map(|p| p.deadline).min() {
+            Some(deadline) => {
+                receiver.recv_timeout(deadline.saturating_duration_since(Instant::now()))
+            }
+            None => receiver.recv().map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        match request {
+            Ok(Request::Sync { file, fd, datasync }) => match pending.get_mut(&file) {
+                Some(queued) => {
+                    queued.datasync &= datasync;
+                    close(fd);
+                }
+                None => {
+                    let deadline = Instant::now() + DELAY;
+                    pending.insert(
+                        file,
+                        Pending {
+                            fd,
+                            datasync,
+                            deadline,
+                        },
+                    );
+                }
+            },
+            Ok(Request::SyncAll(done)) => {
+                pending.drain().for_each(|(_, p)| sync(p));
+                let _ = done.send(());
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                let now = Instant::now();
+                let due: Vec<File> = pending
+                    .iter()
+                    .filter(|(_, p)| p.deadline <= now)
+                    .map(|(&file, _)| file)
+                    .collect();
+                for file in due {
+                    sync(pending.remove(&file).unwrap());
+                }
+            }
+            Err(RecvTimeoutError::Disconnected) => {
+                pending.drain().for_each(|(_, p)| sync(p));
+                return;
+            }
+        }
+    }
+}
+
+fn sync(pending: Pending) {
+    let result = unsafe {
+        if pending.datasync {
+            datasync(pending.fd)
+        } else {
+            libc::fsync(pending.fd)
+        }
+    };
+    if result == -1 {
+        error!("lazy fsync: {}", io::Error::last_os_error());
+    }
+    close(pending.fd);
+}
+
+#[cfg(not(target_os = "macos"))]
+unsafe fn datasync(fd: libc::c_int) -> libc::c_int {
+    libc::fdatasync(fd)
+}
+
+// not supported by macOS
+#[cfg(target_os = "macos")]
+unsafe fn datasync(fd: libc::c_int) -> libc::c_int {
+    libc::fsync(fd)
+}
+
+fn close(fd: libc::c_int) {
+    unsafe {
+        libc::close(fd);
+    }
+}
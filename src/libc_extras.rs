@@ -15,9 +15,9 @@ pub mod libc {
         // Specified by POSIX.1-2008; not sure why this is missing.
         pub fn fchown(fd: c_int, uid: uid_t, gid: gid_t) -> c_int;
 
-        // On Mac OS X, off_t is always 64 bits.
+        // On Mac OS X and FreeBSD, off_t is always 64 bits.
         // https://developer.apple.com/library/mac/documentation/Darwin/Conceptual/64bitPorting/transition/transition.html
-        #[cfg(target_os = "macos")]
+        #[cfg(any(target_os = "macos", target_os = "freebsd"))]
         pub fn truncate(path: *const c_char, size: off_t) -> c_int;
 
         // On Linux, off_t is architecture-dependent, and this is provided for 32-bit systems:
@@ -25,7 +25,8 @@ pub mod libc {
         pub fn truncate64(path: *const c_char, size: off64_t) -> c_int;
 
         // These XATTR functions are missing from the libc crate on Darwin for some reason.
-        #[cfg(any(target_os = "macos", target_os = "freebsd"))]
+        // FreeBSD has extattr_* instead, see below.
+        #[cfg(target_os = "macos")]
         pub fn listxattr(
             path: *const c_char,
             list: *mut c_char,
@@ -33,7 +34,7 @@ pub mod libc {
             options: c_int,
         ) -> ssize_t;
 
-        #[cfg(any(target_os = "macos", target_os = "freebsd"))]
+        #[cfg(target_os = "macos")]
         pub fn getxattr(
             path: *const c_char,
             name: *const c_char,
@@ -43,7 +44,7 @@ pub mod libc {
             options: c_int,
         ) -> ssize_t;
 
-        #[cfg(any(target_os = "macos", target_os = "freebsd"))]
+        #[cfg(target_os = "macos")]
         pub fn setxattr(
             path: *const c_char,
             name: *const c_char,
@@ -53,7 +54,7 @@ pub mod libc {
             position: u32,
         ) -> c_int;
 
-        #[cfg(any(target_os = "macos", target_os = "freebsd"))]
+        #[cfg(target_os = "macos")]
         pub fn removexattr(path: *const c_char, name: *const c_char, flags: c_int) -> c_int;
     }
 
@@ -74,6 +75,16 @@ pub mod libc {
         fstat(fd, stat)
     }
 
+    #[cfg(any(target_os = "macos", target_os = "freebsd"))]
+    pub unsafe fn fstatat64(
+        dirfd: c_int,
+        path: *const c_char,
+        stat: *mut stat64,
+        flags: c_int,
+    ) -> c_int {
+        fstatat(dirfd, path, stat, flags)
+    }
+
     #[cfg(any(target_os = "macos", target_os = "freebsd"))]
     pub unsafe fn ftruncate64(fd: c_int, length: i64) -> c_int {
         ftruncate(fd, length as off_t)
@@ -197,15 +208,15 @@ pub mod libc {
     #[cfg(any(target_os = "macos", target_os = "freebsd"))]
     pub const AT_SYMLINK_NOFOLLOW: c_int = 0x400;
 
-    #[cfg(any(target_os = "macos", target_os = "freebsd"))]
+    #[cfg(target_os = "macos")]
     pub const XATTR_NOFOLLOW: c_int = 1;
 
-    #[cfg(any(target_os = "macos", target_os = "freebsd"))]
+    #[cfg(target_os = "macos")]
     pub unsafe fn llistxattr(path: *const c_char, namebuf: *mut c_char, size: size_t) -> ssize_t {
         listxattr(path, namebuf, size, XATTR_NOFOLLOW)
     }
 
-    #[cfg(any(target_os = "macos", target_os = "freebsd"))]
+    #[cfg(target_os = "macos")]
     pub unsafe fn lgetxattr(
         path: *const c_char,
         name: *const c_char,
@@ -215,7 +226,7 @@ pub mod libc {
         getxattr(path, name, value, size, 0, XATTR_NOFOLLOW)
     }
 
-    #[cfg(any(target_os = "macos", target_os = "freebsd"))]
+    #[cfg(target_os = "macos")]
     pub unsafe fn lsetxattr(
         path: *const c_char,
         name: *const c_char,
@@ -227,8 +238,144 @@ pub mod libc {
         setxattr(path, name, value, size, flags | XATTR_NOFOLLOW, position)
     }
 
-    #[cfg(any(target_os = "macos", target_os = "freebsd"))]
+    #[cfg(target_os = "macos")]
     pub unsafe fn lremovexattr(path: *const c_char, name: *const c_char) -> c_int {
         removexattr(path, name, XATTR_NOFOLLOW)
     }
+
+    //
+    // Linux xattr functions on top of FreeBSD's extattr_*, for the "user." and "system."
+    // namespaces. FreeBSD lists and names attributes without their namespace.
+    //
+
+    #[cfg(target_os = "freebsd")]
+    pub const XATTR_CREATE: c_int = 1;
+
+    #[cfg(target_os = "freebsd")]
+    pub const XATTR_REPLACE: c_int = 2;
+
+    #[cfg(target_os = "freebsd")]
+    const EXTATTR_NAMESPACES: [(c_int, &[u8]); 2] = [
+        (EXTATTR_NAMESPACE_USER, b"user."),
+        (EXTATTR_NAMESPACE_SYSTEM, b"system."),
+    ];
+
+    /// Splits a Linux attribute name into its namespace and the name within it, or fails with
+    /// ENOTSUP for other namespaces.
+    #[cfg(target_os = "freebsd")]
+    unsafe fn extattr_name(name: *const c_char) -> Option<(c_int, *const c_char)> {
+        let bytes = std::ffi::CStr::from_ptr(name).to_bytes();
+        for &(namespace, prefix) in &EXTATTR_NAMESPACES {
+            if bytes.starts_with(prefix) {
+                return Some((namespace, name.add(prefix.len())));
+            }
+        }
+        *__error() = ENOTSUP;
+        None
+    }
+
+    #[cfg(target_os = "freebsd")]
+    pub unsafe fn llistxattr(path: *const c_char, namebuf: *mut c_char, size: size_t) -> ssize_t {
+        let mut names = Vec::new();
+        for &(namespace, prefix) in &EXTATTR_NAMESPACES {
+            let len = extattr_list_link(path, namespace, std::ptr::null_mut(), 0);
+            if len < 0 {
+                // system attributes are only listed for root
+                if namespace == EXTATTR_NAMESPACE_SYSTEM {
+                    continue;
+                }
+                return -1;
+            }
+            let mut list = vec![0u8; len as usize];
+            let len = extattr_list_link(
+                path,
+                namespace,
+                list.as_mut_ptr() as *mut c_void,
+                list.len(),
+            );
+            if len < 0 {
+                return -1;
+            }
+            // a length byte followed by that many bytes of name, each
+            let mut list = &list[..len as usize];
+            while let Some((&len, rest)) = list.split_first() {
+                let len = (len as usize).min(rest.len());
+                names.extend_from_slice(prefix);
+                names.extend_from_slice(&rest[..len]);
+                names.push(0);
+                list = &rest[len..];
+            }
+        }
+        if size == 0 {
+            return names.len() as ssize_t;
+        }
+        if names.len() > size {
+            *__error() = ERANGE;
+            return -1;
+        }
+        std::ptr::copy_nonoverlapping(names.as_ptr() as *const c_char, namebuf, names.len());
+        names.len() as ssize_t
+    }
+
+    #[cfg(target_os = "freebsd")]
+    pub unsafe fn lgetxattr(
+        path: *const c_char,
+        name: *const c_char,
+        value: *mut c_void,
+        size: size_t,
+    ) -> ssize_t {
+        let (namespace, name) = match extattr_name(name) {
+            Some(name) => name,
+            None => return -1,
+        };
+        let len = extattr_get_link(path, namespace, name, std::ptr::null_mut(), 0);
+        if len < 0 || size == 0 {
+            return len;
+        }
+        // FreeBSD truncates the value instead
+        if len as size_t > size {
+            *__error() = ERANGE;
+            return -1;
+        }
+        extattr_get_link(path, namespace, name, value, size)
+    }
+
+    #[cfg(target_os = "freebsd")]
+    pub unsafe fn lsetxattr(
+        path: *const c_char,
+        name: *const c_char,
+        value: *const c_void,
+        size: size_t,
+        flags: c_int,
+        _position: u32,
+    ) -> c_int {
+        let (namespace, name) = match extattr_name(name) {
+            Some(name) => name,
+            None => return -1,
+        };
+        if flags & (XATTR_CREATE | XATTR_REPLACE) != 0 {
+            let exists = extattr_get_link(path, namespace, name, std::ptr::null_mut(), 0) >= 0;
+            if exists && flags & XATTR_CREATE != 0 {
+                *__error() = EEXIST;
+                return -1;
+            }
+            if !exists && flags & XATTR_REPLACE != 0 {
+                *__error() = ENOATTR;
+                return -1;
+            }
+        }
+        if extattr_set_link(path, namespace, name, value, size) < 0 {
+            -1
+        } else {
+            0
+        }
+    }
+
+    #[cfg(target_os = "freebsd")]
+    pub unsafe fn lremovexattr(path: *const c_char, name: *const c_char) -> c_int {
+        match extattr_name(name) {
+            Some((namespace, name)) => extattr_delete_link(path, namespace, name),
+            None => -1,
+        }
+    }
 }
@@ -63,6 +63,105 @@ pub fn closedir(fh: u64) -> Result<(), libc::c_int> {
     }
 }
 
+/// An entry of a directory read by [`read_dir`].
+pub struct DirEntry {
+    pub name: OsString,
+    /// One of the `DT_*` constants, `DT_UNKNOWN` if the file system doesn't tell.
+    pub d_type: u8,
+}
+
+/// Size of the buffer [`read_dir`] reads entries into at once.
+#[cfg(target_os = "linux")]
+const DIRENT_BUFFER_SIZE: usize = 64 * 1024;
+
+/// Reads all entries of the directory open as `fd`, except for `.` and `..`.
+///
+/// They are read with getdents64 as many at once as fit into a buffer, instead of one call of
+/// readdir_r per entry.
+#[cfg(target_os = "linux")]
+pub fn read_dir(fd: u64) -> Result<Vec<DirEntry>, libc::c_int> {
+    // struct linux_dirent64, which libc doesn't bind
+    const RECLEN: usize = 16;
+    const TYPE: usize = 18;
+    const NAME: usize = 19;
+
+    let mut buf = vec![0u8; DIRENT_BUFFER_SIZE];
+    let mut entries = Vec::new();
+    loop {
+        let read = unsafe {
+            libc::syscall(
+                libc::SYS_getdents64,
+                fd as libc::c_int,
+                buf.as_mut_ptr(),
+                buf.len(),
+            )
+        };
+        if read == -1 {
+            return Err(io::Error::last_os_error().raw_os_error().unwrap());
+        }
+        if read == 0 {
+            return Ok(entries);
+        }
+
+        let mut offset = 0;
+        while offset < read as usize {
+            let record = &buf[offset..];
+            let reclen = u16::from_ne_bytes([record[RECLEN], record[RECLEN + 1]]) as usize;
+            let name = &record[NAME..reclen];
+            // padded with NUL bytes up to the next record
+            let len = name
+                .iter()
+                .position(|&byte| byte == 0)
+                .unwrap_or(name.len());
+            let name = &name[..len];
+            if name != b"." && name != b".." {
+                entries.push(DirEntry {
+                    name: OsString::from_vec(name.to_vec()),
+                    d_type: record[TYPE],
+                });
+            }
+            offset += reclen;
+        }
+    }
+}
+
+/// Reads all entries of the directory open as `fd`, except for `.` and `..`.
+#[cfg(not(target_os = "linux"))]
+pub fn read_dir(fd: u64) -> Result<Vec<DirEntry>, libc::c_int> {
+    // the handle takes over the descriptor it reads, which stays the caller's
+    let dup = unsafe { libc::dup(fd as libc::c_int) };
+    if dup == -1 {
+        return Err(io::Error::last_os_error().raw_os_error().unwrap());
+    }
+    let dir = match fdopendir(dup as u64) {
+        Ok(dir) => dir,
+        Err(e) => {
+            let _ = close(dup as u64);
+            return Err(e);
+        }
+    };
+
+    let mut entries = Vec::new();
+    let result = loop {
+        match readdir(dir) {
+            Ok(Some(entry)) => {
+                let name = unsafe { std::ffi::CStr::from_ptr(entry.d_name.as_ptr()) };
+                let name = name.to_bytes();
+                if name != b"." && name != b".." {
+                    entries.push(DirEntry {
+                        name: OsString::from_vec(name.to_vec()),
+                        d_type: entry.d_type,
+                    });
+                }
+            }
+            Ok(None) => break Ok(entries),
+            Err(e) => break Err(e),
+        }
+    };
+    let _ = closedir(dir);
+    result
+}
+
 pub fn open(path: OsString, flags: libc::c_int) -> Result<u64, libc::c_int> {
     let path_c = into_cstring!(path, "open");
 
@@ -74,6 +173,119 @@ pub fn open(path: OsString, flags: libc::c_int) -> Result<u64, libc::c_int> {
     Ok(fd as u64)
 }
 
+/// Opens `path` relative to the directory open as `dirfd`.
+pub fn openat(dirfd: u64, path: OsString, flags: libc::c_int) -> Result<u64, libc::c_int> {
+    let path_c = into_cstring!(path, "openat");
+
+    let fd: libc::c_int = unsafe { libc::openat(dirfd as libc::c_int, path_c.as_ptr(), flags) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error().raw_os_error().unwrap());
+    }
+
+    Ok(fd as u64)
+}
+
+/// Reads the directory open as `fd`, which belongs to the returned handle afterwards.
+pub fn fdopendir(fd: u64) -> Result<u64, libc::c_int> {
+    let dir: *mut libc::DIR = unsafe { libc::fdopendir(fd as libc::c_int) };
+    if dir.is_null() {
+        return Err(io::Error::last_os_error().raw_os_error().unwrap());
+    }
+
+    Ok(dir as u64)
+}
+
+pub fn create(path: OsString, flags: libc::c_int, mode: libc::mode_t) -> Result<u64, libc::c_int> {
+    let path_c = into_cstring!(path, "create");
+
+    let fd: libc::c_int =
+        unsafe { libc::open(path_c.as_ptr(), flags | libc::O_CREAT, mode as libc::c_uint) };
+    if fd == -1 {
+        return Err(io::Error::last_os_error().raw_os_error().unwrap());
+    }
+
+    Ok(fd as u64)
+}
+
+pub fn mknod(path: OsString, mode: libc::mode_t, rdev: libc::dev_t) -> Result<(), libc::c_int> {
+    let path_c = into_cstring!(path, "mknod");
+
+    if -1 == unsafe { libc::mknod(path_c.as_ptr(), mode, rdev) } {
+        Err(io::Error::last_os_error().raw_os_error().unwrap())
+    } else {
+        Ok(())
+    }
+}
+
+/// Sets the access and modification times of `path` without following symlinks.
+pub fn utimensat(path: OsString, times: &[libc::timespec; 2]) -> Result<(), libc::c_int> {
+    let path_c = into_cstring!(path, "utimensat");
+
+    if -1
+        == unsafe {
+            libc::utimensat(
+                libc::AT_FDCWD,
+                path_c.as_ptr(),
+                times.as_ptr(),
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        }
+    {
+        Err(io::Error::last_os_error().raw_os_error().unwrap())
+    } else {
+        Ok(())
+    }
+}
+
+pub fn futimens(fh: u64, times: &[libc::timespec; 2]) -> Result<(), libc::c_int> {
+    if -1 == unsafe { libc::futimens(fh as libc::c_int, times.as_ptr()) } {
+        Err(io::Error::last_os_error().raw_os_error().unwrap())
+    } else {
+        Ok(())
+    }
+}
+
+pub fn chmod(path: OsString, mode: libc::mode_t) -> Result<(), libc::c_int> {
+    let path_c = into_cstring!(path, "chmod");
+
+    if -1 == unsafe { libc::chmod(path_c.as_ptr(), mode) } {
+        Err(io::Error::last_os_error().raw_os_error().unwrap())
+    } else {
+        Ok(())
+    }
+}
+
+pub fn fchmod(fh: u64, mode: libc::mode_t) -> Result<(), libc::c_int> {
+    if -1 == unsafe { libc::fchmod(fh as libc::c_int, mode) } {
+        Err(io::Error::last_os_error().raw_os_error().unwrap())
+    } else {
+        Ok(())
+    }
+}
+
+/// Changes the owner of `path` without following symlinks. `None` leaves the id unchanged.
+pub fn lchown(path: OsString, uid: Option<u32>, gid: Option<u32>) -> Result<(), libc::c_int> {
+    let path_c = into_cstring!(path, "lchown");
+
+    let uid = uid.map_or(libc::uid_t::MAX, |uid| uid as libc::uid_t);
+    let gid = gid.map_or(libc::gid_t::MAX, |gid| gid as libc::gid_t);
+    if -1 == unsafe { libc::lchown(path_c.as_ptr(), uid, gid) } {
+        Err(io::Error::last_os_error().raw_os_error().unwrap())
+    } else {
+        Ok(())
+    }
+}
+
+pub fn fchown(fh: u64, uid: Option<u32>, gid: Option<u32>) -> Result<(), libc::c_int> {
+    let uid = uid.map_or(libc::uid_t::MAX, |uid| uid as libc::uid_t);
+    let gid = gid.map_or(libc::gid_t::MAX, |gid| gid as libc::gid_t);
+    if -1 == unsafe { libc::fchown(fh as libc::c_int, uid, gid) } {
+        Err(io::Error::last_os_error().raw_os_error().unwrap())
+    } else {
+        Ok(())
+    }
+}
+
 pub fn close(fh: u64) -> Result<(), libc::c_int> {
     let fd = fh as libc::c_int;
     if -1 == unsafe { libc::close(fd) } {
@@ -94,6 +306,51 @@ pub fn lstat(path: OsString) -> Result<libc::stat64, libc::c_int> {
     Ok(buf)
 }
 
+/// Reads the stats of `path` relative to the directory open as `dirfd`, without following
+/// symlinks.
+pub fn fstatat(dirfd: u64, path: OsString) -> Result<libc::stat64, libc::c_int> {
+    let path_c = into_cstring!(path, "fstatat");
+
+    let mut buf: libc::stat64 = unsafe { mem::zeroed() };
+    if -1
+        == unsafe {
+            libc::fstatat64(
+                dirfd as libc::c_int,
+                path_c.as_ptr(),
+                &mut buf,
+                libc::AT_SYMLINK_NOFOLLOW,
+            )
+        }
+    {
+        return Err(io::Error::last_os_error().raw_os_error().unwrap());
+    }
+
+    Ok(buf)
+}
+
+/// Reads the target of the symlink `path` relative to the directory open as `dirfd`.
+pub fn readlinkat(dirfd: u64, path: OsString) -> Result<OsString, libc::c_int> {
+    let path_c = into_cstring!(path, "readlinkat");
+
+    // targets can't be any longer
+    let mut buf = vec![0u8; libc::PATH_MAX as usize];
+    let result = unsafe {
+        libc::readlinkat(
+            dirfd as libc::c_int,
+            path_c.as_ptr(),
+            buf.as_mut_ptr() as *mut libc::c_char,
+            buf.len(),
+        )
+    };
+    match result {
+        -1 => Err(io::Error::last_os_error().raw_os_error().unwrap()),
+        nbytes => {
+            buf.truncate(nbytes as usize);
+            Ok(OsString::from_vec(buf))
+        }
+    }
+}
+
 pub fn fstat(fd: u64) -> Result<libc::stat64, libc::c_int> {
     let mut buf: libc::stat64 = unsafe { mem::zeroed() };
     if -1 == unsafe { libc::fstat64(fd as libc::c_int, &mut buf) } {
@@ -5,35 +5,85 @@
 
 #![deny(rust_2018_idioms)]
 
-#[cfg(feature = "mount")]
-use anyhow::Context;
-use anyhow::Result;
+use anyhow::{Context, Result};
 use chrono::Local;
 use clap::{App, AppSettings, Arg, SubCommand};
 use env_logger::Builder;
 use log::LevelFilter;
 #[cfg(feature = "mount")]
-use std::ffi::{OsStr, OsString};
+use std::ffi::OsString;
 use std::io::Write;
 
 #[macro_use]
 extern crate log;
-#[cfg_attr(feature = "cover", macro_use)]
-#[cfg(feature = "cover")]
+#[cfg_attr(any(feature = "cover", feature = "sqlite"), macro_use)]
+#[cfg(any(feature = "cover", feature = "sqlite"))]
 extern crate diesel;
 
+#[cfg(feature = "mount")]
+mod attr_cache;
+#[cfg(feature = "cover")]
+mod audio;
+#[cfg(feature = "mount")]
+mod buffer_cache;
 mod cache;
+#[cfg(feature = "mount")]
+mod control;
 #[cfg(feature = "cover")]
 mod coverdb;
 #[cfg(feature = "mount")]
+mod daemon;
+#[cfg(feature = "mount")]
+mod deadline;
+#[cfg(feature = "mount")]
+mod fetch;
+#[cfg(feature = "mount")]
 mod file_handles;
+#[cfg(feature = "mount")]
+mod id_map;
+#[cfg(feature = "mount")]
+mod invalidate;
+#[cfg(feature = "mount")]
+mod lazy_sync;
 mod libc_extras;
 mod libc_wrappers;
 #[cfg(feature = "mount")]
+mod metrics;
+#[cfg(feature = "mount")]
+mod overlay;
+#[cfg(feature = "mount")]
 mod passthrough;
+#[cfg(feature = "cover")]
+mod placeholder;
+#[cfg(feature = "mount")]
+mod prefetch;
+#[cfg(feature = "mount")]
+mod readahead;
+#[cfg(feature = "mount")]
+mod retry;
+#[cfg(feature = "mount")]
+mod scan_progress;
+mod signing;
+#[cfg(all(feature = "mount", target_os = "linux"))]
+mod sandbox;
+#[cfg(feature = "mount")]
+mod source_health;
 mod stat;
+mod stress;
+#[cfg(all(feature = "mount", target_os = "linux"))]
+mod systemd;
+#[cfg(feature = "sqlite")]
+mod tree_db;
 mod types;
+#[cfg(feature = "mount")]
+mod umount;
+#[cfg(all(feature = "uring", target_os = "linux"))]
+mod uring;
 mod utils;
+#[cfg(all(feature = "mount", target_os = "linux"))]
+mod watch;
+#[cfg(feature = "mount")]
+mod zip_stream;
 
 fn main() -> Result<()> {
     Builder::new()
@@ -49,8 +99,10 @@ fn main() -> Result<()> {
         })
         .filter(Some("fuse_mt"), LevelFilter::Warn)
         .filter(Some("fuse"), LevelFilter::Warn)
-        .filter(None, LevelFilter::Warn)
+        .filter(None, LevelFilter::Trace)
         .init();
+    // Lowered here rather than in the filter, so a running mount can raise it again.
+    log::set_max_level(LevelFilter::Warn);
 
     let mut app = App::new("Ultrastar-Fs")
         .version("0.1.0")
@@ -71,13 +123,247 @@ fn main() -> Result<()> {
                     .takes_value(true)
                     .value_name("FILE")
                     .default_value("cache.zip")
-                    .help("Sets a custom cache file."))
+                    .multiple(true)
+                    .number_of_values(1)
+                    .help("Sets a custom cache file. An http:// or https:// URL is downloaded first, and only again once it changed on the server. Given multiple times, each cache is layered over the previous one, e.g. a delta from 'update --delta' over the cache it was made from."))
+                .arg(Arg::with_name("cache-dir")
+                    .long("cache-dir")
+                    .takes_value(true)
+                    .value_name("DIR")
+                    .help("Keeps caches downloaded from a URL in DIR. [default: $XDG_CACHE_HOME/ultrastar-fs]"))
                 .arg(Arg::with_name("source")
                     .help("Sets the directory that will be mirrored.")
                     .required(true))
                 .arg(Arg::with_name("target")
                     .help("Sets the mount point.")
-                    .required(true));
+                    .required(true))
+                .arg(Arg::with_name("merge-readdir")
+                    .long("merge-readdir")
+                    .takes_value(false)
+                    .help("Merges cached directory listings with the live contents of the source, so new songs show up without rebuilding the cache."))
+                .arg(Arg::with_name("sandbox")
+                    .long("sandbox")
+                    .takes_value(false)
+                    .help("Restricts the filesystem to the source, the cache and /dev/fuse using Landlock and seccomp once mounted. Requests are then handled on a single thread."))
+                .arg(Arg::with_name("overlay")
+                    .long("overlay")
+                    .takes_value(true)
+                    .value_name("DIR")
+                    .help("Redirects all modifications into DIR, leaving the source untouched. Entries in DIR take precedence over the source and the cache."))
+                .arg(Arg::with_name("writable")
+                    .long("writable")
+                    .takes_value(false)
+                    .conflicts_with("overlay")
+                    .help("Passes all modifications straight through to the source. Modified cached files are served from the source from then on, renamed ones are still served from the cache."))
+                .arg(Arg::with_name("override")
+                    .long("override")
+                    .takes_value(true)
+                    .value_name("DIR")
+                    .help("Entries in DIR replace those of the source and the cache, e.g. to patch broken songs locally. It is laid out like the source and only read from; '.wh.<name>' files hide entries below."))
+                .arg(Arg::with_name("offline")
+                    .long("offline")
+                    .takes_value(false)
+                    .conflicts_with_all(&["merge-readdir", "overlay", "writable", "watch"])
+                    .help("Serves only what the cache holds without ever accessing the source, e.g. while the drive holding it is detached. Other files fail to open with EIO."))
+                .arg(Arg::with_name("hide")
+                    .long("hide")
+                    .takes_value(true)
+                    .value_name("GLOB")
+                    .multiple(true)
+                    .number_of_values(1)
+                    .validator(validate_glob)
+                    .help("Hides entries whose name or path relative to the source matches GLOB, e.g. '*.avi' or '.git'. Can be given multiple times."))
+                .arg(Arg::with_name("no-video")
+                    .long("no-video")
+                    .takes_value(false)
+                    .help("Serves cached song files without their #VIDEO and #VIDEOGAP tags, so USDX plays no videos. Unlike hiding the videos, this doesn't make it report missing files."))
+                .arg(Arg::with_name("cover-background")
+                    .long("cover-background")
+                    .takes_value(false)
+                    .requires("no-video")
+                    .help("Gives cached song files without a #BACKGROUND their #COVER as one, to show in place of the video"))
+                .arg(Arg::with_name("prefetch")
+                    .long("prefetch")
+                    .takes_value(false)
+                    .conflicts_with("offline")
+                    .help("Reads the beginning of the files next to a song file in the background once it is opened, so covers and audio come up faster from slow sources. While songs are accessed in the order they are listed, e.g. scrolling through the song list, the song files and audio headers of the next few are loaded ahead as well."))
+                .arg(Arg::with_name("preview-clips")
+                    .long("preview-clips")
+                    .takes_value(false)
+                    .conflicts_with("offline")
+                    .help("Serves the preview clip stored by 'build --preview-clips' the first time an audio file is opened, while the source opens the file in the background"))
+                .arg(Arg::with_name("readahead")
+                    .long("readahead")
+                    .takes_value(true)
+                    .value_name("MIB")
+                    .conflicts_with("offline")
+                    .help("Once a file is read sequentially from the source, keeps up to MIB MiB of it read ahead in memory, which smooths playback over high-latency links."))
+                .arg(Arg::with_name("retry")
+                    .long("retry")
+                    .takes_value(true)
+                    .value_name("COUNT")
+                    .conflicts_with("offline")
+                    .help("Repeats opens and reads of the source failing with one of --retry-errors up to COUNT times, so brief network hiccups stall playback for a moment instead of making USDX drop the song [default: 0]"))
+                .arg(Arg::with_name("retry-backoff")
+                    .long("retry-backoff")
+                    .takes_value(true)
+                    .value_name("MS")
+                    .requires("retry")
+                    .help("How long to wait before repeating a failed call, doubled for each further attempt [default: 100]"))
+                .arg(Arg::with_name("retry-errors")
+                    .long("retry-errors")
+                    .takes_value(true)
+                    .value_name("ERRNOS")
+                    .requires("retry")
+                    .help("The comma separated errors calls are repeated after, out of eio, eagain, eintr, ebusy, etimedout, econnreset, econnaborted, ehostunreach, enetunreach and estale [default: eio,eagain,etimedout,econnreset]"))
+                .arg(Arg::with_name("read-timeout")
+                    .long("read-timeout")
+                    .takes_value(true)
+                    .value_name("SECS")
+                    .conflicts_with("offline")
+                    .validator(|secs| match secs.parse::<u64>() {
+                        Ok(secs) if secs > 0 => Ok(()),
+                        _ => Err(String::from("has to be a positive number")),
+                    })
+                    .help("Fails reads of the source with EIO once they took SECS seconds, so a hung NFS server doesn't freeze the whole mount. Such reads are repeated with --retry, and the source counts as lost until it answers again."))
+                .arg(Arg::with_name("io-uring")
+                    .long("io-uring")
+                    .takes_value(false)
+                    .help("Submits reads of the source to io_uring, so the kernel has all of them in flight at once instead of one per thread, which helps with spinning disks and network mounts. Needs Linux 5.6 and a build with the uring feature."))
+                .arg(Arg::with_name("zero-copy")
+                    .long("zero-copy")
+                    .takes_value(false)
+                    .conflicts_with_all(&["offline", "io-uring"])
+                    .help("Answers large reads of the source, like those of videos, from a mapping of the file instead of copying it into a buffer first, which saves CPU during playback. The kernel still copies the mapped pages into its reply, as the FUSE library offers no way to splice into it. Only for sources on local drives."))
+                .arg(Arg::with_name("source-attr-ttl")
+                    .long("source-attr-ttl")
+                    .takes_value(true)
+                    .value_name("SECS")
+                    .default_value("5")
+                    .validator(|secs| match secs.parse::<u64>() {
+                        Ok(_) => Ok(()),
+                        _ => Err(String::from("has to be a number")),
+                    })
+                    .help("Keeps the attributes of entries the cache doesn't cover, as they changed since it was built or are only in the live listing, for SECS seconds instead of looking them up in the source on every access. Changes made through the mount or seen by --watch apply right away. 0 turns this off."))
+                .arg(Arg::with_name("lazy-sync")
+                    .long("lazy-sync")
+                    .takes_value(false)
+                    .help("Answers fsync right away and syncs within a second in the background, once per file however often it was requested. Speeds up copying many files to high-latency sources, but sync errors are only logged."))
+                .arg(Arg::with_name("no-progress")
+                    .long("no-progress")
+                    .takes_value(false)
+                    .help("Doesn't show a progress bar while UltraStar Deluxe scans the songs on startup."))
+                .arg(Arg::with_name("protect-cached")
+                    .long("protect-cached")
+                    .takes_value(false)
+                    .help("Refuses permission and ownership changes of files served from the cache, with the error of --deny-writes-with. By default they only apply in memory, as long as the mount runs."))
+                .arg(Arg::with_name("squash")
+                    .long("squash")
+                    .takes_value(false)
+                    .help("Reports all entries as owned by the mounting user, who gets the access anyone had. Avoids permission errors when the source stores foreign user and group ids, e.g. on a NAS."))
+                .arg(Arg::with_name("map")
+                    .long("map")
+                    .takes_value(true)
+                    .value_name("IDS")
+                    .help("Translates ownership between the source and the mount, in what is reported and in chown, e.g. '1000:1001,@100:@1001' maps uid 1000 in the source to 1001 and gid 100 to 1001."))
+                .arg(Arg::with_name("map-file")
+                    .long("map-file")
+                    .takes_value(true)
+                    .value_name("FILE")
+                    .help("Reads mappings like those of --map from FILE, one per line."))
+                .arg(Arg::with_name("direct-io")
+                    .long("direct-io")
+                    .takes_value(true)
+                    .value_name("ACTION")
+                    .possible_values(&passthrough::DirectIo::NAMES)
+                    .help("What to do with opens using O_DIRECT: 'strip' drops the flag before opening the source, as some network filesystems refuse it, 'honor' passes it on and bypasses the page cache of the mount as well [default: strip]"))
+                .arg(Arg::with_name("kernel-cache")
+                    .long("kernel-cache")
+                    .takes_value(true)
+                    .value_name("POLICY")
+                    .possible_values(&passthrough::KernelCache::NAMES)
+                    .help("Which files the kernel keeps in its page cache between opens: 'auto' those served from the cache, 'keep' all of them, for sources nothing else modifies, 'direct' those served from the cache while always reading the others from the source, for sources modified by others while open [default: auto]"))
+                .arg(Arg::with_name("deny-writes-with")
+                    .long("deny-writes-with")
+                    .takes_value(true)
+                    .value_name("ERRNO")
+                    .possible_values(&passthrough::DenyWrites::NAMES)
+                    .help("The error modifications the mount doesn't allow fail with, as applications react differently to each [default: erofs]"))
+                .arg(Arg::with_name("watch")
+                    .long("watch")
+                    .takes_value(false)
+                    .help("Watches the source for changes while mounted and serves changed entries from there instead of the cache."))
+                .arg(Arg::with_name("buffer-cache")
+                    .long("buffer-cache")
+                    .takes_value(true)
+                    .value_name("MIB")
+                    .help("Sets how many MiB of decompressed cache files are kept in memory for future opens [default: 64]"))
+                .arg(Arg::with_name("verify-reads")
+                    .long("verify-reads")
+                    .takes_value(true)
+                    .value_name("ACTION")
+                    .possible_values(&cache::VerifyReads::NAMES)
+                    .help("Checks files read from the cache against their checksum, to catch corruption of the storage it is on. A corrupted file is only logged with 'log', and fails to open with 'fail'. Caches built before ultrastar-fs stored checksums can't be checked."))
+                .arg(Arg::with_name("require-signature")
+                    .long("require-signature")
+                    .takes_value(false)
+                    .requires("pubkey")
+                    .help("Refuses caches that weren't signed with the secret key matching --pubkey, e.g. when fetching them over the network. Implies --verify-reads fail, as that is what covers the content of files."))
+                .arg(Arg::with_name("pubkey")
+                    .long("pubkey")
+                    .takes_value(true)
+                    .value_name("FILE")
+                    .requires("require-signature")
+                    .help("The public key caches have to be signed with, see keygen."))
+                .arg(Arg::with_name("allow-other")
+                    .long("allow-other")
+                    .takes_value(false)
+                    .conflicts_with("allow-root")
+                    .help("Allows all users to access the mount, with the kernel checking their access against the permissions of each file. Needs user_allow_other in /etc/fuse.conf unless mounting as root."))
+                .arg(Arg::with_name("allow-root")
+                    .long("allow-root")
+                    .takes_value(false)
+                    .help("Allows root to access the mount, in addition to the mounting user, with the kernel checking access against the permissions of each file. Needs user_allow_other in /etc/fuse.conf unless mounting as root."))
+                .arg(Arg::with_name("daemon")
+                    .long("daemon")
+                    .takes_value(false)
+                    .help("Continues in the background once the filesystem is mounted."))
+                .arg(Arg::with_name("log-file")
+                    .long("log-file")
+                    .takes_value(true)
+                    .value_name("FILE")
+                    .requires("daemon")
+                    .help("Appends the output of the background process to FILE instead of discarding it."))
+                .arg(Arg::with_name("log-rotate")
+                    .long("log-rotate")
+                    .takes_value(true)
+                    .value_name("MIB")
+                    .requires("log-file")
+                    .validator(|size| match size.parse::<u64>() {
+                        Ok(size) if size > 0 => Ok(()),
+                        _ => Err(String::from("has to be a positive number")),
+                    })
+                    .help("Moves the log file aside once it grows beyond MIB, keeping the last 3 as FILE.1 to FILE.3."))
+                .arg(Arg::with_name("pidfile")
+                    .long("pidfile")
+                    .takes_value(true)
+                    .value_name("FILE")
+                    .help("Writes the pid of the mount to FILE, which is removed again after unmounting."))
+                .arg(Arg::with_name("control-socket")
+                    .long("control-socket")
+                    .takes_value(true)
+                    .value_name("SOCKET")
+                    .help("Accepts commands for the running mount on a unix socket at SOCKET, see the control subcommand. [default: one in $XDG_RUNTIME_DIR derived from the mount point, which the stats subcommand finds]"))
+                .arg(Arg::with_name("metrics-addr")
+                    .long("metrics-addr")
+                    .takes_value(true)
+                    .value_name("ADDR")
+                    .help("Serves counters of the running mount at http://ADDR/metrics in the Prometheus format, e.g. 127.0.0.1:9436."))
+                .arg(Arg::with_name("stats-interval")
+                    .long("stats-interval")
+                    .takes_value(true)
+                    .value_name("SECS")
+                    .help("Logs every SECS seconds how many opens, reads and attribute lookups were served from the cache rather than the source, to tell whether the cache covers the collection."));
 
         #[cfg(feature = "cover")]
         {
@@ -111,18 +397,219 @@ fn main() -> Result<()> {
                 .value_name("FILE")
                 .default_value("cache.zip")
                 .help("Specify where the created cache file should be saved."),
+        )
+        .arg(
+            Arg::with_name("dry-run")
+                .long("dry-run")
+                .takes_value(false)
+                .help("Only reports how many files would be cached and the estimated cache size, without writing anything."),
+        );
+
+    let cache_command = build_args(cache_command);
+    app = app.subcommand(cache_command);
+
+    app = app.subcommand(build_args(
+        SubCommand::with_name("update")
+            .about("Brings an existing cache up to date, only processing modified song files")
+            .arg(
+                Arg::with_name("root")
+                    .value_name("ROOT_DIR")
+                    .required(true)
+                    .help("set root directory from which the cache was created."),
+            )
+            .arg(
+                Arg::with_name("cache")
+                    .short("c")
+                    .long("cache")
+                    .takes_value(true)
+                    .value_name("FILE")
+                    .default_value("cache.zip")
+                    .help("Specify the cache file to update."),
+            )
+            .arg(
+                Arg::with_name("delta")
+                    .long("delta")
+                    .takes_value(true)
+                    .value_name("FILE")
+                    .help("Leaves the cache as it is and writes a delta holding only the modified song files to FILE instead, to be mounted with '--cache CACHE --cache FILE'."),
+            ),
+    ));
+
+    app = app.subcommand(archive_args(
+        SubCommand::with_name("merge")
+            .about("Merges caches, e.g. built per drive, into a single one")
+            .after_help("Where caches hold the same entry, the one given first is kept. The cover.db, index and hidden songs of all of them are combined.")
+            .arg(
+                Arg::with_name("caches")
+                    .value_name("CACHE")
+                    .required(true)
+                    .multiple(true)
+                    .min_values(2)
+                    .help("The cache files to merge."),
+            )
+            .arg(
+                Arg::with_name("output")
+                    .short("o")
+                    .long("output")
+                    .takes_value(true)
+                    .value_name("FILE")
+                    .default_value("cache.zip")
+                    .help("Specify where the merged cache file should be saved."),
+            ),
+    ));
+
+    app = app.subcommand(filter_args(
+        SubCommand::with_name("verify")
+            .about("Checks whether a cache is up to date with the song directory")
+            .after_help("Exits with a non-zero status if there are any differences.")
+            .arg(
+                Arg::with_name("root")
+                    .value_name("ROOT_DIR")
+                    .required(true)
+                    .help("set root directory from which the cache was created."),
+            )
+            .arg(
+                Arg::with_name("cache")
+                    .short("c")
+                    .long("cache")
+                    .takes_value(true)
+                    .value_name("FILE")
+                    .default_value("cache.zip")
+                    .help("Specify the cache file to check."),
+            ),
+    ));
+
+    app = app.subcommand(
+        SubCommand::with_name("cat")
+            .about("Prints a file as it is stored in the cache, before a mount rewrites or overrides it")
+            .arg(
+                Arg::with_name("cache")
+                    .value_name("CACHE")
+                    .required(true)
+                    .help("The cache file to read from."),
+            )
+            .arg(
+                Arg::with_name("path")
+                    .value_name("PATH")
+                    .required(true)
+                    .help("Path of the file relative to the song directory."),
+            ),
+    );
+
+    #[cfg(feature = "mount")]
+    {
+        app = app.subcommand(
+            SubCommand::with_name("control")
+                .about("Sends a command to a running mount")
+                .after_help(
+                    "COMMANDS:\n    swap-cache <FILE>    Replaces the cache of the mount with FILE\n    reload-cache         Loads the cache file of the mount again, e.g. after rebuilding it\n    drop-buffers         Frees the decompressed files kept in memory\n    log-level <LEVEL>    Logs up to LEVEL, one of off, error, warn, info, debug and trace\n    stats                Prints live counters of the mount\n\nThe same commands can be written to /.ultrastar-fs/control inside the mount.",
+                )
+                .arg(
+                    Arg::with_name("socket")
+                        .value_name("SOCKET")
+                        .required(true)
+                        .help("The control socket of the mount."),
+                )
+                .arg(
+                    Arg::with_name("command")
+                        .value_name("COMMAND")
+                        .required(true)
+                        .multiple(true)
+                        .help("The command and its arguments."),
+                ),
+        );
+        app = app.subcommand(
+            SubCommand::with_name("stats")
+                .about("Prints live counters of a running mount, like cache hits and open handles")
+                .arg(
+                    Arg::with_name("target")
+                        .required(true)
+                        .help("The mount point, or the control socket given to the mount."),
+                ),
+        );
+        app = app.subcommand(
+            SubCommand::with_name("umount")
+                .about("Unmounts a running mount")
+                .arg(
+                    Arg::with_name("target")
+                        .required(true)
+                        .help("The mount point."),
+                ),
         );
+    }
 
     #[cfg(feature = "cover")]
-    let cache_command = cache_command.arg(Arg::with_name("nocoverdb")
-        .value_name("NO_COVER_DB")
-        .required(false)
-        .short("s")
-        .long("skip-coverdb")
-        .takes_value(false)
-        .help("Skips creation of a relative cover_db file with can be loaded by the mount-command to skip thumbnail generation of ultrastar"));
+    {
+        app = app.subcommand(
+            SubCommand::with_name("coverdb")
+                .about("Works with the cover.db of UltraStar Deluxe")
+                .setting(AppSettings::SubcommandRequiredElseHelp)
+                .subcommand(thumbnail_args(
+                    SubCommand::with_name("verify")
+                        .about("Checks the covers of a cover.db against the song collection")
+                        .after_help("DATABASE is either a cover.db, e.g. the one of a USDX install, or a cache holding one. Reports covers that are gone, changed since their thumbnail was generated or without a valid thumbnail, which USDX would generate again. Exits with a non-zero status if there are any, unless they were fixed.")
+                        .arg(
+                            Arg::with_name("database")
+                                .value_name("DATABASE")
+                                .required(true)
+                                .help("The cover.db or cache to check."),
+                        )
+                        .arg(
+                            Arg::with_name("root")
+                                .long("root")
+                                .takes_value(true)
+                                .value_name("ROOT_DIR")
+                                .help("The song directory relative cover names are looked up in, as stored by caches."),
+                        )
+                        .arg(
+                            Arg::with_name("fix")
+                                .long("fix")
+                                .takes_value(false)
+                                .help("Removes covers that are gone and generates the others again, using --thumb-size and --thumb-format. Only possible for a cover.db file."),
+                        ),
+                )),
+        );
+    }
 
-    app = app.subcommand(cache_command);
+    app = app.subcommand(
+        SubCommand::with_name("keygen")
+            .about("Creates a key pair to sign caches with")
+            .arg(
+                Arg::with_name("secret-key")
+                    .value_name("FILE")
+                    .required(true)
+                    .help("Where to write the secret key, the public key is written to FILE.pub."),
+            ),
+    );
+
+    app = app.subcommand(
+        SubCommand::with_name("stress")
+            .about("Simulates the load of UltraStar Deluxe on a mounted filesystem")
+            .arg(
+                Arg::with_name("mountpoint")
+                    .value_name("MOUNT_POINT")
+                    .required(true)
+                    .help("The mounted filesystem to put under load."),
+            )
+            .arg(
+                Arg::with_name("threads")
+                    .short("t")
+                    .long("threads")
+                    .takes_value(true)
+                    .value_name("COUNT")
+                    .default_value("8")
+                    .help("Number of concurrent workers."),
+            )
+            .arg(
+                Arg::with_name("duration")
+                    .short("d")
+                    .long("duration")
+                    .takes_value(true)
+                    .value_name("SECONDS")
+                    .default_value("30")
+                    .help("How long to run the test for."),
+            ),
+    );
 
     let matches = app.get_matches();
 
@@ -131,6 +618,16 @@ fn main() -> Result<()> {
         ("mount", Some(sub_matches)) => {
             // TODO: load and use cache
 
+            // Checked up front, as fusermount only fails once everything is loaded, and with a
+            // less helpful message.
+            let access = ["allow-other", "allow-root"]
+                .iter()
+                .find(|name| sub_matches.is_present(name))
+                .map(|name| name.replace('-', "_"));
+            if let Some(option) = &access {
+                check_fuse_conf(option)?;
+            }
+
             #[cfg(not(feature = "cover"))]
             let cover = None;
             #[cfg(feature = "cover")]
@@ -138,6 +635,55 @@ fn main() -> Result<()> {
                 .value_of("coverdb")
                 .map(std::path::PathBuf::from);
 
+            let buffer_limit = match sub_matches.value_of("buffer-cache") {
+                Some(mib) => Some(
+                    mib.parse::<u64>()
+                        .context("Invalid buffer cache size")?
+                        * 1024
+                        * 1024,
+                ),
+                None => None,
+            };
+            let readahead = match sub_matches.value_of("readahead") {
+                Some(mib) => Some(
+                    mib.parse::<usize>()
+                        .context("Invalid readahead size")?
+                        * 1024
+                        * 1024,
+                ),
+                None => None,
+            };
+            let mut retry = retry::Retry::default();
+            if let Some(count) = sub_matches.value_of("retry") {
+                retry.attempts = count.parse().context("Invalid retry count")?;
+            }
+            if let Some(ms) = sub_matches.value_of("retry-backoff") {
+                retry.backoff =
+                    std::time::Duration::from_millis(ms.parse().context("Invalid retry backoff")?);
+            }
+            if let Some(spec) = sub_matches.value_of("retry-errors") {
+                retry.errnos = retry::parse_errnos(spec)?;
+            }
+            let mut id_map = id_map::IdMap::default();
+            if let Some(spec) = sub_matches.value_of("map") {
+                id_map.parse(spec)?;
+            }
+            if let Some(path) = sub_matches.value_of_os("map-file") {
+                id_map.read(path)?;
+            }
+
+            let mut caches = Vec::new();
+            for cache in sub_matches.values_of("cache").expect("'cache' has default") {
+                caches.push(if fetch::is_url(cache) {
+                    fetch::fetch(
+                        cache,
+                        sub_matches.value_of_os("cache-dir").map(std::path::Path::new),
+                    )?
+                } else {
+                    std::path::PathBuf::from(cache)
+                });
+            }
+
             let filesystem = passthrough::PassthroughFS::new(
                 sub_matches
                     .value_of_os("source")
@@ -147,39 +693,337 @@ fn main() -> Result<()> {
                     .value_of_os("target")
                     .expect("'target' is required")
                     .into(),
-                sub_matches.value_of("cache").expect("'cache' has default"),
+                &caches,
                 cover,
+                passthrough::MountOptions {
+                    merge_readdir: sub_matches.is_present("merge-readdir"),
+                    sandbox: sub_matches.is_present("sandbox"),
+                    overlay: sub_matches
+                        .value_of_os("overlay")
+                        .map(std::path::PathBuf::from),
+                    override_dir: sub_matches
+                        .value_of_os("override")
+                        .map(std::path::PathBuf::from),
+                    writable: sub_matches.is_present("writable"),
+                    offline: sub_matches.is_present("offline"),
+                    protect_cached: sub_matches.is_present("protect-cached"),
+                    hide: patterns(sub_matches, "hide"),
+                    prefetch: sub_matches.is_present("prefetch"),
+                    preview_clips: sub_matches.is_present("preview-clips"),
+                    lazy_sync: sub_matches.is_present("lazy-sync"),
+                    readahead,
+                    scan_progress: !sub_matches.is_present("no-progress")
+                        && !sub_matches.is_present("daemon"),
+                    buffer_limit,
+                    verify_reads: sub_matches.value_of("verify-reads").map(|name| {
+                        cache::VerifyReads::from_name(name)
+                            .expect("'verify-reads' has possible values")
+                    }),
+                    public_key: match sub_matches.value_of_os("pubkey") {
+                        Some(path) => Some(signing::PublicKey::read(path)?),
+                        None => None,
+                    },
+                    rewrite: cache::Rewrite {
+                        strip_video: sub_matches.is_present("no-video"),
+                        cover_background: sub_matches.is_present("cover-background"),
+                    },
+                    deny_writes: sub_matches
+                        .value_of("deny-writes-with")
+                        .map(|name| {
+                            passthrough::DenyWrites::from_name(name)
+                                .expect("'deny-writes-with' has possible values")
+                        })
+                        .unwrap_or_default(),
+                    squash: sub_matches.is_present("squash"),
+                    id_map,
+                    direct_io: sub_matches
+                        .value_of("direct-io")
+                        .map(|name| {
+                            passthrough::DirectIo::from_name(name)
+                                .expect("'direct-io' has possible values")
+                        })
+                        .unwrap_or_default(),
+                    kernel_cache: sub_matches
+                        .value_of("kernel-cache")
+                        .map(|name| {
+                            passthrough::KernelCache::from_name(name)
+                                .expect("'kernel-cache' has possible values")
+                        })
+                        .unwrap_or_default(),
+                    retry,
+                    read_timeout: sub_matches.value_of("read-timeout").map(|secs| {
+                        std::time::Duration::from_secs(
+                            secs.parse().expect("'read-timeout' is validated"),
+                        )
+                    }),
+                    source_attr_ttl: match sub_matches
+                        .value_of("source-attr-ttl")
+                        .expect("'source-attr-ttl' has default")
+                        .parse()
+                        .expect("'source-attr-ttl' is validated")
+                    {
+                        0 => None,
+                        secs => Some(std::time::Duration::from_secs(secs)),
+                    },
+                    io_uring: sub_matches.is_present("io-uring"),
+                    zero_copy: sub_matches.is_present("zero-copy"),
+                },
             )
             .context("Unable to load filesystem")?;
 
             println!("Filesystem has been created");
 
-            // TODO: add heuristic to detect ultrastardx startup and display progress bar based on that.
-
-            let fuse_args: Vec<&OsStr> = vec![&OsStr::new("-o"), &OsStr::new("auto_unmount")];
-
             let mount_point: OsString = sub_matches
                 .value_of_os("target")
                 .expect("'target' is required")
                 .into();
 
-            fuse_mt::mount(
-                fuse_mt::FuseMT::new(filesystem, 1),
-                &mount_point,
-                &fuse_args,
-            )?
+            // Threads don't survive forking, so this has to come before anything starts one.
+            if sub_matches.is_present("daemon") {
+                daemon::daemonize(
+                    std::path::Path::new(&mount_point),
+                    sub_matches.value_of_os("log-file").map(std::path::Path::new),
+                )?;
+            }
+            let _pidfile = match sub_matches.value_of_os("pidfile") {
+                Some(path) => Some(daemon::PidFile::create(path)?),
+                None => None,
+            };
+            daemon::unmount_on_signal(std::path::Path::new(&mount_point))?;
+            if let (Some(log), Some(mib)) = (
+                sub_matches.value_of_os("log-file"),
+                sub_matches.value_of("log-rotate"),
+            ) {
+                let mib: u64 = mib.parse().expect("'log-rotate' is validated");
+                daemon::rotate_log(std::path::Path::new(log), mib * 1024 * 1024);
+            }
+            #[cfg(target_os = "linux")]
+            systemd::notify_when_mounted(std::path::Path::new(&mount_point))?;
+
+            if sub_matches.is_present("watch") {
+                filesystem
+                    .watch()
+                    .context("Unable to watch the source")?;
+            }
+
+            let control_socket = match sub_matches.value_of_os("control-socket") {
+                Some(socket) => {
+                    filesystem
+                        .control()
+                        .serve(socket)
+                        .context("Unable to start control interface")?;
+                    Some(std::path::PathBuf::from(socket))
+                }
+                // Only a convenience, so the mount goes on without it.
+                None => match control::default_socket(&mount_point)
+                    .and_then(|socket| filesystem.control().serve(&socket).map(|()| socket))
+                {
+                    Ok(socket) => Some(socket),
+                    Err(e) => {
+                        warn!("Unable to start control interface: {:#}", e);
+                        None
+                    }
+                },
+            };
+
+            if let Some(addr) = sub_matches.value_of("metrics-addr") {
+                metrics::serve(addr).context("Unable to start metrics endpoint")?;
+            }
+            if let Some(secs) = sub_matches.value_of("stats-interval") {
+                let secs = secs
+                    .parse::<u64>()
+                    .ok()
+                    .filter(|&secs| secs > 0)
+                    .context("Invalid stats interval")?;
+                metrics::log_every(std::time::Duration::from_secs(secs));
+            }
+
+            #[allow(unused_mut)]
+            let mut fuse_options = Vec::new();
+            // Only Linux' fusermount cleans up after a mount that died.
+            #[cfg(target_os = "linux")]
+            fuse_options.push(fuser::MountOption::AutoUnmount);
+            match access.as_deref() {
+                Some("allow_other") => fuse_options.push(fuser::MountOption::AllowOther),
+                Some("allow_root") => fuse_options.push(fuser::MountOption::AllowRoot),
+                _ => {}
+            }
+            // Requests aren't checked against the user making them, so the kernel has to.
+            if access.is_some() {
+                fuse_options.push(fuser::MountOption::DefaultPermissions);
+            }
+
+            // The sandbox only covers the thread it is entered on, so don't hand requests
+            // off to a thread pool.
+            let threads = if sub_matches.is_present("sandbox") { 0 } else { 1 };
+
+            // Looked up by the invalidation, which may run after changing directory.
+            let absolute_mount_point =
+                std::fs::canonicalize(&mount_point).unwrap_or_else(|_| mount_point.clone().into());
+            let invalidator = filesystem.invalidator();
+            let mut session = fuser::Session::new(
+                fuse_mt::FuseMT::new(filesystem, threads),
+                std::path::Path::new(&mount_point),
+                &fuse_options,
+            )?;
+            invalidator.connect(absolute_mount_point, session.notifier());
+            session.run()?;
+
+            if let Some(socket) = control_socket {
+                // ignore failure
+                let _ = std::fs::remove_file(socket);
+            }
+        }
+        ("build", Some(sub_matches)) if sub_matches.is_present("dry-run") => {
+            cache::dry_run(
+                sub_matches.value_of("root").expect("'root' is required"),
+                &build_options(sub_matches)?,
+            )?;
         }
         ("build", Some(sub_matches)) => {
-            #[cfg(not(feature = "cover"))]
-            let cover = false;
-            #[cfg(feature = "cover")]
-            let cover = !sub_matches.is_present("nocoverdb");
             cache::build(
                 sub_matches.value_of("root").expect("'root' is required"),
                 sub_matches
                     .value_of("output")
                     .expect("'output' has default value"),
-                cover,
+                &build_options(sub_matches)?,
+            )?;
+        }
+        ("update", Some(sub_matches)) => {
+            cache::update(
+                sub_matches.value_of("root").expect("'root' is required"),
+                sub_matches
+                    .value_of("cache")
+                    .expect("'cache' has default value"),
+                sub_matches.value_of_os("delta").map(std::path::Path::new),
+                &build_options(sub_matches)?,
+            )?;
+        }
+        ("merge", Some(sub_matches)) => {
+            let caches: Vec<&std::ffi::OsStr> = sub_matches
+                .values_of_os("caches")
+                .expect("'caches' is required")
+                .collect();
+            cache::merge(
+                &caches,
+                std::path::Path::new(
+                    sub_matches
+                        .value_of_os("output")
+                        .expect("'output' has default value"),
+                ),
+                &archive_options(sub_matches)?,
+            )?;
+        }
+        ("verify", Some(sub_matches)) => {
+            let differences = cache::verify(
+                sub_matches.value_of("root").expect("'root' is required"),
+                sub_matches
+                    .value_of("cache")
+                    .expect("'cache' has default value"),
+                &filter(sub_matches),
+            )?;
+            if differences > 0 {
+                return Err(anyhow::anyhow!(
+                    "Cache is out of date, found {} differences",
+                    differences
+                ));
+            }
+            println!("Cache is up to date");
+        }
+        ("cat", Some(sub_matches)) => {
+            cache::cat(
+                sub_matches.value_of("cache").expect("'cache' is required"),
+                sub_matches.value_of_os("path").expect("'path' is required"),
+                std::io::stdout().lock(),
+            )?;
+        }
+        #[cfg(feature = "mount")]
+        ("control", Some(sub_matches)) => {
+            let command: Vec<&str> = sub_matches
+                .values_of("command")
+                .expect("'command' is required")
+                .collect();
+            control::send(
+                sub_matches.value_of_os("socket").expect("'socket' is required"),
+                &command.join(" "),
+            )?;
+        }
+        #[cfg(feature = "mount")]
+        ("stats", Some(sub_matches)) => {
+            let target = std::path::Path::new(
+                sub_matches.value_of_os("target").expect("'target' is required"),
+            );
+            let socket = if target.is_dir() {
+                control::default_socket(target)?
+            } else {
+                target.to_path_buf()
+            };
+            control::send(socket, "stats").context("Unable to query the mount")?;
+        }
+        #[cfg(feature = "mount")]
+        ("umount", Some(sub_matches)) => {
+            umount::umount(sub_matches.value_of_os("target").expect("'target' is required"))?;
+        }
+        #[cfg(feature = "cover")]
+        ("coverdb", Some(sub_matches)) => {
+            if let ("verify", Some(sub_matches)) = sub_matches.subcommand() {
+                let fix = sub_matches.is_present("fix");
+                let thumbnails = thumbnail_options(sub_matches);
+                let problems = coverdb::verify(
+                    std::path::Path::new(
+                        sub_matches
+                            .value_of_os("database")
+                            .expect("'database' is required"),
+                    ),
+                    sub_matches.value_of_os("root").map(std::path::Path::new),
+                    if fix { Some(&thumbnails) } else { None },
+                )?;
+                if problems > 0 && !fix {
+                    return Err(anyhow::anyhow!(
+                        "cover.db is inconsistent, found {} problems",
+                        problems
+                    ));
+                }
+                if problems > 0 {
+                    println!("Fixed {} problems", problems);
+                } else {
+                    println!("cover.db is consistent");
+                }
+            }
+        }
+        ("keygen", Some(sub_matches)) => {
+            let secret = sub_matches
+                .value_of_os("secret-key")
+                .expect("'secret-key' is required");
+            let mut public = secret.to_os_string();
+            public.push(".pub");
+            let key = signing::SigningKey::generate()?;
+            key.write(secret)?;
+            key.public_key().write(&public)?;
+            println!(
+                "Wrote the secret key to '{}' and the public key to '{}'",
+                secret.to_string_lossy(),
+                public.to_string_lossy()
+            );
+        }
+        ("stress", Some(sub_matches)) => {
+            let threads = sub_matches
+                .value_of("threads")
+                .expect("'threads' has default value")
+                .parse()
+                .context("Invalid thread count")?;
+            let duration = sub_matches
+                .value_of("duration")
+                .expect("'duration' has default value")
+                .parse()
+                .map(std::time::Duration::from_secs)
+                .context("Invalid duration")?;
+            stress::stress(
+                sub_matches
+                    .value_of_os("mountpoint")
+                    .expect("'mountpoint' is required"),
+                threads,
+                duration,
             )?;
         }
         _ => {}
@@ -187,3 +1031,312 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+/// Fails unless unprivileged users may pass `option` (allow_other or allow_root) to FUSE.
+#[cfg(feature = "mount")]
+fn check_fuse_conf(option: &str) -> Result<()> {
+    const FUSE_CONF: &str = "/etc/fuse.conf";
+
+    if unsafe { libc::geteuid() } == 0 {
+        return Ok(());
+    }
+    let allowed = match std::fs::read_to_string(FUSE_CONF) {
+        Ok(conf) => conf
+            .lines()
+            .map(|line| line.split('#').next().unwrap_or("").trim())
+            .any(|line| line == "user_allow_other"),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => false,
+        Err(e) => return Err(e).with_context(|| format!("Unable to read {}", FUSE_CONF)),
+    };
+    if !allowed {
+        return Err(anyhow::anyhow!(
+            "Only root may mount with {} unless user_allow_other is set in {}",
+            option,
+            FUSE_CONF
+        ));
+    }
+    Ok(())
+}
+
+/// Adds the arguments setting how a cache is written.
+fn archive_args<'a, 'b>(command: App<'a, 'b>) -> App<'a, 'b> {
+    command.arg(
+        Arg::with_name("metadata-format")
+            .long("metadata-format")
+            .takes_value(true)
+            .value_name("FORMAT")
+            .possible_values(cache::MetadataFormat::NAMES)
+            .default_value("json")
+            .help("Sets how the directory structure is stored. bincode loads a lot faster at mount, but can't be read by older versions. sqlite stores it in a database that a mount only reads the directories it needs from, which suits very large collections best."),
+    )
+    .arg(
+        Arg::with_name("compression")
+            .long("compression")
+            .takes_value(true)
+            .value_name("METHOD[#LEVEL]")
+            .default_value("deflate")
+            .validator(|spec| cache::Compression::from_spec(&spec).map(|_| ()))
+            .help("Sets how song files are compressed, one of stored, deflate (levels 0-9), bzip2 (levels 1-9) and zstd (levels 1-22), e.g. deflate#9. zstd is much faster to read from at mount, especially on weak hardware, but needs a version of ultrastar-fs supporting it. Generated covers and previews are always stored uncompressed."),
+    )
+    .arg(
+        Arg::with_name("container")
+            .long("container")
+            .takes_value(true)
+            .value_name("CONTAINER")
+            .possible_values(cache::Container::NAMES)
+            .default_value("zip")
+            .help("Sets how song files are laid out. pack stores them uncompressed one after another with an index, so a mount reads each with a single copy and no decompression. Meant for caches on a fast local SSD, as it ignores --compression for song files and makes the cache larger."),
+    )
+    .arg(
+        Arg::with_name("shard")
+            .long("shard")
+            .takes_value(false)
+            .help("Stores the contents of each directory separately, so a mount only loads them once they are first accessed. Speeds up mounting very large collections."),
+    )
+    .arg(
+        Arg::with_name("sign")
+            .long("sign")
+            .takes_value(true)
+            .value_name("SECRET_KEY")
+            .help("Signs the cache with the secret key in this file, see keygen. Mounts with --require-signature only accept it with the matching public key."),
+    )
+}
+
+/// Adds the arguments shared by all subcommands that write a cache from a song directory.
+fn build_args<'a, 'b>(command: App<'a, 'b>) -> App<'a, 'b> {
+    let command = archive_args(filter_args(command)).arg(
+        Arg::with_name("prescan")
+            .long("prescan")
+            .takes_value(false)
+            .help("Counts the entries of the root directory first, to show the progress of the build with an ETA. update estimates it from the previous cache otherwise."),
+    )
+    .arg(
+        Arg::with_name("normalize-encoding")
+            .long("normalize-encoding")
+            .takes_value(false)
+            .help("Converts song files in Windows-1252 to UTF-8 in the cache and declares it with #ENCODING, so USDX shows their umlauts correctly"),
+    )
+    .arg(
+        Arg::with_name("repair-references")
+            .long("repair-references")
+            .takes_value(false)
+            .help("Points #MP3, #COVER, #BACKGROUND and #VIDEO of cached song files at the file next to them if it only differs in case or extension, and warns about ones that don't exist at all"),
+    )
+    .arg(
+        Arg::with_name("xattrs")
+            .long("xattrs")
+            .takes_value(false)
+            .help("Records the extended attributes of all entries, so a mount serves them from the cache instead of asking the source, also when offline"),
+    );
+    #[cfg(feature = "cover")]
+    let command = command.arg(Arg::with_name("nocoverdb")
+        .value_name("NO_COVER_DB")
+        .required(false)
+        .short("s")
+        .long("skip-coverdb")
+        .takes_value(false)
+        .help("Skips creation of a relative cover_db file with can be loaded by the mount-command to skip thumbnail generation of ultrastar"))
+        .arg(Arg::with_name("placeholder-covers")
+            .long("placeholder-covers")
+            .takes_value(false)
+            .help("Generates placeholder covers showing artist and title for songs without a cover"))
+        .arg(Arg::with_name("with-video-thumbs")
+            .long("with-video-thumbs")
+            .takes_value(false)
+            .help("Stores the first keyframe of each song's video next to it, which becomes the background of songs without one. Needs ffmpeg."))
+        .arg(Arg::with_name("with-covers")
+            .long("with-covers")
+            .takes_value(false)
+            .help("Stores the cover of each song in the cache, so mounts serve them without going to the source"))
+        .arg(Arg::with_name("cover-size")
+            .long("cover-size")
+            .takes_value(true)
+            .value_name("PIXELS")
+            .requires("with-covers")
+            .validator(|size| match size.parse::<u32>() {
+                Ok(size) if size > 0 => Ok(()),
+                _ => Err(String::from("has to be a positive number")),
+            })
+            .help("Scales stored covers down to at most this width and height"))
+        .arg(Arg::with_name("export-index")
+            .long("export-index")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Writes the index of all songs with artist, title, language, year and whether they have a video to this file as well, as CSV if it ends in .csv and JSON otherwise. The cache always contains it as index.json."))
+        .arg(Arg::with_name("report-duplicates")
+            .long("report-duplicates")
+            .takes_value(true)
+            .value_name("FILE")
+            .help("Lists the songs in different folders with the same artist and title or the same audio in this file. Reads every audio file whose size matches that of another one."))
+        .arg(Arg::with_name("hide-duplicates")
+            .long("hide-duplicates")
+            .takes_value(false)
+            .help("Hides the song files of duplicate songs at mount, keeping those in one folder of each"))
+        .arg(Arg::with_name("durations")
+            .long("durations")
+            .takes_value(false)
+            .help("Reads the length of each song from the header of its audio file into index.json"))
+        .arg(Arg::with_name("preview-tags")
+            .long("preview-tags")
+            .takes_value(false)
+            .help("Adds #PREVIEWSTART and #END to cached song files that lack them, from the length of their audio, so USDX shows previews without reading the audio first. Implies --durations."))
+        .arg(Arg::with_name("preview-clips")
+            .long("preview-clips")
+            .takes_value(false)
+            .help("Stores the first 30 seconds of each song's audio as Opus in the cache, to be served by 'mount --preview-clips' while the source warms up. Needs ffmpeg."));
+    #[cfg(feature = "cover")]
+    let command = thumbnail_args(command);
+    command
+}
+
+/// Adds the arguments setting how thumbnails for the cover.db are generated.
+#[cfg(feature = "cover")]
+fn thumbnail_args<'a, 'b>(command: App<'a, 'b>) -> App<'a, 'b> {
+    command
+        .arg(Arg::with_name("thumb-size")
+            .long("thumb-size")
+            .takes_value(true)
+            .value_name("PIXELS")
+            .default_value("256")
+            .validator(|size| match size.parse::<u32>() {
+                Ok(size) if size > 0 => Ok(()),
+                _ => Err(String::from("has to be a positive number")),
+            })
+            .help("Sets the largest width and height of thumbnails in the cover.db. Has to match the texture size USDX is configured with."))
+        .arg(Arg::with_name("thumb-format")
+            .long("thumb-format")
+            .takes_value(true)
+            .value_name("FORMAT")
+            .possible_values(coverdb::ThumbnailFormat::NAMES)
+            .default_value("rgb")
+            .help("Sets the pixel format of thumbnails in the cover.db."))
+}
+
+#[cfg(feature = "cover")]
+fn thumbnail_options(matches: &clap::ArgMatches<'_>) -> coverdb::ThumbnailOptions {
+    coverdb::ThumbnailOptions {
+        size: matches
+            .value_of("thumb-size")
+            .expect("'thumb-size' has default value")
+            .parse()
+            .expect("'thumb-size' is validated"),
+        format: coverdb::ThumbnailFormat::from_name(
+            matches
+                .value_of("thumb-format")
+                .expect("'thumb-format' has default value"),
+        )
+        .expect("'thumb-format' has possible values"),
+    }
+}
+
+fn validate_glob(pattern: String) -> Result<(), String> {
+    glob::Pattern::new(&pattern)
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+/// Parses the glob patterns given for `name`, which have to be validated with `validate_glob`.
+fn patterns(matches: &clap::ArgMatches<'_>, name: &str) -> Vec<glob::Pattern> {
+    matches
+        .values_of(name)
+        .into_iter()
+        .flatten()
+        .map(|pattern| glob::Pattern::new(pattern).expect("patterns are validated"))
+        .collect()
+}
+
+/// Adds the arguments selecting which entries of the song directory belong into a cache.
+fn filter_args<'a, 'b>(command: App<'a, 'b>) -> App<'a, 'b> {
+    command
+        .arg(
+            Arg::with_name("exclude")
+                .long("exclude")
+                .takes_value(true)
+                .value_name("GLOB")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(validate_glob)
+                .help("Skips entries whose path relative to the root directory matches GLOB, along with everything below them. Can be given multiple times."),
+        )
+        .arg(
+            Arg::with_name("include")
+                .long("include")
+                .takes_value(true)
+                .value_name("GLOB")
+                .multiple(true)
+                .number_of_values(1)
+                .validator(validate_glob)
+                .help("Only adds files whose path relative to the root directory matches GLOB. Directories are always added unless excluded. Can be given multiple times."),
+        )
+}
+
+fn filter(matches: &clap::ArgMatches<'_>) -> cache::Filter {
+    cache::Filter {
+        include: patterns(matches, "include"),
+        exclude: patterns(matches, "exclude"),
+    }
+}
+
+/// The options set by [`archive_args`].
+fn archive_options(matches: &clap::ArgMatches<'_>) -> Result<cache::BuildOptions> {
+    Ok(cache::BuildOptions {
+        metadata_format: cache::MetadataFormat::from_name(
+            matches
+                .value_of("metadata-format")
+                .expect("'metadata-format' has default value"),
+        )
+        .expect("'metadata-format' has possible values"),
+        compression: cache::Compression::from_spec(
+            matches
+                .value_of("compression")
+                .expect("'compression' has default value"),
+        )
+        .expect("'compression' is validated"),
+        container: cache::Container::from_name(
+            matches
+                .value_of("container")
+                .expect("'container' has default value"),
+        )
+        .expect("'container' has possible values"),
+        shard: matches.is_present("shard"),
+        signing_key: match matches.value_of_os("sign") {
+            Some(path) => Some(signing::SigningKey::read(path)?),
+            None => None,
+        },
+        ..Default::default()
+    })
+}
+
+fn build_options(matches: &clap::ArgMatches<'_>) -> Result<cache::BuildOptions> {
+    #[allow(unused_mut)]
+    let mut options = cache::BuildOptions {
+        filter: filter(matches),
+        prescan: matches.is_present("prescan"),
+        normalize_encoding: matches.is_present("normalize-encoding"),
+        repair_references: matches.is_present("repair-references"),
+        xattrs: matches.is_present("xattrs"),
+        ..archive_options(matches)?
+    };
+    #[cfg(feature = "cover")]
+    {
+        options.coverdb = !matches.is_present("nocoverdb");
+        options.placeholder_covers = matches.is_present("placeholder-covers");
+        options.video_previews = matches.is_present("with-video-thumbs");
+        options.thumbnails = thumbnail_options(matches);
+        options.embed_covers = matches.is_present("with-covers");
+        options.embedded_cover_size = matches
+            .value_of("cover-size")
+            .map(|size| size.parse().expect("'cover-size' is validated"));
+        options.export_index = matches
+            .value_of_os("export-index")
+            .map(std::path::PathBuf::from);
+        options.report_duplicates = matches
+            .value_of_os("report-duplicates")
+            .map(std::path::PathBuf::from);
+        options.hide_duplicates = matches.is_present("hide-duplicates");
+        options.durations = matches.is_present("durations");
+        options.preview_tags = matches.is_present("preview-tags");
+        options.preview_clips = matches.is_present("preview-clips");
+    }
+    Ok(options)
+}
@@ -0,0 +1,266 @@
+// Metrics :: Counters of a running mount, served in the Prometheus text format.
+//
+// Everything is recorded into the global `METRICS` as it happens, which is cheap enough to do
+// unconditionally. The HTTP endpoint only answers `GET /metrics`, one connection at a time.
+// Alternatively a summary of how much was served from the cache is logged periodically.
+//
+
+use anyhow::{Context, Result};
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::thread;
+use std::time::Duration;
+
+pub static METRICS: Metrics = Metrics {
+    opens_cache: AtomicU64::new(0),
+    opens_source: AtomicU64::new(0),
+    reads_cache: AtomicU64::new(0),
+    reads_source: AtomicU64::new(0),
+    bytes_cache: AtomicU64::new(0),
+    bytes_source: AtomicU64::new(0),
+    attrs_cache: AtomicU64::new(0),
+    attrs_source: AtomicU64::new(0),
+    open_handles: AtomicU64::new(0),
+    lazy_open: Histogram::new(),
+    zip_decode: Histogram::new(),
+};
+
+/// Upper bounds of the histogram buckets in microseconds, from 100µs to 5s.
+const BUCKETS: [u64; 10] = [
+    100, 500, 1_000, 5_000, 10_000, 50_000, 100_000, 500_000, 1_000_000, 5_000_000,
+];
+
+pub struct Histogram {
+    /// Observations per bucket, the last one for everything beyond the largest bound.
+    counts: [AtomicU64; 11],
+    sum_micros: AtomicU64,
+}
+
+impl Histogram {
+    const fn new() -> Self {
+        Self {
+            counts: [
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+                AtomicU64::new(0),
+            ],
+            sum_micros: AtomicU64::new(0),
+        }
+    }
+
+    pub fn observe(&self, duration: Duration) {
+        let micros = duration.as_micros() as u64;
+        let bucket = BUCKETS
+            .iter()
+            .position(|&bound| micros <= bound)
+            .unwrap_or(BUCKETS.len());
+        self.counts[bucket].fetch_add(1, Ordering::Relaxed);
+        self.sum_micros.fetch_add(micros, Ordering::Relaxed);
+    }
+
+    fn render(&self, out: &mut String, name: &str, help: &str) {
+        let _ = writeln!(out, "# HELP {} {}", name, help);
+        let _ = writeln!(out, "# TYPE {} histogram", name);
+        let mut cumulative = 0;
+        for (i, count) in self.counts.iter().enumerate() {
+            cumulative += count.load(Ordering::Relaxed);
+            let bound = match BUCKETS.get(i) {
+                Some(&bound) => (bound as f64 / 1e6).to_string(),
+                None => String::from("+Inf"),
+            };
+            let _ = writeln!(out, "{}_bucket{{le=\"{}\"}} {}", name, bound, cumulative);
+        }
+        let sum = self.sum_micros.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(out, "{}_sum {}", name, sum);
+        let _ = writeln!(out, "{}_count {}", name, cumulative);
+    }
+}
+
+pub struct Metrics {
+    pub opens_cache: AtomicU64,
+    pub opens_source: AtomicU64,
+    pub reads_cache: AtomicU64,
+    pub reads_source: AtomicU64,
+    pub bytes_cache: AtomicU64,
+    pub bytes_source: AtomicU64,
+    /// Attribute lookups answered from the entries of the cache.
+    pub attrs_cache: AtomicU64,
+    /// Attribute lookups that had to stat the source or the overlay.
+    pub attrs_source: AtomicU64,
+    /// File and directory handles handed out to the kernel and not yet released.
+    pub open_handles: AtomicU64,
+    /// Time it takes to open a file in the source.
+    pub lazy_open: Histogram,
+    /// Time it takes to decompress a file from the cache.
+    pub zip_decode: Histogram,
+}
+
+impl Metrics {
+    fn render(&self) -> String {
+        let mut out = String::new();
+        render_by_source(
+            &mut out,
+            "ultrastar_fs_opens_total",
+            "Files opened, by where their content comes from.",
+            &self.opens_cache,
+            &self.opens_source,
+        );
+        render_by_source(
+            &mut out,
+            "ultrastar_fs_reads_total",
+            "Read requests, by where their content comes from.",
+            &self.reads_cache,
+            &self.reads_source,
+        );
+        render_by_source(
+            &mut out,
+            "ultrastar_fs_read_bytes_total",
+            "Bytes read, by where they come from.",
+            &self.bytes_cache,
+            &self.bytes_source,
+        );
+        render_by_source(
+            &mut out,
+            "ultrastar_fs_attr_lookups_total",
+            "Attribute lookups, by where they are answered from.",
+            &self.attrs_cache,
+            &self.attrs_source,
+        );
+        let _ = writeln!(
+            out,
+            "# HELP ultrastar_fs_open_handles Handles currently open."
+        );
+        let _ = writeln!(out, "# TYPE ultrastar_fs_open_handles gauge");
+        let _ = writeln!(
+            out,
+            "ultrastar_fs_open_handles {}",
+            self.open_handles.load(Ordering::Relaxed)
+        );
+        self.lazy_open.render(
+            &mut out,
+            "ultrastar_fs_lazy_open_seconds",
+            "Time it takes to open a file in the source.",
+        );
+        self.zip_decode.render(
+            &mut out,
+            "ultrastar_fs_zip_decode_seconds",
+            "Time it takes to decompress a file from the cache.",
+        );
+        out
+    }
+
+    /// The share served from the cache of each kind of request, as a line for the log.
+    fn summary(&self) -> String {
+        let share = |cache: &AtomicU64, source: &AtomicU64| {
+            let cache = cache.load(Ordering::Relaxed);
+            let total = cache + source.load(Ordering::Relaxed);
+            let percent = if total == 0 {
+                100.0
+            } else {
+                cache as f64 * 100.0 / total as f64
+            };
+            format!("{} of {} ({:.1}%)", cache, total, percent)
+        };
+        format!(
+            "Served from the cache: {} opens, {} reads, {} attribute lookups",
+            share(&self.opens_cache, &self.opens_source),
+            share(&self.reads_cache, &self.reads_source),
+            share(&self.attrs_cache, &self.attrs_source),
+        )
+    }
+}
+
+/// Renders a counter with one value served from the cache and one from the source.
+fn render_by_source(
+    out: &mut String,
+    name: &str,
+    help: &str,
+    cache: &AtomicU64,
+    source: &AtomicU64,
+) {
+    let _ = writeln!(out, "# HELP {} {}", name, help);
+    let _ = writeln!(out, "# TYPE {} counter", name);
+    for (label, value) in &[("cache", cache), ("source", source)] {
+        let _ = writeln!(
+            out,
+            "{}{{from=\"{}\"}} {}",
+            name,
+            label,
+            value.load(Ordering::Relaxed)
+        );
+    }
+}
+
+/// Serves the metrics over HTTP at `addr` in a background thread.
+pub fn serve(addr: &str) -> Result<()> {
+    let listener =
+        TcpListener::bind(addr).with_context(|| format!("Unable to listen on '{}'", addr))?;
+    thread::spawn(move || {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    if let Err(e) = respond(stream) {
+                        debug!("metrics: {}", e);
+                    }
+                }
+                Err(e) => error!("metrics: unable to accept connection: {}", e),
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Logs a summary of the counters every `interval` in a background thread, whenever anything
+/// happened since the last one.
+pub fn log_every(interval: Duration) {
+    thread::spawn(move || {
+        let mut last = String::new();
+        loop {
+            thread::sleep(interval);
+            let summary = METRICS.summary();
+            if summary != last {
+                info!("{}", summary);
+                last = summary;
+            }
+        }
+    });
+}
+
+fn respond(stream: TcpStream) -> std::io::Result<()> {
+    stream.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&stream);
+    let mut request = String::new();
+    reader.read_line(&mut request)?;
+    // skip the headers
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut stream = &stream;
+    let mut parts = request.split_whitespace();
+    if let (Some("GET"), Some("/metrics")) = (parts.next(), parts.next()) {
+        let body = METRICS.render();
+        write!(
+            stream,
+            "HTTP/1.0 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\n\r\n{}",
+            body.len(),
+            body
+        )
+    } else {
+        write!(
+            stream,
+            "HTTP/1.0 404 Not Found\r\nContent-Length: 0\r\n\r\n"
+        )
+    }
+}
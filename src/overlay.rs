@@ -0,0 +1,149 @@
+// Overlay :: A writable upper directory on top of the source and the cache.
+//
+// Modified files are copied into the upper directory first and shadow their originals from then
+// on. Deletions of entries that exist below are recorded as whiteouts: empty marker files named
+// `.wh.<name>` next to where the entry would be. A directory that replaces a deleted one is marked
+// opaque with a `.wh..wh..opq` file, hiding everything that was below it.
+//
+
+use crate::libc_extras::libc;
+use crate::libc_wrappers;
+use crate::stat::mode_to_filetype;
+use crate::utils::*;
+use anyhow::{anyhow, Result};
+use fuse_mt::DirectoryEntry;
+use std::collections::HashSet;
+use std::ffi::{OsStr, OsString};
+use std::fs::{self, File};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+
+const WHITEOUT_PREFIX: &str = ".wh.";
+const OPAQUE: &str = ".wh..wh..opq";
+
+/// The contents of a directory in the upper layer.
+pub struct UpperDir {
+    pub entries: Vec<DirectoryEntry>,
+    /// Names of entries below that were deleted.
+    pub whiteouts: HashSet<OsString>,
+    /// Nothing below this directory is visible.
+    pub opaque: bool,
+}
+
+pub struct Overlay {
+    upper: PathBuf,
+}
+
+impl Overlay {
+    pub fn new<P: Into<PathBuf>>(upper: P) -> Result<Self> {
+        let upper = upper.into();
+        if !upper.is_dir() {
+            return Err(anyhow!("'{}' is no directory", upper.display()));
+        }
+        Ok(Self { upper })
+    }
+
+    pub fn upper(&self) -> &Path {
+        &self.upper
+    }
+
+    /// Names that can't be used inside the overlay, as they would be mistaken for markers.
+    pub fn is_reserved(name: &OsStr) -> bool {
+        name.as_bytes().starts_with(WHITEOUT_PREFIX.as_bytes())
+    }
+
+    pub fn path(&self, partial: &Path) -> PathBuf {
+        self.upper.join(path_to_rel(partial))
+    }
+
+    pub fn lstat(&self, partial: &Path) -> Result<libc::stat64, libc::c_int> {
+        libc_wrappers::lstat(self.path(partial).into_os_string())
+    }
+
+    pub fn exists(&self, partial: &Path) -> bool {
+        self.lstat(partial).is_ok()
+    }
+
+    /// Whether the entry at `partial` in the lower layers was deleted, either by itself or by
+    /// deleting one of its parents.
+    pub fn hides(&self, partial: &Path) -> bool {
+        let mut dir = self.upper.clone();
+        for component in path_to_rel(partial).components() {
+            let name = component.as_os_str();
+            if dir.join(OPAQUE).exists() || dir.join(whiteout_name(name)).exists() {
+                return true;
+            }
+            dir.push(name);
+        }
+        false
+    }
+
+    /// Records the deletion of `partial`. Its parent has to exist in the upper layer.
+    pub fn whiteout(&self, partial: &Path) -> io::Result<()> {
+        File::create(self.whiteout_path(partial)).map(|_| ())
+    }
+
+    /// Removes the whiteout of `partial`, returning whether there was one.
+    pub fn remove_whiteout(&self, partial: &Path) -> io::Result<bool> {
+        match fs::remove_file(self.whiteout_path(partial)) {
+            Ok(()) => Ok(true),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(false),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Hides everything below the upper directory at `partial`.
+    pub fn set_opaque(&self, partial: &Path) -> io::Result<()> {
+        File::create(self.path(partial).join(OPAQUE)).map(|_| ())
+    }
+
+    pub fn read_dir(&self, partial: &Path) -> io::Result<UpperDir> {
+        let mut dir = UpperDir {
+            entries: vec![],
+            whiteouts: HashSet::new(),
+            opaque: false,
+        };
+        for entry in fs::read_dir(self.path(partial))? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if name == OsStr::new(OPAQUE) {
+                dir.opaque = true;
+            } else if Overlay::is_reserved(&name) {
+                let hidden = &name.as_bytes()[WHITEOUT_PREFIX.len()..];
+                dir.whiteouts
+                    .insert(OsStr::from_bytes(hidden).to_os_string());
+            } else {
+                let mode = entry.metadata()?.mode();
+                dir.entries.push(DirectoryEntry {
+                    name,
+                    kind: mode_to_filetype(mode as libc::mode_t),
+                });
+            }
+        }
+        Ok(dir)
+    }
+
+    /// Removes the markers of an otherwise empty upper directory, so it can be deleted.
+    pub fn clear_dir(&self, partial: &Path) -> io::Result<()> {
+        for entry in fs::read_dir(self.path(partial))? {
+            let entry = entry?;
+            if Overlay::is_reserved(&entry.file_name()) {
+                fs::remove_file(entry.path())?;
+            }
+        }
+        Ok(())
+    }
+
+    fn whiteout_path(&self, partial: &Path) -> PathBuf {
+        let name = partial.file_name().expect("the root can't be deleted");
+        self.path(partial).with_file_name(whiteout_name(name))
+    }
+}
+
+fn whiteout_name(name: &OsStr) -> OsString {
+    let mut whiteout = OsString::from(WHITEOUT_PREFIX);
+    whiteout.push(name);
+    whiteout
+}
@@ -5,51 +5,288 @@
 // Copyright (c) 2016-2020 by William R. Fraser
 //
 use anyhow::{Context, Result};
-
-use std::ffi::{CStr, CString, OsStr, OsString};
-use std::fs::File;
-use std::io::{self, Cursor, Read, Seek, SeekFrom, Write};
-use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use glob::Pattern;
+
+use std::collections::{HashMap, HashSet};
+use std::ffi::{CString, OsStr, OsString};
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::{DirBuilderExt, FileExt, PermissionsExt};
 use std::os::unix::io::{FromRawFd, IntoRawFd};
 use std::path::{Path, PathBuf};
 
 use crate::libc_extras::libc;
 use crate::libc_wrappers;
 
-use crate::cache::{load_from_zip, Entry};
+use crate::attr_cache::AttrCache;
+use crate::cache::{Cache, Content, Entry, Rewrite, VerifyReads, Xattrs};
+use crate::control::Control;
+use crate::deadline::Deadline;
 use crate::file_handles::*;
+use crate::id_map::IdMap;
+use crate::invalidate::Invalidator;
+use crate::lazy_sync::LazySync;
+use crate::metrics::METRICS;
+use crate::overlay::Overlay;
+use crate::prefetch::Prefetcher;
+use crate::readahead::Readahead;
+use crate::retry::Retry;
+use crate::scan_progress::ScanProgress;
+#[cfg(target_os = "linux")]
+use crate::sandbox::Sandbox;
+use crate::signing::PublicKey;
+use crate::source_health::SourceHealth;
 use crate::stat::*;
+use crate::types::{SerializableFileAttr, SerializableTimespec};
+#[cfg(all(feature = "uring", target_os = "linux"))]
+use crate::uring::Uring;
 use crate::utils::*;
 use fuse_mt::*;
-use std::sync::Mutex;
-use time::*;
-use zip::ZipArchive;
+use memmap2::{Mmap, MmapOptions};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime};
+
+/// Behaviour switches for a mount, set from the command line.
+#[derive(Debug, Default)]
+pub struct MountOptions {
+    /// Union cached directory listings with a live readdir of the source.
+    pub merge_readdir: bool,
+    /// Restrict the filesystem to the source, the cache and /dev/fuse once mounted.
+    pub sandbox: bool,
+    /// Redirect all modifications into this directory, leaving the source untouched.
+    pub overlay: Option<PathBuf>,
+    /// Entries in this directory replace those of the source and the cache, read-only.
+    pub override_dir: Option<PathBuf>,
+    /// Pass all modifications straight through to the source.
+    pub writable: bool,
+    /// Serves only what the cache holds, without ever accessing the source.
+    pub offline: bool,
+    /// Refuse attribute changes of files served from the cache, instead of only applying them in
+    /// memory.
+    pub protect_cached: bool,
+    /// Entries matching any of these by name or relative path don't show up in the mount.
+    pub hide: Vec<Pattern>,
+    /// Reads ahead the media of songs whose song file is opened.
+    pub prefetch: bool,
+    /// Serve the preview clip of an audio file from the cache, while the source opens it for the
+    /// first time in the background.
+    pub preview_clips: bool,
+    /// Answer fsync right away and sync in the background, coalescing repeated syncs.
+    pub lazy_sync: bool,
+    /// Bytes to buffer ahead of files read sequentially from the source.
+    pub readahead: Option<usize>,
+    /// Shows a progress bar while the song collection is scanned.
+    pub scan_progress: bool,
+    /// Bytes of decompressed cache files to keep in memory, if not the default.
+    pub buffer_limit: Option<u64>,
+    /// Check files read from the cache against their checksum.
+    pub verify_reads: Option<VerifyReads>,
+    /// Only accept caches signed with the secret key of this one.
+    pub public_key: Option<PublicKey>,
+    /// How the headers of cached song files are changed.
+    pub rewrite: Rewrite,
+    /// How modifications the mount doesn't allow are refused.
+    pub deny_writes: DenyWrites,
+    /// Report every entry as owned by the mounting user, whatever the source stores.
+    pub squash: bool,
+    /// Translates ownership between the source and the mount.
+    pub id_map: IdMap,
+    /// What happens to opens with O_DIRECT.
+    pub direct_io: DirectIo,
+    /// Which files the kernel keeps in its page cache between opens.
+    pub kernel_cache: KernelCache,
+    /// How opens and reads of the source failing now and then are repeated.
+    pub retry: Retry,
+    /// How long reads of the source may take before they fail with EIO, if they are limited.
+    pub read_timeout: Option<Duration>,
+    /// How long attributes looked up in the source for entries the cache doesn't cover are
+    /// kept, if at all.
+    pub source_attr_ttl: Option<Duration>,
+    /// Read from the source through io_uring.
+    pub io_uring: bool,
+    /// Answer large reads of the source from a mapping of the file instead of a copy.
+    pub zero_copy: bool,
+}
+
+/// Which files the kernel keeps in its page cache between opens.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum KernelCache {
+    /// Files served from the cache, which only change along with it, while files of the source
+    /// are read again on each open.
+    #[default]
+    Auto,
+    /// All files, for sources that nothing else modifies.
+    Keep,
+    /// Files served from the cache, while reads of files of the source always bypass the page
+    /// cache, for sources modified by others while open.
+    Direct,
+}
+
+impl KernelCache {
+    pub const NAMES: [&'static str; 3] = ["auto", "keep", "direct"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "auto" => Some(KernelCache::Auto),
+            "keep" => Some(KernelCache::Keep),
+            "direct" => Some(KernelCache::Direct),
+            _ => None,
+        }
+    }
+}
+
+/// How opens with O_DIRECT are handled.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum DirectIo {
+    /// Drops the flag before opening the source, as some network filesystems refuse it.
+    #[default]
+    Strip,
+    /// Passes it on and has the kernel bypass its page cache for the handle as well.
+    Honor,
+}
+
+impl DirectIo {
+    pub const NAMES: [&'static str; 2] = ["strip", "honor"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "strip" => Some(DirectIo::Strip),
+            "honor" => Some(DirectIo::Honor),
+            _ => None,
+        }
+    }
+}
+
+/// Reads of at least this many bytes are answered from a mapping of the source with
+/// [`MountOptions::zero_copy`].
+const ZERO_COPY_MIN: u32 = 64 * 1024;
+
+/// Reply flag of open telling the kernel to bypass its page cache for the handle.
+const FOPEN_DIRECT_IO: u32 = 1 << 0;
+/// Reply flag of open telling the kernel to keep what it cached of the file.
+const FOPEN_KEEP_CACHE: u32 = 1 << 1;
+
+#[cfg(not(target_os = "macos"))]
+const O_DIRECT: libc::c_int = libc::O_DIRECT;
+// not supported by macOS
+#[cfg(target_os = "macos")]
+const O_DIRECT: libc::c_int = 0;
+
+/// The error modifications the mount doesn't allow fail with.
+///
+/// Applications react differently to each: some give up on EROFS, while others retry or only
+/// recognize EACCES as the file being read-only.
+#[derive(Debug, Clone, Copy, Default)]
+pub enum DenyWrites {
+    #[default]
+    Erofs,
+    Eacces,
+    Enosys,
+}
+
+impl DenyWrites {
+    pub const NAMES: [&'static str; 3] = ["erofs", "eacces", "enosys"];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "erofs" => Some(DenyWrites::Erofs),
+            "eacces" => Some(DenyWrites::Eacces),
+            "enosys" => Some(DenyWrites::Enosys),
+            _ => None,
+        }
+    }
+
+    fn errno(self) -> libc::c_int {
+        match self {
+            DenyWrites::Erofs => libc::EROFS,
+            DenyWrites::Eacces => libc::EACCES,
+            DenyWrites::Enosys => libc::ENOSYS,
+        }
+    }
+}
 
 pub struct PassthroughFS {
     source: OsString,
-    struct_cache: Entry,
-    files_cache: Mutex<ZipArchive<File>>,
-    file_handles: Mutex<FileHandles>,
+    cache: Arc<RwLock<Arc<Cache>>>,
+    file_handles: FileHandles,
+    options: MountOptions,
+    overlay: Option<Overlay>,
+    /// Laid out like the overlay, but never written to.
+    override_dir: Option<Overlay>,
+    #[cfg(target_os = "linux")]
+    sandbox: Option<Sandbox>,
+    prefetcher: Option<Prefetcher>,
+    lazy_sync: Option<LazySync>,
+    readahead: Option<Readahead>,
+    /// Limits reads of the source to [`MountOptions::read_timeout`].
+    deadline: Option<Deadline>,
+    #[cfg(all(feature = "uring", target_os = "linux"))]
+    uring: Option<Arc<Uring>>,
+    /// Shared with the watch, which forgets what changed.
+    attrs: Option<Arc<AttrCache>>,
+    /// Shared with the watch and the control commands, which tell the kernel what changed.
+    kernel: Arc<Invalidator>,
+    /// Audio files whose preview clip was served, and whether the source opened them since.
+    warmed: Mutex<HashMap<PathBuf, Arc<AtomicBool>>>,
+    scan_progress: Option<ScanProgress>,
+    /// Whether the source is reachable, see [`Self::offline`].
+    health: SourceHealth,
+    /// The uid and gid entries are reported with, see [`MountOptions::squash`].
+    squash: Option<(u32, u32)>,
+}
+
+/// Opens the cache at `path` the way `options` have it checked and buffered.
+fn open_cache(path: &Path, options: &MountOptions) -> Result<Cache> {
+    let mut cache = Cache::open(path)?;
+    if let Some(limit) = options.buffer_limit {
+        cache = cache.with_buffer_limit(limit);
+    }
+    if let Some(verify) = options.verify_reads {
+        cache = cache
+            .with_verify_reads(verify)
+            .context("Unable to load the checksums of the cache")?;
+    }
+    if let Some(key) = options.public_key {
+        cache = cache
+            .with_public_key(key)
+            .context("Unable to verify the signature of the cache")?;
+    }
+    Ok(cache)
 }
 
 impl PassthroughFS {
+    /// Mounts `source` at `target` using the caches at `cache_paths`, each one layered over the
+    /// one before.
     #[allow(unused_variables)]
-    pub fn new<P: AsRef<Path>>(
+    pub fn new(
         source: OsString,
         target: OsString,
-        cache_path: P,
+        cache_paths: &[PathBuf],
         coverdb: Option<PathBuf>,
+        options: MountOptions,
     ) -> Result<Self> {
-        let cache_path = cache_path.as_ref();
-        let file = File::open(cache_path)
-            .with_context(|| format!("Failed to open cache zip at '{}'", cache_path.display()))?;
-        let mut zip = zip::ZipArchive::new(file).context("Failed to parse cache file as zip")?;
-        let struct_cache = load_from_zip(&mut zip).context("Unable to load cache")?;
+        let (cache_path, base_paths) = cache_paths
+            .split_last()
+            .ok_or_else(|| anyhow::anyhow!("No cache given"))?;
+        let mut base = None;
+        for path in base_paths {
+            let mut cache = open_cache(path, &options)?;
+            if let Some(below) = base {
+                cache = cache.with_base(below);
+            }
+            base = Some(Arc::new(cache));
+        }
+        let mut cache = open_cache(cache_path, &options)?.with_rewrite(options.rewrite);
+        if let Some(base) = base {
+            cache = cache.with_base(base);
+        }
 
         #[cfg(feature = "cover")]
         if let Some(dest) = coverdb {
             // don't fail if the cache was created without a coverdb
-            if let Ok(mut coverdb) = zip.by_name("cover.db") {
+            if let Some(mut coverdb) = cache.archive().by_name("cover.db") {
                 let mut src = tempfile::NamedTempFile::new()
                     .context("Failed to create temporary file for the src coverdb")?;
                 io::copy(&mut coverdb, &mut src).context("Failed to extract cache coverdb")?;
@@ -58,65 +295,1041 @@ impl PassthroughFS {
             }
         }
 
+        let overlay = match &options.overlay {
+            Some(upper) => Some(Overlay::new(upper).context("Unable to set up overlay")?),
+            None => None,
+        };
+        let override_dir = match &options.override_dir {
+            Some(dir) => Some(Overlay::new(dir).context("Unable to set up override directory")?),
+            None => None,
+        };
+
+        #[cfg(target_os = "linux")]
+        let sandbox = if options.sandbox {
+            let sandbox = Sandbox::new(cache_path).context("Unable to prepare sandbox")?;
+            for path in base_paths {
+                sandbox
+                    .allow_file_reads(path)
+                    .context("Unable to prepare sandbox")?;
+            }
+            // Mutating passthrough calls end up in the source as well.
+            if !options.offline {
+                sandbox
+                    .allow_writes(&source)
+                    .context("Unable to prepare sandbox")?;
+            }
+            if let Some(overlay) = &overlay {
+                sandbox
+                    .allow_writes(overlay.upper())
+                    .context("Unable to prepare sandbox")?;
+            }
+            if let Some(override_dir) = &override_dir {
+                sandbox
+                    .allow_reads(override_dir.upper())
+                    .context("Unable to prepare sandbox")?;
+            }
+            Some(sandbox)
+        } else {
+            None
+        };
+        #[cfg(not(target_os = "linux"))]
+        if options.sandbox {
+            return Err(anyhow::anyhow!("Sandboxing is only supported on Linux"));
+        }
+
+        let prefetcher = if options.prefetch {
+            Some(Prefetcher::default())
+        } else {
+            None
+        };
+        let lazy_sync = if options.lazy_sync {
+            Some(LazySync::default())
+        } else {
+            None
+        };
+        let readahead = options
+            .readahead
+            .map(|size| Readahead::new(size, options.read_timeout));
+        let deadline = options.read_timeout.map(Deadline::new);
+        #[cfg(all(feature = "uring", target_os = "linux"))]
+        let uring = if options.io_uring {
+            Some(Arc::new(Uring::new()?))
+        } else {
+            None
+        };
+        #[cfg(not(all(feature = "uring", target_os = "linux")))]
+        if options.io_uring {
+            return Err(anyhow::anyhow!(
+                "io_uring is only supported on Linux, when built with the uring feature"
+            ));
+        }
+        // A mapping can't be cut short once the server is away, only a read can.
+        if options.zero_copy
+            && SourceHealth::is_network(&source).context("Unable to check the source")?
+        {
+            return Err(anyhow::anyhow!(
+                "Zero-copy reads are only supported on sources stored locally"
+            ));
+        }
+        let attrs = options
+            .source_attr_ttl
+            .map(|ttl| Arc::new(AttrCache::new(ttl)));
+        let scan_progress = if options.scan_progress {
+            Some(ScanProgress::default())
+        } else {
+            None
+        };
+        let squash = if options.squash {
+            Some(unsafe { (libc::getuid(), libc::getgid()) })
+        } else {
+            None
+        };
+
         Ok(Self {
+            health: SourceHealth::new(source.clone()),
             source,
-            struct_cache,
-            files_cache: Mutex::new(zip),
-            file_handles: Mutex::new(FileHandles::new()),
+            cache: Arc::new(RwLock::new(Arc::new(cache))),
+            file_handles: FileHandles::new(),
+            options,
+            overlay,
+            override_dir,
+            #[cfg(target_os = "linux")]
+            sandbox,
+            prefetcher,
+            lazy_sync,
+            readahead,
+            deadline,
+            #[cfg(all(feature = "uring", target_os = "linux"))]
+            uring,
+            attrs,
+            kernel: Arc::default(),
+            warmed: Mutex::default(),
+            scan_progress,
+            squash,
         })
     }
 
+    /// Returns a handle for controlling the filesystem while it is mounted.
+    pub fn control(&self) -> Control {
+        Control::new(self.cache.clone(), self.kernel.clone())
+    }
+
+    /// Tells the kernel about changes to the cache once it is connected to the session.
+    pub fn invalidator(&self) -> Arc<Invalidator> {
+        self.kernel.clone()
+    }
+
+    /// Keeps the cache up to date with changes to the source while mounted.
+    #[cfg(target_os = "linux")]
+    pub fn watch(&self) -> Result<()> {
+        crate::watch::watch(
+            &self.source,
+            self.cache.clone(),
+            self.attrs.clone(),
+            self.kernel.clone(),
+        )
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    pub fn watch(&self) -> Result<()> {
+        Err(anyhow::anyhow!("Watching the source is only supported on Linux"))
+    }
+
+    /// The currently active cache. Hold on to it for the duration of an operation, so it isn't
+    /// swapped out halfway through.
+    fn cache(&self) -> Arc<Cache> {
+        self.cache.read().unwrap().clone()
+    }
+
     fn real_path(&self, partial: &Path) -> OsString {
         PathBuf::from(&self.source)
             .join(path_to_rel(partial))
             .into_os_string()
     }
 
+    /// The path of `partial` in the overlay if it was modified there, in the override directory
+    /// if it is replaced there, in the source otherwise.
+    fn layer_path(&self, partial: &Path) -> OsString {
+        match &self.overlay {
+            Some(overlay) if overlay.exists(partial) => overlay.path(partial).into_os_string(),
+            _ => self.lower_path(partial),
+        }
+    }
+
+    /// The path of `partial` in the override directory if it is replaced there, in the source
+    /// otherwise.
+    fn lower_path(&self, partial: &Path) -> OsString {
+        match &self.override_dir {
+            Some(dir) if dir.exists(partial) => dir.path(partial).into_os_string(),
+            _ => self.real_path(partial),
+        }
+    }
+
+    /// Looks up `path` in a layer above the source, `None` meaning it's up to the ones below.
+    fn stat_layer(&self, layer: &Overlay, path: &Path) -> Option<io::Result<FileAttr>> {
+        match layer.lstat(path) {
+            Ok(stat) => {
+                METRICS.attrs_source.fetch_add(1, Ordering::Relaxed);
+                Some(Ok(stat_to_fuse(stat)))
+            }
+            Err(_) if layer.hides(path) => Some(Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "entry was deleted in a layer above",
+            ))),
+            Err(_) => None,
+        }
+    }
+
     fn stat_real(&self, path: &Path) -> io::Result<FileAttr> {
-        match self.struct_cache.find(path) {
-            Ok(Entry::Dict {
-                name: _,
-                contents: _,
-                stat,
-            }) => Ok((*stat).into()),
-            Ok(Entry::File { name: _, stat }) => Ok((*stat).into()),
+        if let Some(overlay) = &self.overlay {
+            if let Some(result) = self.stat_layer(overlay, path) {
+                return result;
+            }
+        }
+        self.stat_lower(path)
+    }
+
+    /// Looks up `path` in the override directory, the source and the cache, ignoring the overlay.
+    fn stat_lower(&self, path: &Path) -> io::Result<FileAttr> {
+        if let Some(dir) = &self.override_dir {
+            if let Some(result) = self.stat_layer(dir, path) {
+                return result;
+            }
+        }
+        if self.cache().is_stale(path) {
+            return self.lstat_source(path);
+        }
+        let cache = self.cache();
+        let found = cache.entries(path).find(path).map(|entry| -> FileAttr {
+            let stat = match entry {
+                Entry::Dict {
+                    name: _,
+                    contents: _,
+                    stat,
+                } => return (*stat).into(),
+                Entry::Link { stat, .. } => return (*stat).into(),
+                Entry::File { name: _, stat } => stat,
+            };
+            let mut attr: FileAttr = (*stat).into();
+            if let Some(size) = cache.rewritten_size(path) {
+                attr.size = size;
+            }
+            // Content served from the cache takes up what it would on disk, whatever the source
+            // file does, also in caches built before the blocks were counted like that.
+            if cache.is_cached(path) {
+                attr.blocks = size_to_blocks(attr.size);
+            }
+            attr
+        });
+        match found {
+            Ok(attr) => {
+                METRICS.attrs_cache.fetch_add(1, Ordering::Relaxed);
+                Ok(attr)
+            }
+            // Entries only known to the live listing have to be looked up in the source.
+            Err(_) if self.options.merge_readdir => self.lstat_source(path),
             Err(_) => Err(std::io::Error::new(
                 std::io::ErrorKind::NotFound,
                 "entry not found in cache",
             )),
         }
     }
+
+    /// Looks up `path` in the source, for entries the cache doesn't cover.
+    fn lstat_source(&self, path: &Path) -> io::Result<FileAttr> {
+        let lookup = || {
+            METRICS.attrs_source.fetch_add(1, Ordering::Relaxed);
+            libc_wrappers::lstat(self.real_path(path)).map(stat_to_fuse)
+        };
+        match &self.attrs {
+            Some(attrs) => attrs.get(path, lookup),
+            None => lookup(),
+        }
+        .map_err(io::Error::from_raw_os_error)
+    }
+
+    /// Reads all entries of an open directory handle of the source, skipping `.` and `..`.
+    fn read_real_dir(&self, path: &Path, handle: u64) -> ResultReaddir {
+        let read = match libc_wrappers::read_dir(handle) {
+            Ok(read) => read,
+            Err(e) => {
+                error!("readdir: {:?}: {}", path, e);
+                return Err(e);
+            }
+        };
+
+        let mut entries: Vec<DirectoryEntry> = Vec::with_capacity(read.len());
+        for entry in read {
+            let filetype = match entry.d_type {
+                libc::DT_DIR => FileType::Directory,
+                libc::DT_REG => FileType::RegularFile,
+                libc::DT_LNK => FileType::Symlink,
+                libc::DT_BLK => FileType::BlockDevice,
+                libc::DT_CHR => FileType::CharDevice,
+                libc::DT_FIFO => FileType::NamedPipe,
+                libc::DT_SOCK => {
+                    warn!("FUSE doesn't support Socket file type; translating to NamedPipe instead.");
+                    FileType::NamedPipe
+                }
+                _ => {
+                    let entry_path = PathBuf::from(path).join(&entry.name);
+                    let real_path = self.real_path(&entry_path);
+                    match libc_wrappers::lstat(real_path) {
+                        Ok(stat64) => mode_to_filetype(stat64.st_mode),
+                        // most likely removed since the directory was read
+                        Err(errno) => {
+                            let ioerr = io::Error::from_raw_os_error(errno);
+                            warn!("readdir: skipping {:?}, getdents gave no file type and lstat failed: {}",
+                                  entry_path, ioerr);
+                            continue;
+                        }
+                    }
+                }
+            };
+
+            entries.push(DirectoryEntry {
+                name: entry.name,
+                kind: filetype,
+            })
+        }
+
+        Ok(entries)
+    }
+
+    /// Lists a directory of the source directly, bypassing the cache.
+    fn read_live_dir(&self, path: &Path) -> ResultReaddir {
+        let handle = libc_wrappers::open(
+            self.real_path(path),
+            libc::O_RDONLY | libc::O_DIRECTORY | libc::O_CLOEXEC,
+        )?;
+        let entries = self.read_real_dir(path, handle);
+        if let Err(e) = libc_wrappers::close(handle) {
+            error!("close({:?}): {}", path, io::Error::from_raw_os_error(e));
+        }
+        entries
+    }
+
+    /// Adds the entries of the live source directory that are missing from `entries`.
+    fn merge_live_dir(&self, path: &Path, entries: &mut Vec<DirectoryEntry>) {
+        match self.read_live_dir(path) {
+            Ok(live) => {
+                let known: HashSet<OsString> = entries.iter().map(|e| e.name.clone()).collect();
+                entries.extend(live.into_iter().filter(|e| !known.contains(&e.name)));
+            }
+            Err(e) => debug!(
+                "readdir: unable to merge live listing of {:?}: {}",
+                path,
+                io::Error::from_raw_os_error(e)
+            ),
+        }
+    }
+
+    /// Lists a directory the way it is presented by the mount.
+    fn list_dir(&self, path: &Path) -> ResultReaddir {
+        let mut entries: Vec<DirectoryEntry> = vec![];
+        let cache = self.cache();
+        let lower = match cache.entries(path).find(path) {
+            Ok(e) => match e {
+                Entry::Dict {
+                    name: _,
+                    contents,
+                    stat: _,
+                } => {
+                    for entry in contents {
+                        match entry {
+                            Entry::Dict {
+                                name,
+                                contents: _,
+                                stat,
+                            } => entries.push(DirectoryEntry {
+                                name: OsString::from(name),
+                                kind: stat.kind.into(),
+                            }),
+                            Entry::File { name, stat } | Entry::Link { name, stat, .. } => entries
+                                .push(DirectoryEntry {
+                                    name: OsString::from(name),
+                                    kind: stat.kind.into(),
+                                }),
+                        }
+                    }
+                    if self.options.merge_readdir {
+                        self.merge_live_dir(path, &mut entries);
+                    }
+                    Ok(entries)
+                }
+                Entry::File { .. } | Entry::Link { .. } => Err(libc::ENOTDIR),
+            },
+            // Directories created after the cache was built.
+            Err(_) if self.options.merge_readdir => self.read_live_dir(path),
+            Err(_) => Err(libc::ENOENT),
+        };
+
+        let lower = match &self.override_dir {
+            Some(dir) => self.merge_upper_dir(dir, path, lower),
+            None => lower,
+        };
+        match &self.overlay {
+            Some(overlay) => self.merge_upper_dir(overlay, path, lower),
+            None => lower,
+        }
+    }
+
+    /// Applies the changes made in the overlay, or the override directory, to the listing of a
+    /// lower directory.
+    fn merge_upper_dir(&self, overlay: &Overlay, path: &Path, lower: ResultReaddir) -> ResultReaddir {
+        let upper = match overlay.read_dir(path) {
+            Ok(upper) => upper,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                return if overlay.hides(path) {
+                    Err(libc::ENOENT)
+                } else {
+                    lower
+                };
+            }
+            Err(e) => {
+                error!("readdir: {:?} in {:?}: {}", path, overlay.upper(), e);
+                return Err(io_errno(e));
+            }
+        };
+
+        let mut entries = match lower {
+            Ok(entries) if !upper.opaque => entries,
+            _ => vec![],
+        };
+        let shadowed: HashSet<OsString> = upper.entries.iter().map(|e| e.name.clone()).collect();
+        entries.retain(|e| !shadowed.contains(&e.name) && !upper.whiteouts.contains(&e.name));
+        entries.extend(upper.entries);
+        Ok(entries)
+    }
+
+    /// Whether `path` exists below the overlay and is still visible.
+    fn lower_exists(&self, overlay: &Overlay, path: &Path) -> bool {
+        !overlay.hides(path) && self.stat_lower(path).is_ok()
+    }
+
+    /// Copies `path` from below into the overlay, so it can be modified there. Directories are
+    /// created empty, as their contents stay visible from below.
+    fn copy_up(&self, overlay: &Overlay, path: &Path) -> Result<(), libc::c_int> {
+        if overlay.exists(path) {
+            return Ok(());
+        }
+        let attr = self.stat_real(path).map_err(|_| libc::ENOENT)?;
+        if let Some(parent) = path.parent() {
+            self.copy_up(overlay, parent)?;
+        }
+
+        debug!("copy_up: {:?}", path);
+        let upper = overlay.path(path);
+        let real = self.lower_path(path);
+        let overridden = real != self.real_path(path);
+        // Cached song files are served read-only, so take the permissions from the source.
+        let perm = match libc_wrappers::lstat(real.clone()) {
+            Ok(stat) => stat.st_mode & 0o7777,
+            Err(_) => attr.perm as u32,
+        };
+        let result = match attr.kind {
+            FileType::Directory => fs::DirBuilder::new().mode(perm).create(&upper),
+            FileType::Symlink => {
+                fs::read_link(&real).and_then(|target| std::os::unix::fs::symlink(target, &upper))
+            }
+            FileType::RegularFile => {
+                let cache = self.cache();
+                match cache.read_file(path).filter(|_| !overridden) {
+                    Some(data) => data.and_then(|data| fs::write(&upper, &*data)),
+                    None => fs::copy(&real, &upper).map(|_| ()),
+                }
+                .and_then(|_| fs::set_permissions(&upper, fs::Permissions::from_mode(perm)))
+            }
+            _ => return Err(libc::EPERM),
+        };
+        result.map_err(|e| {
+            error!("copy_up({:?}): {}", path, e);
+            io_errno(e)
+        })
+    }
+
+    /// Makes room for a new entry at `path` in the overlay, returning whether it replaces a
+    /// deleted one.
+    fn prepare_upper_entry(&self, overlay: &Overlay, path: &Path) -> Result<bool, libc::c_int> {
+        let name = path.file_name().ok_or(libc::EINVAL)?;
+        if Overlay::is_reserved(name) {
+            return Err(libc::EINVAL);
+        }
+        self.copy_up(overlay, path.parent().ok_or(libc::EINVAL)?)?;
+        overlay.remove_whiteout(path).map_err(io_errno)
+    }
+
+    fn require_overlay(&self) -> Result<&Overlay, libc::c_int> {
+        // Without an overlay or --writable, mutating the structure of the source isn't supported.
+        self.overlay.as_ref().ok_or_else(|| self.denied())
+    }
+
+    /// The error a modification the mount doesn't allow fails with.
+    fn denied(&self) -> libc::c_int {
+        self.options.deny_writes.errno()
+    }
+
+    /// Brings the cache up to date after `path` was modified in the source, so it is served from
+    /// there from now on. The attributes of its parent are updated as well.
+    fn invalidate(&self, path: &Path) {
+        self.refresh(path, true);
+        if let Some(parent) = path.parent() {
+            self.refresh(parent, false);
+        }
+    }
+
+    fn refresh(&self, path: &Path, recursive: bool) {
+        if let Some(attrs) = &self.attrs {
+            attrs.forget(path, recursive);
+        }
+        let real = PathBuf::from(self.real_path(path));
+        if let Err(e) = self.cache().refresh(path, &real, recursive) {
+            warn!("unable to refresh {:?} in the cache: {:#}", path, e);
+        }
+    }
+
+    /// Applies a modification to `path` directly to the source, for writable mounts.
+    fn modify_source<F>(&self, op: &str, path: &Path, f: F) -> Result<(), libc::c_int>
+    where
+        F: FnOnce(&Path) -> io::Result<()>,
+    {
+        if let Err(e) = f(Path::new(&self.real_path(path))) {
+            error!("{}({:?}): {}", op, path, e);
+            return Err(io_errno(e));
+        }
+        self.invalidate(path);
+        Ok(())
+    }
+
+    /// Changes the attributes of `path` with `apply`, which is given an open handle of the file
+    /// if there is one, or its path otherwise. `update` keeps the attributes in memory in line.
+    ///
+    /// Files served from the cache are left alone in the source unless the mount is writable,
+    /// and only change in memory, as their content stays the same.
+    fn change_attrs<A, U>(
+        &self,
+        op: &str,
+        path: &Path,
+        fh: Option<u64>,
+        apply: A,
+        update: U,
+    ) -> ResultEmpty
+    where
+        A: FnOnce(Target) -> Result<(), libc::c_int>,
+        U: Fn(&mut SerializableFileAttr),
+    {
+        if let Some(overlay) = &self.overlay {
+            self.copy_up(overlay, path)?;
+            return apply(Target::Path(overlay.path(path).into_os_string()));
+        }
+
+        let cache = self.cache();
+        if !self.options.writable && self.served_from_cache(path) {
+            if self.options.protect_cached {
+                return Err(self.denied());
+            }
+            cache.update_stat(path, update);
+            return Ok(());
+        }
+
+        let target = match fh {
+            Some(fh) => match self.file_handles.find(fh).as_deref_mut() {
+                Ok(Descriptor::Handle(h)) => Target::Handle(*h),
+                Ok(_) => Target::Path(self.real_path(path)),
+                Err(_) => return Err(libc::EBADF),
+            },
+            None => Target::Path(self.real_path(path)),
+        };
+        if let Err(e) = apply(target) {
+            error!("{}({:?}): {}", op, path, io::Error::from_raw_os_error(e));
+            return Err(e);
+        }
+        cache.update_stat(path, update);
+        Ok(())
+    }
+
+    fn is_hidden(&self, path: &Path) -> bool {
+        let path = path_to_rel(path);
+        self.options.hide.iter().any(|pattern| {
+            pattern.matches_path(path)
+                || path
+                    .file_name()
+                    .is_some_and(|name| pattern.matches_path(Path::new(name)))
+        }) || self.cache().is_hidden(path)
+    }
+
+    /// Returns the preview clip of the audio file at `path` to serve in its place, unless the
+    /// source opened `real` already. The first time, the source is made to open it in the
+    /// background, so it is quick by the time the audio itself is needed.
+    fn open_clip(&self, path: &Path, real: &OsStr) -> Option<Content> {
+        if !self.options.preview_clips || !has_extension(path, AUDIO_EXTENSIONS) {
+            return None;
+        }
+        let content = match self.cache().open_clip(path)? {
+            Ok(content) => content,
+            Err(e) => {
+                warn!("open({:?}): unable to read preview clip: {}", path, e);
+                return None;
+            }
+        };
+        let mut warmed = self.warmed.lock().unwrap();
+        match warmed.get(path) {
+            Some(ready) if ready.load(Ordering::Acquire) => return None,
+            Some(_) => {}
+            None => {
+                let ready = Arc::new(AtomicBool::new(false));
+                warmed.insert(path.to_path_buf(), ready.clone());
+                let real = real.to_os_string();
+                std::thread::spawn(move || {
+                    let mut head = [0; 64 * 1024];
+                    if let Err(e) = File::open(&real).and_then(|mut file| file.read(&mut head)) {
+                        warn!("unable to open {:?} ahead: {}", real, e);
+                    }
+                    ready.store(true, Ordering::Release);
+                });
+            }
+        }
+        Some(content)
+    }
+
+    /// The extended attributes of `path` recorded in the cache, `None` if they have to be read
+    /// from the layer it is in or the source.
+    fn cached_xattrs(&self, path: &Path) -> Option<Xattrs> {
+        if self.in_layer(path) {
+            return None;
+        }
+        self.cache().xattrs(path)
+    }
+
+    /// Whether `path` is in the overlay or the override directory, which the cache knows nothing
+    /// about.
+    fn in_layer(&self, path: &Path) -> bool {
+        if let Some(overlay) = &self.overlay {
+            if overlay.exists(path) {
+                return true;
+            }
+        }
+        if let Some(dir) = &self.override_dir {
+            if dir.exists(path) {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Whether only the cache is used, as asked for or because the source is lost for now.
+    fn offline(&self) -> bool {
+        self.options.offline || self.health.is_lost()
+    }
+
+    /// Whether the source of `path` is left alone, as its content is served from the cache.
+    fn served_from_cache(&self, path: &Path) -> bool {
+        self.offline() || self.cache().is_cached(path)
+    }
+
+    /// Attributes of the virtual control directory or file at `path`, owned like the root.
+    fn control_attr(&self, path: &Path) -> Option<FileAttr> {
+        let (kind, perm) = if path == Path::new(CONTROL_DIR) {
+            (FileType::Directory, 0o500)
+        } else if path == Path::new(CONTROL_FILE) {
+            (FileType::RegularFile, 0o200)
+        } else {
+            return None;
+        };
+        let mut attr: FileAttr = (*self.cache().entries(Path::new("/")).stat()).into();
+        attr.kind = kind;
+        attr.perm = perm;
+        attr.size = 0;
+        attr.blocks = 0;
+        attr.nlink = 1;
+        Some(attr)
+    }
+
+    /// Runs each line written to the control file as a control command.
+    fn write_control(&self, data: &[u8]) -> ResultWrite {
+        let control = self.control();
+        let commands = String::from_utf8_lossy(data);
+        for line in commands.lines().filter(|line| !line.trim().is_empty()) {
+            match control.execute(line) {
+                Ok(output) if !output.is_empty() => info!("control: {}", output),
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("control: {}: {:#}", line.trim(), e);
+                    return Err(libc::EINVAL);
+                }
+            }
+        }
+        Ok(data.len() as u32)
+    }
+
+    /// The flags to open the file for `flags` with and the flags to reply to the kernel with,
+    /// according to `--direct-io`.
+    fn direct_io(&self, flags: u32) -> (u32, u32) {
+        if flags as libc::c_int & O_DIRECT == 0 {
+            return (flags, 0);
+        }
+        match self.options.direct_io {
+            DirectIo::Strip => (flags & !(O_DIRECT as u32), 0),
+            DirectIo::Honor => (flags, FOPEN_DIRECT_IO),
+        }
+    }
+
+    /// Adds the page cache hints of `--kernel-cache` to the `reply` of an open, for a file served
+    /// from the cache if `cached`.
+    fn cache_hints(&self, cached: bool, reply: u32) -> u32 {
+        if reply & FOPEN_DIRECT_IO != 0 {
+            return reply;
+        }
+        match self.options.kernel_cache {
+            _ if cached => reply | FOPEN_KEEP_CACHE,
+            KernelCache::Auto => reply,
+            KernelCache::Keep => reply | FOPEN_KEEP_CACHE,
+            KernelCache::Direct => reply | FOPEN_DIRECT_IO,
+        }
+    }
+
+    /// Opens the file at `real` in the background and returns its handle.
+    ///
+    /// Opens that truncate are done right away instead, so the new size is seen by the time the
+    /// open returns.
+    ///
+    /// Handles only open for reading are read ahead with `--readahead`, unless they bypass
+    /// caching with O_DIRECT.
+    fn open_real<P: Into<PathBuf>>(&self, real: P, flags: u32) -> Result<u64, libc::c_int> {
+        let flags_c = flags as libc::c_int;
+        let descriptor = if flags_c & libc::O_TRUNC != 0 {
+            let real = real.into().into_os_string();
+            let open = || libc_wrappers::open(real.clone(), flags_c);
+            let handle = self.options.retry.run(format_args!("open({:?})", real), open)?;
+            Descriptor::Handle(handle)
+        } else {
+            Descriptor::lazy(real, flags, self.options.retry.clone())
+        };
+        let fh = self.file_handles.register_handle(descriptor);
+        if let Some(readahead) = &self.readahead {
+            if flags_c & libc::O_ACCMODE == libc::O_RDONLY && flags_c & O_DIRECT == 0 {
+                readahead.track(fh);
+            }
+        }
+        Ok(fh)
+    }
+
+    fn stat_source(&self, path: &Path) -> ResultEntry {
+        let stat = libc_wrappers::lstat(self.real_path(path)).inspect_err(|&e| {
+            self.health.report(e);
+        })?;
+        Ok((TTL, self.map_owner(stat_to_fuse(stat))))
+    }
+
+    /// Reports the ownership of `attr` as the mount presents it: translated by the id map, or
+    /// with `--squash` as owned by the mounting user, granting them the access anyone had.
+    fn map_owner(&self, mut attr: FileAttr) -> FileAttr {
+        attr.uid = self.options.id_map.mount_uid(attr.uid);
+        attr.gid = self.options.id_map.mount_gid(attr.gid);
+        if let Some((uid, gid)) = self.squash {
+            attr.uid = uid;
+            attr.gid = gid;
+            let anyone = (attr.perm >> 6 | attr.perm >> 3 | attr.perm) & 0o7;
+            attr.perm |= anyone << 6;
+        }
+        attr
+    }
+
+    /// Reads `size` bytes at `offset` of `path` from the source through `handle`, open as `fh`.
+    fn read_handle(
+        &self,
+        path: &Path,
+        fh: u64,
+        handle: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<Vec<u8>, libc::c_int> {
+        let buffered = self
+            .readahead
+            .as_ref()
+            .and_then(|readahead| readahead.read(fh, handle, offset, size as usize));
+        match buffered {
+            Some(Ok(data)) => {
+                METRICS.reads_source.fetch_add(1, Ordering::Relaxed);
+                METRICS.bytes_source.fetch_add(data.len() as u64, Ordering::Relaxed);
+                return Ok(data);
+            }
+            // read again directly, which is retried
+            Some(Err(e)) if self.options.retry.retries(e) => {}
+            Some(Err(e)) => {
+                error!(
+                    "read {:?}, {:#x} @ {:#x}: {}",
+                    path,
+                    size,
+                    offset,
+                    io::Error::from_raw_os_error(e)
+                );
+                return Err(e);
+            }
+            None => {}
+        }
+
+        let result = self.options.retry.run(
+            format_args!("read {:?}, {:#x} @ {:#x}", path, size, offset),
+            || {
+                #[cfg(all(feature = "uring", target_os = "linux"))]
+                let uring = self.uring.clone();
+                // positioned, as with a deadline it may still run once the handle is released
+                let read = move || {
+                    #[cfg(all(feature = "uring", target_os = "linux"))]
+                    if let Some(uring) = &uring {
+                        return uring.read(handle, offset, size as usize);
+                    }
+                    let file = unsafe { UnmanagedFile::new(handle) };
+                    let mut data = vec![0u8; size as usize];
+
+                    let n = file.read_at(&mut data, offset).map_err(io_errno)?;
+                    data.truncate(n);
+                    Ok(data)
+                };
+                match &self.deadline {
+                    Some(deadline) => deadline.run(
+                        format_args!("read {:?}, {:#x} @ {:#x}", path, size, offset),
+                        read,
+                    ),
+                    None => read(),
+                }
+            },
+        );
+        match result {
+            Ok(data) => {
+                METRICS.reads_source.fetch_add(1, Ordering::Relaxed);
+                METRICS.bytes_source.fetch_add(data.len() as u64, Ordering::Relaxed);
+                Ok(data)
+            }
+            Err(e) => {
+                error!(
+                    "read {:?}, {:#x} @ {:#x}: {}",
+                    path,
+                    size,
+                    offset,
+                    io::Error::from_raw_os_error(e)
+                );
+                Err(e)
+            }
+        }
+    }
+
+    /// Maps `size` bytes at `offset` of `path` from the source through `handle`, so the reply is
+    /// written straight from the page cache. Returns `None` where the range can't be mapped, like
+    /// past the end of the file, to read it as usual.
+    ///
+    /// The pages are faulted in while mapping, retried and limited by the deadline like reads.
+    /// Should the file still be truncated before the reply is written, the kernel fails the
+    /// request with EIO when it can't copy the reply, no signal is raised in the mount.
+    fn map_handle(
+        &self,
+        path: &Path,
+        handle: u64,
+        offset: u64,
+        size: u32,
+    ) -> Result<Option<Mmap>, libc::c_int> {
+        let len = (libc_wrappers::fstat(handle)?.st_size as u64)
+            .saturating_sub(offset)
+            .min(size as u64);
+        if len == 0 {
+            return Ok(None);
+        }
+        let map = move || {
+            // Safety: the mapping is only handed to the kernel, which copies from it.
+            unsafe {
+                MmapOptions::new()
+                    .offset(offset)
+                    .len(len as usize)
+                    .populate()
+                    .map(handle as libc::c_int)
+            }
+            .map_err(io_errno)
+        };
+        let result = self.options.retry.run(
+            format_args!("map {:?}, {:#x} @ {:#x}", path, size, offset),
+            || match &self.deadline {
+                Some(deadline) => deadline.run(
+                    format_args!("map {:?}, {:#x} @ {:#x}", path, size, offset),
+                    map,
+                ),
+                None => map(),
+            },
+        );
+        match result {
+            Ok(map) => {
+                METRICS.reads_source.fetch_add(1, Ordering::Relaxed);
+                METRICS.bytes_source.fetch_add(len, Ordering::Relaxed);
+                Ok(Some(map))
+            }
+            // the source doesn't support mapping, or not of this range
+            Err(e @ libc::ENODEV) | Err(e @ libc::EINVAL) => {
+                debug!(
+                    "read {:?}, {:#x} @ {:#x}: unable to map: {}",
+                    path,
+                    size,
+                    offset,
+                    io::Error::from_raw_os_error(e)
+                );
+                Ok(None)
+            }
+            Err(e) => {
+                error!(
+                    "read {:?}, {:#x} @ {:#x}: {}",
+                    path,
+                    size,
+                    offset,
+                    io::Error::from_raw_os_error(e)
+                );
+                Err(e)
+            }
+        }
+    }
+
+    fn read_content(
+        &self,
+        path: &Path,
+        content: &mut Content,
+        offset: u64,
+        size: u32,
+        callback: impl FnOnce(ResultSlice<'_>) -> CallbackResult,
+    ) -> CallbackResult {
+        match content.read_at(offset, size as usize) {
+            Ok(data) => {
+                METRICS.reads_cache.fetch_add(1, Ordering::Relaxed);
+                METRICS.bytes_cache.fetch_add(data.len() as u64, Ordering::Relaxed);
+                callback(Ok(data))
+            }
+            Err(e) => {
+                error!(
+                    "read {:?}, {:#x} @ {:#x}: unable to read from cache: {}",
+                    path, size, offset, e
+                );
+                callback(Err(libc::EIO))
+            }
+        }
+    }
+
+    /// Replaces the source handle of `path` in `descriptor`, open as `fh`, after it failed with
+    /// `errno` as the source was lost: by the content of the cache if it holds the file, or by
+    /// opening it again if the source is back. Returns whether it did.
+    fn recover_handle(
+        &self,
+        path: &Path,
+        fh: u64,
+        descriptor: &mut Descriptor,
+        errno: libc::c_int,
+    ) -> bool {
+        let handle = match descriptor {
+            Descriptor::Handle(handle) => *handle,
+            _ => return false,
+        };
+        if !self.health.report(errno) {
+            return false;
+        }
+        // the flags given to open(), which the stale descriptor still has
+        let flags = match unsafe { libc::fcntl(handle as libc::c_int, libc::F_GETFL) } {
+            -1 => return false,
+            flags => flags & !(libc::O_CREAT | libc::O_EXCL | libc::O_TRUNC),
+        };
+        let read_only = flags & libc::O_ACCMODE == libc::O_RDONLY;
+        let replacement = match self.cache().open_file(path) {
+            Some(Ok(content)) if read_only => Descriptor::File { content },
+            _ if self.health.is_lost() => return false,
+            _ => match libc_wrappers::open(self.layer_path(path), flags) {
+                Ok(handle) => Descriptor::Handle(handle),
+                Err(_) => return false,
+            },
+        };
+        if let Some(readahead) = &self.readahead {
+            readahead.forget(fh);
+            let reopened = matches!(replacement, Descriptor::Handle(_));
+            if reopened && read_only && flags & O_DIRECT == 0 {
+                readahead.track(fh);
+            }
+        }
+        info!("recovered the handle of {:?}", path);
+        if let Err(e) = libc_wrappers::close(handle) {
+            debug!("close({:?}): {}", path, io::Error::from_raw_os_error(e));
+        }
+        *descriptor = replacement;
+        true
+    }
+}
+
+/// What an attribute change applies to in the source or the overlay.
+enum Target {
+    Handle(u64),
+    Path(OsString),
 }
 
-const TTL: Timespec = Timespec { sec: 1, nsec: 0 };
+fn io_errno(e: io::Error) -> libc::c_int {
+    e.raw_os_error().unwrap_or(libc::EIO)
+}
+
+/// Answers an xattr request with `data`, or with its size if the caller asks for that with a
+/// `size` of 0.
+fn xattr_reply(data: Vec<u8>, size: u32) -> ResultXattr {
+    if size == 0 {
+        Ok(Xattr::Size(data.len() as u32))
+    } else if data.len() > size as usize {
+        Err(libc::ERANGE)
+    } else {
+        Ok(Xattr::Data(data))
+    }
+}
+
+const TTL: Duration = Duration::from_secs(1);
+/// A virtual directory that isn't listed, holding the control file.
+const CONTROL_DIR: &str = "/.ultrastar-fs";
+/// A virtual write-only file, each line written to it is run as a control command.
+const CONTROL_FILE: &str = "/.ultrastar-fs/control";
 
-// TODO: for all operations that change the file structure (e.g. delete, create, rename, chmod, ..)
-//       and for write operations on cached files return ENOSYS?
 impl FilesystemMT for PassthroughFS {
     fn init(&self, _req: RequestInfo) -> ResultEmpty {
         debug!("init");
+        #[cfg(target_os = "linux")]
+        if let Some(sandbox) = &self.sandbox {
+            if let Err(e) = sandbox.restrict() {
+                error!("init: unable to sandbox the filesystem: {:#}", e);
+                return Err(libc::EPERM);
+            }
+            info!("Filesystem is sandboxed");
+        }
         Ok(())
     }
 
-    fn destroy(&self, _req: RequestInfo) {
+    fn destroy(&self) {
         debug!("destroy");
+        if let Some(lazy_sync) = &self.lazy_sync {
+            lazy_sync.sync_all();
+        }
     }
 
     fn getattr(&self, _req: RequestInfo, path: &Path, fh: Option<u64>) -> ResultEntry {
         debug!("getattr: {:?}", path);
+        if let Some(attr) = self.control_attr(path) {
+            return Ok((TTL, self.map_owner(attr)));
+        }
+        if self.is_hidden(path) {
+            return Err(libc::ENOENT);
+        }
 
-        if let Some(fh) = fh {
-            match self.file_handles.lock().unwrap().find(fh) {
+        let found = if let Some(fh) = fh {
+            match self.file_handles.find(fh).as_deref_mut() {
                 Ok(d) => match d {
                     Descriptor::Path(_) => match self.stat_real(path) {
                         Ok(attr) => Ok((TTL, attr)),
                         Err(_) => Err(libc::ENOENT),
                     },
-                    Descriptor::Handle(h) => match libc_wrappers::fstat(*h) {
-                        Ok(stat) => Ok((TTL, stat_to_fuse(stat))),
-                        Err(e) => Err(e),
-                    },
-                    Descriptor::File { path: _, cursor: _ } => match self.stat_real(path) {
+                    Descriptor::Handle(h) => {
+                        METRICS.attrs_source.fetch_add(1, Ordering::Relaxed);
+                        match libc_wrappers::fstat(*h) {
+                            Ok(stat) => Ok((TTL, stat_to_fuse(stat))),
+                            Err(e) => Err(e),
+                        }
+                    }
+                    Descriptor::File { .. } => match self.stat_real(path) {
                         Ok(attr) => Ok((TTL, attr)),
                         Err(_) => Err(libc::ENOENT),
                     },
@@ -130,15 +1343,31 @@ impl FilesystemMT for PassthroughFS {
                 Ok(attr) => Ok((TTL, attr)),
                 Err(_) => Err(libc::ENOENT),
             }
-        }
+        };
+        found.map(|(ttl, attr)| (ttl, self.map_owner(attr)))
     }
 
-    #[allow(unused_variables)]
     fn chmod(&self, _req: RequestInfo, path: &Path, fh: Option<u64>, mode: u32) -> ResultEmpty {
-        Err(libc::ENOSYS)
+        debug!("chmod: {:?} to {:#o}", path, mode);
+        let mode = mode & 0o7777;
+        // Cached song files stay read-only unless they can be written through to the source.
+        let perm = if !self.options.writable && self.cache().is_cached(path) {
+            mode & 0o5555
+        } else {
+            mode
+        };
+        self.change_attrs(
+            "chmod",
+            path,
+            fh,
+            |target| match target {
+                Target::Handle(fh) => libc_wrappers::fchmod(fh, mode as libc::mode_t),
+                Target::Path(path) => libc_wrappers::chmod(path, mode as libc::mode_t),
+            },
+            |stat| stat.perm = perm as u16,
+        )
     }
 
-    #[allow(unused_variables)]
     fn chown(
         &self,
         _req: RequestInfo,
@@ -147,40 +1376,65 @@ impl FilesystemMT for PassthroughFS {
         uid: Option<u32>,
         gid: Option<u32>,
     ) -> ResultEmpty {
-        Err(libc::ENOSYS)
+        debug!("chown: {:?} to {:?}:{:?}", path, uid, gid);
+        let uid = uid.map(|uid| self.options.id_map.source_uid(uid));
+        let gid = gid.map(|gid| self.options.id_map.source_gid(gid));
+        self.change_attrs(
+            "chown",
+            path,
+            fh,
+            |target| match target {
+                Target::Handle(fh) => libc_wrappers::fchown(fh, uid, gid),
+                Target::Path(path) => libc_wrappers::lchown(path, uid, gid),
+            },
+            |stat| {
+                if let Some(uid) = uid {
+                    stat.uid = uid;
+                }
+                if let Some(gid) = gid {
+                    stat.gid = gid;
+                }
+            },
+        )
     }
 
     fn truncate(&self, _req: RequestInfo, path: &Path, fh: Option<u64>, size: u64) -> ResultEmpty {
         debug!("truncate: {:?} to {:#x}", path, size);
+        if path == Path::new(CONTROL_FILE) {
+            // done by the shell when redirecting into it
+            return Ok(());
+        }
 
         let result = if let Some(fd) = fh {
-            match self.file_handles.lock().unwrap().find(fd) {
+            match self.file_handles.find(fd).as_deref_mut() {
                 Ok(Descriptor::Handle(h)) => unsafe {
                     libc::ftruncate64(*h as libc::c_int, size as i64)
                 },
-                // TODO: maybe EROFS? How will other files be handled if we return that?
-                Ok(Descriptor::Path(_)) => return Err(libc::EACCES),
+                Ok(Descriptor::Path(_)) => return Err(self.denied()),
                 Err(_) => return Err(libc::ENOENT),
-                Ok(Descriptor::File { path: _, cursor: _ }) => return Err(libc::EACCES),
+                Ok(Descriptor::File { .. }) => return Err(self.denied()),
                 Ok(Descriptor::Lazy(_)) => unreachable!("Find does not return Descriptor::Lazy"),
                 Ok(Descriptor::Error(_)) => unreachable!("Find does not return Descriptor::Error"),
             }
+        } else if let Some(overlay) = &self.overlay {
+            self.copy_up(overlay, path)?;
+            unsafe {
+                let path_c =
+                    CString::from_vec_unchecked(overlay.path(path).into_os_string().into_vec());
+                libc::truncate64(path_c.as_ptr(), size as i64)
+            }
         } else {
-            let mut zip = self.files_cache.lock().unwrap();
-            let result = match path_to_rel(path)
-                .to_str()
-                .map(|x| zip.by_name(x))
-                .transpose()
-            {
-                Err(_) | Ok(None) => {
-                    let real = self.real_path(path);
-                    unsafe {
-                        let path_c = CString::from_vec_unchecked(real.into_vec());
-                        libc::truncate64(path_c.as_ptr(), size as i64)
-                    }
-                }
-                Ok(_) => return Err(libc::EACCES),
+            if !self.options.writable && self.served_from_cache(path) {
+                return Err(self.denied());
+            }
+            let real = self.real_path(path);
+            let result = unsafe {
+                let path_c = CString::from_vec_unchecked(real.into_vec());
+                libc::truncate64(path_c.as_ptr(), size as i64)
             };
+            if result != -1 && self.options.writable {
+                self.invalidate(path);
+            }
             result
         };
 
@@ -193,29 +1447,78 @@ impl FilesystemMT for PassthroughFS {
         }
     }
 
-    #[allow(unused_variables)]
     fn utimens(
         &self,
         _req: RequestInfo,
         path: &Path,
         fh: Option<u64>,
-        atime: Option<Timespec>,
-        mtime: Option<Timespec>,
+        atime: Option<SystemTime>,
+        mtime: Option<SystemTime>,
     ) -> ResultEmpty {
-        Err(libc::ENOSYS)
+        debug!("utimens: {:?}: {:?}, {:?}", path, atime, mtime);
+
+        fn timespec(time: Option<SystemTime>) -> libc::timespec {
+            match time.map(SerializableTimespec::from) {
+                Some(time) => libc::timespec {
+                    tv_sec: time.sec as libc::time_t,
+                    tv_nsec: time.nsec as libc::c_long,
+                },
+                None => libc::timespec {
+                    tv_sec: 0,
+                    tv_nsec: libc::UTIME_OMIT,
+                },
+            }
+        }
+        let times = [timespec(atime), timespec(mtime)];
+
+        if let Some(fh) = fh {
+            match self.file_handles.find(fh).as_deref_mut() {
+                Ok(Descriptor::Handle(h)) => return libc_wrappers::futimens(*h, &times),
+                // Nothing open in the source, so go by the path.
+                Ok(Descriptor::Path(_)) | Ok(Descriptor::File { .. }) => {}
+                Err(_) => return Err(libc::EBADF),
+                Ok(Descriptor::Lazy(_)) => unreachable!("Find does not return Descriptor::Lazy"),
+                Ok(Descriptor::Error(_)) => unreachable!("Find does not return Descriptor::Error"),
+            }
+        }
+
+        if let Some(overlay) = &self.overlay {
+            self.copy_up(overlay, path)?;
+            return libc_wrappers::utimensat(overlay.path(path).into_os_string(), &times);
+        }
+        // Like truncate, leave the source of files served from the cache alone.
+        if !self.options.writable && self.served_from_cache(path) {
+            return Err(self.denied());
+        }
+        if let Err(e) = libc_wrappers::utimensat(self.real_path(path), &times) {
+            error!("utimens({:?}): {}", path, io::Error::from_raw_os_error(e));
+            return Err(e);
+        }
+        if self.options.writable {
+            self.invalidate(path);
+        }
+        Ok(())
     }
 
     fn readlink(&self, _req: RequestInfo, path: &Path) -> ResultData {
         debug!("readlink: {:?}", path);
+        if !self.in_layer(path) {
+            if let Some(target) = self.cache().link_target(path) {
+                return Ok(target.into_vec());
+            }
+        }
+        if self.offline() {
+            // caches before format version 8 don't store link targets
+            return Err(libc::EIO);
+        }
 
-        let real = self.real_path(path);
+        let real = self.layer_path(path);
         match ::std::fs::read_link(real) {
             Ok(target) => Ok(target.into_os_string().into_vec()),
             Err(e) => Err(e.raw_os_error().unwrap()),
         }
     }
 
-    #[allow(unused_variables)]
     fn mknod(
         &self,
         _req: RequestInfo,
@@ -224,25 +1527,103 @@ impl FilesystemMT for PassthroughFS {
         mode: u32,
         rdev: u32,
     ) -> ResultEntry {
-        Err(libc::ENOSYS)
+        debug!("mknod: {:?}/{:?} (mode={:#o}, rdev={})", parent_path, name, mode, rdev);
+        if !self.options.writable {
+            return Err(self.denied());
+        }
+        let path = parent_path.join(name);
+        libc_wrappers::mknod(self.real_path(&path), mode as libc::mode_t, rdev as libc::dev_t)?;
+        self.invalidate(&path);
+        self.stat_source(&path)
     }
 
-    #[allow(unused_variables)]
     fn mkdir(&self, _req: RequestInfo, parent_path: &Path, name: &OsStr, mode: u32) -> ResultEntry {
-        Err(libc::ENOSYS)
+        debug!("mkdir: {:?}/{:?} (mode={:#o})", parent_path, name, mode);
+        let path = parent_path.join(name);
+        if self.options.writable {
+            self.modify_source("mkdir", &path, |real| {
+                fs::DirBuilder::new().mode(mode).create(real)
+            })?;
+            return self.stat_source(&path);
+        }
+        let overlay = self.require_overlay()?;
+        if self.stat_real(&path).is_ok() {
+            return Err(libc::EEXIST);
+        }
+
+        let replaces_deleted = self.prepare_upper_entry(overlay, &path)?;
+        if let Err(e) = fs::DirBuilder::new().mode(mode).create(overlay.path(&path)) {
+            error!("mkdir({:?}): {}", path, e);
+            return Err(io_errno(e));
+        }
+        if replaces_deleted {
+            overlay.set_opaque(&path).map_err(io_errno)?;
+        }
+
+        let stat = overlay.lstat(&path)?;
+        Ok((TTL, self.map_owner(stat_to_fuse(stat))))
     }
 
-    #[allow(unused_variables)]
     fn unlink(&self, _req: RequestInfo, parent_path: &Path, name: &OsStr) -> ResultEmpty {
-        Err(libc::ENOSYS)
+        debug!("unlink: {:?}/{:?}", parent_path, name);
+        let path = parent_path.join(name);
+        if self.options.writable {
+            return self.modify_source("unlink", &path, |real| fs::remove_file(real));
+        }
+        let overlay = self.require_overlay()?;
+        match self.stat_real(&path) {
+            Ok(attr) if attr.kind == FileType::Directory => return Err(libc::EISDIR),
+            Ok(_) => {}
+            Err(_) => return Err(libc::ENOENT),
+        }
+
+        let lower = self.lower_exists(overlay, &path);
+        if let Err(e) = fs::remove_file(overlay.path(&path)) {
+            if e.kind() != io::ErrorKind::NotFound {
+                error!("unlink({:?}): {}", path, e);
+                return Err(io_errno(e));
+            }
+        }
+        if lower {
+            self.copy_up(overlay, parent_path)?;
+            overlay.whiteout(&path).map_err(io_errno)?;
+        }
+        Ok(())
     }
 
-    #[allow(unused_variables)]
     fn rmdir(&self, _req: RequestInfo, parent_path: &Path, name: &OsStr) -> ResultEmpty {
-        Err(libc::ENOSYS)
+        debug!("rmdir: {:?}/{:?}", parent_path, name);
+        let path = parent_path.join(name);
+        if self.options.writable {
+            return self.modify_source("rmdir", &path, |real| fs::remove_dir(real));
+        }
+        let overlay = self.require_overlay()?;
+        match self.stat_real(&path) {
+            Ok(attr) if attr.kind != FileType::Directory => return Err(libc::ENOTDIR),
+            Ok(_) => {}
+            Err(_) => return Err(libc::ENOENT),
+        }
+        if !self.list_dir(&path)?.is_empty() {
+            return Err(libc::ENOTEMPTY);
+        }
+
+        let lower = self.lower_exists(overlay, &path);
+        if overlay.exists(&path) {
+            if let Err(e) = overlay
+                .clear_dir(&path)
+                .and_then(|_| fs::remove_dir(overlay.path(&path)))
+            {
+                error!("rmdir({:?}): {}", path, e);
+                return Err(io_errno(e));
+            }
+        }
+        if lower {
+            self.copy_up(overlay, parent_path)?;
+            overlay.whiteout(&path).map_err(io_errno)?;
+        }
+        Ok(())
     }
 
-    #[allow(unused_variables)]
     fn symlink(
         &self,
         _req: RequestInfo,
@@ -250,10 +1631,29 @@ impl FilesystemMT for PassthroughFS {
         name: &OsStr,
         target: &Path,
     ) -> ResultEntry {
-        Err(libc::ENOSYS)
+        debug!("symlink: {:?}/{:?} -> {:?}", parent_path, name, target);
+        let path = parent_path.join(name);
+        if self.options.writable {
+            self.modify_source("symlink", &path, |real| {
+                std::os::unix::fs::symlink(target, real)
+            })?;
+            return self.stat_source(&path);
+        }
+        let overlay = self.require_overlay()?;
+        if self.stat_real(&path).is_ok() {
+            return Err(libc::EEXIST);
+        }
+
+        self.prepare_upper_entry(overlay, &path)?;
+        if let Err(e) = std::os::unix::fs::symlink(target, overlay.path(&path)) {
+            error!("symlink({:?}, {:?}): {}", path, target, e);
+            return Err(io_errno(e));
+        }
+
+        let stat = overlay.lstat(&path)?;
+        Ok((TTL, self.map_owner(stat_to_fuse(stat))))
     }
 
-    #[allow(unused_variables)]
     fn rename(
         &self,
         _req: RequestInfo,
@@ -262,10 +1662,74 @@ impl FilesystemMT for PassthroughFS {
         newparent_path: &Path,
         newname: &OsStr,
     ) -> ResultEmpty {
-        Err(libc::ENOSYS)
+        debug!(
+            "rename: {:?}/{:?} -> {:?}/{:?}",
+            parent_path, name, newparent_path, newname
+        );
+        let from = parent_path.join(name);
+        let to = newparent_path.join(newname);
+        if self.options.writable {
+            let to_real = PathBuf::from(self.real_path(&to));
+            if let Err(e) = fs::rename(self.real_path(&from), &to_real) {
+                error!("rename({:?}, {:?}): {}", from, to, e);
+                return Err(io_errno(e));
+            }
+            // Moving songs around keeps serving them from the cache.
+            if let Err(e) = self.cache().rename(&from, &to, &to_real) {
+                warn!("unable to move {:?} in the cache: {:#}", from, e);
+            }
+            if let Some(attrs) = &self.attrs {
+                attrs.forget(&from, true);
+                attrs.forget(&to, true);
+            }
+            self.refresh(parent_path, false);
+            if newparent_path != parent_path {
+                self.refresh(newparent_path, false);
+            }
+            return Ok(());
+        }
+        let overlay = self.require_overlay()?;
+
+        let attr = self.stat_real(&from).map_err(|_| libc::ENOENT)?;
+        let is_dir = attr.kind == FileType::Directory;
+        let from_lower = self.lower_exists(overlay, &from);
+        if is_dir && from_lower {
+            // Like overlayfs, leave moving directories with contents below to the caller, which
+            // falls back to copying.
+            return Err(libc::EXDEV);
+        }
+        if let Ok(target) = self.stat_real(&to) {
+            match (is_dir, target.kind == FileType::Directory) {
+                (false, true) => return Err(libc::EISDIR),
+                (true, false) => return Err(libc::ENOTDIR),
+                (true, true) => {
+                    if !self.list_dir(&to)?.is_empty() {
+                        return Err(libc::ENOTEMPTY);
+                    }
+                    if overlay.exists(&to) {
+                        overlay.clear_dir(&to).map_err(io_errno)?;
+                    }
+                }
+                (false, false) => {}
+            }
+        }
+        let to_lower = self.lower_exists(overlay, &to);
+
+        self.copy_up(overlay, &from)?;
+        let replaces_deleted = self.prepare_upper_entry(overlay, &to)?;
+        if let Err(e) = fs::rename(overlay.path(&from), overlay.path(&to)) {
+            error!("rename({:?}, {:?}): {}", from, to, e);
+            return Err(io_errno(e));
+        }
+        if from_lower {
+            overlay.whiteout(&from).map_err(io_errno)?;
+        }
+        if is_dir && (to_lower || replaces_deleted) {
+            overlay.set_opaque(&to).map_err(io_errno)?;
+        }
+        Ok(())
     }
 
-    #[allow(unused_variables)]
     fn link(
         &self,
         _req: RequestInfo,
@@ -273,43 +1737,117 @@ impl FilesystemMT for PassthroughFS {
         newparent: &Path,
         newname: &OsStr,
     ) -> ResultEntry {
-        Err(libc::ENOSYS)
+        debug!("link: {:?} -> {:?}/{:?}", path, newparent, newname);
+        if !self.options.writable {
+            return Err(self.denied());
+        }
+        let new_path = newparent.join(newname);
+        let real = self.real_path(path);
+        self.modify_source("link", &new_path, |new_real| fs::hard_link(real, new_real))?;
+        // the link count changed
+        self.invalidate(path);
+        self.stat_source(&new_path)
     }
 
-    fn open(&self, _req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
+    fn open(&self, req: RequestInfo, path: &Path, flags: u32) -> ResultOpen {
         debug!("open: {:?} flags={:#x}", path, flags);
-        let mut zip = self.files_cache.lock().unwrap();
-        let result = match path_to_rel(path)
-            .to_str()
-            .map(|x| zip.by_name(x))
-            .transpose()
-        {
-            Err(_) | Ok(None) => {
+        if self.is_hidden(path) {
+            return Err(libc::ENOENT);
+        }
+        let flags_c = flags as libc::c_int;
+        if path == Path::new(CONTROL_FILE) {
+            if flags_c & libc::O_ACCMODE != libc::O_WRONLY {
+                return Err(libc::EACCES);
+            }
+            // Its mode alone doesn't keep other users out, the kernel may not check it.
+            if req.uid != 0 && req.uid != unsafe { libc::getuid() } {
+                return Err(libc::EACCES);
+            }
+            let fh = self.file_handles.register_handle(Descriptor::new(path));
+            // its content is virtual
+            return Ok((fh, FOPEN_DIRECT_IO));
+        }
+        let (flags, reply) = self.direct_io(flags);
+        let source_reply = self.cache_hints(false, reply);
+        let modifies = flags_c & libc::O_ACCMODE != libc::O_RDONLY || flags_c & libc::O_TRUNC != 0;
+        if modifies && self.options.writable {
+            // From now on the file is served from the source, including what was cached.
+            self.invalidate(path);
+        }
+        if let Some(overlay) = &self.overlay {
+            if modifies {
+                self.copy_up(overlay, path)?;
+            }
+            if overlay.exists(path) {
+                METRICS.opens_source.fetch_add(1, Ordering::Relaxed);
+                return Ok((self.open_real(overlay.path(path), flags)?, source_reply));
+            }
+            if overlay.hides(path) {
+                return Err(libc::ENOENT);
+            }
+        }
+        if let Some(dir) = &self.override_dir {
+            if dir.exists(path) {
+                // the override directory is only read from
+                if modifies {
+                    return Err(self.denied());
+                }
+                METRICS.opens_source.fetch_add(1, Ordering::Relaxed);
+                return Ok((self.open_real(dir.path(path), flags)?, source_reply));
+            }
+            if dir.hides(path) {
+                return Err(libc::ENOENT);
+            }
+        }
+        let cache = self.cache();
+        if let (Some(prefetcher), Some(dir)) = (&self.prefetcher, path.parent()) {
+            if !modifies && !self.offline() {
+                prefetcher.song_accessed(cache.clone(), dir, Path::new(&self.real_path(dir)));
+            }
+        }
+        let result = match cache.open_file(path) {
+            None if self.offline() => {
+                return match self.stat_real(path) {
+                    // known, but only available in the source
+                    Ok(_) => Err(libc::EIO),
+                    Err(_) => Err(libc::ENOENT),
+                };
+            }
+            None => {
                 let real = self.real_path(path);
-                if self.struct_cache.find(path).is_ok() {
-                    Ok((self.file_handles
-                            .lock()
-                            .unwrap()
-                            .register_handle(Descriptor::lazy(real, flags)),
-                        flags
-                    ))
+                if !modifies {
+                    if let Some(content) = self.open_clip(path, &real) {
+                        METRICS.opens_cache.fetch_add(1, Ordering::Relaxed);
+                        let fh = self
+                            .file_handles
+                            .register_handle(Descriptor::File { content });
+                        // the kernel mustn't keep the clip as the content of the file
+                        return Ok((fh, reply | FOPEN_DIRECT_IO));
+                    }
+                }
+                if self.stat_real(path).is_ok() {
+                    METRICS.opens_source.fetch_add(1, Ordering::Relaxed);
+                    Ok((self.open_real(real, flags)?, source_reply))
                 } else {
                     return Err(libc::ENOENT)
                 }
             }
-            Ok(Some(mut file)) => {
-                let mut buf = Vec::new();
-                file.read_to_end(&mut buf)
-                    .expect("Zip cache was forcefully closed?");
+            Some(Err(e)) => {
+                error!("open({:?}): unable to read from cache: {}", path, e);
+                Err(libc::EIO)
+            }
+            // served read-only from memory
+            Some(Ok(_)) if modifies => Err(self.denied()),
+            Some(Ok(content)) => {
+                METRICS.opens_cache.fetch_add(1, Ordering::Relaxed);
+                if let Some(prefetcher) = &self.prefetcher {
+                    if path.extension().is_some_and(|x| x == "txt") {
+                        prefetcher.song_opened(Path::new(&self.real_path(path)));
+                    }
+                }
                 Ok((
-                    self.file_handles
-                        .lock()
-                        .unwrap()
-                        .register_handle(Descriptor::File {
-                            path: path.to_path_buf().into_os_string(),
-                            cursor: Cursor::new(buf),
-                        }),
-                    flags,
+                    self.file_handles.register_handle(Descriptor::File { content }),
+                    self.cache_hints(true, reply),
                 ))
             }
         };
@@ -327,51 +1865,40 @@ impl FilesystemMT for PassthroughFS {
     ) -> CallbackResult {
         debug!("read: {:?} {:#x} @ {:#x}", path, size, offset);
 
-        // TODO: remove code duplication
-        match self.file_handles.lock().unwrap().find(fh) {
+        match self.file_handles.find(fh).as_deref_mut() {
             Ok(d) => match d {
-                Descriptor::Path(_) => return callback(Err(libc::EISDIR)),
+                Descriptor::Path(_) => callback(Err(libc::EISDIR)),
                 Descriptor::Handle(handle) => {
-                    let mut file = unsafe { UnmanagedFile::new(*handle) };
-                    let mut data = Vec::<u8>::with_capacity(size as usize);
-                    unsafe { data.set_len(size as usize) };
-
-                    if let Err(e) = file.seek(SeekFrom::Start(offset)) {
-                        error!("seek({:?}, {}): {}", path, offset, e);
-                        return callback(Err(e.raw_os_error().unwrap()));
-                    }
-                    match file.read(&mut data) {
-                        Ok(n) => {
-                            data.truncate(n);
-                        }
-                        Err(e) => {
-                            error!("read {:?}, {:#x} @ {:#x}: {}", path, size, offset, e);
-                            return callback(Err(e.raw_os_error().unwrap()));
+                    let handle = *handle;
+                    let result = if self.options.zero_copy && size >= ZERO_COPY_MIN {
+                        match self.map_handle(path, handle, offset, size) {
+                            Ok(Some(map)) => return callback(Ok(&map)),
+                            Ok(None) => self.read_handle(path, fh, handle, offset, size),
+                            Err(e) => Err(e),
                         }
+                    } else {
+                        self.read_handle(path, fh, handle, offset, size)
+                    };
+                    let result = match result {
+                        Err(e) if self.recover_handle(path, fh, d, e) => match d {
+                            Descriptor::Handle(handle) => {
+                                self.read_handle(path, fh, *handle, offset, size)
+                            }
+                            Descriptor::File { content } => {
+                                return self.read_content(path, content, offset, size, callback)
+                            }
+                            _ => Err(e),
+                        },
+                        result => result,
+                    };
+                    match result {
+                        Ok(data) => callback(Ok(&data)),
+                        Err(e) => callback(Err(e)),
                     }
-
-                    callback(Ok(&data))
                 }
-                Descriptor::File { path: _, cursor } => {
-                    let mut data = Vec::<u8>::with_capacity(size as usize);
-                    unsafe { data.set_len(size as usize) };
-
-                    if let Err(e) = cursor.seek(SeekFrom::Start(offset)) {
-                        error!("seek({:?}, {}): {}", path, offset, e);
-                        return callback(Err(e.raw_os_error().unwrap()));
-                    }
-                    match cursor.read(&mut data) {
-                        Ok(n) => {
-                            data.truncate(n);
-                        }
-                        Err(e) => {
-                            error!("read {:?}, {:#x} @ {:#x}: {}", path, size, offset, e);
-                            return callback(Err(e.raw_os_error().unwrap()));
-                        }
-                    }
-
-                    callback(Ok(&data))
-                },
+                Descriptor::File { content } => {
+                    self.read_content(path, content, offset, size, callback)
+                }
                 Descriptor::Lazy(_) => unreachable!("Find does not return Descriptor::Lazy"),
                 Descriptor::Error(_) => unreachable!("Find does not return Descriptor::Error"),
             },
@@ -388,16 +1915,27 @@ impl FilesystemMT for PassthroughFS {
         data: Vec<u8>,
         _flags: u32,
     ) -> ResultWrite {
-        let handle = match self.file_handles.lock().unwrap().find(fh) {
+        if path == Path::new(CONTROL_FILE) {
+            return self.write_control(&data);
+        }
+        let handle = match self.file_handles.find(fh).as_deref_mut() {
             Ok(Descriptor::Handle(h)) => *h,
-            _ => return Err(libc::EACCES),
+            Err(_) => return Err(libc::EBADF),
+            _ => return Err(self.denied()),
         };
         debug!("write: {:?} {:#x} @ {:#x}", path, data.len(), offset);
         let mut file = unsafe { UnmanagedFile::new(handle) };
 
-        if let Err(e) = file.seek(SeekFrom::Start(offset)) {
-            error!("seek({:?}, {}): {}", path, offset, e);
-            return Err(e.raw_os_error().unwrap());
+        // Appending writes go to the current end of the file, wherever the kernel thinks it is.
+        let append = match unsafe { libc::fcntl(handle as libc::c_int, libc::F_GETFL) } {
+            -1 => false,
+            flags => flags & libc::O_APPEND != 0,
+        };
+        if !append {
+            if let Err(e) = file.seek(SeekFrom::Start(offset)) {
+                error!("seek({:?}, {}): {}", path, offset, e);
+                return Err(e.raw_os_error().unwrap());
+            }
         }
         let nwritten: u32 = match file.write(&data) {
             Ok(n) => n as u32,
@@ -406,6 +1944,9 @@ impl FilesystemMT for PassthroughFS {
                 return Err(e.raw_os_error().unwrap());
             }
         };
+        if let Some(attrs) = &self.attrs {
+            attrs.forget(path, false);
+        }
 
         Ok(nwritten)
     }
@@ -413,7 +1954,7 @@ impl FilesystemMT for PassthroughFS {
     fn flush(&self, _req: RequestInfo, path: &Path, fh: u64, _lock_owner: u64) -> ResultEmpty {
         debug!("flush: {:?}", path);
 
-        let handle = match self.file_handles.lock().unwrap().find(fh) {
+        let handle = match self.file_handles.find(fh).as_deref_mut() {
             Ok(Descriptor::Handle(h)) => *h,
             _ => return Ok(()),
         };
@@ -439,8 +1980,11 @@ impl FilesystemMT for PassthroughFS {
         _flush: bool,
     ) -> ResultEmpty {
         debug!("release: {:?}", path);
-        match self.file_handles.lock().unwrap().free_handle(fh) {
-            Ok(Descriptor::File { path: _, cursor: _ }) => Ok(()),
+        if let Some(readahead) = &self.readahead {
+            readahead.forget(fh);
+        }
+        match self.file_handles.free_handle(fh) {
+            Ok(Descriptor::File { .. }) => Ok(()),
             Ok(Descriptor::Handle(handle)) => libc_wrappers::close(handle),
             Ok(Descriptor::Path(_)) | Ok(Descriptor::Lazy(_)) | Ok(Descriptor::Error(_)) => Ok(()),
             Err(_) => Err(libc::EBADF),
@@ -450,11 +1994,17 @@ impl FilesystemMT for PassthroughFS {
     fn fsync(&self, _req: RequestInfo, path: &Path, fh: u64, datasync: bool) -> ResultEmpty {
         debug!("fsync: {:?}, data={:?}", path, datasync);
 
-        let handle = match self.file_handles.lock().unwrap().find(fh) {
+        let handle = match self.file_handles.find(fh).as_deref_mut() {
             Ok(Descriptor::Handle(h)) => *h,
             _ => return Err(libc::EACCES),
         };
 
+        if let Some(lazy_sync) = &self.lazy_sync {
+            return lazy_sync.sync(handle, datasync).inspect_err(|&e| {
+                error!("fsync({:?}, {:?}): {}", path, datasync, io::Error::from_raw_os_error(e));
+            });
+        }
+
         let file = unsafe { UnmanagedFile::new(handle) };
 
         if let Err(e) = if datasync {
@@ -471,14 +2021,27 @@ impl FilesystemMT for PassthroughFS {
 
     fn opendir(&self, _req: RequestInfo, path: &Path, _flags: u32) -> ResultOpen {
         debug!("opendir: {:?} (flags = {:#o})", path, _flags);
-        match self.struct_cache.find(path) {
-            Ok(_) => Ok((
-                self.file_handles
-                    .lock()
-                    .unwrap()
-                    .register_handle(Descriptor::new(path)),
-                0,
-            )),
+        if self.is_hidden(path) {
+            return Err(libc::ENOENT);
+        }
+        // Look up the entry before stat_real, which takes the lock on the entries again.
+        let found = self.cache().entries(path).find(path).map(|_| ());
+        if let (Ok(()), Some(progress)) = (&found, &self.scan_progress) {
+            progress.dir_opened(path, || self.cache().all_entries().dirs());
+        }
+        let found = match found {
+            Err(_)
+                if (self.options.merge_readdir
+                    || self.overlay.is_some()
+                    || self.override_dir.is_some())
+                    && self.stat_real(path).is_ok() =>
+            {
+                Ok(())
+            }
+            found => found,
+        };
+        match found {
+            Ok(_) => Ok((self.file_handles.register_handle(Descriptor::new(path)), 0)),
             Err(e) => {
                 error!("opendir({:?}): {}", path, e);
                 Err(libc::ENOENT)
@@ -488,103 +2051,30 @@ impl FilesystemMT for PassthroughFS {
 
     fn readdir(&self, _req: RequestInfo, path: &Path, fh: u64) -> ResultReaddir {
         debug!("readdir: {:?}", path);
-        let mut entries: Vec<DirectoryEntry> = vec![];
 
-        match self.file_handles.lock().unwrap().find(fh).unwrap() {
+        let mut entries = match &mut *self.file_handles.find(fh).unwrap() {
             Descriptor::Path(s) => {
                 assert_eq!(path, Path::new(&s));
-                match self.struct_cache.find(path) {
-                    Ok(e) => match e {
-                        Entry::Dict {
-                            name: _,
-                            contents,
-                            stat: _,
-                        } => {
-                            for entry in contents {
-                                match entry {
-                                    Entry::Dict {
-                                        name,
-                                        contents: _,
-                                        stat,
-                                    } => entries.push(DirectoryEntry {
-                                        name: OsString::from(name),
-                                        kind: stat.kind.into(),
-                                    }),
-                                    Entry::File { name, stat } => entries.push(DirectoryEntry {
-                                        name: OsString::from(name),
-                                        kind: stat.kind.into(),
-                                    }),
-                                }
-                            }
-                            Ok(entries)
-                        }
-                        Entry::File { name: _, stat: _ } => Err(libc::ENOTDIR),
-                    },
-                    Err(_) => Err(libc::ENOENT),
-                }
-            }
-            Descriptor::Handle(handle) => {
-                loop {
-                    match libc_wrappers::readdir(*handle) {
-                        Ok(Some(entry)) => {
-                            let name_c = unsafe { CStr::from_ptr(entry.d_name.as_ptr()) };
-                            let name = OsStr::from_bytes(name_c.to_bytes()).to_owned();
-
-                            let filetype = match entry.d_type {
-                                libc::DT_DIR => FileType::Directory,
-                                libc::DT_REG => FileType::RegularFile,
-                                libc::DT_LNK => FileType::Symlink,
-                                libc::DT_BLK => FileType::BlockDevice,
-                                libc::DT_CHR => FileType::CharDevice,
-                                libc::DT_FIFO => FileType::NamedPipe,
-                                libc::DT_SOCK => {
-                                    warn!("FUSE doesn't support Socket file type; translating to NamedPipe instead.");
-                                    FileType::NamedPipe
-                                }
-                                _ => {
-                                    let entry_path = PathBuf::from(path).join(&name);
-                                    let real_path = self.real_path(&entry_path);
-                                    match libc_wrappers::lstat(real_path) {
-                                        Ok(stat64) => mode_to_filetype(stat64.st_mode),
-                                        Err(errno) => {
-                                            let ioerr = io::Error::from_raw_os_error(errno);
-                                            panic!("lstat failed after readdir_r gave no file type for {:?}: {}",
-                                                   entry_path, ioerr);
-                                        }
-                                    }
-                                }
-                            };
-
-                            entries.push(DirectoryEntry {
-                                name,
-                                kind: filetype,
-                            })
-                        }
-                        Ok(None) => {
-                            break;
-                        }
-                        Err(e) => {
-                            error!("readdir: {:?}: {}", path, e);
-                            return Err(e);
-                        }
-                    }
-                }
-
-                Ok(entries)
+                self.list_dir(path)
             }
-            Descriptor::File { path: _, cursor: _ } => Err(libc::ENOTDIR),
+            Descriptor::Handle(handle) => self.read_real_dir(path, *handle),
+            Descriptor::File { .. } => Err(libc::ENOTDIR),
             Descriptor::Lazy(_) => unreachable!("Find does not return Descriptor::Lazy"),
             Descriptor::Error(_) => unreachable!("Find does not return Descriptor::Error"),
+        }?;
+        if !self.options.hide.is_empty() {
+            entries.retain(|entry| !self.is_hidden(&path.join(&entry.name)));
         }
+        Ok(entries)
     }
 
     // TODO: should fail if called on a non-dir
     fn releasedir(&self, _req: RequestInfo, path: &Path, fh: u64, _flags: u32) -> ResultEmpty {
         debug!("releasedir: {:?}", path);
-        match self.file_handles.lock().unwrap().free_handle(fh) {
-            Ok(Descriptor::Handle(handle)) => libc_wrappers::closedir(handle),
+        match self.file_handles.free_handle(fh) {
+            Ok(Descriptor::Handle(handle)) => libc_wrappers::close(handle),
             Ok(Descriptor::Path(_))
-             | Ok(Descriptor::File { path: _, cursor: _ })
+             | Ok(Descriptor::File { .. })
              | Ok(Descriptor::Lazy(_))
              | Ok(Descriptor::Error(_)) => Ok(()),
             Err(_) => Err(libc::EBADF),
@@ -594,7 +2084,7 @@ impl FilesystemMT for PassthroughFS {
     fn fsyncdir(&self, _req: RequestInfo, path: &Path, fh: u64, datasync: bool) -> ResultEmpty {
         debug!("fsyncdir: {:?} (datasync = {:?})", path, datasync);
 
-        let handle = match self.file_handles.lock().unwrap().find(fh) {
+        let handle = match self.file_handles.find(fh).as_deref_mut() {
             Ok(Descriptor::Handle(h)) => *h,
             _ => return Err(libc::EACCES),
         };
@@ -612,6 +2102,10 @@ impl FilesystemMT for PassthroughFS {
 
     fn statfs(&self, _req: RequestInfo, path: &Path) -> ResultStatfs {
         debug!("statfs: {:?}", path);
+        if self.offline() {
+            let (blocks, files) = self.cache().all_entries().usage();
+            return Ok(cached_statfs(blocks, files));
+        }
 
         let real = self.real_path(path);
         let mut buf: libc::statfs = unsafe { ::std::mem::zeroed() };
@@ -621,15 +2115,17 @@ impl FilesystemMT for PassthroughFS {
         };
 
         if -1 == result {
+            // Rather than failing while the source is unreachable, describe what the cache
+            // holds, which is all that can be served anyway.
             let e = io::Error::last_os_error();
-            error!("statfs({:?}): {}", path, e);
-            Err(e.raw_os_error().unwrap())
+            warn!("statfs({:?}): {}, reporting the cached tree instead", path, e);
+            let (blocks, files) = self.cache().all_entries().usage();
+            Ok(cached_statfs(blocks, files))
         } else {
             Ok(statfs_to_fuse(buf))
         }
     }
 
-    #[allow(unused_variables)]
     fn setxattr(
         &self,
         _req: RequestInfo,
@@ -639,13 +2135,42 @@ impl FilesystemMT for PassthroughFS {
         flags: u32,
         position: u32,
     ) -> ResultEmpty {
-        Err(libc::ENOSYS)
+        debug!(
+            "setxattr: {:?} {:?} {} bytes, flags = {:#x}, pos = {}",
+            path,
+            name,
+            value.len(),
+            flags,
+            position
+        );
+        if !self.options.writable {
+            return Err(self.denied());
+        }
+        libc_wrappers::lsetxattr(
+            self.real_path(path),
+            name.to_owned(),
+            value,
+            flags,
+            position,
+        )?;
+        self.cache().forget_xattrs(path);
+        Ok(())
     }
 
     fn getxattr(&self, _req: RequestInfo, path: &Path, name: &OsStr, size: u32) -> ResultXattr {
         debug!("getxattr: {:?} {:?} {}", path, name, size);
+        if let Some(xattrs) = self.cached_xattrs(path) {
+            // names that aren't UTF-8 would have kept them from being recorded
+            return match name.to_str().and_then(|name| xattrs.get(name)) {
+                Some(value) => xattr_reply(value.clone(), size),
+                None => Err(libc::ENODATA),
+            };
+        }
+        if self.offline() {
+            return Err(libc::ENOTSUP);
+        }
 
-        let real = self.real_path(path);
+        let real = self.layer_path(path);
 
         if size > 0 {
             let mut data = Vec::<u8>::with_capacity(size as usize);
@@ -661,8 +2186,19 @@ impl FilesystemMT for PassthroughFS {
 
     fn listxattr(&self, _req: RequestInfo, path: &Path, size: u32) -> ResultXattr {
         debug!("listxattr: {:?}", path);
+        if let Some(xattrs) = self.cached_xattrs(path) {
+            let mut names = Vec::new();
+            for name in xattrs.keys() {
+                names.extend_from_slice(name.as_bytes());
+                names.push(0);
+            }
+            return xattr_reply(names, size);
+        }
+        if self.offline() {
+            return Err(libc::ENOTSUP);
+        }
 
-        let real = self.real_path(path);
+        let real = self.layer_path(path);
 
         if size > 0 {
             let mut data = Vec::<u8>::with_capacity(size as usize);
@@ -676,12 +2212,16 @@ impl FilesystemMT for PassthroughFS {
         }
     }
 
-    #[allow(unused_variables)]
     fn removexattr(&self, _req: RequestInfo, path: &Path, name: &OsStr) -> ResultEmpty {
-        Err(libc::ENOSYS)
+        debug!("removexattr: {:?} {:?}", path, name);
+        if !self.options.writable {
+            return Err(self.denied());
+        }
+        libc_wrappers::lremovexattr(self.real_path(path), name.to_owned())?;
+        self.cache().forget_xattrs(path);
+        Ok(())
     }
 
-    #[allow(unused_variables)]
     fn create(
         &self,
         _req: RequestInfo,
@@ -690,20 +2230,59 @@ impl FilesystemMT for PassthroughFS {
         mode: u32,
         flags: u32,
     ) -> ResultCreate {
-        Err(libc::ENOSYS)
+        debug!("create: {:?}/{:?} (mode={:#o}, flags={:#x})", parent, name, mode, flags);
+        let path = parent.join(name);
+        let target = if self.options.writable {
+            self.real_path(&path)
+        } else {
+            let overlay = self.require_overlay()?;
+            if self.stat_real(&path).is_ok() {
+                // O_CREAT on an existing file just opens it, so work on a copy.
+                self.copy_up(overlay, &path)?;
+            } else {
+                self.prepare_upper_entry(overlay, &path)?;
+            }
+            overlay.path(&path).into_os_string()
+        };
+
+        let (flags, reply) = self.direct_io(flags);
+        let fh = match libc_wrappers::create(target, flags as libc::c_int, mode as libc::mode_t) {
+            Ok(fh) => fh,
+            Err(e) => {
+                error!("create({:?}): {}", path, io::Error::from_raw_os_error(e));
+                return Err(e);
+            }
+        };
+        if self.options.writable {
+            // adds the entry to the tree, served from the source
+            self.invalidate(&path);
+        }
+        let attr = match libc_wrappers::fstat(fh) {
+            Ok(stat) => stat_to_fuse(stat),
+            Err(e) => {
+                let _ = libc_wrappers::close(fh);
+                return Err(e);
+            }
+        };
+        Ok(CreatedEntry {
+            ttl: TTL,
+            attr: self.map_owner(attr),
+            fh: self.file_handles.register_handle(Descriptor::Handle(fh)),
+            flags: self.cache_hints(false, reply),
+        })
     }
 
     #[cfg(target_os = "macos")]
     fn setvolname(&self, _req: RequestInfo, name: &OsStr) -> ResultEmpty {
-        Err(libc::ENOSYS)
+        Err(self.denied())
     }
 
     #[cfg(target_os = "macos")]
     fn getxtimes(&self, _req: RequestInfo, path: &Path) -> ResultXTimes {
         debug!("getxtimes: {:?}", path);
         let xtimes = XTimes {
-            bkuptime: Timespec { sec: 0, nsec: 0 },
-            crtime: Timespec { sec: 0, nsec: 0 },
+            bkuptime: std::time::UNIX_EPOCH,
+            crtime: std::time::UNIX_EPOCH,
         };
         Ok(xtimes)
     }
@@ -726,6 +2305,9 @@ impl UnmanagedFile {
     fn sync_data(&self) -> io::Result<()> {
         self.inner.as_ref().unwrap().sync_data()
     }
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.inner.as_ref().unwrap().read_at(buf, offset)
+    }
 }
 
 impl Drop for UnmanagedFile {
@@ -0,0 +1,212 @@
+// Placeholder covers :: Simple tiles for songs that come without artwork.
+//
+// The tiles are colored by a hash of artist and title, so the same song always gets the same
+// tile, and have both rendered onto them using a built-in 5x7 bitmap font.
+
+use image::{Rgb, RgbImage};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+
+/// Edge length of a placeholder tile in pixels.
+pub const SIZE: u32 = 256;
+
+const SCALE: u32 = 3;
+const GLYPH_WIDTH: u32 = 5;
+const GLYPH_HEIGHT: u32 = 7;
+const ADVANCE: u32 = (GLYPH_WIDTH + 1) * SCALE;
+const LINE_HEIGHT: u32 = (GLYPH_HEIGHT + 3) * SCALE;
+const MARGIN: u32 = 12;
+const CHARS_PER_LINE: usize = ((SIZE - 2 * MARGIN) / ADVANCE) as usize;
+const ARTIST_LINES: usize = 3;
+const TITLE_LINES: usize = 4;
+
+pub fn render(artist: &str, title: &str) -> RgbImage {
+    let background = tile_color(artist, title);
+    let mut image = RgbImage::from_pixel(SIZE, SIZE, background);
+
+    let artist = wrap(artist, ARTIST_LINES);
+    let title = wrap(title, TITLE_LINES);
+    // one empty line between artist and title
+    let lines = artist.len() + title.len() + 1;
+    let mut y = SIZE.saturating_sub(lines as u32 * LINE_HEIGHT) / 2;
+
+    for line in &artist {
+        draw_line(&mut image, line, y);
+        y += LINE_HEIGHT;
+    }
+    y += LINE_HEIGHT;
+    for line in &title {
+        draw_line(&mut image, line, y);
+        y += LINE_HEIGHT;
+    }
+
+    image
+}
+
+fn tile_color(artist: &str, title: &str) -> Rgb<u8> {
+    let mut hasher = DefaultHasher::new();
+    artist.hash(&mut hasher);
+    title.hash(&mut hasher);
+    let hue = (hasher.finish() % 360) as f32;
+
+    // HSV with fixed saturation and value, dark enough for white text
+    let (s, v) = (0.55, 0.55);
+    let c = v * s;
+    let x = c * (1.0 - ((hue / 60.0) % 2.0 - 1.0).abs());
+    let m = v - c;
+    let (r, g, b) = match hue as u32 / 60 {
+        0 => (c, x, 0.0),
+        1 => (x, c, 0.0),
+        2 => (0.0, c, x),
+        3 => (0.0, x, c),
+        4 => (x, 0.0, c),
+        _ => (c, 0.0, x),
+    };
+    Rgb([
+        ((r + m) * 255.0) as u8,
+        ((g + m) * 255.0) as u8,
+        ((b + m) * 255.0) as u8,
+    ])
+}
+
+/// Splits `text` into at most `max_lines` lines that fit onto the tile, breaking at spaces
+/// where possible.
+fn wrap(text: &str, max_lines: usize) -> Vec<Vec<char>> {
+    let mut lines: Vec<Vec<char>> = vec![];
+    let mut current: Vec<char> = vec![];
+
+    for word in text.split_whitespace() {
+        let mut word: Vec<char> = word.chars().map(fold).collect();
+        loop {
+            let needed = if current.is_empty() {
+                word.len()
+            } else {
+                current.len() + 1 + word.len()
+            };
+            if needed <= CHARS_PER_LINE {
+                if !current.is_empty() {
+                    current.push(' ');
+                }
+                current.append(&mut word);
+                break;
+            }
+            if current.is_empty() {
+                // the word alone is too long, hard break it
+                let rest = word.split_off(CHARS_PER_LINE);
+                lines.push(word);
+                word = rest;
+            } else {
+                lines.push(std::mem::take(&mut current));
+            }
+        }
+    }
+    if !current.is_empty() {
+        lines.push(current);
+    }
+
+    if lines.len() > max_lines {
+        lines.truncate(max_lines);
+        let last = &mut lines[max_lines - 1];
+        last.truncate(CHARS_PER_LINE - 3);
+        last.extend_from_slice(&['.', '.', '.']);
+    }
+    lines
+}
+
+fn draw_line(image: &mut RgbImage, line: &[char], y: u32) {
+    let width = line.len() as u32 * ADVANCE - SCALE;
+    let mut x = SIZE.saturating_sub(width) / 2;
+    for &c in line {
+        draw_glyph(image, glyph(c), x, y);
+        x += ADVANCE;
+    }
+}
+
+fn draw_glyph(image: &mut RgbImage, glyph: [u8; 7], x: u32, y: u32) {
+    for (row, bits) in glyph.iter().enumerate() {
+        for col in 0..GLYPH_WIDTH {
+            if bits & (1 << (GLYPH_WIDTH - 1 - col)) == 0 {
+                continue;
+            }
+            for dy in 0..SCALE {
+                for dx in 0..SCALE {
+                    let px = x + col * SCALE + dx;
+                    let py = y + row as u32 * SCALE + dy;
+                    if px < SIZE && py < SIZE {
+                        image.put_pixel(px, py, Rgb([255, 255, 255]));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Maps a character onto the subset the font covers.
+fn fold(c: char) -> char {
+    match c {
+        'À'..='Å' | 'à'..='å' => 'A',
+        'Ç' | 'ç' => 'C',
+        'È'..='Ë' | 'è'..='ë' => 'E',
+        'Ì'..='Ï' | 'ì'..='ï' => 'I',
+        'Ñ' | 'ñ' => 'N',
+        'Ò'..='Ö' | 'Ø' | 'ò'..='ö' | 'ø' => 'O',
+        'Ù'..='Ü' | 'ù'..='ü' => 'U',
+        'Ý' | 'ý' | 'ÿ' => 'Y',
+        'ß' => 'S',
+        c => c.to_ascii_uppercase(),
+    }
+}
+
+fn glyph(c: char) -> [u8; 7] {
+    match c {
+        'A' => [0x0E, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'B' => [0x1E, 0x11, 0x11, 0x1E, 0x11, 0x11, 0x1E],
+        'C' => [0x0E, 0x11, 0x10, 0x10, 0x10, 0x11, 0x0E],
+        'D' => [0x1C, 0x12, 0x11, 0x11, 0x11, 0x12, 0x1C],
+        'E' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x1F],
+        'F' => [0x1F, 0x10, 0x10, 0x1E, 0x10, 0x10, 0x10],
+        'G' => [0x0E, 0x11, 0x10, 0x17, 0x11, 0x11, 0x0F],
+        'H' => [0x11, 0x11, 0x11, 0x1F, 0x11, 0x11, 0x11],
+        'I' => [0x0E, 0x04, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        'J' => [0x07, 0x02, 0x02, 0x02, 0x02, 0x12, 0x0C],
+        'K' => [0x11, 0x12, 0x14, 0x18, 0x14, 0x12, 0x11],
+        'L' => [0x10, 0x10, 0x10, 0x10, 0x10, 0x10, 0x1F],
+        'M' => [0x11, 0x1B, 0x15, 0x15, 0x11, 0x11, 0x11],
+        'N' => [0x11, 0x11, 0x19, 0x15, 0x13, 0x11, 0x11],
+        'O' => [0x0E, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'P' => [0x1E, 0x11, 0x11, 0x1E, 0x10, 0x10, 0x10],
+        'Q' => [0x0E, 0x11, 0x11, 0x11, 0x15, 0x12, 0x0D],
+        'R' => [0x1E, 0x11, 0x11, 0x1E, 0x14, 0x12, 0x11],
+        'S' => [0x0F, 0x10, 0x10, 0x0E, 0x01, 0x01, 0x1E],
+        'T' => [0x1F, 0x04, 0x04, 0x04, 0x04, 0x04, 0x04],
+        'U' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x11, 0x0E],
+        'V' => [0x11, 0x11, 0x11, 0x11, 0x11, 0x0A, 0x04],
+        'W' => [0x11, 0x11, 0x11, 0x15, 0x15, 0x15, 0x0A],
+        'X' => [0x11, 0x11, 0x0A, 0x04, 0x0A, 0x11, 0x11],
+        'Y' => [0x11, 0x11, 0x0A, 0x04, 0x04, 0x04, 0x04],
+        'Z' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x10, 0x1F],
+        '0' => [0x0E, 0x11, 0x13, 0x15, 0x19, 0x11, 0x0E],
+        '1' => [0x04, 0x0C, 0x04, 0x04, 0x04, 0x04, 0x0E],
+        '2' => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x08, 0x1F],
+        '3' => [0x1F, 0x02, 0x04, 0x02, 0x01, 0x11, 0x0E],
+        '4' => [0x02, 0x06, 0x0A, 0x12, 0x1F, 0x02, 0x02],
+        '5' => [0x1F, 0x10, 0x1E, 0x01, 0x01, 0x11, 0x0E],
+        '6' => [0x06, 0x08, 0x10, 0x1E, 0x11, 0x11, 0x0E],
+        '7' => [0x1F, 0x01, 0x02, 0x04, 0x08, 0x08, 0x08],
+        '8' => [0x0E, 0x11, 0x11, 0x0E, 0x11, 0x11, 0x0E],
+        '9' => [0x0E, 0x11, 0x11, 0x0F, 0x01, 0x02, 0x0C],
+        ' ' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00],
+        '-' => [0x00, 0x00, 0x00, 0x1F, 0x00, 0x00, 0x00],
+        '.' => [0x00, 0x00, 0x00, 0x00, 0x00, 0x0C, 0x0C],
+        ',' => [0x00, 0x00, 0x00, 0x00, 0x0C, 0x04, 0x08],
+        ':' => [0x00, 0x0C, 0x0C, 0x00, 0x0C, 0x0C, 0x00],
+        '\'' => [0x04, 0x04, 0x08, 0x00, 0x00, 0x00, 0x00],
+        '!' => [0x04, 0x04, 0x04, 0x04, 0x04, 0x00, 0x04],
+        '&' => [0x0C, 0x12, 0x14, 0x08, 0x15, 0x12, 0x0D],
+        '(' => [0x02, 0x04, 0x08, 0x08, 0x08, 0x04, 0x02],
+        ')' => [0x08, 0x04, 0x02, 0x02, 0x02, 0x04, 0x08],
+        '/' => [0x00, 0x01, 0x02, 0x04, 0x08, 0x10, 0x00],
+        // '?' and everything the font doesn't know
+        _ => [0x0E, 0x11, 0x01, 0x02, 0x04, 0x00, 0x04],
+    }
+}
@@ -0,0 +1,244 @@
+// Prefetch :: Warms up the media of a song while its song file is served from the cache.
+//
+// Opening a song file is usually followed by reads of its cover, background and audio, which all
+// have to come from the source. Their first bytes are read in the background as soon as the song
+// file is opened, so the source has them at hand by the time they are needed.
+//
+// While the song list is scrolled through, or scanned, song folders are accessed one after the
+// other in the order they are listed. Once a few were accessed like that, the song files of the
+// next ones are loaded from the cache into memory, and the headers of their audio read from the
+// source, before they are asked for.
+//
+
+use crate::cache::{Cache, Entry};
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread;
+
+/// How much of each file is read ahead.
+const PREFETCH_SIZE: u64 = 256 * 1024;
+/// How much of the audio of upcoming songs is read ahead, enough for its headers.
+const HEADER_SIZE: u64 = 64 * 1024;
+/// How many song folders are remembered, which aren't prefetched again.
+const RECENT: usize = 64;
+/// Songs accessed in a row in the order of their folder before the next ones are prefetched.
+const SEQUENTIAL: u32 = 2;
+/// How many songs are prefetched ahead.
+const AHEAD: isize = 4;
+const AUDIO: [&str; 7] = ["mp3", "ogg", "opus", "flac", "wav", "m4a", "mp4"];
+
+enum Job {
+    /// Read ahead the files of the song folder at this path in the source.
+    Song(PathBuf),
+    /// A file of the song folder `dir` was accessed, `real` in the source.
+    Accessed {
+        cache: Arc<Cache>,
+        dir: PathBuf,
+        real: PathBuf,
+    },
+}
+
+/// The song folder accessed last, and how the ones before it were.
+struct Position {
+    folder: PathBuf,
+    index: usize,
+    /// 1 while working down the list, -1 while working up.
+    step: isize,
+    /// Songs accessed in a row in that direction.
+    streak: u32,
+}
+
+#[derive(Default)]
+pub struct Prefetcher {
+    /// The worker is started on the first request, so it runs in the sandbox of the thread
+    /// handling it.
+    sender: Mutex<Option<Sender<Job>>>,
+}
+
+impl Prefetcher {
+    /// Reads ahead the files next to the song file at `real`, its path in the source.
+    pub fn song_opened(&self, real: &Path) {
+        if let Some(dir) = real.parent() {
+            self.send(Job::Song(dir.to_path_buf()));
+        }
+    }
+
+    /// Notes that a file in the folder `dir`, at `real` in the source, was accessed, to prefetch
+    /// the songs after it if they are worked through in order.
+    pub fn song_accessed(&self, cache: Arc<Cache>, dir: &Path, real: &Path) {
+        self.send(Job::Accessed {
+            cache,
+            dir: dir.to_path_buf(),
+            real: real.to_path_buf(),
+        });
+    }
+
+    fn send(&self, job: Job) {
+        let mut sender = self.sender.lock().unwrap();
+        let sender = sender.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel();
+            thread::spawn(move || run(receiver));
+            sender
+        });
+        // the worker only stops once the sender is gone
+        let _ = sender.send(job);
+    }
+}
+
+fn run(receiver: Receiver<Job>) {
+    let mut recent = VecDeque::with_capacity(RECENT);
+    let mut ahead = VecDeque::with_capacity(RECENT);
+    let mut position = None;
+    for job in receiver {
+        match job {
+            Job::Song(dir) => {
+                if remember(&mut recent, &dir) {
+                    prefetch_dir(&dir);
+                }
+            }
+            Job::Accessed { cache, dir, real } => {
+                let (folder, real_folder) = match (dir.parent(), real.parent()) {
+                    (Some(folder), Some(real_folder)) => (folder, real_folder),
+                    _ => continue,
+                };
+                for name in upcoming(&mut position, &cache, &dir) {
+                    let dir = folder.join(&name);
+                    if remember(&mut ahead, &dir) {
+                        prefetch_song(&cache, &dir, &real_folder.join(&name));
+                    }
+                }
+            }
+        }
+    }
+}
+
+/// Adds `dir` to the folders prefetched recently, returning whether it wasn't among them.
+fn remember(recent: &mut VecDeque<PathBuf>, dir: &Path) -> bool {
+    if recent.iter().any(|other| other == dir) {
+        return false;
+    }
+    if recent.len() == RECENT {
+        recent.pop_front();
+    }
+    recent.push_back(dir.to_path_buf());
+    true
+}
+
+/// Moves `position` to the song folder `dir`, returning the names of the folders next to it
+/// that come next if songs are accessed in the order they are listed.
+fn upcoming(position: &mut Option<Position>, cache: &Cache, dir: &Path) -> Vec<OsString> {
+    let (folder, name) = match (dir.parent(), dir.file_name()) {
+        (Some(folder), Some(name)) => (folder, name),
+        _ => return Vec::new(),
+    };
+    let entries = cache.entries(folder);
+    let contents = match entries.find(folder) {
+        Ok(Entry::Dict { contents, .. }) => contents,
+        _ => return Vec::new(),
+    };
+    // contents are sorted by name
+    let index = match contents.binary_search_by(|entry| entry.name().cmp(name)) {
+        Ok(index) => index,
+        Err(_) => return Vec::new(),
+    };
+    let (step, streak) = match position {
+        Some(last) if last.folder == folder => {
+            let step = index as isize - last.index as isize;
+            if step == 0 {
+                // another file of the same song
+                return Vec::new();
+            }
+            let in_order = step.abs() == 1 && (last.streak == 0 || last.step == step);
+            (step, if in_order { last.streak + 1 } else { 0 })
+        }
+        _ => (0, 0),
+    };
+    *position = Some(Position {
+        folder: folder.to_path_buf(),
+        index,
+        step,
+        streak,
+    });
+    if streak < SEQUENTIAL {
+        return Vec::new();
+    }
+    (1..=AHEAD)
+        .map(|i| index as isize + i * step)
+        .take_while(|&i| i >= 0)
+        .filter_map(|i| contents.get(i as usize))
+        .filter(|entry| matches!(entry, Entry::Dict { .. }))
+        .map(|entry| entry.name().to_os_string())
+        .collect()
+}
+
+/// Keeps the song files of the song folder `dir` in memory and reads the headers of its audio
+/// at `real` in the source.
+fn prefetch_song(cache: &Cache, dir: &Path, real: &Path) {
+    let files: Vec<OsString> = {
+        let entries = cache.entries(dir);
+        match entries.find(dir) {
+            Ok(Entry::Dict { contents, .. }) => contents
+                .iter()
+                .filter(|entry| matches!(entry, Entry::File { .. }))
+                .map(|entry| entry.name().to_os_string())
+                .collect(),
+            _ => return,
+        }
+    };
+    for name in files {
+        let extension = Path::new(&name)
+            .extension()
+            .and_then(|x| x.to_str())
+            .map(str::to_lowercase);
+        match extension.as_deref() {
+            Some("txt") => {
+                if let Some(Err(e)) = cache.read_file(&dir.join(&name)) {
+                    debug!("prefetch: unable to read {:?} from the cache: {}", name, e);
+                }
+            }
+            Some(extension) if AUDIO.contains(&extension) => {
+                let path = real.join(&name);
+                if let Err(e) = prefetch_file(&path, HEADER_SIZE) {
+                    debug!("prefetch: unable to read {:?}: {}", path, e);
+                }
+            }
+            _ => {}
+        }
+    }
+}
+
+fn prefetch_dir(dir: &Path) {
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            debug!("prefetch: unable to list {:?}: {}", dir, e);
+            return;
+        }
+    };
+    for entry in entries.filter_map(|e| e.ok()) {
+        let path = entry.path();
+        // song files are served from the cache
+        if path.extension().is_some_and(|x| x == "txt") {
+            continue;
+        }
+        match entry.file_type() {
+            Ok(file_type) if file_type.is_file() => {}
+            _ => continue,
+        }
+        if let Err(e) = prefetch_file(&path, PREFETCH_SIZE) {
+            debug!("prefetch: unable to read {:?}: {}", path, e);
+        }
+    }
+}
+
+fn prefetch_file(path: &Path, size: u64) -> io::Result<()> {
+    // Network filesystems tend to ignore read-ahead advice, so actually read.
+    let file = File::open(path)?;
+    io::copy(&mut file.take(size), &mut io::sink())?;
+    Ok(())
+}
@@ -0,0 +1,229 @@
+// Readahead :: Buffers media streamed from the source ahead of where it is read.
+//
+// Reads of files served from the source wait for it each time, which stalls playback over
+// high-latency links. Once a read-only handle was read sequentially a few times, a thread reads
+// the file ahead of it into memory and the reads are answered from there. Reading anywhere else
+// stops the thread again, until the reads are sequential once more.
+//
+
+use crate::libc_extras::libc;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How much is read from the source at once.
+const CHUNK: usize = 256 * 1024;
+/// Sequential reads in a row before reading ahead.
+const SEQUENTIAL: u32 = 2;
+
+pub struct Readahead {
+    /// Bytes buffered ahead of each handle.
+    size: usize,
+    /// How long a read waits for the thread at most.
+    timeout: Option<Duration>,
+    handles: Mutex<HashMap<u64, Arc<Mutex<Tracker>>>>,
+}
+
+#[derive(Default)]
+struct Tracker {
+    /// Where the next read starts if the handle is read sequentially.
+    next: u64,
+    sequential: u32,
+    stream: Option<Stream>,
+}
+
+impl Readahead {
+    pub fn new(size: usize, timeout: Option<Duration>) -> Self {
+        Self {
+            size,
+            timeout,
+            handles: Mutex::default(),
+        }
+    }
+
+    /// Starts watching the reads of the handle `fh`.
+    pub fn track(&self, fh: u64) {
+        self.handles.lock().unwrap().insert(fh, Arc::default());
+    }
+
+    /// Stops watching the handle `fh`, which is released.
+    pub fn forget(&self, fh: u64) {
+        self.handles.lock().unwrap().remove(&fh);
+    }
+
+    /// Reads `size` bytes at `offset` of the handle `fh` open as `fd` from the buffer, or returns
+    /// `None` if they have to be read from the source.
+    pub fn read(
+        &self,
+        fh: u64,
+        fd: u64,
+        offset: u64,
+        size: usize,
+    ) -> Option<Result<Vec<u8>, libc::c_int>> {
+        let tracker = self.handles.lock().unwrap().get(&fh).cloned()?;
+        let mut tracker = tracker.lock().unwrap();
+        if let Some(stream) = &tracker.stream {
+            if let Some(result) = stream.read(offset, size, self.timeout) {
+                return Some(result);
+            }
+            tracker.stream = None;
+        }
+        if offset == tracker.next {
+            tracker.sequential += 1;
+        } else {
+            tracker.sequential = 0;
+        }
+        tracker.next = offset + size as u64;
+        if tracker.sequential >= SEQUENTIAL {
+            match Stream::start(fd, tracker.next, self.size) {
+                Ok(stream) => tracker.stream = Some(stream),
+                Err(e) => debug!("readahead: unable to start: {}", e),
+            }
+        }
+        None
+    }
+}
+
+/// A thread reading ahead of a handle.
+struct Stream {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    state: Mutex<State>,
+    changed: Condvar,
+}
+
+struct State {
+    /// The offset of the first byte of `data`.
+    start: u64,
+    data: VecDeque<u8>,
+    /// Set once the end of the file or an error was reached after `data`.
+    end: Option<Result<(), libc::c_int>>,
+    stop: bool,
+}
+
+impl Stream {
+    /// Reads ahead of `offset` in the file open as `fd`, keeping up to `size` bytes buffered.
+    fn start(fd: u64, offset: u64, size: usize) -> io::Result<Self> {
+        // the handle may be released while a read is running
+        let fd = unsafe { libc::dup(fd as libc::c_int) };
+        if fd == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        let shared = Arc::new(Shared {
+            state: Mutex::new(State {
+                start: offset,
+                data: VecDeque::new(),
+                end: None,
+                stop: false,
+            }),
+            changed: Condvar::new(),
+        });
+        let worker = shared.clone();
+        thread::spawn(move || {
+            run(&worker, fd, size);
+            unsafe {
+                libc::close(fd);
+            }
+        });
+        Ok(Self { shared })
+    }
+
+    /// Reads `size` bytes at `offset` from the buffer, waiting for them up to `timeout` if
+    /// necessary, or returns `None` if `offset` isn't buffered.
+    fn read(
+        &self,
+        offset: u64,
+        size: usize,
+        timeout: Option<Duration>,
+    ) -> Option<Result<Vec<u8>, libc::c_int>> {
+        let deadline = timeout.map(|timeout| Instant::now() + timeout);
+        let mut state = self.shared.state.lock().unwrap();
+        if offset < state.start || offset > state.start + state.data.len() as u64 {
+            return None;
+        }
+        let skip = (offset - state.start) as usize;
+        state.data.drain(..skip);
+        state.start = offset;
+        self.shared.changed.notify_all();
+        while state.data.len() < size && state.end.is_none() {
+            state = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        warn!("readahead: no answer within {:?}", timeout.unwrap());
+                        return Some(Err(libc::EIO));
+                    }
+                    self.shared
+                        .changed
+                        .wait_timeout(state, deadline - now)
+                        .unwrap()
+                        .0
+                }
+                None => self.shared.changed.wait(state).unwrap(),
+            };
+        }
+        if let Some(Err(e)) = state.end {
+            if state.data.is_empty() {
+                return Some(Err(e));
+            }
+        }
+        let n = size.min(state.data.len());
+        let data: Vec<u8> = state.data.drain(..n).collect();
+        state.start += n as u64;
+        self.shared.changed.notify_all();
+        Some(Ok(data))
+    }
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        self.shared.state.lock().unwrap().stop = true;
+        self.shared.changed.notify_all();
+    }
+}
+
+fn run(shared: &Shared, fd: libc::c_int, size: usize) {
+    let mut buf = vec![0; CHUNK];
+    loop {
+        let position = {
+            let mut state = shared.state.lock().unwrap();
+            while !state.stop && state.data.len() >= size {
+                state = shared.changed.wait(state).unwrap();
+            }
+            if state.stop {
+                return;
+            }
+            state.start + state.data.len() as u64
+        };
+        let n = unsafe {
+            libc::pread(
+                fd,
+                buf.as_mut_ptr() as *mut libc::c_void,
+                buf.len(),
+                position as libc::off_t,
+            )
+        };
+        let error = io::Error::last_os_error();
+
+        let mut state = shared.state.lock().unwrap();
+        if state.stop {
+            return;
+        }
+        match n {
+            -1 => {
+                debug!("readahead: {}", error);
+                state.end = Some(Err(error.raw_os_error().unwrap()));
+            }
+            0 => state.end = Some(Ok(())),
+            n => state.data.extend(&buf[..n as usize]),
+        }
+        shared.changed.notify_all();
+        if state.end.is_some() {
+            return;
+        }
+    }
+}
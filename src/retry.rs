@@ -0,0 +1,107 @@
+// Retry :: Repeats calls on the source that fail with errors that tend to go away by themselves.
+//
+// SMB and NFS clients occasionally fail a single open or read with EIO or a timeout while the
+// connection is reestablished. Instead of passing that on, which makes USDX drop the song, the
+// call is repeated a few times with a growing delay, stalling playback for a moment at most.
+//
+
+use crate::libc_extras::libc;
+use anyhow::{anyhow, Result};
+use std::fmt;
+use std::io;
+use std::thread::sleep;
+use std::time::Duration;
+
+/// Errors that can be named in a retry policy.
+const ERRNOS: [(&str, libc::c_int); 10] = [
+    ("eio", libc::EIO),
+    ("eagain", libc::EAGAIN),
+    ("eintr", libc::EINTR),
+    ("ebusy", libc::EBUSY),
+    ("etimedout", libc::ETIMEDOUT),
+    ("econnreset", libc::ECONNRESET),
+    ("econnaborted", libc::ECONNABORTED),
+    ("ehostunreach", libc::EHOSTUNREACH),
+    ("enetunreach", libc::ENETUNREACH),
+    ("estale", libc::ESTALE),
+];
+
+/// The errors retried unless others are given.
+pub const DEFAULT_ERRNOS: &str = "eio,eagain,etimedout,econnreset";
+
+/// How often and after which errors calls on the source are repeated.
+#[derive(Debug, Clone)]
+pub struct Retry {
+    /// How often a call is repeated at most, none by default.
+    pub attempts: u32,
+    /// The delay before the first repetition, which doubles for each one after.
+    pub backoff: Duration,
+    pub errnos: Vec<libc::c_int>,
+}
+
+impl Default for Retry {
+    fn default() -> Self {
+        Self {
+            attempts: 0,
+            backoff: Duration::from_millis(100),
+            errnos: parse_errnos(DEFAULT_ERRNOS).expect("default errors are known"),
+        }
+    }
+}
+
+/// Parses a comma separated list of error names like `eio,etimedout`.
+pub fn parse_errnos(spec: &str) -> Result<Vec<libc::c_int>> {
+    spec.split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(|name| {
+            let lower = name.to_lowercase();
+            ERRNOS
+                .iter()
+                .find(|(known, _)| *known == lower)
+                .map(|&(_, errno)| errno)
+                .ok_or_else(|| {
+                    let names: Vec<_> = ERRNOS.iter().map(|(known, _)| *known).collect();
+                    anyhow!(
+                        "Unknown error '{}', expected one of {}",
+                        name,
+                        names.join(", ")
+                    )
+                })
+        })
+        .collect()
+}
+
+impl Retry {
+    /// Whether calls failing with `errno` are repeated.
+    pub fn retries(&self, errno: libc::c_int) -> bool {
+        self.attempts > 0 && self.errnos.contains(&errno)
+    }
+
+    /// Runs `call` until it succeeds, fails with an error that isn't retried or ran out of
+    /// attempts. `what` names the call in the log.
+    pub fn run<T>(
+        &self,
+        what: impl fmt::Display,
+        mut call: impl FnMut() -> Result<T, libc::c_int>,
+    ) -> Result<T, libc::c_int> {
+        let mut delay = self.backoff;
+        let mut attempts = 0;
+        loop {
+            match call() {
+                Err(e) if attempts < self.attempts && self.errnos.contains(&e) => {
+                    attempts += 1;
+                    warn!(
+                        "{}: {}, retrying in {:?}",
+                        what,
+                        io::Error::from_raw_os_error(e),
+                        delay
+                    );
+                    sleep(delay);
+                    delay *= 2;
+                }
+                result => return result,
+            }
+        }
+    }
+}
@@ -0,0 +1,261 @@
+// Sandbox :: Restricts a running mount to the files it actually needs.
+//
+// Filesystem access is limited with Landlock, and syscalls a passthrough filesystem never needs
+// (exec, ptrace, mount, module loading, ...) are refused with a seccomp filter. Both only apply to
+// the calling thread and the threads it spawns afterwards.
+//
+// Landlock ABI: https://docs.kernel.org/userspace-api/landlock.html
+//
+
+use crate::libc_extras::libc;
+use anyhow::{anyhow, Context, Result};
+use std::ffi::CString;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::ptr;
+
+// The landlock syscalls share their numbers across all architectures.
+const SYS_LANDLOCK_CREATE_RULESET: libc::c_long = 444;
+const SYS_LANDLOCK_ADD_RULE: libc::c_long = 445;
+const SYS_LANDLOCK_RESTRICT_SELF: libc::c_long = 446;
+
+const LANDLOCK_CREATE_RULESET_VERSION: u32 = 1;
+const LANDLOCK_RULE_PATH_BENEATH: libc::c_int = 1;
+
+const ACCESS_FS_EXECUTE: u64 = 1 << 0;
+const ACCESS_FS_WRITE_FILE: u64 = 1 << 1;
+const ACCESS_FS_READ_FILE: u64 = 1 << 2;
+const ACCESS_FS_READ_DIR: u64 = 1 << 3;
+// All rights of ABI v1, from EXECUTE up to MAKE_SYM.
+const ACCESS_FS_ALL: u64 = (1 << 13) - 1;
+
+#[repr(C)]
+struct RulesetAttr {
+    handled_access_fs: u64,
+}
+
+#[repr(C, packed)]
+struct PathBeneathAttr {
+    allowed_access: u64,
+    parent_fd: i32,
+}
+
+pub struct Sandbox {
+    ruleset: libc::c_int,
+}
+
+impl Sandbox {
+    /// Prepares a sandbox allowing access to `cache` and `/dev/fuse` only.
+    pub fn new<P: AsRef<Path>>(cache: P) -> Result<Self> {
+        let abi = unsafe {
+            libc::syscall(
+                SYS_LANDLOCK_CREATE_RULESET,
+                ptr::null::<RulesetAttr>(),
+                0usize,
+                LANDLOCK_CREATE_RULESET_VERSION,
+            )
+        };
+        if abi < 1 {
+            return Err(anyhow!(
+                "Landlock is not available: {}",
+                io::Error::last_os_error()
+            ));
+        }
+
+        let attr = RulesetAttr {
+            handled_access_fs: ACCESS_FS_ALL,
+        };
+        let ruleset = unsafe {
+            libc::syscall(
+                SYS_LANDLOCK_CREATE_RULESET,
+                &attr as *const RulesetAttr,
+                mem::size_of::<RulesetAttr>(),
+                0u32,
+            )
+        };
+        if ruleset < 0 {
+            return Err(io::Error::last_os_error()).context("Unable to create Landlock ruleset");
+        }
+        let sandbox = Sandbox {
+            ruleset: ruleset as libc::c_int,
+        };
+
+        sandbox.allow(cache.as_ref(), ACCESS_FS_READ_FILE)?;
+        sandbox.allow(
+            Path::new("/dev/fuse"),
+            ACCESS_FS_READ_FILE | ACCESS_FS_WRITE_FILE,
+        )?;
+        Ok(sandbox)
+    }
+
+    /// Additionally allows reading the file at `path`.
+    pub fn allow_file_reads<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.allow(path.as_ref(), ACCESS_FS_READ_FILE)
+    }
+
+    /// Additionally allows reading everything beneath `path`.
+    pub fn allow_reads<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.allow(path.as_ref(), ACCESS_FS_READ_FILE | ACCESS_FS_READ_DIR)
+    }
+
+    /// Additionally allows reading and modifying everything beneath `path`, except for executing.
+    pub fn allow_writes<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        self.allow(path.as_ref(), ACCESS_FS_ALL & !ACCESS_FS_EXECUTE)
+    }
+
+    fn allow(&self, path: &Path, access: u64) -> Result<()> {
+        let path_c = CString::new(path.as_os_str().as_bytes())?;
+        let fd = unsafe { libc::open(path_c.as_ptr(), libc::O_PATH | libc::O_CLOEXEC) };
+        if fd < 0 {
+            return Err(io::Error::last_os_error())
+                .with_context(|| format!("Unable to open '{}' for the sandbox", path.display()));
+        }
+
+        let attr = PathBeneathAttr {
+            allowed_access: access,
+            parent_fd: fd,
+        };
+        let result = unsafe {
+            libc::syscall(
+                SYS_LANDLOCK_ADD_RULE,
+                self.ruleset,
+                LANDLOCK_RULE_PATH_BENEATH,
+                &attr as *const PathBeneathAttr,
+                0u32,
+            )
+        };
+        let err = io::Error::last_os_error();
+        unsafe { libc::close(fd) };
+
+        if result < 0 {
+            Err(err).with_context(|| format!("Unable to allow '{}' in the sandbox", path.display()))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Restricts the calling thread, and all threads it spawns from now on.
+    pub fn restrict(&self) -> Result<()> {
+        let zero: libc::c_ulong = 0;
+        if -1 == unsafe { libc::prctl(libc::PR_SET_NO_NEW_PRIVS, 1 as libc::c_ulong, zero, zero, zero) }
+        {
+            return Err(io::Error::last_os_error()).context("Unable to set no_new_privs");
+        }
+        if -1 == unsafe { libc::syscall(SYS_LANDLOCK_RESTRICT_SELF, self.ruleset, 0u32) } {
+            return Err(io::Error::last_os_error()).context("Unable to enforce Landlock ruleset");
+        }
+        install_seccomp_filter()
+    }
+}
+
+impl Drop for Sandbox {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.ruleset) };
+    }
+}
+
+#[cfg(target_arch = "x86_64")]
+const AUDIT_ARCH: u32 = 0xC000_003E;
+#[cfg(target_arch = "aarch64")]
+const AUDIT_ARCH: u32 = 0xC000_00B7;
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+const DENIED_SYSCALLS: &[libc::c_long] = &[
+    libc::SYS_execve,
+    libc::SYS_execveat,
+    libc::SYS_ptrace,
+    libc::SYS_process_vm_readv,
+    libc::SYS_process_vm_writev,
+    libc::SYS_mount,
+    libc::SYS_umount2,
+    libc::SYS_pivot_root,
+    libc::SYS_chroot,
+    libc::SYS_unshare,
+    libc::SYS_setns,
+    libc::SYS_kexec_load,
+    libc::SYS_init_module,
+    libc::SYS_finit_module,
+    libc::SYS_delete_module,
+    libc::SYS_bpf,
+    libc::SYS_perf_event_open,
+    libc::SYS_keyctl,
+    libc::SYS_add_key,
+    libc::SYS_request_key,
+    libc::SYS_swapon,
+    libc::SYS_swapoff,
+    libc::SYS_reboot,
+];
+
+const SECCOMP_MODE_FILTER: libc::c_ulong = 2;
+const SECCOMP_RET_ALLOW: u32 = 0x7fff_0000;
+const SECCOMP_RET_ERRNO: u32 = 0x0005_0000;
+
+const BPF_LD_W_ABS: u16 = 0x20;
+const BPF_JMP_JEQ_K: u16 = 0x15;
+const BPF_JMP_JGE_K: u16 = 0x35;
+const BPF_RET_K: u16 = 0x06;
+
+// offsets into struct seccomp_data
+const SECCOMP_DATA_NR: u32 = 0;
+const SECCOMP_DATA_ARCH: u32 = 4;
+
+/// Set in the numbers of x32 syscalls, which share the architecture of x86_64 but would pass
+/// the checks of their x86_64 numbers.
+const X32_SYSCALL_BIT: u32 = 0x4000_0000;
+
+fn bpf(code: u16, k: u32, jt: u8, jf: u8) -> libc::sock_filter {
+    libc::sock_filter { code, jt, jf, k }
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+fn install_seccomp_filter() -> Result<()> {
+    let deny = SECCOMP_RET_ERRNO | libc::EPERM as u32;
+    let mut filter = vec![
+        bpf(BPF_LD_W_ABS, SECCOMP_DATA_ARCH, 0, 0),
+        bpf(BPF_JMP_JEQ_K, AUDIT_ARCH, 1, 0),
+        bpf(BPF_RET_K, deny, 0, 0),
+        bpf(BPF_LD_W_ABS, SECCOMP_DATA_NR, 0, 0),
+        // jump over the checks below and the ALLOW to the final DENY
+        bpf(
+            BPF_JMP_JGE_K,
+            X32_SYSCALL_BIT,
+            (DENIED_SYSCALLS.len() + 1) as u8,
+            0,
+        ),
+    ];
+    for (i, nr) in DENIED_SYSCALLS.iter().enumerate() {
+        // jump over the remaining checks and the ALLOW to the final DENY
+        let to_deny = (DENIED_SYSCALLS.len() - i) as u8;
+        filter.push(bpf(BPF_JMP_JEQ_K, *nr as u32, to_deny, 0));
+    }
+    filter.push(bpf(BPF_RET_K, SECCOMP_RET_ALLOW, 0, 0));
+    filter.push(bpf(BPF_RET_K, deny, 0, 0));
+
+    let program = libc::sock_fprog {
+        len: filter.len() as u16,
+        filter: filter.as_mut_ptr(),
+    };
+    let zero: libc::c_ulong = 0;
+    if -1
+        == unsafe {
+            libc::prctl(
+                libc::PR_SET_SECCOMP,
+                SECCOMP_MODE_FILTER,
+                &program as *const libc::sock_fprog,
+                zero,
+                zero,
+            )
+        }
+    {
+        return Err(io::Error::last_os_error()).context("Unable to install seccomp filter");
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+fn install_seccomp_filter() -> Result<()> {
+    warn!("No seccomp filter for this architecture, only filesystem access is restricted");
+    Ok(())
+}
@@ -0,0 +1,120 @@
+// Scan progress :: Shows how far UltraStar Deluxe got scanning the song collection.
+//
+// On startup USDX opens every directory of the collection in quick succession. Once that many
+// distinct directories are opened within a short window, a progress bar of the directories seen
+// so far against all directories in the cache is shown. It goes away once every directory was
+// seen, or after no new one was for a while, and the next burst starts over.
+//
+
+use indicatif::{ProgressBar, ProgressStyle};
+use std::collections::{HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// How many distinct directories have to be opened within `BURST_WINDOW` to count as a scan.
+const BURST_SIZE: usize = 20;
+const BURST_WINDOW: Duration = Duration::from_secs(2);
+/// How long a scan may pause before it is considered finished.
+const IDLE: Duration = Duration::from_secs(3);
+const TICK: Duration = Duration::from_millis(500);
+
+struct State {
+    seen: HashSet<PathBuf>,
+    /// When the directories of the current window were first opened.
+    recent: VecDeque<Instant>,
+    last: Instant,
+    bar: Option<ProgressBar>,
+}
+
+impl State {
+    fn reset(&mut self) {
+        self.seen.clear();
+        self.recent.clear();
+        self.bar = None;
+    }
+}
+
+#[derive(Clone)]
+pub struct ScanProgress {
+    state: Arc<Mutex<State>>,
+}
+
+impl Default for ScanProgress {
+    fn default() -> Self {
+        Self {
+            state: Arc::new(Mutex::new(State {
+                seen: HashSet::new(),
+                recent: VecDeque::new(),
+                last: Instant::now(),
+                bar: None,
+            })),
+        }
+    }
+}
+
+impl ScanProgress {
+    /// Records that the directory at `path` was opened, `total` counts all of them.
+    pub fn dir_opened<F: FnOnce() -> u64>(&self, path: &Path, total: F) {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        if state.bar.is_none() && now.duration_since(state.last) > IDLE {
+            state.reset();
+        }
+        if !state.seen.insert(path.to_path_buf()) {
+            return;
+        }
+        state.last = now;
+
+        let seen = state.seen.len() as u64;
+        if let Some(bar) = &state.bar {
+            bar.set_position(seen);
+            if seen >= bar.length() {
+                bar.finish_with_message("scan complete");
+                state.reset();
+            }
+            return;
+        }
+
+        state.recent.push_back(now);
+        while state
+            .recent
+            .front()
+            .is_some_and(|&start| now.duration_since(start) > BURST_WINDOW)
+        {
+            state.recent.pop_front();
+        }
+        if state.recent.len() >= BURST_SIZE {
+            let bar = ProgressBar::new(total());
+            bar.set_style(
+                ProgressStyle::default_bar().template(
+                    "Song scan {bar:40.cyan/blue} {pos}/{len} directories, ETA {eta} {msg}",
+                ),
+            );
+            bar.set_position(seen);
+            state.bar = Some(bar);
+            let progress = self.clone();
+            thread::spawn(move || progress.watch());
+        }
+    }
+
+    /// Ends the progress bar once the scan paused for too long.
+    fn watch(self) {
+        loop {
+            thread::sleep(TICK);
+            let mut state = self.state.lock().unwrap();
+            let bar = match &state.bar {
+                Some(bar) => bar,
+                // finished by dir_opened
+                None => return,
+            };
+            bar.tick();
+            if state.last.elapsed() > IDLE {
+                bar.abandon_with_message("scan finished");
+                state.reset();
+                return;
+            }
+        }
+    }
+}
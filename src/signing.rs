@@ -0,0 +1,117 @@
+// Signing :: Ed25519 keys to sign caches with at build and check their signature at mount.
+//
+// Both keys are kept as hex in text files, the secret one only readable by its owner. What gets
+// signed is the manifest of a cache, see `CacheArchive::manifest`.
+//
+
+use crate::utils::{from_hex, to_hex};
+use anyhow::{anyhow, Context, Result};
+use ed25519_dalek::{Keypair, Signer};
+#[cfg(feature = "mount")]
+use ed25519_dalek::{Signature, Verifier};
+#[cfg(feature = "mount")]
+use std::convert::TryFrom;
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+pub struct SigningKey {
+    keypair: Keypair,
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // never print the secret
+        f.debug_struct("SigningKey")
+            .field("public", &self.public_key())
+            .finish()
+    }
+}
+
+impl SigningKey {
+    pub fn generate() -> Result<Self> {
+        let mut secret = [0; ed25519_dalek::SECRET_KEY_LENGTH];
+        File::open("/dev/urandom")
+            .and_then(|mut random| random.read_exact(&mut secret))
+            .context("Unable to read random bytes")?;
+        Self::from_secret(&secret)
+    }
+
+    fn from_secret(secret: &[u8]) -> Result<Self> {
+        let secret = ed25519_dalek::SecretKey::from_bytes(secret)
+            .map_err(|_| anyhow!("Secret key has to be 32 bytes"))?;
+        let public = ed25519_dalek::PublicKey::from(&secret);
+        Ok(Self {
+            keypair: Keypair { secret, public },
+        })
+    }
+
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        read_hex(path)
+            .and_then(|secret| Self::from_secret(&secret))
+            .with_context(|| format!("'{}' is no valid secret key", path.display()))
+    }
+
+    /// Writes the secret key to a new file at `path`, which only its owner can read.
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path)
+            .and_then(|mut file| writeln!(file, "{}", to_hex(self.keypair.secret.as_bytes())))
+            .with_context(|| format!("Unable to write secret key to '{}'", path.display()))
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        PublicKey(self.keypair.public)
+    }
+
+    /// Signs `message`, returning the signature as hex.
+    pub fn sign(&self, message: &[u8]) -> String {
+        to_hex(&self.keypair.sign(message).to_bytes())
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct PublicKey(ed25519_dalek::PublicKey);
+
+impl PublicKey {
+    #[cfg(feature = "mount")]
+    pub fn read<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        read_hex(path)
+            .and_then(|public| {
+                ed25519_dalek::PublicKey::from_bytes(&public)
+                    .map_err(|_| anyhow!("Public key has to be 32 bytes"))
+            })
+            .map(PublicKey)
+            .with_context(|| format!("'{}' is no valid public key", path.display()))
+    }
+
+    pub fn write<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let path = path.as_ref();
+        fs::write(path, format!("{}\n", to_hex(self.0.as_bytes())))
+            .with_context(|| format!("Unable to write public key to '{}'", path.display()))
+    }
+
+    /// Checks that `signature`, as hex, was made for `message` with the matching secret key.
+    #[cfg(feature = "mount")]
+    pub fn verify(&self, message: &[u8], signature: &str) -> Result<()> {
+        let signature = from_hex(signature)
+            .and_then(|bytes| Signature::try_from(&bytes[..]).ok())
+            .context("Signature is malformed")?;
+        self.0.verify(message, &signature).map_err(|_| {
+            anyhow!("Signature doesn't match, the cache was modified or signed with another key")
+        })
+    }
+}
+
+fn read_hex(path: &Path) -> Result<Vec<u8>> {
+    let hex = fs::read_to_string(path).context("Unable to read it")?;
+    from_hex(hex.trim()).context("It is no hex")
+}
@@ -0,0 +1,118 @@
+// Source health :: Notices when a network source goes away and when it is back.
+//
+// Once the server of an NFS or SMB source disappears, every call on it fails with ESTALE,
+// ENOTCONN or EIO, and handles opened before keep failing even after it came back. The first such
+// error marks the source as lost: the mount then serves what it can from the cache, like with
+// --offline. While lost, the root of the source is probed every few seconds, and handles that
+// fail are opened again once it answers.
+//
+
+use crate::libc_extras::libc;
+use crate::libc_wrappers;
+#[cfg(not(target_os = "linux"))]
+use std::ffi::CStr;
+use std::ffi::{CString, OsStr, OsString};
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long to wait between probes of a lost source.
+const PROBE_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Magic numbers of network filesystems in `f_type`.
+#[cfg(target_os = "linux")]
+const NETWORK_MAGICS: &[u32] = &[
+    0x6969,      // NFS
+    0x517b,      // SMB
+    0xff53_4d42, // CIFS
+    0xfe53_4d42, // SMB2
+    0x6573_5546, // FUSE
+    0x00c3_6400, // Ceph
+    0x5346_414f, // AFS
+    0x7375_7245, // Coda
+    0x0102_1997, // 9P
+];
+/// Names of network filesystems in `f_fstypename`.
+#[cfg(not(target_os = "linux"))]
+const NETWORK_TYPES: &[&[u8]] = &[b"nfs", b"smbfs", b"fusefs"];
+
+pub struct SourceHealth {
+    root: OsString,
+    lost: AtomicBool,
+    /// When the lost source was last probed.
+    probed: Mutex<Instant>,
+}
+
+impl SourceHealth {
+    pub fn new(root: OsString) -> Self {
+        Self {
+            root,
+            lost: AtomicBool::new(false),
+            probed: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Whether `errno` says the source itself is gone, rather than a single file.
+    pub fn is_lost_error(errno: libc::c_int) -> bool {
+        matches!(
+            errno,
+            libc::ESTALE | libc::ENOTCONN | libc::EIO | libc::EHOSTDOWN | libc::ENETDOWN
+        )
+    }
+
+    /// Whether `path` is on a network filesystem, whose calls hang or fail while the server is
+    /// away. FUSE filesystems count as well, as they are mostly sshfs and the like.
+    pub fn is_network(path: &OsStr) -> io::Result<bool> {
+        let path_c = CString::new(path.as_bytes())?;
+        let mut buf: libc::statfs = unsafe { std::mem::zeroed() };
+        if unsafe { libc::statfs(path_c.as_ptr(), &mut buf) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+        #[cfg(target_os = "linux")]
+        return Ok(NETWORK_MAGICS.contains(&(buf.f_type as u32)));
+        #[cfg(not(target_os = "linux"))]
+        {
+            let name = unsafe { CStr::from_ptr(buf.f_fstypename.as_ptr()) };
+            Ok(NETWORK_TYPES.contains(&name.to_bytes()))
+        }
+    }
+
+    /// Takes note of `errno` returned by the source, returning whether it means the source is
+    /// lost.
+    pub fn report(&self, errno: libc::c_int) -> bool {
+        if !Self::is_lost_error(errno) {
+            return false;
+        }
+        if !self.lost.swap(true, Ordering::AcqRel) {
+            warn!(
+                "Lost the source ({}), serving from the cache until it is back",
+                io::Error::from_raw_os_error(errno)
+            );
+        }
+        true
+    }
+
+    /// Whether the source is lost at the moment, probing it if it is and wasn't for a while.
+    pub fn is_lost(&self) -> bool {
+        if !self.lost.load(Ordering::Acquire) {
+            return false;
+        }
+        {
+            let mut probed = self.probed.lock().unwrap();
+            if probed.elapsed() < PROBE_INTERVAL {
+                return true;
+            }
+            *probed = Instant::now();
+        }
+        match libc_wrappers::lstat(self.root.clone()) {
+            Ok(_) => {
+                info!("The source is back");
+                self.lost.store(false, Ordering::Release);
+                false
+            }
+            Err(_) => true,
+        }
+    }
+}
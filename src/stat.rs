@@ -2,7 +2,7 @@
 use crate::libc_extras::libc;
 use crate::types::{SerializableFileAttr, SerializableFileType, SerializableTimespec};
 #[cfg(feature = "mount")]
-use fuse::FileType;
+use fuse_mt::FileType;
 #[cfg(feature = "mount")]
 use fuse_mt::{FileAttr, Statfs};
 
@@ -26,6 +26,15 @@ pub(crate) fn mode_to_filetype(mode: libc::mode_t) -> FileType {
     mode_to_filetype_serializable(mode).into()
 }
 
+/// The block size fuse_mt reports for every file, it doesn't let the filesystem choose one.
+pub(crate) const BLOCK_SIZE: u64 = 4096;
+
+/// The number of 512 byte blocks `size` bytes take up in whole [`BLOCK_SIZE`] blocks, as they
+/// would in a file on disk.
+pub(crate) fn size_to_blocks(size: u64) -> u64 {
+    size.div_ceil(BLOCK_SIZE) * (BLOCK_SIZE / 512)
+}
+
 pub(crate) fn stat_to_fuse_serializable(stat: libc::stat64) -> SerializableFileAttr {
     // st_mode encodes both the kind and the permissions
     let kind = mode_to_filetype_serializable(stat.st_mode);
@@ -62,7 +71,7 @@ pub(crate) fn stat_to_fuse(stat: libc::stat64) -> FileAttr {
     stat_to_fuse_serializable(stat).into()
 }
 
-#[cfg(all(any(target_os = "macos", target_os = "freebsd"), feature = "mount"))]
+#[cfg(all(target_os = "macos", feature = "mount"))]
 pub(crate) fn statfs_to_fuse(statfs: libc::statfs) -> Statfs {
     Statfs {
         blocks: statfs.f_blocks,
@@ -76,6 +85,21 @@ pub(crate) fn statfs_to_fuse(statfs: libc::statfs) -> Statfs {
     }
 }
 
+#[cfg(all(target_os = "freebsd", feature = "mount"))]
+pub(crate) fn statfs_to_fuse(statfs: libc::statfs) -> Statfs {
+    // f_bsize is what statvfs calls the fragment size there, and f_iosize the block size.
+    Statfs {
+        blocks: statfs.f_blocks,
+        bfree: statfs.f_bfree,
+        bavail: statfs.f_bavail.max(0) as u64,
+        files: statfs.f_files,
+        ffree: statfs.f_ffree.max(0) as u64,
+        bsize: statfs.f_iosize as u32,
+        namelen: statfs.f_namemax,
+        frsize: statfs.f_bsize as u32,
+    }
+}
+
 #[cfg(all(target_os = "linux", feature = "mount"))]
 pub(crate) fn statfs_to_fuse(statfs: libc::statfs) -> Statfs {
     Statfs {
@@ -89,3 +113,19 @@ pub(crate) fn statfs_to_fuse(statfs: libc::statfs) -> Statfs {
         frsize: statfs.f_frsize as u32,
     }
 }
+
+/// Describes a filesystem holding `blocks` blocks of 512 bytes in `files` entries, without any
+/// room left.
+#[cfg(feature = "mount")]
+pub(crate) fn cached_statfs(blocks: u64, files: u64) -> Statfs {
+    Statfs {
+        blocks,
+        bfree: 0,
+        bavail: 0,
+        files,
+        ffree: 0,
+        bsize: 512,
+        namelen: 255,
+        frsize: 512,
+    }
+}
@@ -0,0 +1,234 @@
+// Stress test :: Simulates the access patterns of UltraStar Deluxe against a mounted filesystem.
+//
+// Every worker thread repeatedly picks one of the operations USDX performs during a library scan
+// and song selection, and records how long it took.
+
+use crate::utils::AUDIO_EXTENSIONS;
+use anyhow::{anyhow, Result};
+use std::collections::hash_map::DefaultHasher;
+use std::fs::{self, File};
+use std::hash::{Hash, Hasher};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant, SystemTime};
+use walkdir::WalkDir;
+
+const AUDIO_HEADER_SIZE: u64 = 16 * 1024;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Operation {
+    Readdir,
+    Getattr,
+    OpenTxt,
+    AudioHeader,
+}
+
+const OPERATIONS: [Operation; 4] = [
+    Operation::Readdir,
+    Operation::Getattr,
+    Operation::OpenTxt,
+    Operation::AudioHeader,
+];
+
+impl Operation {
+    fn name(self) -> &'static str {
+        match self {
+            Operation::Readdir => "readdir",
+            Operation::Getattr => "getattr",
+            Operation::OpenTxt => "open txt",
+            Operation::AudioHeader => "audio header",
+        }
+    }
+}
+
+#[derive(Default)]
+struct Samples {
+    latencies: Vec<Duration>,
+    errors: u64,
+}
+
+/// Paths the workers pick their targets from.
+struct Targets {
+    dirs: Vec<PathBuf>,
+    files: Vec<PathBuf>,
+    txts: Vec<PathBuf>,
+    audio: Vec<PathBuf>,
+}
+
+impl Targets {
+    fn collect(mountpoint: &Path) -> Self {
+        let mut targets = Targets {
+            dirs: vec![],
+            files: vec![],
+            txts: vec![],
+            audio: vec![],
+        };
+        for entry in WalkDir::new(mountpoint).into_iter().filter_map(|e| e.ok()) {
+            let path = entry.path().to_path_buf();
+            if entry.file_type().is_dir() {
+                targets.dirs.push(path);
+                continue;
+            }
+            match path.extension().and_then(|x| x.to_str()) {
+                Some("txt") => targets.txts.push(path.clone()),
+                Some(x) if AUDIO_EXTENSIONS.contains(&x.to_lowercase().as_str()) => {
+                    targets.audio.push(path.clone())
+                }
+                _ => {}
+            }
+            targets.files.push(path);
+        }
+        targets
+    }
+
+    fn pick(&self, operation: Operation) -> &[PathBuf] {
+        match operation {
+            Operation::Readdir => &self.dirs,
+            Operation::Getattr => &self.files,
+            Operation::OpenTxt => &self.txts,
+            Operation::AudioHeader => &self.audio,
+        }
+    }
+}
+
+/// A xorshift generator, good enough to pick targets.
+struct Random(u64);
+
+impl Random {
+    fn new(seed: usize) -> Self {
+        let mut hasher = DefaultHasher::new();
+        seed.hash(&mut hasher);
+        SystemTime::now().hash(&mut hasher);
+        Random(hasher.finish() | 1)
+    }
+
+    fn below(&mut self, bound: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % bound as u64) as usize
+    }
+}
+
+fn run(operation: Operation, path: &Path) -> std::io::Result<()> {
+    match operation {
+        Operation::Readdir => {
+            for entry in fs::read_dir(path)? {
+                entry?.metadata()?;
+            }
+        }
+        Operation::Getattr => {
+            fs::symlink_metadata(path)?;
+        }
+        Operation::OpenTxt => {
+            File::open(path)?.read_to_end(&mut Vec::new())?;
+        }
+        Operation::AudioHeader => {
+            File::open(path)?
+                .take(AUDIO_HEADER_SIZE)
+                .read_to_end(&mut Vec::new())?;
+        }
+    }
+    Ok(())
+}
+
+fn worker(id: usize, targets: &Targets, deadline: Instant) -> Vec<Samples> {
+    let mut random = Random::new(id);
+    let mut samples: Vec<Samples> = OPERATIONS.iter().map(|_| Samples::default()).collect();
+
+    while Instant::now() < deadline {
+        let index = random.below(OPERATIONS.len());
+        let candidates = targets.pick(OPERATIONS[index]);
+        if candidates.is_empty() {
+            continue;
+        }
+        let path = &candidates[random.below(candidates.len())];
+
+        let start = Instant::now();
+        let result = run(OPERATIONS[index], path);
+        samples[index].latencies.push(start.elapsed());
+        if let Err(err) = result {
+            samples[index].errors += 1;
+            warn!("{} {:?}: {}", OPERATIONS[index].name(), path, err);
+        }
+    }
+    samples
+}
+
+fn percentile(sorted: &[Duration], percent: usize) -> String {
+    format!("{:?}", sorted[(sorted.len() - 1) * percent / 100])
+}
+
+pub fn stress<P: AsRef<Path>>(mountpoint: P, threads: usize, duration: Duration) -> Result<()> {
+    let mountpoint = mountpoint.as_ref();
+    if !mountpoint.is_dir() {
+        return Err(anyhow!("'{}' is no directory", mountpoint.display()));
+    }
+
+    println!("Collecting targets in '{}'", mountpoint.display());
+    let targets = Targets::collect(mountpoint);
+    println!(
+        "Found {} directories, {} files, {} song files and {} audio files",
+        targets.dirs.len(),
+        targets.files.len(),
+        targets.txts.len(),
+        targets.audio.len()
+    );
+
+    println!(
+        "Running {} threads for {} seconds",
+        threads,
+        duration.as_secs()
+    );
+    let deadline = Instant::now() + duration;
+    let targets = Arc::new(targets);
+    let handles: Vec<_> = (0..threads)
+        .map(|id| {
+            let targets = targets.clone();
+            thread::spawn(move || worker(id, &targets, deadline))
+        })
+        .collect();
+    let results = handles
+        .into_iter()
+        .map(|handle| handle.join().map_err(|_| anyhow!("Stress worker panicked")))
+        .collect::<Result<Vec<_>>>()?;
+
+    println!(
+        "{:<14}{:>10}{:>8}{:>12}{:>12}{:>12}{:>12}",
+        "operation", "count", "errors", "mean", "p50", "p99", "max"
+    );
+    let mut failed = false;
+    for (index, operation) in OPERATIONS.iter().enumerate() {
+        let mut latencies: Vec<Duration> = vec![];
+        let mut errors = 0;
+        for samples in &results {
+            latencies.extend_from_slice(&samples[index].latencies);
+            errors += samples[index].errors;
+        }
+        failed |= errors > 0;
+        if latencies.is_empty() {
+            println!("{:<14}{:>10}{:>8}", operation.name(), 0, errors);
+            continue;
+        }
+        latencies.sort();
+        let total: Duration = latencies.iter().sum();
+        println!(
+            "{:<14}{:>10}{:>8}{:>12}{:>12}{:>12}{:>12}",
+            operation.name(),
+            latencies.len(),
+            errors,
+            format!("{:?}", total / latencies.len() as u32),
+            percentile(&latencies, 50),
+            percentile(&latencies, 99),
+            percentile(&latencies, 100)
+        );
+    }
+
+    if failed {
+        Err(anyhow!("Some operations failed"))
+    } else {
+        Ok(())
+    }
+}
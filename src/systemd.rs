@@ -0,0 +1,83 @@
+// systemd :: Reports the state of a mount to systemd, for units of Type=notify.
+//
+// Messages are datagrams sent to the unix socket in $NOTIFY_SOCKET, see sd_notify(3). A name
+// starting with '@' refers to the abstract namespace. Without $NOTIFY_SOCKET nothing is sent.
+//
+
+use crate::daemon;
+use crate::libc_extras::libc;
+use anyhow::{anyhow, Context, Result};
+use std::env;
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::thread;
+
+const NOTIFY_SOCKET: &str = "NOTIFY_SOCKET";
+
+/// Sends `state`, e.g. `READY=1`, to systemd if it asked for it.
+pub fn notify(state: &str) {
+    if let Err(e) = try_notify(state) {
+        warn!("Unable to notify systemd: {:#}", e);
+    }
+}
+
+fn try_notify(state: &str) -> Result<()> {
+    let socket = match env::var_os(NOTIFY_SOCKET) {
+        Some(socket) if !socket.is_empty() => socket,
+        _ => return Ok(()),
+    };
+    let name = socket.as_bytes();
+
+    let mut addr: libc::sockaddr_un = unsafe { mem::zeroed() };
+    addr.sun_family = libc::AF_UNIX as libc::sa_family_t;
+    if name.len() >= addr.sun_path.len() {
+        return Err(anyhow!("{} is too long", NOTIFY_SOCKET));
+    }
+    for (dst, src) in addr.sun_path.iter_mut().zip(name) {
+        *dst = *src as libc::c_char;
+    }
+    if name[0] == b'@' {
+        addr.sun_path[0] = 0;
+    }
+    let len = mem::size_of::<libc::sa_family_t>() + name.len();
+
+    let fd = unsafe { libc::socket(libc::AF_UNIX, libc::SOCK_DGRAM | libc::SOCK_CLOEXEC, 0) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error()).context("Unable to create socket");
+    }
+    let sent = unsafe {
+        libc::sendto(
+            fd,
+            state.as_ptr() as *const libc::c_void,
+            state.len(),
+            libc::MSG_NOSIGNAL,
+            &addr as *const libc::sockaddr_un as *const libc::sockaddr,
+            len as libc::socklen_t,
+        )
+    };
+    let err = io::Error::last_os_error();
+    unsafe { libc::close(fd) };
+    if sent < 0 {
+        return Err(err).with_context(|| format!("Unable to send '{}'", state));
+    }
+    Ok(())
+}
+
+/// Reports the mount as ready once it shows up at `target`.
+pub fn notify_when_mounted(target: &Path) -> Result<()> {
+    if env::var_os(NOTIFY_SOCKET).is_none() {
+        return Ok(());
+    }
+    let unmounted = daemon::device(target)?;
+    let target = target.to_path_buf();
+    // The mount exits if mounting fails, so this doesn't have to give up.
+    thread::spawn(move || {
+        while !daemon::is_mounted(&target, unmounted) {
+            thread::sleep(daemon::POLL_INTERVAL);
+        }
+        notify("READY=1");
+    });
+    Ok(())
+}
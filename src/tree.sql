@@ -0,0 +1,11 @@
+CREATE TABLE [Entries] (
+    [ID] INTEGER  NOT NULL PRIMARY KEY,
+    [Parent] INTEGER  NULL REFERENCES [Entries]([ID]),
+    [Name] BLOB  NOT NULL,
+    [Dir] BOOLEAN  NOT NULL,
+    [Stat] TEXT  NOT NULL,
+    [Target] BLOB  NULL
+);
+CREATE UNIQUE INDEX [Entries_Parent_Name_IDX] ON [Entries]([Parent], [Name]);
+
+PRAGMA user_version = 2;
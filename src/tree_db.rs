@@ -0,0 +1,215 @@
+// Tree db :: The directory structure of a cache in an SQLite database.
+//
+// Every entry is a row pointing at the row of its directory, and the rows of a directory are
+// found through an index on that. A mount only reads the rows of the directories it looks into,
+// rather than parsing the whole structure up front, which the other formats only get close to by
+// sharding. SQLite can only open files, so the database is copied out of the cache first.
+//
+
+// diesel's `table!` and derives implement their traits inside of functions
+#![allow(non_local_definitions)]
+
+use crate::cache::{Contents, Entry};
+use crate::types::SerializableFileAttr;
+use anyhow::{anyhow, Context, Result};
+use diesel::connection::SimpleConnection;
+use diesel::prelude::*;
+use diesel::sqlite::SqliteConnection;
+use std::ffi::OsString;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::os::unix::ffi::{OsStrExt, OsStringExt};
+use std::sync::Mutex;
+use tempfile::NamedTempFile;
+
+table! {
+    #[allow(non_snake_case)]
+    Entries (ID) {
+        ID -> BigInt,
+        Parent -> Nullable<BigInt>,
+        Name -> Binary,
+        Dir -> Bool,
+        Stat -> Text,
+        Target -> Nullable<Binary>,
+    }
+}
+
+/// The schema version of `tree.sql`, in `PRAGMA user_version`.
+const SCHEMA_VERSION: i32 = 2;
+
+#[derive(QueryableByName)]
+struct UserVersion {
+    #[sql_type = "diesel::sql_types::Integer"]
+    user_version: i32,
+}
+
+/// A row of [`Entries`] without its parent: its id, name, whether it is a directory, stats and
+/// the target of a symlink.
+type Row = (i64, Vec<u8>, bool, String, Option<Vec<u8>>);
+
+/// The row of the root directory.
+const ROOT: i64 = 0;
+
+/// Writes `root` and everything below it as a database to `out`.
+pub fn write<W: Write>(root: &Entry, mut out: W) -> Result<()> {
+    let mut file = NamedTempFile::new().context("Unable to open temporary files.db file")?;
+    {
+        let conn = connect(&file)?;
+        conn.batch_execute(include_str!("tree.sql"))
+            .context("Failed to initialize database")?;
+        conn.transaction(|| {
+            let mut next = ROOT;
+            insert(&conn, root, None, &mut next)
+        })?;
+    }
+    file.seek(SeekFrom::Start(0))?;
+    io::copy(&mut file, &mut out).context("Unable to write files.db")?;
+    Ok(())
+}
+
+/// Adds `entry` and everything below it with rows from `next` on, in the directory `parent`.
+fn insert(
+    conn: &SqliteConnection,
+    entry: &Entry,
+    parent: Option<i64>,
+    next: &mut i64,
+) -> Result<()> {
+    let id = *next;
+    *next += 1;
+    let contents = match entry {
+        Entry::Dict { contents, .. } => Some(contents),
+        Entry::File { .. } | Entry::Link { .. } => None,
+    };
+    diesel::insert_into(Entries::table)
+        .values((
+            Entries::ID.eq(id),
+            Entries::Parent.eq(parent),
+            Entries::Name.eq(entry.name().as_bytes()),
+            Entries::Dir.eq(contents.is_some()),
+            Entries::Stat.eq(serde_json::to_string(entry.stat())?),
+            Entries::Target.eq(entry.target().map(OsStrExt::as_bytes)),
+        ))
+        .execute(conn)
+        .with_context(|| format!("Unable to add {:?} to files.db", entry.name()))?;
+    for child in contents.into_iter().flatten() {
+        insert(conn, child, Some(id), next)?;
+    }
+    Ok(())
+}
+
+fn connect(file: &NamedTempFile) -> Result<SqliteConnection> {
+    let path = file
+        .path()
+        .to_str()
+        .expect("NamedFile path is no valid UTF-8");
+    SqliteConnection::establish(path).context("Unable to open files.db")
+}
+
+/// The directory structure stored in a cache, read directory by directory.
+pub struct TreeDb {
+    /// Deleted once the database is closed.
+    _file: NamedTempFile,
+    conn: Mutex<SqliteConnection>,
+}
+
+impl TreeDb {
+    /// Opens the database in the archive member `member`.
+    pub fn extract<R: Read>(mut member: R) -> Result<Self> {
+        let mut file = NamedTempFile::new().context("Unable to open temporary files.db file")?;
+        io::copy(&mut member, &mut file).context("Unable to copy files.db out of the cache")?;
+        file.flush()?;
+        let conn = connect(&file)?;
+        migrate(&conn)?;
+        Ok(Self {
+            _file: file,
+            conn: Mutex::new(conn),
+        })
+    }
+
+    /// The root directory, see [`TreeDb::dir`].
+    pub fn root(&self) -> Result<(Entry, Vec<(OsString, i64)>)> {
+        self.dir(ROOT)
+    }
+
+    /// The directory in row `id` with the entries directly in it, those in its subdirectories
+    /// left out, and the rows of these subdirectories by their name.
+    pub fn dir(&self, id: i64) -> Result<(Entry, Vec<(OsString, i64)>)> {
+        let conn = self.conn.lock().unwrap();
+        let (name, stat): (Vec<u8>, String) = Entries::table
+            .find(id)
+            .filter(Entries::Dir.eq(true))
+            .select((Entries::Name, Entries::Stat))
+            .first(&*conn)
+            .with_context(|| format!("files.db holds no directory {}", id))?;
+        let rows: Vec<Row> = Entries::table
+            .filter(Entries::Parent.eq(id))
+            .order(Entries::Name)
+            .select((
+                Entries::ID,
+                Entries::Name,
+                Entries::Dir,
+                Entries::Stat,
+                Entries::Target,
+            ))
+            .load(&*conn)
+            .with_context(|| format!("Unable to read directory {} of files.db", id))?;
+
+        let mut contents = Vec::with_capacity(rows.len());
+        let mut dirs = Vec::new();
+        for (id, name, is_dir, stat, target) in rows {
+            let name = OsString::from_vec(name);
+            let stat = parse_stat(&name, &stat)?;
+            if is_dir {
+                dirs.push((name.clone(), id));
+                contents.push(Entry::Dict {
+                    name,
+                    contents: Contents::default(),
+                    stat,
+                });
+            } else if let Some(target) = target {
+                contents.push(Entry::Link {
+                    name,
+                    stat,
+                    target: OsString::from_vec(target),
+                });
+            } else {
+                contents.push(Entry::File { name, stat });
+            }
+        }
+        let name = OsString::from_vec(name);
+        let stat = parse_stat(&name, &stat)?;
+        Ok((
+            Entry::Dict {
+                name,
+                contents: contents.into(),
+                stat,
+            },
+            dirs,
+        ))
+    }
+}
+
+/// Brings a database written by an older version up to the current schema.
+fn migrate(conn: &SqliteConnection) -> Result<()> {
+    let version = diesel::sql_query("PRAGMA user_version")
+        .get_result::<UserVersion>(conn)
+        .context("Unable to read the schema version of files.db")?
+        .user_version;
+    if version > SCHEMA_VERSION {
+        return Err(anyhow!(
+            "files.db has schema version {}, but only versions up to {} are supported",
+            version,
+            SCHEMA_VERSION
+        ));
+    }
+    // Version 1 stored symlinks as files, without their target.
+    if version < 2 {
+        conn.batch_execute("ALTER TABLE [Entries] ADD COLUMN [Target] BLOB NULL;")
+            .context("Unable to migrate files.db")?;
+    }
+    Ok(())
+}
+
+fn parse_stat(name: &OsString, stat: &str) -> Result<SerializableFileAttr> {
+    serde_json::from_str(stat)
+        .map_err(|e| anyhow!("Attributes of {:?} in files.db are malformed: {}", name, e))
+}
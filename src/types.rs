@@ -1,11 +1,9 @@
 #[cfg(feature = "mount")]
-use fuse::FileType;
-#[cfg(feature = "mount")]
-use fuse_mt::FileAttr;
+use fuse_mt::{FileAttr, FileType};
 use serde::{Deserialize, Serialize};
-use time::Timespec;
 
 use std::convert::{From, Into};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 #[derive(Clone, Copy, Debug, Serialize, Deserialize)]
 pub struct SerializableFileAttr {
@@ -134,20 +132,38 @@ pub struct SerializableTimespec {
     pub nsec: i32,
 }
 
-impl From<Timespec> for SerializableTimespec {
-    fn from(timespec: Timespec) -> Self {
-        Self {
-            sec: timespec.sec,
-            nsec: timespec.nsec,
+impl From<SystemTime> for SerializableTimespec {
+    fn from(time: SystemTime) -> Self {
+        match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => Self {
+                sec: since.as_secs() as i64,
+                nsec: since.subsec_nanos() as i32,
+            },
+            // Like struct timespec, the nanoseconds count forward from the second before.
+            Err(e) => {
+                let before = e.duration();
+                match before.subsec_nanos() {
+                    0 => Self {
+                        sec: -(before.as_secs() as i64),
+                        nsec: 0,
+                    },
+                    nsec => Self {
+                        sec: -(before.as_secs() as i64) - 1,
+                        nsec: (1_000_000_000 - nsec) as i32,
+                    },
+                }
+            }
         }
     }
 }
 
-impl Into<Timespec> for SerializableTimespec {
-    fn into(self) -> Timespec {
-        Timespec {
-            sec: self.sec,
-            nsec: self.nsec,
+impl From<SerializableTimespec> for SystemTime {
+    fn from(time: SerializableTimespec) -> Self {
+        let nsec = Duration::from_nanos(time.nsec as u64);
+        if time.sec >= 0 {
+            UNIX_EPOCH + Duration::from_secs(time.sec as u64) + nsec
+        } else {
+            UNIX_EPOCH - Duration::from_secs(-time.sec as u64) + nsec
         }
     }
 }
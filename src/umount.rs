@@ -0,0 +1,97 @@
+// Umount :: Unmounts a running mount, the way fusermount -u does.
+//
+// Unprivileged users can only unmount FUSE filesystems through the setuid fusermount helper, so
+// it is called after checking that there actually is a FUSE mount at the target.
+//
+
+use anyhow::{anyhow, Context, Result};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+#[cfg(target_os = "linux")]
+const COMMANDS: &[&str] = &["fusermount", "fusermount3"];
+#[cfg(not(target_os = "linux"))]
+const COMMANDS: &[&str] = &["umount"];
+
+/// Unmounts the FUSE filesystem mounted at `target`.
+pub fn umount<P: AsRef<Path>>(target: P) -> Result<()> {
+    // A mount whose process died can't be resolved anymore, so don't canonicalize.
+    let target = absolute(target.as_ref())?;
+    #[cfg(target_os = "linux")]
+    check_mounted(&target)?;
+
+    for command in COMMANDS {
+        let mut cmd = Command::new(command);
+        #[cfg(target_os = "linux")]
+        cmd.arg("-u");
+        match cmd.arg(&target).status() {
+            Ok(status) if status.success() => {
+                println!("Unmounted '{}'", target.display());
+                return Ok(());
+            }
+            Ok(status) => return Err(anyhow!("{} failed with {}", command, status)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => continue,
+            Err(e) => return Err(e).with_context(|| format!("Unable to run {}", command)),
+        }
+    }
+    Err(anyhow!("Unable to find {}", COMMANDS.join(" or ")))
+}
+
+fn absolute(path: &Path) -> Result<PathBuf> {
+    if path.is_absolute() {
+        return Ok(path.to_path_buf());
+    }
+    let cwd = std::env::current_dir().context("Unable to get the current directory")?;
+    Ok(cwd.join(path))
+}
+
+/// Fails unless a FUSE filesystem is mounted at `target`.
+#[cfg(target_os = "linux")]
+fn check_mounted(target: &Path) -> Result<()> {
+    let mounts = std::fs::read_to_string("/proc/mounts").context("Unable to read /proc/mounts")?;
+    let fstype = mounts.lines().find_map(|line| {
+        let mut fields = line.split(' ');
+        let mount_point = unescape(fields.nth(1)?);
+        let fstype = fields.next()?;
+        if Path::new(&mount_point) == target {
+            Some(fstype)
+        } else {
+            None
+        }
+    });
+    match fstype {
+        Some(fstype) if fstype == "fuse" || fstype.starts_with("fuse.") => Ok(()),
+        Some(fstype) => Err(anyhow!(
+            "'{}' is no FUSE mount but {}",
+            target.display(),
+            fstype
+        )),
+        None => Err(anyhow!("Nothing is mounted at '{}'", target.display())),
+    }
+}
+
+/// Undoes the octal escapes of spaces, tabs, newlines and backslashes in /proc/mounts.
+#[cfg(target_os = "linux")]
+fn unescape(field: &str) -> String {
+    let mut unescaped = String::with_capacity(field.len());
+    let mut rest = field;
+    while let Some(index) = rest.find('\\') {
+        unescaped.push_str(&rest[..index]);
+        let code = rest
+            .get(index + 1..index + 4)
+            .and_then(|code| u8::from_str_radix(code, 8).ok());
+        match code {
+            Some(code) => {
+                unescaped.push(code as char);
+                rest = &rest[index + 4..];
+            }
+            None => {
+                unescaped.push('\\');
+                rest = &rest[index + 1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    unescaped
+}
@@ -0,0 +1,143 @@
+// Uring :: Reads from the source through io_uring.
+//
+// Every FUSE thread reading from the source otherwise blocks in its own pread, one request per
+// thread on its way to the disk or server. Reads are submitted to one ring instead, where the
+// kernel has all of them in flight at once and can order them for a spinning disk or pipeline
+// them over the network. A reaper thread, started along with the first read, waits for their
+// completions and hands each one back to the thread that submitted it.
+//
+
+use crate::libc_extras::libc;
+use anyhow::{Context, Result};
+use io_uring::{opcode, types, IoUring};
+use std::collections::HashMap;
+use std::io;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{sync_channel, SyncSender};
+use std::sync::{Arc, Mutex, Once};
+use std::thread;
+
+/// Reads that can be queued at once, beyond that submitting waits for the kernel to take some.
+const ENTRIES: u32 = 256;
+
+type Pending = HashMap<u64, SyncSender<i32>>;
+
+pub struct Uring {
+    ring: Arc<IoUring>,
+    /// Held while pushing to the submission queue, which only one thread may use at a time.
+    submission: Mutex<()>,
+    /// Where the results of the reads in flight go, by their user data. `None` once the reaper
+    /// is gone.
+    pending: Arc<Mutex<Option<Pending>>>,
+    /// The reaper is started on the first read, so it runs in the process that serves the mount
+    /// once it daemonized, and after its signal handlers were set up.
+    reaper: Once,
+    next: AtomicU64,
+}
+
+impl Uring {
+    /// Sets up the ring, the thread reaping its completions is only started by the first read.
+    pub fn new() -> Result<Self> {
+        let ring = Arc::new(IoUring::new(ENTRIES).context("Unable to set up io_uring")?);
+        Ok(Self {
+            ring,
+            submission: Mutex::new(()),
+            pending: Arc::new(Mutex::new(Some(Pending::new()))),
+            reaper: Once::new(),
+            next: AtomicU64::new(0),
+        })
+    }
+
+    fn start_reaper(&self) {
+        let ring = self.ring.clone();
+        let pending = self.pending.clone();
+        let started = thread::Builder::new()
+            .name("uring-reaper".into())
+            .spawn(move || reap(&ring, &pending));
+        if let Err(e) = started {
+            error!("Unable to start the io_uring reaper: {}", e);
+            // fails all reads
+            *self.pending.lock().unwrap() = None;
+        }
+    }
+
+    /// Reads up to `size` bytes at `offset` from `fd`, returning what was read.
+    pub fn read(&self, fd: u64, offset: u64, size: usize) -> Result<Vec<u8>, libc::c_int> {
+        self.reaper.call_once(|| self.start_reaper());
+        let mut data = vec![0u8; size];
+        let key = self.next.fetch_add(1, Ordering::Relaxed);
+        let (tx, rx) = sync_channel(1);
+        match &mut *self.pending.lock().unwrap() {
+            Some(pending) => pending.insert(key, tx),
+            None => return Err(libc::EIO),
+        };
+
+        let entry = opcode::Read::new(types::Fd(fd as i32), data.as_mut_ptr(), size as u32)
+            .offset(offset as _)
+            .build()
+            .user_data(key);
+        if let Err(e) = self.submit(&entry) {
+            if let Some(pending) = &mut *self.pending.lock().unwrap() {
+                pending.remove(&key);
+            }
+            return Err(e);
+        }
+
+        match rx.recv() {
+            Ok(result) if result < 0 => Err(-result),
+            Ok(read) => {
+                data.truncate(read as usize);
+                Ok(data)
+            }
+            Err(_) => {
+                // The kernel may still write into it, so it has to stay around.
+                std::mem::forget(data);
+                error!("The io_uring reaper is gone");
+                Err(libc::EIO)
+            }
+        }
+    }
+
+    fn submit(&self, entry: &io_uring::squeue::Entry) -> Result<(), libc::c_int> {
+        let _submission = self.submission.lock().unwrap();
+        // the queue is only used while holding the lock
+        while unsafe { self.ring.submission_shared().push(entry) }.is_err() {
+            // full, submitting what is queued makes room
+            if let Err(e) = self.ring.submitter().submit() {
+                error!("Unable to submit to io_uring: {}", e);
+                return Err(e.raw_os_error().unwrap_or(libc::EIO));
+            }
+        }
+        if let Err(e) = self.ring.submitter().submit() {
+            // The read is queued already and goes along with the next submission.
+            warn!("Unable to submit to io_uring: {}", e);
+        }
+        Ok(())
+    }
+}
+
+/// Waits for completions on `ring` and sends their results to whoever waits for them in
+/// `pending`.
+fn reap(ring: &IoUring, pending: &Mutex<Option<Pending>>) {
+    loop {
+        if let Err(e) = ring.submitter().submit_and_wait(1) {
+            if e.kind() == io::ErrorKind::Interrupted || e.raw_os_error() == Some(libc::EBUSY) {
+                continue;
+            }
+            error!("Unable to wait for io_uring completions: {}", e);
+            // fails the reads waiting and those to come
+            *pending.lock().unwrap() = None;
+            return;
+        }
+        // this is the only thread using the completion queue
+        for completion in unsafe { ring.completion_shared() } {
+            let tx = match &mut *pending.lock().unwrap() {
+                Some(pending) => pending.remove(&completion.user_data()),
+                None => None,
+            };
+            if let Some(tx) = tx {
+                let _ = tx.send(completion.result());
+            }
+        }
+    }
+}
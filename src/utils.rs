@@ -1,4 +1,17 @@
+use std::borrow::Cow;
 use std::path::Path;
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+
+pub const AUDIO_EXTENSIONS: &[&str] = &["mp3", "ogg", "m4a", "flac", "wav", "opus"];
+pub const IMAGE_EXTENSIONS: &[&str] = &["jpg", "jpeg", "png", "bmp"];
+pub const VIDEO_EXTENSIONS: &[&str] = &["mp4", "avi", "mkv", "webm", "mpg", "mpeg", "divx", "flv"];
+
+/// Whether the extension of `path` is one of `extensions`, ignoring case.
+pub fn has_extension(path: &Path, extensions: &[&str]) -> bool {
+    path.extension()
+        .and_then(|x| x.to_str())
+        .is_some_and(|x| extensions.contains(&x.to_lowercase().as_str()))
+}
 
 pub fn path_to_rel(path: &Path) -> &Path {
     if path.starts_with("/") {
@@ -9,3 +22,47 @@ pub fn path_to_rel(path: &Path) -> &Path {
         path
     }
 }
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Decodes `hex`, two digits per byte, or returns `None` if it isn't.
+pub fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+/// `name` in Unicode normalization form C, with characters composed wherever possible.
+pub fn nfc(name: &str) -> Cow<'_, str> {
+    if is_nfc(name) {
+        Cow::Borrowed(name)
+    } else {
+        Cow::Owned(name.nfc().collect())
+    }
+}
+
+/// Characters of the bytes 0x80 to 0x9F in Windows-1252, the rest is the same as in Latin-1.
+/// Bytes it leaves undefined are mapped to the control characters Latin-1 has there.
+const CP1252_HIGH: [char; 32] = [
+    '\u{20AC}', '\u{0081}', '\u{201A}', '\u{0192}', '\u{201E}', '\u{2026}', '\u{2020}', '\u{2021}',
+    '\u{02C6}', '\u{2030}', '\u{0160}', '\u{2039}', '\u{0152}', '\u{008D}', '\u{017D}', '\u{008F}',
+    '\u{0090}', '\u{2018}', '\u{2019}', '\u{201C}', '\u{201D}', '\u{2022}', '\u{2013}', '\u{2014}',
+    '\u{02DC}', '\u{2122}', '\u{0161}', '\u{203A}', '\u{0153}', '\u{009D}', '\u{017E}', '\u{0178}',
+];
+
+/// Decodes `bytes` as Windows-1252, which every byte sequence is.
+pub fn decode_cp1252(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|&byte| match byte {
+            0x80..=0x9F => CP1252_HIGH[(byte - 0x80) as usize],
+            _ => char::from(byte),
+        })
+        .collect()
+}
@@ -0,0 +1,210 @@
+// Watch :: Keeps the cache in line with the source while mounted.
+//
+// inotify has to watch every directory on its own, so a watch is added for each directory of the
+// source up front and for new ones as they show up. Every change refreshes the affected entry,
+// which from then on is served from the source instead of the cache.
+//
+
+use crate::attr_cache::AttrCache;
+use crate::cache::Cache;
+use crate::invalidate::Invalidator;
+use crate::libc_extras::libc;
+use anyhow::{anyhow, Context, Result};
+use std::collections::HashMap;
+use std::ffi::{CString, OsStr};
+use std::io;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use walkdir::WalkDir;
+
+const EVENTS: u32 = libc::IN_CREATE
+    | libc::IN_DELETE
+    | libc::IN_MOVED_FROM
+    | libc::IN_MOVED_TO
+    | libc::IN_CLOSE_WRITE
+    | libc::IN_ATTRIB
+    | libc::IN_ONLYDIR
+    | libc::IN_DONT_FOLLOW;
+
+struct Watcher {
+    fd: libc::c_int,
+    source: PathBuf,
+    cache: Arc<RwLock<Arc<Cache>>>,
+    attrs: Option<Arc<AttrCache>>,
+    kernel: Arc<Invalidator>,
+    /// Watch descriptors and the directories they belong to, relative to the source.
+    watches: HashMap<libc::c_int, PathBuf>,
+}
+
+/// Starts watching `source` in a background thread, updating whichever cache is active,
+/// forgetting the attributes in `attrs` that changed and telling the kernel about them.
+pub fn watch<P: Into<PathBuf>>(
+    source: P,
+    cache: Arc<RwLock<Arc<Cache>>>,
+    attrs: Option<Arc<AttrCache>>,
+    kernel: Arc<Invalidator>,
+) -> Result<()> {
+    let fd = unsafe { libc::inotify_init1(libc::IN_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error()).context("Unable to initialize inotify");
+    }
+    let mut watcher = Watcher {
+        fd,
+        source: source.into(),
+        cache,
+        attrs,
+        kernel,
+        watches: HashMap::new(),
+    };
+    watcher.add_tree(Path::new(""))?;
+    info!("Watching {} directories", watcher.watches.len());
+
+    thread::spawn(move || watcher.run());
+    Ok(())
+}
+
+impl Watcher {
+    fn add_tree(&mut self, path: &Path) -> Result<()> {
+        let root = self.source.join(path);
+        for entry in WalkDir::new(&root).into_iter().filter_map(|e| e.ok()) {
+            if !entry.file_type().is_dir() {
+                continue;
+            }
+            let rel = entry
+                .path()
+                .strip_prefix(&self.source)
+                .expect("WalkDir yields paths below its root")
+                .to_path_buf();
+            self.add_watch(entry.path(), rel)?;
+        }
+        Ok(())
+    }
+
+    fn add_watch(&mut self, real: &Path, rel: PathBuf) -> Result<()> {
+        let path_c = CString::new(real.as_os_str().as_bytes())?;
+        let wd = unsafe { libc::inotify_add_watch(self.fd, path_c.as_ptr(), EVENTS) };
+        if wd < 0 {
+            let e = io::Error::last_os_error();
+            return match e.raw_os_error() {
+                // removed in the meantime
+                Some(libc::ENOENT) => Ok(()),
+                Some(libc::ENOSPC) => Err(anyhow!(
+                    "Out of inotify watches, raise fs.inotify.max_user_watches"
+                )),
+                _ => Err(e).with_context(|| format!("Unable to watch '{}'", real.display())),
+            };
+        }
+        self.watches.insert(wd, rel);
+        Ok(())
+    }
+
+    /// Stops watching `path` and everything below it.
+    fn remove_tree(&mut self, path: &Path) {
+        let fd = self.fd;
+        self.watches.retain(|wd, dir| {
+            if dir.starts_with(path) {
+                unsafe { libc::inotify_rm_watch(fd, *wd) };
+                false
+            } else {
+                true
+            }
+        });
+    }
+
+    fn run(mut self) {
+        let mut buf = vec![0u8; 64 * 1024];
+        loop {
+            let n =
+                unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut libc::c_void, buf.len()) };
+            if n < 0 {
+                let e = io::Error::last_os_error();
+                if e.kind() == io::ErrorKind::Interrupted {
+                    continue;
+                }
+                error!("watch: unable to read events, no longer watching: {}", e);
+                return;
+            }
+
+            let mut offset = 0;
+            while offset < n as usize {
+                let event: libc::inotify_event = unsafe {
+                    ptr::read_unaligned(buf[offset..].as_ptr() as *const libc::inotify_event)
+                };
+                let name_start = offset + mem::size_of::<libc::inotify_event>();
+                offset = name_start + event.len as usize;
+                // the name is padded with NUL bytes
+                let name = &buf[name_start..offset];
+                let name = match name.iter().position(|&b| b == 0) {
+                    Some(end) => &name[..end],
+                    None => name,
+                };
+                self.handle(event.wd, event.mask, OsStr::from_bytes(name));
+            }
+        }
+    }
+
+    fn handle(&mut self, wd: libc::c_int, mask: u32, name: &OsStr) {
+        if mask & libc::IN_Q_OVERFLOW != 0 {
+            warn!("watch: events were lost, the cache may be out of date until remounting");
+            return;
+        }
+        if mask & libc::IN_IGNORED != 0 {
+            self.watches.remove(&wd);
+            return;
+        }
+        let dir = match self.watches.get(&wd) {
+            Some(dir) => dir.clone(),
+            None => return,
+        };
+        if name.is_empty() {
+            // the watched directory itself changed
+            self.refresh(&dir, false);
+            self.kernel.inode(&dir);
+            return;
+        }
+
+        let path = dir.join(name);
+        let is_dir = mask & libc::IN_ISDIR != 0;
+        let created = mask & (libc::IN_CREATE | libc::IN_MOVED_TO) != 0;
+        let removed = mask & (libc::IN_DELETE | libc::IN_MOVED_FROM) != 0;
+        debug!("watch: {:?} (mask = {:#x})", path, mask);
+
+        if is_dir && removed {
+            self.remove_tree(&path);
+            if let Some(attrs) = &self.attrs {
+                attrs.forget(&path, true);
+            }
+        }
+        if is_dir && created {
+            if let Err(e) = self.add_tree(&path) {
+                warn!("watch: {:#}", e);
+            }
+        }
+        self.refresh(&path, created);
+        self.kernel.entry(&path);
+        if created || removed {
+            self.refresh(&dir, false);
+            self.kernel.inode(&dir);
+        }
+    }
+
+    fn refresh(&self, path: &Path, recursive: bool) {
+        if let Some(attrs) = &self.attrs {
+            attrs.forget(path, recursive);
+        }
+        let cache = self.cache.read().unwrap().clone();
+        if let Err(e) = cache.refresh(path, &self.source.join(path), recursive) {
+            warn!("watch: unable to refresh {:?}: {:#}", path, e);
+        }
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        unsafe { libc::close(self.fd) };
+    }
+}
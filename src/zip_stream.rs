@@ -0,0 +1,142 @@
+// Zip stream :: Decompresses a file from the cache only as far as it is read.
+//
+// The zip crate's readers borrow the archive, so a stream decodes the raw member data itself
+// from its own view of the cache file. The last `WINDOW` bytes before a read are kept, so
+// reads going back a little don't start over. Anything further back decompresses the whole file
+// once and serves it from memory from then on.
+//
+
+use crate::cache::SharedFile;
+use bzip2::read::BzDecoder;
+use flate2::read::DeflateDecoder;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::Arc;
+use zip::read::ZipFile;
+use zip::CompressionMethod;
+
+/// Decompressed bytes kept before the offset of the latest read.
+const WINDOW: u64 = 64 * 1024;
+
+pub struct ZipStream {
+    file: SharedFile,
+    data_start: u64,
+    compressed_size: u64,
+    method: CompressionMethod,
+    size: u64,
+    decoder: Box<dyn Read + Send>,
+    /// How far `decoder` got.
+    position: u64,
+    /// The decompressed bytes from `window_start` up to `position`.
+    window: Vec<u8>,
+    window_start: u64,
+    /// The whole content, once it was needed.
+    whole: Option<Arc<[u8]>>,
+}
+
+impl ZipStream {
+    /// Streams the member `entry` from `file`, or returns `None` if it can't be decoded here.
+    pub fn new(file: SharedFile, entry: &ZipFile<'_>) -> Option<io::Result<Self>> {
+        let supported = matches!(
+            entry.compression(),
+            CompressionMethod::Stored
+                | CompressionMethod::Deflated
+                | CompressionMethod::Bzip2
+                | CompressionMethod::Zstd
+        );
+        if !supported {
+            return None;
+        }
+        let data_start = entry.data_start();
+        let compressed_size = entry.compressed_size();
+        let method = entry.compression();
+        Some(
+            decoder(&file, data_start, compressed_size, method).map(|decoder| Self {
+                file,
+                data_start,
+                compressed_size,
+                method,
+                size: entry.size(),
+                decoder,
+                position: 0,
+                window: Vec::new(),
+                window_start: 0,
+                whole: None,
+            }),
+        )
+    }
+
+    /// The up to `size` bytes at `offset`.
+    pub fn read_at(&mut self, offset: u64, size: usize) -> io::Result<&[u8]> {
+        if self.whole.is_none() && offset < self.window_start {
+            self.materialize()?;
+        }
+        if self.whole.is_some() {
+            let whole = self.whole.as_deref().unwrap();
+            let start = offset.min(whole.len() as u64) as usize;
+            let end = start.saturating_add(size).min(whole.len());
+            return Ok(&whole[start..end]);
+        }
+
+        let end = offset.saturating_add(size as u64).min(self.size);
+        let keep_from = offset.saturating_sub(WINDOW);
+        while self.position < end {
+            let len = self.window.len();
+            let chunk = (end - self.position).min(WINDOW) as usize;
+            self.window.resize(len + chunk, 0);
+            let n = self.decoder.read(&mut self.window[len..])?;
+            self.window.truncate(len + n);
+            if n == 0 {
+                // shorter than the archive claims
+                break;
+            }
+            self.position += n as u64;
+            // Skipping ahead mustn't hold on to everything in between.
+            self.forget_before(keep_from);
+        }
+        self.forget_before(keep_from);
+
+        let start = (offset.min(self.position) - self.window_start) as usize;
+        let end = (end.min(self.position) - self.window_start) as usize;
+        Ok(&self.window[start..end.max(start)])
+    }
+
+    /// Drops the decompressed bytes before `offset` from the window.
+    fn forget_before(&mut self, offset: u64) {
+        let keep = offset.max(self.window_start).min(self.position);
+        self.window.drain(..(keep - self.window_start) as usize);
+        self.window_start = keep;
+    }
+
+    /// Decompresses the whole file, so it can be read anywhere.
+    fn materialize(&mut self) -> io::Result<()> {
+        let mut decoder = decoder(
+            &self.file,
+            self.data_start,
+            self.compressed_size,
+            self.method,
+        )?;
+        let mut whole = Vec::with_capacity(self.size as usize);
+        decoder.read_to_end(&mut whole)?;
+        self.whole = Some(whole.into());
+        self.window = Vec::new();
+        Ok(())
+    }
+}
+
+fn decoder(
+    file: &SharedFile,
+    data_start: u64,
+    compressed_size: u64,
+    method: CompressionMethod,
+) -> io::Result<Box<dyn Read + Send>> {
+    let mut file = file.clone();
+    file.seek(SeekFrom::Start(data_start))?;
+    let raw = file.take(compressed_size);
+    Ok(match method {
+        CompressionMethod::Deflated => Box::new(DeflateDecoder::new(raw)),
+        CompressionMethod::Bzip2 => Box::new(BzDecoder::new(raw)),
+        CompressionMethod::Zstd => Box::new(zstd::stream::read::Decoder::new(raw)?),
+        // checked by ZipStream::new
+        _ => Box::new(raw),
+    })
+}